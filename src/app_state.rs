@@ -18,8 +18,10 @@
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::errors::*;
+use crate::met_controller::ControllerMsg;
+use error_chain::bail;
 use std::io::{stdin, Read};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -31,6 +33,14 @@ pub trait AppState {
     // Interprets a key-press, given the amount of time since the last
     // event.
     fn keypress(&mut self, key: Keycode, time: Duration) -> (StateTransition, TickCommand);
+
+    // Carries out a request sent by a frontend other than the
+    // keyboard, which has already been translated into a message.
+    fn message(
+        &mut self,
+        msg: ControllerMsg,
+        source: InputSource,
+    ) -> (StateTransition, TickCommand);
 }
 
 // A transition from one application state to another.
@@ -69,6 +79,34 @@ pub enum TickCommand {
     Toggle,
 }
 
+// What to do once stdin closes, e.g. when the metronome was started
+// detached from a terminal or its SSH session dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StdinClose {
+    // Quit the program.
+    #[default]
+    Quit,
+
+    // Pause, and carry on without a display, leaving the other
+    // frontends in control.
+    Pause,
+
+    // Keep playing without a display.
+    Play,
+}
+
+impl StdinClose {
+    // Parses a behavior's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<StdinClose> {
+        Ok(match name {
+            "quit" => StdinClose::Quit,
+            "pause" => StdinClose::Pause,
+            "play" => StdinClose::Play,
+            _ => bail!(format!("Unknown stdin close behavior {}", name)),
+        })
+    }
+}
+
 // Outputs from the keyboard thread.
 pub enum Keycode {
     // Successfully received a key, here it is as a raw u8 byte.
@@ -78,107 +116,221 @@ pub enum Keycode {
     NoKey,
 }
 
-// Runs the main program loop, given the initial state.
-pub fn state_loop(init_state: Box<dyn AppState>) -> Result<()> {
-    let kbd = init_kbd_thread();
+// The frontends inputs can come from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputSource {
+    // The terminal's keyboard.
+    Keyboard,
 
-    let mut state = init_state;
-    let mut tick_time: Option<Duration> = Some(Duration::new(0, 0));
-    let mut paused = false;
+    // A signal sent to the process, e.g. SIGTERM.
+    Signal,
 
-    let mut exit = false;
-    while !exit {
-        let start_time = Instant::now();
-        let key = if !paused {
-            if let Some(tick_time) = tick_time {
-                kbd.recv_timeout(tick_time)
-            } else {
-                Ok(kbd.recv()?)
-            }
+    // The JACK transport.
+    Jack,
+
+    // A remote control, over OSC.
+    Osc,
+
+    // A remote control, over the HTTP API.
+    Http,
+
+    // Another instance of the metronome, over the control socket.
+    Control,
+}
+
+// The inputs frontends can send.
+pub enum InputEvent {
+    // A raw key press, to be interpreted by the current state.
+    Key(Keycode),
+
+    // A request that has already been interpreted.
+    Message(ControllerMsg),
+}
+
+// An input, tagged with the frontend it came from.
+pub struct Input {
+    pub source: InputSource,
+    pub event: InputEvent,
+}
+
+// Handle through which a frontend sends its inputs.
+pub type InputSender = Sender<Input>;
+
+// Queue that all the frontends feed their inputs into, so that any
+// number of them can control the application at once. Each frontend
+// gets its own sender, typically running on its own thread.
+pub struct InputQueue {
+    send: InputSender,
+    recv: Receiver<Input>,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        let (send, recv) = channel();
+        InputQueue { send, recv }
+    }
+
+    // Gets a handle a frontend can send inputs through.
+    pub fn sender(&self) -> InputSender {
+        self.send.clone()
+    }
+
+    // Takes the receiving end of the queue. Only the frontends hold
+    // senders from here on, so the receiver reports an error if they
+    // all go away.
+    fn into_receiver(self) -> Receiver<Input> {
+        self.recv
+    }
+}
+
+impl Default for InputQueue {
+    fn default() -> InputQueue {
+        InputQueue::new()
+    }
+}
+
+// Bookkeeping for the main loop: the current state, and when it next
+// wants to tick. The loop below just waits for the next input or tick
+// and hands it to step().
+struct StateManager {
+    state: Box<dyn AppState>,
+
+    // Time until the next tick, as of the start of the current wait.
+    tick_time: Option<Duration>,
+    paused: bool,
+    exit: bool,
+}
+
+impl StateManager {
+    fn new(state: Box<dyn AppState>) -> StateManager {
+        StateManager {
+            state,
+            tick_time: Some(Duration::new(0, 0)),
+            paused: false,
+            exit: false,
+        }
+    }
+
+    // How long to wait for an input before ticking, or None to wait
+    // for an input indefinitely.
+    fn timeout(&self) -> Option<Duration> {
+        if self.paused {
+            None
         } else {
-            Ok(kbd.recv()?)
-        };
+            self.tick_time
+        }
+    }
 
-        let (st, tc) = if let Ok(key) = key {
-            let tmp = state.keypress(key, start_time.elapsed());
-            if let Some(tick_time_unwrapped) = tick_time {
-                if !paused {
-                    tick_time = match tick_time_unwrapped.checked_sub(start_time.elapsed()) {
-                        Some(time) => Some(time),
-                        None => Some(Duration::new(0, 0)),
-                    };
+    // Runs one iteration of the loop, given the input that ended the
+    // wait (None if it timed out) and how long the wait took.
+    fn step(&mut self, input: Option<Input>, waited: Duration) {
+        let (st, tc) = match input {
+            Some(input) => {
+                let tmp = match input.event {
+                    InputEvent::Key(key) => self.state.keypress(key, waited),
+                    InputEvent::Message(msg) => {
+                        log::debug!("Message {:?} from {:?}", msg, input.source);
+                        self.state.message(msg, input.source)
+                    }
+                };
+                if let Some(tick_time) = self.tick_time {
+                    if !self.paused {
+                        self.tick_time = match tick_time.checked_sub(waited) {
+                            Some(time) => Some(time),
+                            None => Some(Duration::new(0, 0)),
+                        };
+                    }
                 }
+                tmp
             }
-            tmp
-        } else {
-            state.tick()
+            None => self.state.tick(),
         };
-        proc_transition(st, tc, &mut state, &mut tick_time, &mut paused, &mut exit);
+        self.proc_transition(st, tc);
     }
 
-    Ok(())
-}
+    // Processes a set of transition commands on the program state.
+    fn proc_transition(&mut self, st: StateTransition, tc: TickCommand) {
+        match st {
+            StateTransition::NoChange => {}
+            StateTransition::Exit => {
+                log::debug!("Exit requested");
+                self.exit = true;
+            }
+            StateTransition::To(new_state) => {
+                log::debug!("Switching application state");
+                self.state = new_state;
+            }
+        };
 
-// Processes a set of transition commands on the program state.
-fn proc_transition(
-    st: StateTransition,
-    tc: TickCommand,
-    state: &mut Box<dyn AppState>,
-    tick_time: &mut Option<Duration>,
-    paused: &mut bool,
-    exit: &mut bool,
-) {
-    match st {
-        StateTransition::NoChange => {}
-        StateTransition::Exit => {
-            *exit = true;
-        }
-        StateTransition::To(new_state) => {
-            *state = new_state;
-        }
-    };
+        match tc {
+            TickCommand::None => {}
+            TickCommand::Set(_) => {}
+            _ => log::debug!("Tick command {:?}", tc),
+        };
 
-    match tc {
-        TickCommand::None => {}
-        TickCommand::Set(d) => {
-            *tick_time = Some(d);
-            *paused = false;
-        }
-        TickCommand::Pause => {
-            *paused = true;
-        }
-        TickCommand::Resume => {
-            *paused = false;
-        }
-        TickCommand::Toggle => {
-            *paused = !*paused;
-        }
-    };
+        match tc {
+            TickCommand::None => {}
+            TickCommand::Set(d) => {
+                self.tick_time = Some(d);
+                self.paused = false;
+            }
+            TickCommand::Pause => {
+                self.paused = true;
+            }
+            TickCommand::Resume => {
+                self.paused = false;
+            }
+            TickCommand::Toggle => {
+                self.paused = !self.paused;
+            }
+        };
+    }
 }
 
-// Sets up a keyboard thread, and returns a receiver for keystrokes;
-// None is sent when stdin closes or an input error occurs, and
-// further messages should not be read by the caller.
-fn init_kbd_thread() -> Receiver<Keycode> {
-    let (send, recv) = channel();
+// Runs the main program loop, given the initial state and the queue
+// its frontends feed.
+pub fn state_loop(init_state: Box<dyn AppState>, inputs: InputQueue) -> Result<()> {
+    let inputs = inputs.into_receiver();
+    let mut manager = StateManager::new(init_state);
+    while !manager.exit {
+        let start_time = Instant::now();
+        let input = match manager.timeout() {
+            Some(timeout) => match inputs.recv_timeout(timeout) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(e) => return Err(e.into()),
+            },
+            None => Some(inputs.recv()?),
+        };
+        manager.step(input, start_time.elapsed());
+    }
+
+    Ok(())
+}
 
+// Sets up a keyboard thread, which sends keystrokes to the given
+// queue; NoKey is sent when stdin closes or an input error occurs,
+// after which the thread stops.
+pub fn init_kbd_thread(send: InputSender) {
     thread::spawn(move || {
         use Keycode::*;
         let mut input = stdin();
 
         loop {
             let mut buf = vec![0];
-            match input.read_exact(&mut buf) {
-                Err(_) => {
-                    send.send(NoKey).unwrap();
-                    return;
-                }
-                Ok(_) => {
-                    send.send(Key(buf[0])).unwrap();
-                }
+            let key = match input.read_exact(&mut buf) {
+                Err(_) => NoKey,
+                Ok(_) => Key(buf[0]),
+            };
+            let done = matches!(key, NoKey);
+
+            let input = Input {
+                source: InputSource::Keyboard,
+                event: InputEvent::Key(key),
+            };
+            if send.send(input).is_err() || done {
+                return;
             }
         }
     });
-
-    return recv;
 }
@@ -0,0 +1,193 @@
+// Keeps time by the audio device's clock, counting the frames it plays,
+// so the metronome's ticks stay in step with the sound it makes.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The audio device's clock, as seen from the system clock. The mixer
+// tells it how many frames it mixes and when; times on the device
+// clock are seconds' worth of frames since the stream started.
+#[derive(Default)]
+pub struct AudioClock {
+    state: Mutex<ClockState>,
+}
+
+#[derive(Default)]
+struct ClockState {
+    // The stream's sample rate.
+    rate: f64,
+
+    // Where the stream is up to, once it's started.
+    anchor: Option<Anchor>,
+
+    // Counts the streams the clock has followed, so a schedule can
+    // tell when the one it was following has been replaced.
+    epoch: u32,
+}
+
+struct Anchor {
+    // When the first buffer of the stream was mixed.
+    start: Instant,
+
+    // Frames mixed so far.
+    frames: u64,
+
+    // How far behind the system clock the device clock has fallen, in
+    // seconds, smoothed over many buffers to even out how unevenly
+    // they're asked for.
+    offset: f64,
+}
+
+impl AudioClock {
+    pub fn new() -> AudioClock {
+        AudioClock::default()
+    }
+
+    // Starts following a new stream at the given sample rate.
+    pub fn restart(&self, rate: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate as f64;
+        state.anchor = None;
+        state.epoch += 1;
+    }
+
+    // Notes that a buffer of the given number of frames is being
+    // mixed, as of `now`.
+    pub fn mixed(&self, frames: usize, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let rate = state.rate;
+        let anchor = state.anchor.get_or_insert(Anchor {
+            start: now,
+            frames: 0,
+            offset: 0.0,
+        });
+        let behind = (now - anchor.start).as_secs_f64() - anchor.frames as f64 / rate;
+        anchor.offset += (behind - anchor.offset) * constants::AUDIO_CLOCK_SMOOTHING;
+        anchor.frames += frames as u64;
+    }
+
+    // The time on the device clock at the given instant, along with
+    // the stream's epoch; None if the stream hasn't started yet.
+    fn time(&self, at: Instant) -> Option<(u32, f64)> {
+        let state = self.state.lock().unwrap();
+        let anchor = state.anchor.as_ref()?;
+        let since = match at.checked_duration_since(anchor.start) {
+            Some(since) => since.as_secs_f64(),
+            None => -(anchor.start - at).as_secs_f64(),
+        };
+        Some((state.epoch, since - anchor.offset))
+    }
+
+    // The instant the device clock reaches the given time, if it's
+    // still following the stream of the given epoch.
+    fn instant(&self, epoch: u32, time: f64) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        let anchor = state.anchor.as_ref().filter(|_| state.epoch == epoch)?;
+        let since = time + anchor.offset;
+        if since >= 0.0 {
+            Some(anchor.start + Duration::from_secs_f64(since))
+        } else {
+            anchor.start.checked_sub(Duration::from_secs_f64(-since))
+        }
+    }
+}
+
+// A schedule of ticks kept on the device clock.
+pub struct AudioSchedule {
+    clock: Arc<AudioClock>,
+
+    // The last time the schedule gave out: the stream's epoch, the time
+    // on the device clock, and the matching instant.
+    last: Option<(u32, f64, Instant)>,
+}
+
+impl AudioSchedule {
+    pub fn new(clock: Arc<AudioClock>) -> AudioSchedule {
+        AudioSchedule { clock, last: None }
+    }
+
+    // The time `by` after `from`. For as long as each time follows on
+    // from the last one this gave out, the schedule is kept entirely
+    // on the device clock, so it can't drift away from the sound;
+    // anything else (a pause, a sync) starts it over from `from`.
+    // Until the stream starts, this falls back on the system clock.
+    pub fn advance(&mut self, from: Instant, by: Duration) -> Instant {
+        let start = match self.last {
+            Some((epoch, time, at)) if at == from => Some((epoch, time)),
+            _ => self.clock.time(from),
+        };
+        let next = start.and_then(|(epoch, time)| {
+            let time = time + by.as_secs_f64();
+            Some((epoch, time, self.clock.instant(epoch, time)?))
+        });
+
+        self.last = next;
+        match next {
+            Some((_, _, at)) => at,
+            None => from + by,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_clock_test() {
+        let clock = Arc::new(AudioClock::new());
+        let mut schedule = AudioSchedule::new(clock.clone());
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        // Before the stream starts, the schedule goes by the system
+        // clock.
+        assert_eq!(schedule.advance(start, second), start + second);
+
+        // A device running 0.1% slow: buffers of 10 frames at 1000 Hz
+        // are asked for every 10.01 ms.
+        clock.restart(1000);
+        let buffer = Duration::from_micros(10_010);
+        let mut n = 0;
+        let mut mix = |buffers| {
+            for _ in 0..buffers {
+                clock.mixed(10, start + buffer * n);
+                n += 1;
+            }
+        };
+        mix(2000);
+
+        // Seconds on the device clock are that much longer, tick after
+        // tick.
+        let first = schedule.advance(start + buffer * 2000, second);
+        let mut last = first;
+        for _ in 0..10 {
+            mix(100);
+            last = schedule.advance(last, second);
+        }
+        let spacing = (last - first).as_secs_f64() / 10.0;
+        assert!((spacing - 1.001).abs() < 1e-4, "{}", spacing);
+
+        // Once the stream's replaced, the schedule waits for the new
+        // one to start.
+        clock.restart(1000);
+        assert_eq!(schedule.advance(last, second), last + second);
+    }
+}
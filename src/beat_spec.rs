@@ -16,13 +16,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::constants;
 use crate::errors::*;
 use error_chain::bail;
 use std::convert::TryInto;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 // Description of precisely what events should occur and when during a
 // single measure.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BeatSpec {
     // The set of events to run during each tick in a measure.
     ticks: Vec<Event>,
@@ -43,6 +49,115 @@ pub enum Event {
     // Could add other types of sounds, messages etc. in the future.
 }
 
+// How strongly a click is played, which sets its pitch and volume.
+// This is separate from the crossbeat a beep comes from: any number
+// of crossbeats may share an emphasis, so that a measure with many
+// layers doesn't need as many pitches.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Emphasis {
+    Primary,
+    Secondary,
+    Subdivision,
+}
+
+impl Emphasis {
+    // Parses the name of an emphasis.
+    pub fn parse(name: &str) -> Result<Emphasis> {
+        match name {
+            "primary" => Ok(Emphasis::Primary),
+            "secondary" => Ok(Emphasis::Secondary),
+            "subdivision" => Ok(Emphasis::Subdivision),
+            _ => bail!(format!(
+                "Unknown emphasis {}; expected primary, secondary or subdivision",
+                name
+            )),
+        }
+    }
+
+    // Pitch of a click with this emphasis, relative to a primary
+    // click's.
+    pub fn pitch(self) -> f64 {
+        match self {
+            Emphasis::Primary => 1.0,
+            Emphasis::Secondary => constants::SECONDARY_PITCH,
+            Emphasis::Subdivision => constants::SUBDIVISION_PITCH,
+        }
+    }
+
+    // How many emphases are stronger than this one: 0 for primary
+    // clicks, up to 2 for subdivisions.
+    pub fn rank(self) -> u32 {
+        match self {
+            Emphasis::Primary => 0,
+            Emphasis::Secondary => 1,
+            Emphasis::Subdivision => 2,
+        }
+    }
+
+    // Volume of a click with this emphasis, relative to a primary
+    // click's.
+    pub fn gain(self) -> f64 {
+        match self {
+            Emphasis::Primary => 1.0,
+            Emphasis::Secondary => constants::SECONDARY_GAIN,
+            Emphasis::Subdivision => constants::SUBDIVISION_GAIN,
+        }
+    }
+}
+
+// Which emphasis the beeps from each crossbeat are played with. The
+// last entry carries on to every deeper crossbeat. Unless the
+// emphases are given outright, they follow the rhythm being played:
+// grouped meters have a layer for their group accents, so their plain
+// beats are a layer deeper than other rhythms' and are played as
+// secondary clicks all the same. Clones share the rhythm they follow.
+#[derive(Debug, Clone, Default)]
+pub struct EmphasisPolicy {
+    levels: Option<Vec<Emphasis>>,
+    grouped: Arc<AtomicBool>,
+}
+
+impl EmphasisPolicy {
+    // Parses a comma-separated list of emphases, one per crossbeat
+    // starting from the downbeat, e.g.
+    // "primary,secondary,secondary,subdivision".
+    pub fn parse(spec: &str) -> Result<EmphasisPolicy> {
+        let levels = spec
+            .split(',')
+            .map(|name| Emphasis::parse(name.trim()))
+            .collect::<Result<Vec<Emphasis>>>()?;
+        Ok(EmphasisPolicy {
+            levels: Some(levels),
+            ..EmphasisPolicy::default()
+        })
+    }
+
+    // Follows a change of rhythm.
+    pub fn follow(&self, rhythm: &BeatSpec) {
+        self.grouped
+            .store(rhythm.has_group_layer(), Ordering::Relaxed);
+    }
+
+    // The emphasis to play the given event with, or None for rests.
+    pub fn of(&self, evt: &Event) -> Option<Emphasis> {
+        use Emphasis::*;
+        let levels = match &self.levels {
+            Some(levels) => &levels[..],
+            None if self.grouped.load(Ordering::Relaxed) => {
+                &[Primary, Secondary, Secondary, Subdivision]
+            }
+            None => &[Primary, Secondary, Subdivision],
+        };
+        match *evt {
+            Event::Rest => None,
+            Event::Beep(layer) => levels
+                .get(layer as usize)
+                .or_else(|| levels.last())
+                .copied(),
+        }
+    }
+}
+
 impl BeatSpec {
     // Creates a BeatSpec given a number of beats per measure and
     // subdivisions per beat.
@@ -85,59 +200,218 @@ impl BeatSpec {
         }
     }
 
-    // Creates a BeatSpec from a rhythm specification string.
+    // Creates a BeatSpec from a rhythm specification string, or from
+    // an additive meter such as "3+3+2", one tick per beat.
     pub fn from_rhythmspec(spec: &str) -> Result<BeatSpec> {
+        if let Some(groups) = parse_additive(spec)? {
+            return Ok(BeatSpec::from_groups(&groups, 1));
+        }
+        let spec = expand_rhythmspec(spec)?;
+        let slots = parse_slots(&mut spec.chars(), false)?;
+
+        // Tuplets need a finer grid than the plain ticks around them;
+        // each plain tick spans `res` ticks of it.
+        let res = resolution(&slots)?;
+        if timed(&slots) as usize * res as usize > constants::MAX_SPEC_TICKS {
+            bail!(format!(
+                "Rhythm spec needs more than {} ticks to fit its tuplets",
+                constants::MAX_SPEC_TICKS
+            ));
+        }
         let mut ticks = vec![];
-        ticks.reserve(spec.len());
-        let mut beat_len = 1;
-
-        let mut n = 0;
-        for c in spec.chars() {
-            match c {
-                '0'..='9' => {
-                    ticks.push(Event::Beep(c as u32 - '0' as u32));
-                }
-                '.' => {
-                    ticks.push(Event::Rest);
+        let mut beat_len = None;
+        lay_out(&slots, res, &mut ticks, &mut beat_len);
+
+        if ticks.is_empty() {
+            bail!("Rhythm spec must have at least one tick");
+        }
+
+        let beat_len = match beat_len {
+            Some(0) => bail!("Beat length mark '!' must come after at least one tick"),
+            Some(len) => len,
+            // Guess from the plain ticks, taking each tuplet by its
+            // first tick.
+            None => {
+                let plain: Vec<Event> = ticks.iter().step_by(res as usize).cloned().collect();
+                infer_beat_len(&plain) * res
+            }
+        };
+
+        Ok(BeatSpec { ticks, beat_len })
+    }
+
+    // Creates a BeatSpec with a fractional number of beats per
+    // measure, e.g. 2.5, each of which is subdivided evenly. Each
+    // beat gets enough ticks for the measure to end on a tick.
+    pub fn from_fractional(beats: f64, subdiv: u32) -> Result<BeatSpec> {
+        let denom = match (1..=constants::MAX_BEAT_DENOMINATOR)
+            .find(|d| (beats * *d as f64 - (beats * *d as f64).round()).abs() < 1e-9)
+        {
+            Some(d) => d,
+            None => bail!(format!("Can't divide a measure into {} beats", beats)),
+        };
+
+        let beat_len = lcm(&[denom, subdiv]);
+        let sub_len = beat_len / subdiv;
+        let n_ticks = (beats * beat_len as f64).round() as u32;
+
+        let ticks = (0..n_ticks)
+            .map(|n| {
+                if n == 0 {
+                    Event::Beep(0)
+                } else if n % beat_len == 0 {
+                    Event::Beep(1)
+                } else if n % sub_len == 0 {
+                    Event::Beep(2)
+                } else {
+                    Event::Rest
                 }
-                '!' => {
-                    beat_len = n;
+            })
+            .collect();
+
+        Ok(BeatSpec { ticks, beat_len })
+    }
+
+    // Creates a BeatSpec from groups of beats, each of which is
+    // subdivided evenly. The first beat of each group is accented
+    // more strongly than the others, e.g. 2+2+3 with one subdivision
+    // gives "0212122".
+    pub fn from_groups(groups: &[u32], subdiv: u32) -> BeatSpec {
+        let mut ticks = vec![];
+        for (n, group) in groups.iter().enumerate() {
+            for beat in 0..*group {
+                for sub in 0..subdiv {
+                    ticks.push(Event::Beep(match (n, beat, sub) {
+                        (0, 0, 0) => 0,
+                        (_, 0, 0) => 1,
+                        (_, _, 0) => 2,
+                        _ => 3,
+                    }));
                 }
-                _ => {
-                    bail!(String::from("Unknown rhythm spec command ") + &String::from(c));
+            }
+        }
+
+        BeatSpec {
+            ticks,
+            beat_len: subdiv,
+        }
+    }
+
+    // Creates a BeatSpec from a time signature, e.g. "7/8" or
+    // "3+3+2/8", with the beat always a quarter note, so songs can
+    // switch between signatures at the same tempo. Groups of notes
+    // are accented as given; without them, quarter notes and longer
+    // are each a group, and shorter notes are grouped idiomatically,
+    // e.g. 7/8 as 2+2+3.
+    pub fn from_signature(sig: &str) -> Result<BeatSpec> {
+        let (top, bottom) = match sig.rsplit_once('/') {
+            Some(parts) => parts,
+            None => bail!(format!(
+                "Time signature {} must be <beats>/<note value>",
+                sig
+            )),
+        };
+        let denom: u32 = match bottom.trim().parse() {
+            Ok(d) if u32::is_power_of_two(d) && d <= constants::MAX_SIGNATURE_DENOMINATOR => d,
+            _ => bail!(format!(
+                "Note value in time signature {} must be a power of 2 up to {}",
+                sig,
+                constants::MAX_SIGNATURE_DENOMINATOR
+            )),
+        };
+        let groups = match parse_additive(top)? {
+            Some(groups) => groups,
+            None => match top.trim().parse() {
+                Ok(notes) if (1..=constants::MAX_BEATS_PER_MEASURE).contains(&notes) => {
+                    signature_groups(notes, denom)
                 }
+                _ => bail!(format!("Invalid number of beats in time signature {}", sig)),
+            },
+        };
+
+        // The grid is in quarter notes or the signature's notes,
+        // whichever is shorter.
+        let span = (4 / denom).max(1) as usize;
+        let mut ticks = vec![];
+        for (n, group) in groups.iter().enumerate() {
+            for note in 0..*group {
+                ticks.push(Event::Beep(match (n, note) {
+                    (0, 0) => 0,
+                    (_, 0) => 1,
+                    _ => 2,
+                }));
+                ticks.extend((1..span).map(|_| Event::Rest));
             }
-            n += 1;
         }
 
-        Ok(BeatSpec { ticks, beat_len })
+        Ok(BeatSpec {
+            ticks,
+            beat_len: (denom / 4).max(1),
+        })
     }
 
-    // Constructs a BeatSpec with the same content as this one, but
-    // subdivided to be divisible by the given integer. E.g., given a
-    // 4/4 measure of quarter notes and the parameter 8, this returns
-    // an 8/8 measure with rests on the off-beats, which sounds
-    // exactly the same as the 4/4 measure.
-    pub fn make_divisible(&self, value: u32) -> BeatSpec {
-        let n_ticks = self.beat_len as u32;
-        let factor = value / euclid(n_ticks, value);
+    // Constructs the smallest BeatSpec that sounds the same as this
+    // one, without rests that only pad out its beats.
+    pub fn reduce(&self) -> BeatSpec {
+        let len = self.ticks.len() as u32;
+        let factor = (1..=euclid(len, self.beat_len))
+            .rev()
+            .find(|f| {
+                len.is_multiple_of(*f)
+                    && self.beat_len.is_multiple_of(*f)
+                    && self
+                        .ticks
+                        .iter()
+                        .enumerate()
+                        .all(|(n, ev)| (n as u32).is_multiple_of(*f) || *ev == Event::Rest)
+            })
+            .unwrap_or(1);
 
         BeatSpec {
-            ticks: {
-                let mut v = Vec::new();
-                v.reserve((n_ticks * factor) as usize);
-                for b in self.ticks.iter() {
-                    v.push(b.clone());
-                    for _ in 1..factor {
-                        v.push(Event::Rest);
-                    }
-                }
-                v
-            },
-            beat_len: self.beat_len * factor,
+            ticks: self
+                .ticks
+                .iter()
+                .step_by(factor as usize)
+                .cloned()
+                .collect(),
+            beat_len: self.beat_len / factor,
         }
     }
 
+    // Renders this BeatSpec as a rhythm specification string, which
+    // from_rhythmspec() parses back to the same BeatSpec. A rhythm spec
+    // has only one digit per tick, so emphases past 9, which only many
+    // crossbeats give, are written as 9.
+    pub fn to_rhythmspec(&self) -> String {
+        let mut spec = String::new();
+        for (n, ev) in self.ticks.iter().enumerate() {
+            if n as u32 == self.beat_len {
+                spec.push('!');
+            }
+            match ev {
+                Event::Rest => spec.push('.'),
+                Event::Beep(emph) => spec.push_str(&(*emph).min(9).to_string()),
+            }
+        }
+        // A measure of one beat ends with the mark, which otherwise
+        // wouldn't have been written at all.
+        if self.beat_len as usize == self.ticks.len() {
+            spec.push('!');
+        }
+
+        spec
+    }
+
+    // Whether the measure has a layer for group accents, as grouped
+    // meters like 2+2+3 do: some of its beats are on the layer other
+    // rhythms keep for subdivisions, the third.
+    pub fn has_group_layer(&self) -> bool {
+        self.ticks
+            .iter()
+            .step_by(self.beat_len as usize)
+            .any(|tick| *tick == Event::Beep(2))
+    }
+
     // Accessor functions
     pub fn get_ticks(&self) -> &[Event] {
         &self.ticks
@@ -148,6 +422,418 @@ impl BeatSpec {
     }
 }
 
+// A simple meter: some number of beats per measure, each subdivided
+// evenly, optionally with the beats accented in groups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meter {
+    pub beats: u32,
+    pub subdiv: u32,
+
+    // How the beats are grouped for accents, e.g. [2, 2, 3] in 7; an
+    // empty vector means the beats are not grouped.
+    pub groups: Vec<u32>,
+}
+
+impl Meter {
+    // Creates an ungrouped Meter.
+    pub fn new(beats: u32, subdiv: u32) -> Meter {
+        Meter {
+            beats,
+            subdiv,
+            groups: vec![],
+        }
+    }
+
+    // Creates an additive meter, with beats in the given groups,
+    // e.g. [3, 3, 2].
+    pub fn additive(groups: Vec<u32>, subdiv: u32) -> Meter {
+        Meter {
+            beats: groups.iter().sum(),
+            subdiv,
+            groups,
+        }
+    }
+
+    // Builds the BeatSpec this meter describes.
+    pub fn to_beatspec(&self) -> BeatSpec {
+        if self.groups.is_empty() {
+            BeatSpec::from_subdiv(self.beats, self.subdiv)
+        } else {
+            BeatSpec::from_groups(&self.groups, self.subdiv)
+        }
+    }
+
+    // Returns this meter with the next idiomatic grouping from the
+    // preset table, cycling back to no grouping after the last one.
+    pub fn next_grouping(&self) -> Meter {
+        let presets = accent_groupings(self.beats);
+        let groups = match presets.iter().position(|g| *g == &self.groups[..]) {
+            Some(n) if n + 1 < presets.len() => presets[n + 1].to_vec(),
+            Some(_) => vec![],
+            None if self.groups.is_empty() && !presets.is_empty() => presets[0].to_vec(),
+            None => vec![],
+        };
+
+        Meter { groups, ..*self }
+    }
+}
+
+impl fmt::Display for Meter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let groups: Vec<String> = self.groups.iter().map(|g| g.to_string()).collect();
+        write!(f, "{}/{}/{}", self.beats, self.subdiv, groups.join("+"))
+    }
+}
+
+// A swing feel: the subdivisions of each beat are taken in pairs, and
+// the second of each pair is played late, so the first takes up the
+// given percentage of the pair. 50% is straight, and about 67% a
+// triplet shuffle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Swing(pub f64);
+
+impl Default for Swing {
+    fn default() -> Swing {
+        Swing(constants::DEF_SWING)
+    }
+}
+
+impl Swing {
+    // Parses a swing percentage, as given on the command line.
+    pub fn parse(arg: &str) -> Result<Swing> {
+        match arg.trim_end_matches('%').parse() {
+            Ok(percent) if (constants::DEF_SWING..=constants::MAX_SWING).contains(&percent) => {
+                Ok(Swing(percent))
+            }
+            _ => bail!(format!(
+                "Swing must be from {}% (straight) to {}%, got {}",
+                constants::DEF_SWING,
+                constants::MAX_SWING,
+                arg
+            )),
+        }
+    }
+
+    // How late the given tick of a rhythm with the given beat length
+    // is played, as a fraction of a tick. An odd tick out at the end
+    // of a beat has no partner, and is played straight.
+    pub fn delay(self, tick: usize, beat_len: u32) -> f64 {
+        let within = tick % beat_len as usize;
+        if within % 2 == 1 {
+            (self.0 / 100.0 - 0.5) * 2.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// Two crossbeats played against each other, e.g. 3 against 4; the
+// first one is the beat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Polyrhythm {
+    pub beats: u32,
+    pub against: u32,
+}
+
+impl Polyrhythm {
+    // Builds the BeatSpec playing the two crossbeats.
+    pub fn to_beatspec(self) -> BeatSpec {
+        BeatSpec::from_crossbeats(&[self.beats, self.against])
+    }
+
+    // Returns this polyrhythm with one of its crossbeats (0 for the
+    // beat, 1 for the one against it) changed by the given amount,
+    // staying between 1 and EXPLORE_MAX.
+    pub fn adjust(self, which: usize, by: i32) -> Polyrhythm {
+        let step = |n: u32| (n as i32 + by).clamp(1, constants::EXPLORE_MAX as i32) as u32;
+        match which {
+            0 => Polyrhythm {
+                beats: step(self.beats),
+                ..self
+            },
+            _ => Polyrhythm {
+                against: step(self.against),
+                ..self
+            },
+        }
+    }
+
+    // Number of ticks in a measure: the shortest grid both
+    // crossbeats fall on.
+    pub fn ticks(self) -> u32 {
+        lcm(&[self.beats, self.against])
+    }
+
+    // Whether each of the crossbeats falls on the given tick.
+    pub fn hits(self, tick: u32) -> (bool, bool) {
+        let ticks = self.ticks();
+        (
+            tick.is_multiple_of(ticks / self.beats),
+            tick.is_multiple_of(ticks / self.against),
+        )
+    }
+}
+
+impl fmt::Display for Polyrhythm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.beats, self.against)
+    }
+}
+
+// Idiomatic ways of grouping the beats of a measure, for each number
+// of beats per measure.
+pub fn accent_groupings(beats: u32) -> &'static [&'static [u32]] {
+    match beats {
+        5 => &[&[2, 3], &[3, 2]],
+        6 => &[&[3, 3], &[2, 2, 2]],
+        7 => &[&[2, 2, 3], &[3, 2, 2], &[2, 3, 2]],
+        8 => &[&[3, 3, 2], &[3, 2, 3], &[2, 3, 3]],
+        9 => &[&[2, 2, 2, 3], &[3, 3, 3], &[2, 2, 3, 2]],
+        10 => &[&[3, 3, 2, 2], &[2, 3, 2, 3], &[3, 2, 2, 3]],
+        11 => &[&[2, 2, 3, 2, 2], &[3, 3, 3, 2], &[2, 2, 2, 2, 3]],
+        12 => &[&[3, 3, 3, 3], &[2, 2, 2, 2, 2, 2]],
+        _ => &[],
+    }
+}
+
+// The usual grouping of the notes in a measure of a time signature
+// with no groups given: compound meters like 6/8 in threes, odd
+// meters from the preset table, and otherwise in twos.
+fn signature_groups(notes: u32, denom: u32) -> Vec<u32> {
+    if denom <= 4 {
+        return vec![1; notes as usize];
+    }
+    if notes.is_multiple_of(3) {
+        return vec![3; notes as usize / 3];
+    }
+    if let Some(groups) = accent_groupings(notes).first() {
+        return groups.to_vec();
+    }
+    if notes < 2 {
+        return vec![notes];
+    }
+
+    let mut groups = vec![2; notes as usize / 2];
+    if notes % 2 == 1 {
+        *groups.last_mut().unwrap() = 3;
+    }
+    groups
+}
+
+// Parses an additive meter, e.g. "3+3+2", into the number of beats in
+// each group. Returns None if the string isn't one, i.e. has no '+'.
+pub fn parse_additive(spec: &str) -> Result<Option<Vec<u32>>> {
+    if !spec.contains('+') {
+        return Ok(None);
+    }
+
+    let mut groups = vec![];
+    for group in spec.split('+') {
+        match group.trim().parse() {
+            Ok(beats) if beats >= 1 => groups.push(beats),
+            _ => bail!(format!(
+                "Groups in additive meter {} must be at least 1 beat, got \"{}\"",
+                spec, group
+            )),
+        }
+    }
+    if groups.iter().map(|&beats| beats as u64).sum::<u64>()
+        > constants::MAX_BEATS_PER_MEASURE as u64
+    {
+        bail!(format!(
+            "Additive meter {} has more than {} beats",
+            spec,
+            constants::MAX_BEATS_PER_MEASURE
+        ));
+    }
+    Ok(Some(groups))
+}
+
+// Expands the grouping and repetition syntax in a rhythm
+// specification string, returning a string of plain commands and
+// tuplets. Groups are enclosed in "(...)" or "[...]", may be nested,
+// and may be followed by "x<n>" or "*<n>" to repeat them n times;
+// whitespace is ignored. E.g., "(12)x3 0" expands to "1212120".
+// Tuplets, in "{...}", are repeated the same way but keep their
+// braces.
+pub fn expand_rhythmspec(spec: &str) -> Result<String> {
+    expand_group(&mut spec.chars().peekable(), None)
+}
+
+// Expands commands up to the given closing bracket, or to the end of
+// the string if there is none.
+fn expand_group(chars: &mut Peekable<Chars>, close: Option<char>) -> Result<String> {
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' | '{' => {
+                let close = match c {
+                    '(' => ')',
+                    '[' => ']',
+                    _ => '}',
+                };
+                let mut inner = expand_group(chars, Some(close))?;
+                if c == '{' {
+                    inner = format!("{{{}}}", inner);
+                }
+                let count = parse_repeat_count(chars)?;
+                match inner.len().checked_mul(count) {
+                    Some(len) if out.len() + len <= constants::MAX_SPEC_TICKS => {
+                        out.push_str(&inner.repeat(count))
+                    }
+                    _ => bail!(format!(
+                        "Rhythm spec expands to more than {} ticks",
+                        constants::MAX_SPEC_TICKS
+                    )),
+                }
+            }
+            ')' | ']' | '}' => {
+                if close == Some(c) {
+                    return Ok(out);
+                }
+                bail!(format!("Unmatched '{}' in rhythm spec", c));
+            }
+            c if c.is_whitespace() => {}
+            c => out.push(c),
+        }
+    }
+
+    match close {
+        Some(c) => bail!(format!("Missing '{}' in rhythm spec", c)),
+        None => Ok(out),
+    }
+}
+
+// Parses the optional "x<n>" or "*<n>" suffix following a group.
+fn parse_repeat_count(chars: &mut Peekable<Chars>) -> Result<usize> {
+    if chars.next_if(|c| *c == 'x' || *c == '*').is_none() {
+        return Ok(1);
+    }
+
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+
+    match digits.parse::<usize>() {
+        Ok(0) | Err(_) if digits.trim_start_matches('0').is_empty() => {
+            bail!("Expected a positive repeat count after group in rhythm spec")
+        }
+        Ok(n) if n <= constants::MAX_SPEC_TICKS => Ok(n),
+        _ => bail!(format!(
+            "Repeat count {} in rhythm spec is more than the limit of {}",
+            digits,
+            constants::MAX_SPEC_TICKS
+        )),
+    }
+}
+
+// A command in an expanded rhythm spec, or a tuplet of them.
+enum Slot {
+    Tick(Event),
+    Mark,
+    Tuplet(Vec<Slot>),
+}
+
+// Parses the commands in an expanded rhythm spec, up to the end of
+// the tuplet they're in, if any.
+fn parse_slots(chars: &mut Chars, tuplet: bool) -> Result<Vec<Slot>> {
+    let mut slots = vec![];
+    while let Some(c) = chars.next() {
+        slots.push(match c {
+            '0'..='9' => Slot::Tick(Event::Beep(c as u32 - '0' as u32)),
+            '.' => Slot::Tick(Event::Rest),
+            '!' => Slot::Mark,
+            '{' => {
+                let inner = parse_slots(chars, true)?;
+                if timed(&inner) == 0 {
+                    bail!("Tuplet in rhythm spec must have at least one tick");
+                }
+                Slot::Tuplet(inner)
+            }
+            '}' if tuplet => return Ok(slots),
+            _ => bail!(String::from("Unknown rhythm spec command ") + &String::from(c)),
+        });
+    }
+
+    Ok(slots)
+}
+
+// Number of the slots that take up time, i.e. all but the marks.
+fn timed(slots: &[Slot]) -> u32 {
+    slots
+        .iter()
+        .filter(|slot| !matches!(slot, Slot::Mark))
+        .count() as u32
+}
+
+// Number of grid ticks each of the slots must span for the tuplets
+// among them to fall on the grid.
+fn resolution(slots: &[Slot]) -> Result<u32> {
+    let mut res = 1;
+    for slot in slots {
+        if let Slot::Tuplet(inner) = slot {
+            let needed = timed(inner)
+                .checked_mul(resolution(inner)?)
+                .filter(|&needed| needed <= constants::MAX_TUPLET_RESOLUTION);
+            res = match needed.map(|needed| lcm(&[res, needed])) {
+                Some(res) if res <= constants::MAX_TUPLET_RESOLUTION => res,
+                _ => bail!("Tuplets in rhythm spec divide a tick too finely"),
+            };
+        }
+    }
+
+    Ok(res)
+}
+
+// Lays the slots out on the grid, each `len` grid ticks long, noting
+// where the beat length mark falls.
+fn lay_out(slots: &[Slot], len: u32, ticks: &mut Vec<Event>, mark: &mut Option<u32>) {
+    for slot in slots {
+        match slot {
+            Slot::Tick(ev) => {
+                ticks.push(ev.clone());
+                ticks.extend((1..len).map(|_| Event::Rest));
+            }
+            Slot::Mark => {
+                // Only the first mark counts, since repeated groups
+                // may contain several copies of it.
+                if mark.is_none() {
+                    *mark = Some(ticks.len() as u32);
+                }
+            }
+            Slot::Tuplet(inner) => lay_out(inner, len / timed(inner), ticks, mark),
+        }
+    }
+}
+
+// Guesses a plausible beat length, in ticks, for a measure whose
+// beat length wasn't given explicitly. We look for the shortest beat
+// of 2 to 4 ticks, with at least two beats per measure, for which
+// every beat starts with a tick louder than the rest of the beat;
+// failing that, each tick is taken to be a beat.
+pub fn infer_beat_len(ticks: &[Event]) -> u32 {
+    for len in 2..=4 {
+        if !ticks.len().is_multiple_of(len) || ticks.len() / len < 2 {
+            continue;
+        }
+
+        let starts_loudest = ticks.chunks(len).all(|beat| match beat[0] {
+            Event::Beep(emph) => beat[1..].iter().all(|ev| match ev {
+                Event::Beep(other) => emph < *other,
+                Event::Rest => true,
+            }),
+            Event::Rest => false,
+        });
+
+        if starts_loudest {
+            return len as u32;
+        }
+    }
+
+    1
+}
+
 // Returns the lowest common multiple of the set of integers.
 fn lcm(nums: &[u32]) -> u32 {
     let mut lcm = 1;
@@ -158,6 +844,21 @@ fn lcm(nums: &[u32]) -> u32 {
     lcm
 }
 
+// Number of ticks in a measure of the given crossbeats, as
+// from_crossbeats() would make it, or None if that's more than a
+// measure may have.
+pub fn crossbeat_ticks(beats: &[u32]) -> Option<u32> {
+    let mut ticks: u64 = 1;
+    for &n in beats {
+        ticks *= (n / euclid(ticks as u32, n)) as u64;
+        if ticks > constants::MAX_SPEC_TICKS as u64 {
+            return None;
+        }
+    }
+
+    Some(ticks as u32)
+}
+
 // Returns the greatest common divisor of two integers (Euclidean
 // algorithm).
 fn euclid(a: u32, b: u32) -> u32 {
@@ -198,6 +899,70 @@ mod tests {
         assert_eq!(bs.ticks[5], Event::Beep(2));
     }
 
+    #[test]
+    fn group_emphasis_test() {
+        use Emphasis::*;
+        let policy = EmphasisPolicy::default();
+        let emphases = |rhythm: &BeatSpec| {
+            policy.follow(rhythm);
+            rhythm
+                .get_ticks()
+                .iter()
+                .map(|tick| policy.of(tick).unwrap())
+                .collect::<Vec<Emphasis>>()
+        };
+
+        // In a 3+3+2 measure split in two, every beat but the downbeat
+        // is secondary, the group accents and the plain beats alike,
+        // and only the ticks between them are subdivisions.
+        let grouped = emphases(&BeatSpec::from_groups(&[3, 3, 2], 2));
+        let beats: Vec<Emphasis> = grouped.iter().step_by(2).copied().collect();
+        assert_eq!(
+            beats,
+            [
+                Primary, Secondary, Secondary, Secondary, Secondary, Secondary, Secondary,
+                Secondary
+            ]
+        );
+        assert!(grouped.iter().skip(1).step_by(2).all(|&e| e == Subdivision));
+
+        // The same goes for grouped meters without subdivisions, and
+        // for time signatures.
+        for rhythm in [
+            BeatSpec::from_groups(&[2, 2, 3], 1),
+            BeatSpec::from_signature("7/8").unwrap(),
+        ] {
+            let notes = emphases(&rhythm);
+            assert_eq!(notes[0], Primary);
+            assert!(notes[1..].iter().all(|&e| e == Secondary));
+        }
+
+        // Plain meters still have subdivisions on their third layer.
+        let plain = emphases(&BeatSpec::from_subdiv(4, 2));
+        assert_eq!(&plain[..4], [Primary, Subdivision, Secondary, Subdivision]);
+    }
+
+    #[test]
+    fn emphasis_test() {
+        let policy = EmphasisPolicy::default();
+        assert_eq!(policy.of(&Event::Rest), None);
+        assert_eq!(policy.of(&Event::Beep(0)), Some(Emphasis::Primary));
+        assert_eq!(policy.of(&Event::Beep(1)), Some(Emphasis::Secondary));
+        assert_eq!(policy.of(&Event::Beep(2)), Some(Emphasis::Subdivision));
+        assert_eq!(policy.of(&Event::Beep(7)), Some(Emphasis::Subdivision));
+
+        let policy = EmphasisPolicy::parse("primary, secondary,secondary,subdivision").unwrap();
+        assert_eq!(policy.of(&Event::Beep(2)), Some(Emphasis::Secondary));
+        assert_eq!(policy.of(&Event::Beep(3)), Some(Emphasis::Subdivision));
+
+        // Emphases given outright don't follow the rhythm.
+        policy.follow(&BeatSpec::from_subdiv(4, 2));
+        assert_eq!(policy.of(&Event::Beep(2)), Some(Emphasis::Secondary));
+
+        assert!(EmphasisPolicy::parse("primary,loud").is_err());
+        assert!(EmphasisPolicy::parse("").is_err());
+    }
+
     #[test]
     fn rspec_test() {
         let bs = BeatSpec::from_rhythmspec("02!1212").unwrap();
@@ -206,6 +971,253 @@ mod tests {
         assert_eq!(bs.beat_len, 2);
     }
 
+    #[test]
+    fn rspec_repeat_test() {
+        assert_eq!(expand_rhythmspec("(12)x3 0").unwrap(), "1212120");
+        assert_eq!(expand_rhythmspec("0 [121212]*2").unwrap(), "0121212121212");
+        assert_eq!(expand_rhythmspec("(0 1)").unwrap(), "01");
+
+        // Nested groups, in both bracket styles.
+        assert_eq!(expand_rhythmspec("((01)x2 2)x2").unwrap(), "0101201012");
+        assert_eq!(expand_rhythmspec("[0(1.)*2]x2").unwrap(), "01.1.01.1.");
+
+        // Only the first beat length mark counts.
+        let bs = BeatSpec::from_rhythmspec("(0!1)x4").unwrap();
+        assert_eq!(bs.ticks.len(), 8);
+        assert_eq!(bs.beat_len, 1);
+
+        assert!(expand_rhythmspec("(12").is_err());
+        assert!(expand_rhythmspec("12)").is_err());
+        assert!(expand_rhythmspec("(12]").is_err());
+        assert!(expand_rhythmspec("(12)x").is_err());
+        assert!(expand_rhythmspec("(12)x0").is_err());
+
+        // Repeats are capped, however they're nested.
+        let max = constants::MAX_SPEC_TICKS;
+        assert_eq!(
+            expand_rhythmspec(&format!("(0)x{}", max)).unwrap().len(),
+            max
+        );
+        assert!(expand_rhythmspec(&format!("(0)x{}", max + 1)).is_err());
+        assert!(expand_rhythmspec(&format!("(01)x{}", max / 2 + 1)).is_err());
+        assert!(expand_rhythmspec("[(0)x4294967296]x4294967296").is_err());
+        assert!(expand_rhythmspec("[(0)x65536]x65536").is_err());
+        assert!(expand_rhythmspec("(0)x99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn tuplet_test() {
+        let spec = |spec| BeatSpec::from_rhythmspec(spec).unwrap();
+
+        // Quarter, triplet, quarter.
+        let bs = spec("0{121}2");
+        assert_eq!(bs.to_rhythmspec(), "0..!1212..");
+        assert_eq!(bs.beat_len, 3);
+
+        // Tuplets nest, repeat, and take the beat length mark.
+        assert_eq!(spec("{1{22}}").to_rhythmspec(), "1.22!");
+        assert_eq!(spec("0{12}x2 0").to_rhythmspec(), "0.!12120.");
+        assert_eq!(spec("0!{111}1").beat_len, 3);
+        assert_eq!(expand_rhythmspec("({1.}2)x2").unwrap(), "{1.}2{1.}2");
+
+        assert!(BeatSpec::from_rhythmspec("0{}").is_err());
+        assert!(BeatSpec::from_rhythmspec("0{!}").is_err());
+        assert!(BeatSpec::from_rhythmspec("0{12").is_err());
+        assert!(BeatSpec::from_rhythmspec("0{12)").is_err());
+        assert!(BeatSpec::from_rhythmspec(&format!("0{{{}}}", "1".repeat(5000))).is_err());
+        assert!(BeatSpec::from_rhythmspec("(0{12345678901})x3000").is_err());
+    }
+
+    #[test]
+    fn signature_test() {
+        let spec = |sig| BeatSpec::from_signature(sig).unwrap().to_rhythmspec();
+
+        assert_eq!(spec("4/4"), "0!111");
+        assert_eq!(spec("7/8"), "02!12122");
+        assert_eq!(spec("6/8"), "02!2122");
+        assert_eq!(spec("3+3+2/8"), "02!212212");
+        assert_eq!(spec("2/2"), "0!.1.");
+        assert_eq!(spec("5/16"), "0212!2");
+        assert_eq!(spec("4/8"), "02!12");
+
+        assert!(BeatSpec::from_signature("7").is_err());
+        assert!(BeatSpec::from_signature("7/6").is_err());
+        assert!(BeatSpec::from_signature("0/4").is_err());
+        assert!(BeatSpec::from_signature("7/64").is_err());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let round_trip = |bs: &BeatSpec| BeatSpec::from_rhythmspec(&bs.to_rhythmspec()).unwrap();
+
+        for bs in [
+            BeatSpec::from_subdiv(4, 2),
+            BeatSpec::from_subdiv(1, 3),
+            BeatSpec::from_subdiv(1, 1),
+            BeatSpec::from_rhythmspec("0{121}2").unwrap(),
+            BeatSpec::from_fractional(2.5, 2).unwrap(),
+        ] {
+            assert_eq!(round_trip(&bs), bs);
+        }
+        assert_eq!(BeatSpec::from_subdiv(1, 3).to_rhythmspec(), "022!");
+
+        // Emphases too big for one digit are written as the quietest
+        // one that is, keeping one tick per tick.
+        let many = BeatSpec::from_crossbeats(&(2..=12).collect::<Vec<_>>());
+        let clamped: Vec<Event> = many
+            .ticks
+            .iter()
+            .map(|ev| match ev {
+                Event::Beep(emph) => Event::Beep((*emph).min(9)),
+                Event::Rest => Event::Rest,
+            })
+            .collect();
+        let back = round_trip(&many);
+        assert_eq!(back.ticks, clamped);
+        assert_eq!(back.beat_len, many.beat_len);
+    }
+
+    #[test]
+    fn reduce_test() {
+        let bs = BeatSpec::from_rhythmspec("0.1.!2.1.").unwrap();
+        let reduced = bs.reduce();
+        assert_eq!(reduced.ticks.len(), 4);
+        assert_eq!(reduced.beat_len, 2);
+
+        let bs = BeatSpec::from_rhythmspec("0...2...!1...2...1...2...").unwrap();
+        assert_eq!(bs.reduce().to_rhythmspec(), "02!1212");
+        assert_eq!(
+            BeatSpec::from_rhythmspec(&bs.reduce().to_rhythmspec())
+                .unwrap()
+                .ticks,
+            bs.reduce().ticks
+        );
+    }
+
+    #[test]
+    fn infer_beat_len_test() {
+        let len = |spec| BeatSpec::from_rhythmspec(spec).unwrap().beat_len;
+
+        assert_eq!(len("0.1.1.1."), 2);
+        assert_eq!(len("0..1..1..1.."), 3);
+        assert_eq!(len("01210121"), 4);
+
+        // No plausible grouping, so each tick is a beat.
+        assert_eq!(len("0111"), 1);
+        assert_eq!(len("0.."), 1);
+
+        // An explicit beat length always wins.
+        assert_eq!(len("01!21"), 2);
+    }
+
+    #[test]
+    fn fractional_test() {
+        let bs = BeatSpec::from_fractional(2.5, 1).unwrap();
+        assert_eq!(bs.beat_len, 2);
+        assert_eq!(bs.to_rhythmspec(), "0.!1.1");
+
+        let bs = BeatSpec::from_fractional(2.5, 2).unwrap();
+        assert_eq!(bs.to_rhythmspec(), "02!121");
+
+        let bs = BeatSpec::from_fractional(1.75, 2).unwrap();
+        assert_eq!(bs.beat_len, 4);
+        assert_eq!(bs.to_rhythmspec(), "0.2.!1.2");
+
+        assert!(BeatSpec::from_fractional(std::f64::consts::PI, 1).is_err());
+    }
+
+    #[test]
+    fn groups_test() {
+        let bs = BeatSpec::from_groups(&[2, 2, 3], 1);
+
+        assert_eq!(bs.ticks.len(), 7);
+        assert_eq!(bs.beat_len, 1);
+        assert_eq!(bs.ticks[0], Event::Beep(0));
+        assert_eq!(bs.ticks[2], Event::Beep(1));
+        assert_eq!(bs.ticks[4], Event::Beep(1));
+        assert_eq!(bs.ticks[6], Event::Beep(2));
+
+        let bs = BeatSpec::from_groups(&[3, 2], 2);
+        assert_eq!(bs.ticks.len(), 10);
+        assert_eq!(bs.beat_len, 2);
+        assert_eq!(bs.ticks[1], Event::Beep(3));
+        assert_eq!(bs.ticks[6], Event::Beep(1));
+    }
+
+    #[test]
+    fn additive_test() {
+        assert_eq!(parse_additive("3+3+2").unwrap(), Some(vec![3, 3, 2]));
+        assert_eq!(parse_additive(" 2 + 3 ").unwrap(), Some(vec![2, 3]));
+        assert_eq!(parse_additive("0.1.").unwrap(), None);
+        assert!(parse_additive("3++2").is_err());
+        assert!(parse_additive("3+0").is_err());
+        assert!(parse_additive("3+x").is_err());
+
+        // Rhythm specs can be written as additive meters too, with
+        // the groups accented.
+        let bs = BeatSpec::from_rhythmspec("3+3+2").unwrap();
+        assert_eq!(bs.to_rhythmspec(), "0!2212212");
+        assert_eq!(
+            Meter::additive(vec![3, 3, 2], 2).to_beatspec().ticks.len(),
+            16
+        );
+    }
+
+    #[test]
+    fn grouping_cycle_test() {
+        let m = Meter::new(7, 1);
+        let m = m.next_grouping();
+        assert_eq!(m.groups, vec![2, 2, 3]);
+        let m = m.next_grouping().next_grouping();
+        assert_eq!(m.groups, vec![2, 3, 2]);
+        let m = m.next_grouping();
+        assert!(m.groups.is_empty());
+
+        // Meters without presets stay ungrouped.
+        assert!(Meter::new(4, 1).next_grouping().groups.is_empty());
+    }
+
+    #[test]
+    fn swing_test() {
+        assert_eq!(Swing::default().delay(1, 2), 0.0);
+        assert!(Swing::parse("49").is_err());
+        assert!(Swing::parse("76%").is_err());
+
+        // A triplet shuffle puts the second eighth on the last
+        // triplet, a third of a beat late.
+        let swing = Swing::parse("66.667%").unwrap();
+        assert_eq!(swing.delay(0, 2), 0.0);
+        assert!((swing.delay(1, 2) - 1.0 / 3.0).abs() < 1e-4);
+
+        // With four subdivisions, the second and fourth are late; with
+        // three, the third has no partner.
+        let swing = Swing::parse("75").unwrap();
+        let delays: Vec<f64> = (0..4).map(|tick| swing.delay(tick, 4)).collect();
+        assert_eq!(delays, vec![0.0, 0.5, 0.0, 0.5]);
+        assert_eq!(swing.delay(2, 3), 0.0);
+        assert_eq!(swing.delay(3, 1), 0.0);
+    }
+
+    #[test]
+    fn polyrhythm_test() {
+        let poly = Polyrhythm {
+            beats: 3,
+            against: 4,
+        };
+        assert_eq!(poly.ticks(), 12);
+        assert_eq!(poly.to_beatspec().ticks.len(), 12);
+        assert_eq!(poly.to_beatspec().beat_len, 4);
+        assert_eq!(poly.hits(0), (true, true));
+        assert_eq!(poly.hits(4), (true, false));
+        assert_eq!(poly.hits(3), (false, true));
+        assert_eq!(poly.hits(5), (false, false));
+
+        let poly = poly.adjust(0, 1).adjust(1, 1);
+        assert_eq!(poly.to_string(), "4:5");
+        assert_eq!(poly.adjust(0, -10).beats, 1);
+        assert_eq!(poly.adjust(1, 100).against, constants::EXPLORE_MAX);
+    }
+
     #[test]
     fn lcm_test() {
         assert_eq!(euclid(12, 12), 12);
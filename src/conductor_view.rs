@@ -0,0 +1,187 @@
+// Full-screen "conductor" display, which flashes the number of the
+// current beat in large digits.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::met_view::ViewBackend;
+use crate::model_event::{ModelEvent, Subscriber};
+use crate::termios_handler::terminal_size;
+use crate::tick_clock::TickClock;
+use colorful::Color;
+use colorful::Colorful;
+use std::io::{stdout, Write};
+use std::time::Instant;
+
+// Bitmaps for the digits 0-9, three pixels wide and five tall.
+const FONT: [[&str; 5]; 10] = [
+    ["###", "#.#", "#.#", "#.#", "###"],
+    [".#.", "##.", ".#.", ".#.", "###"],
+    ["###", "..#", "###", "#..", "###"],
+    ["###", "..#", "###", "..#", "###"],
+    ["#.#", "#.#", "###", "..#", "..#"],
+    ["###", "#..", "###", "..#", "###"],
+    ["###", "#..", "###", "#.#", "###"],
+    ["###", "..#", "..#", "..#", "..#"],
+    ["###", "#.#", "###", "#.#", "###"],
+    ["###", "#.#", "###", "..#", "###"],
+];
+
+const FONT_WIDTH: usize = 3;
+const FONT_HEIGHT: usize = 5;
+
+pub struct ConductorView {
+    // The current beat number, counting from 1.
+    beat: u32,
+
+    // Whether the number is currently lit; it flashes on at the start
+    // of each beat and goes dark halfway through.
+    lit: bool,
+
+    // Whether `beat` is a count-in countdown rather than the beat
+    // number.
+    counting: bool,
+
+    // Whether the screen needs redrawing.
+    dirty: bool,
+
+    // Where the metronome is in the measure, so the number can go
+    // dark halfway through a beat even without a tick there.
+    clock: TickClock,
+}
+
+impl Default for ConductorView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConductorView {
+    pub fn new() -> Self {
+        Self {
+            beat: 1,
+            lit: false,
+            counting: false,
+            dirty: true,
+            clock: TickClock::new(),
+        }
+    }
+
+    // Sets the current beat (counting from 1), and the progress
+    // through it from 0 to 1.
+    fn set_beat(&mut self, beat: u32, progress: f64) {
+        let lit = progress < 0.5;
+        if beat != self.beat || lit != self.lit || self.counting {
+            self.dirty = true;
+        }
+        self.beat = beat;
+        self.lit = lit;
+        self.counting = false;
+    }
+
+    // Catches the beat up with the clock.
+    fn follow_clock(&mut self) {
+        if let Some((beat, progress)) = self.clock.beat(Instant::now()) {
+            self.set_beat(beat as u32 + 1, progress);
+        }
+    }
+
+    // Draws the ConductorView over the whole terminal, if anything
+    // changed since it was last drawn.
+    pub fn draw(&mut self) {
+        // The clock knows nothing of the count-in, so it only takes
+        // over again at the next tick.
+        if !self.counting {
+            self.follow_clock();
+        }
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        // Clear the screen.
+        print!("\x1B[2J");
+
+        if self.lit {
+            let (cols, rows) = terminal_size();
+            let digits: Vec<usize> = self
+                .beat
+                .to_string()
+                .chars()
+                .map(|c| c as usize - '0' as usize)
+                .collect();
+
+            // Each digit is followed by a one-pixel gap, except the
+            // last. Pixels are twice as wide as they are tall, since
+            // terminal cells are roughly twice as tall as they are
+            // wide.
+            let width_px = digits.len() * (FONT_WIDTH + 1) - 1;
+            let scale = (rows * 3 / 4 / FONT_HEIGHT)
+                .min(cols * 3 / 4 / (width_px * 2))
+                .max(1);
+
+            let top = rows.saturating_sub(FONT_HEIGHT * scale) / 2;
+            let left = cols.saturating_sub(width_px * scale * 2) / 2;
+            let color = if self.counting {
+                Color::Yellow
+            } else if self.beat == 1 {
+                Color::LightRed
+            } else {
+                Color::Green
+            };
+
+            for y in 0..FONT_HEIGHT * scale {
+                let mut line = String::new();
+                for (n, digit) in digits.iter().enumerate() {
+                    if n > 0 {
+                        line.push_str(&"  ".repeat(scale));
+                    }
+                    for pixel in FONT[*digit][y / scale].chars() {
+                        let cell = if pixel == '#' { "██" } else { "  " };
+                        line.push_str(&cell.repeat(scale));
+                    }
+                }
+
+                // Terminal rows and columns count from 1.
+                print!("\x1B[{};{}H{}", top + y + 1, left + 1, line.color(color));
+            }
+        }
+
+        stdout().flush().unwrap();
+    }
+}
+
+impl ViewBackend for ConductorView {
+    fn draw(&mut self) {
+        ConductorView::draw(self);
+    }
+}
+
+impl Subscriber for ConductorView {
+    fn notify(&mut self, event: &ModelEvent) {
+        self.clock.notify(event, Instant::now());
+        match event {
+            ModelEvent::Tick { .. } => self.follow_clock(),
+            ModelEvent::CountIn(left) => {
+                self.beat = *left;
+                self.lit = true;
+                self.counting = true;
+                self.dirty = true;
+            }
+            _ => {}
+        }
+    }
+}
@@ -42,17 +42,148 @@ pub const DEF_TEMPO: f64 = 120.0;
 pub const DEF_BEATS_PER_MEASURE: u32 = 4;
 pub const DEF_SUBDIV_PER_BEAT: u32 = 1;
 
+// Most beats a measure given by beats and subdivisions may have.
+pub const MAX_BEATS_PER_MEASURE: u32 = 1024;
+
+// Most crossbeats a cross-rhythm may have; each gets an emphasis of
+// its own, written as one digit in a rhythm spec.
+pub const MAX_CROSSBEATS: usize = 9;
+
+// Largest denominator allowed in a fractional number of beats per
+// measure, e.g. 8 allows 2.125 beats but not 2.1.
+pub const MAX_BEAT_DENOMINATOR: u32 = 16;
+
+// Most ticks a measure may have, however it's written, so that a
+// mistyped repeat count can't run the metronome out of memory.
+pub const MAX_SPEC_TICKS: usize = 1 << 16;
+
+// Most ticks a plain tick in a rhythm spec can be split into to fit
+// the tuplets in it, e.g. 15 for a triplet next to a quintuplet.
+pub const MAX_TUPLET_RESOLUTION: u32 = 4096;
+
+// Largest note value allowed under a time signature, e.g. 32 for
+// 7/32.
+pub const MAX_SIGNATURE_DENOMINATOR: u32 = 32;
+
+// Default swing, and the most allowed, as the percentage of each pair
+// of subdivisions the first takes up. 50% is straight.
+pub const DEF_SWING: f64 = 50.0;
+pub const MAX_SWING: f64 = 75.0;
+
 // Default volume of beeps, from 0.0 to 1.0.
 pub const DEF_VOLUME: f64 = 0.5;
 
+// Default volume of the background noise or drone, from 0.0 to 1.0.
+pub const DEF_BACKGROUND_VOLUME: f64 = 0.3;
+
 // ---- Sound options ----
 
-// Length of a beep, in milliseconds.
+// Default length of a beep, in milliseconds.
 pub const BEAT_LEN: u64 = 150;
 
+// Default number of BPM over which subdivisions fade out, past the
+// tempo they start to fade at, with --ghost-above.
+pub const GHOST_FADE_WIDTH: f64 = 20.0;
+
+// Most of the time until the next click that a click may last, so
+// that fast clicks stay apart.
+pub const CLICK_TICK_FRACTION: f64 = 0.8;
+
 // Pitch of the highest beep the metronome produces.
 pub const BEEP_PITCH: f64 = 880.0;
 
+// Length, in milliseconds, and pitch of the blip played to confirm a
+// tempo change that didn't come from the keyboard.
+pub const CONFIRM_LEN: u64 = 30;
+pub const CONFIRM_PITCH: f64 = 1760.0;
+
+// Lengths, in seconds, of the linear fade-in and fade-out applied to
+// every beep, which keep it from popping when it starts and stops.
+pub const ENVELOPE_ATTACK: f64 = 0.002;
+pub const ENVELOPE_RELEASE: f64 = 0.01;
+
+// Time constants, in seconds, of the exponential decay of the hi-hat
+// and woodblock timbres.
+pub const HIHAT_DECAY: f64 = 0.015;
+pub const WOODBLOCK_DECAY: f64 = 0.02;
+
+// Loudness matching of the click sounds: the sample rate the timbres'
+// clicks are measured at, the level below a sound's peak, as a
+// fraction of it, that's taken as silence around the sound rather
+// than part of it, and the most a quiet sound is boosted by.
+pub const NORMALIZE_RATE: u32 = 48000;
+pub const NORMALIZE_GATE: f64 = 0.1;
+pub const NORMALIZE_MAX_GAIN: f64 = 2.0;
+
+// Pitch and volume of secondary and subdivision clicks, relative to
+// primary clicks.
+pub const SECONDARY_PITCH: f64 = 0.5;
+pub const SUBDIVISION_PITCH: f64 = 1.0 / 3.0;
+pub const SECONDARY_GAIN: f64 = 1.0;
+pub const SUBDIVISION_GAIN: f64 = 0.8;
+
+// Pitch of clicks played with the left hand in a sticking drill,
+// relative to the right hand's.
+pub const STICKING_LEFT_PITCH: f64 = 0.75;
+
+// Most a humanized click's volume may vary, as a fraction of the
+// volume it would otherwise have.
+pub const HUMANIZE_VOLUME: f64 = 0.15;
+
+// Two-tone accents: the interval of the upper note of a chord, and
+// of the start of a sweep, above the accent's usual pitch.
+pub const ACCENT_CHORD_RATIO: f64 = 1.5;
+pub const ACCENT_SWEEP_RATIO: f64 = 2.0;
+
+// Volume of the grace note in a flammed downbeat, relative to the
+// main click.
+pub const FLAM_GRACE_VOLUME: f64 = 0.5;
+
+// Longest spacing, in milliseconds, allowed between a flam's grace
+// note and its main click. Every click is held back by the spacing, so
+// it can't be long.
+pub const MAX_FLAM: u64 = 100;
+
+// Range, in decibels, over which volumes from 0% to 100% are spread:
+// 100% is full scale, and each step down is an equal fraction of this
+// range quieter.
+pub const VOL_DYNAMIC_RANGE: f64 = 40.0;
+
+// Most clicks that may be mixed at once. Past this, the oldest click
+// still playing is faded out early to make room.
+pub const MAX_VOICES: usize = 8;
+
+// Most rendered clicks to keep for reuse. There's normally one per
+// emphasis, but each change of pitch or click length adds more.
+pub const CLICK_CACHE_SIZE: usize = 32;
+
+// How often, in milliseconds, the output stream checks whether the
+// audio device has gone away and it needs to start over on a new one.
+pub const OUTPUT_POLL: u64 = 500;
+
+// How late, in milliseconds, a click may be by the time the stream
+// gets to it before it's dropped rather than played, e.g. after the
+// device comes back.
+pub const STALE_CLICK: u64 = 250;
+
+// Gain applied to pink noise, which brings its peaks within full
+// scale.
+pub const PINK_NOISE_GAIN: f64 = 0.1;
+
+// Time constant, in seconds, with which the output limiter lets the
+// volume back up after holding down a peak.
+pub const LIMITER_RELEASE: f64 = 0.05;
+
+// ---- MIDI options ----
+
+// Default MIDI channel to send clicks on: the General MIDI percussion
+// channel.
+pub const MIDI_CHANNEL: u8 = 10;
+
+// Default MIDI notes for each emphasis level: General MIDI's high and
+// low woodblocks.
+pub const MIDI_NOTES: [u8; 2] = [76, 77];
+
 // ---- Controller options ----
 
 // Measure by which volume is adjusted per press of the volume
@@ -60,22 +191,160 @@ pub const BEEP_PITCH: f64 = 880.0;
 // and 1.0 is max volume.
 pub const VOL_ADJUST: f64 = 0.1;
 
-// Volumen minimum and maximum.
+// Decibels the volume is adjusted by per press when the volume is
+// shown in decibels.
+pub const VOL_ADJUST_DB: f64 = 2.0;
+
+// Volumen minimum and maximum. Volumes above 1.0 are boosted, and
+// limited on output.
 pub const VOL_MIN: f64 = 0.0;
-pub const VOL_MAX: f64 = 1.0;
+pub const VOL_MAX: f64 = 2.0;
 
 // Measure by which tempo is adjusted per press of the tempo increase
 // or decrease button, in beats per minute.
 pub const TEMPO_ADJUST: f64 = 1.0;
 
+// Default acceleration of held tempo keys: the largest tempo change
+// per press, in beats per minute, and the time in seconds it takes
+// to ramp up to it.
+pub const TEMPO_ACCEL_MAX: f64 = 5.0;
+pub const TEMPO_ACCEL_RAMP: f64 = 1.0;
+
+// Longest time, in seconds, between key repeats of a held tempo key.
+// This is shorter than the delay before a held key starts repeating,
+// so separate taps never accelerate.
+pub const TEMPO_REPEAT_GAP: f64 = 0.15;
+
+// Longest time, in seconds, between two presses of a key for them to
+// count as a double press.
+pub const DOUBLE_PRESS_TIME: f64 = 0.3;
+
 // Tempo minimum and maximum.
 pub const TEMPO_MIN: f64 = 10.0;
 pub const TEMPO_MAX: f64 = 300.0;
 
+// Largest crossbeat in the polyrhythm explorer.
+pub const EXPLORE_MAX: u32 = 16;
+
+// ---- JACK options ----
+
+// How often, in milliseconds, to check the JACK transport for changes.
+pub const JACK_POLL: u64 = 10;
+
+// ---- HTTP API options ----
+
+// How long, in milliseconds, a client has to send its whole request
+// and to take the response, and the largest request, in bytes, that's
+// read.
+pub const HTTP_TIMEOUT: u64 = 2000;
+pub const HTTP_MAX_REQUEST: usize = 8192;
+
+// Most clients served at once; any more are turned away until one
+// finishes.
+pub const HTTP_MAX_CLIENTS: usize = 16;
+
+// ---- Instance options ----
+
+// How long, in milliseconds, to wait on the other end of a control
+// socket connection, and the longest command, in bytes, that's read.
+pub const CONTROL_TIMEOUT: u64 = 2000;
+pub const CONTROL_MAX_COMMAND: u64 = 256;
+
+// How long, in milliseconds, to wait for the running instance to quit
+// when taking over from it, and how often to check whether it has.
+pub const TAKEOVER_WAIT: u64 = 3000;
+pub const TAKEOVER_POLL: u64 = 50;
+
+// ---- Strict timing options ----
+
+// How late, in milliseconds, a tick may be played before it counts as
+// late.
+pub const STRICT_MAX_JITTER: u64 = 5;
+
+// Number of recent ticks to watch, and how many of them must be late
+// before we warn.
+pub const STRICT_WINDOW: usize = 32;
+pub const STRICT_LATE_TICKS: usize = 4;
+
+// How late, in milliseconds, the watchdog lets a tick be played before
+// counting it as missed.
+pub const WATCHDOG_LATE_MS: u64 = 15;
+
 // ---- View options ----
 
 // Width of the tempo and volume indicators.
 pub const NUM_INDIC_WIDTH: usize = 3;
 
-// Width of the measure progress indicator.
-pub const MEAS_INDIC_WIDTH: usize = 40;
+// Default width of the measure progress indicator, and the narrowest
+// it can be made.
+pub const DEF_MEAS_INDIC_WIDTH: usize = 40;
+pub const MIN_MEAS_INDIC_WIDTH: usize = 8;
+
+// Columns left over for the rest of the display when the measure
+// progress indicator is sized to the terminal.
+pub const MEAS_INDIC_MARGIN: usize = 21;
+
+// Time, in milliseconds, between redraws of the display while the
+// metronome is playing, which move the measure indicator along
+// between ticks.
+pub const FRAME_INTERVAL: u64 = 20;
+
+// How much of the gap between the audio device's clock and the
+// system's, as measured at each buffer, the audio clock takes on;
+// smaller values even out uneven buffers more, but follow drift more
+// slowly.
+pub const AUDIO_CLOCK_SMOOTHING: f64 = 0.01;
+
+// Real-time priorities of the audio thread and the main loop, which
+// ticks the metronome, with real-time scheduling turned on. The audio
+// thread comes first, as a late buffer is heard as a glitch.
+pub const REALTIME_AUDIO_PRIORITY: i32 = 70;
+pub const REALTIME_TICK_PRIORITY: i32 = 60;
+
+// Number of spaces between the keys listed in the hint bar.
+pub const HINT_GAP: usize = 2;
+
+// Default and largest number of LEDs on an LED ring or strip.
+pub const DEF_LED_COUNT: usize = 24;
+pub const MAX_LED_COUNT: usize = 1024;
+
+// Brightness, out of 255, of the LEDs marking the beats.
+pub const LED_MARKER: u8 = 24;
+
+// ---- Profile options ----
+
+// Environment variable naming the profile to use when --profile isn't
+// given.
+pub const PROFILE_VAR: &str = "METRONOME_PROFILE";
+
+// ---- Render options ----
+
+// Sample rate and number of channels of rendered click tracks.
+pub const RENDER_SAMPLE_RATE: u32 = 44100;
+pub const RENDER_CHANNELS: usize = 2;
+
+// Number of measures rendered when --measures isn't given.
+pub const DEF_RENDER_MEASURES: u32 = 16;
+
+// MIDI ticks per beat in exported MIDI files.
+pub const SMF_DIVISION: u16 = 480;
+
+// ---- Tempo detection options ----
+
+// Length, in seconds, of the frames a recording is cut into to find
+// its tempo.
+pub const DETECT_HOP: f64 = 0.005;
+
+// Range of tempos to look for in recordings.
+pub const DETECT_MIN_TEMPO: f64 = 50.0;
+pub const DETECT_MAX_TEMPO: f64 = 220.0;
+
+// Tempo that detection leans toward when a recording could be heard
+// at either of two tempos an octave apart.
+pub const DETECT_PRIOR_TEMPO: f64 = 120.0;
+
+// ---- Journal options ----
+
+// Size in bytes past which the state journal is compacted down to its
+// latest entry.
+pub const JOURNAL_MAX_SIZE: u64 = 16 * 1024;
@@ -0,0 +1,93 @@
+// View listing the most useful keys in the current mode along the
+// bottom of the display, like nano's shortcut list.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use crate::termios_handler::terminal_size;
+use colorful::Colorful;
+use std::io::{stdout, Write};
+
+pub struct HintBar {
+    // The keys to list, with their labels, most useful first.
+    hints: Vec<(String, &'static str)>,
+}
+
+impl HintBar {
+    pub fn new(hints: Vec<(String, &'static str)>) -> Self {
+        Self { hints }
+    }
+
+    // The hints that fit on a line `width` columns wide, keeping the
+    // most useful ones and leaving the last column free so the
+    // terminal doesn't wrap.
+    fn fitting(&self, width: usize) -> &[(String, &'static str)] {
+        let mut used = 0;
+        for (n, (key, label)) in self.hints.iter().enumerate() {
+            let gap = if n == 0 { 0 } else { constants::HINT_GAP };
+            used += gap + key.chars().count() + 1 + label.chars().count();
+            if used >= width {
+                return &self.hints[..n];
+            }
+        }
+        &self.hints
+    }
+
+    // Draws the HintBar on the current line.
+    pub fn draw(&self) {
+        let (cols, _) = terminal_size();
+        print!("\r\x1B[K");
+        for (n, (key, label)) in self.fitting(cols).iter().enumerate() {
+            if n > 0 {
+                print!("{}", " ".repeat(constants::HINT_GAP));
+            }
+            print!("{} {}", key.as_str().reverse(), label);
+        }
+
+        stdout().flush().unwrap();
+    }
+
+    // Draws the HintBar on the bottom line of the terminal, leaving
+    // the cursor where it was.
+    pub fn draw_bottom(&self) {
+        let (_, rows) = terminal_size();
+        print!("\x1B7\x1B[{};1H", rows);
+        self.draw();
+        print!("\x1B8");
+
+        stdout().flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fitting_test() {
+        let bar = HintBar::new(vec![
+            ("h".to_string(), "Slower"),
+            ("l".to_string(), "Faster"),
+            ("q".to_string(), "Quit"),
+        ]);
+        // "h Slower" is 8 columns, and each later hint adds a gap.
+        assert_eq!(bar.fitting(80).len(), 3);
+        assert_eq!(bar.fitting(8 + constants::HINT_GAP + 8 + 1).len(), 2);
+        assert_eq!(bar.fitting(8 + constants::HINT_GAP + 8).len(), 1);
+        assert_eq!(bar.fitting(4).len(), 0);
+    }
+}
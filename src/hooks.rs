@@ -0,0 +1,168 @@
+// Hooks that run external commands when the metronome starts and
+// stops playing, e.g. to record a take in time with the click.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+// Subscriber that launches `on_start` whenever the metronome starts
+// or resumes playing, after any count-in, and terminates it when the
+// metronome stops or pauses, running `on_stop` in its place.
+pub struct ExecHooks {
+    on_start: Option<String>,
+    on_stop: Option<String>,
+
+    // The start command, while it's running.
+    running: Option<Child>,
+}
+
+impl ExecHooks {
+    pub fn new(on_start: Option<String>, on_stop: Option<String>) -> ExecHooks {
+        ExecHooks {
+            on_start,
+            on_stop,
+            running: None,
+        }
+    }
+
+    // Launches the start command, unless it's already running.
+    fn start(&mut self) {
+        if self.running.is_some() {
+            return;
+        }
+        if let Some(command) = &self.on_start {
+            self.running = launch(command);
+        }
+    }
+
+    // Terminates the start command if it's running, and launches the
+    // stop command.
+    fn stop(&mut self) {
+        if let (Some(child), Some(command)) = (self.running.take(), &self.on_start) {
+            terminate(command, child);
+        }
+        if let Some(command) = &self.on_stop {
+            if let Some(child) = launch(command) {
+                reap(command.clone(), child);
+            }
+        }
+    }
+}
+
+// Runs a command through the shell, in its own process group so that
+// everything it starts can be terminated along with it. It gets no
+// input or output, so it doesn't fight with us over the terminal.
+fn launch(command: &str) -> Option<Child> {
+    let res = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn();
+    match res {
+        Ok(child) => {
+            log::info!("Launched `{}` as process {}", command, child.id());
+            Some(child)
+        }
+        Err(e) => {
+            log::error!("Couldn't launch `{}`: {}", command, e);
+            None
+        }
+    }
+}
+
+// Asks a command's process group to exit, the same way as a Ctrl-C
+// in a shell would, so that e.g. a recorder finishes its file
+// properly.
+fn terminate(command: &str, child: Child) {
+    let pid = child.id() as libc::pid_t;
+    // SAFETY: kill has no memory safety requirements. The child
+    // hasn't been waited for, so its group ID can't have been reused.
+    unsafe { libc::kill(-pid, libc::SIGINT) };
+    reap(command.to_string(), child);
+}
+
+// Waits for a command to exit in the background, logging how it
+// went. Being interrupted counts as finishing normally, since that's
+// how we stop commands.
+fn reap(command: String, mut child: Child) {
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() || status.signal() == Some(libc::SIGINT) => {
+            log::info!("`{}` finished", command)
+        }
+        Ok(status) => log::warn!("`{}` exited with {}", command, status),
+        Err(e) => log::error!("Couldn't wait for `{}`: {}", command, e),
+    });
+}
+
+impl Subscriber for ExecHooks {
+    fn notify(&mut self, event: &ModelEvent) {
+        match event {
+            ModelEvent::Transport(Transport::Start)
+            | ModelEvent::Transport(Transport::Continue) => self.start(),
+            ModelEvent::Transport(Transport::Stop) => self.stop(),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for ExecHooks {
+    fn drop(&mut self) {
+        if let (Some(child), Some(command)) = (self.running.take(), &self.on_start) {
+            terminate(command, child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Whether a process is still around.
+    fn alive(pid: u32) -> bool {
+        unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+    }
+
+    #[test]
+    fn hooks_test() {
+        let mut hooks = ExecHooks::new(Some("sleep 10".to_string()), None);
+        hooks.notify(&ModelEvent::Transport(Transport::Start));
+        let pid = hooks.running.as_ref().unwrap().id();
+
+        // Resuming while it's still running leaves it be.
+        hooks.notify(&ModelEvent::Transport(Transport::Continue));
+        assert_eq!(hooks.running.as_ref().unwrap().id(), pid);
+        assert!(alive(pid));
+
+        // Stopping ends it, and it's reaped in the background.
+        hooks.notify(&ModelEvent::Transport(Transport::Stop));
+        assert!(hooks.running.is_none());
+        for _ in 0..100 {
+            if !alive(pid) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Start command wasn't terminated");
+    }
+}
@@ -0,0 +1,488 @@
+// Embedded HTTP server with a small JSON API, for controlling the
+// metronome from home automation and control surfaces.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::app_state::InputSender;
+use crate::errors::*;
+use crate::met_controller::ControllerMsg;
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use error_chain::bail;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "http")]
+use crate::app_state::{Input, InputEvent, InputSource};
+#[cfg(feature = "http")]
+use crate::constants;
+#[cfg(feature = "http")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "http")]
+use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "http")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "http")]
+use std::thread;
+#[cfg(feature = "http")]
+use std::time::{Duration, Instant};
+
+// What GET /status reports: the metronome's state as of the last
+// change published on the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiStatus {
+    pub tempo: f64,
+    pub volume: f64,
+    pub playing: bool,
+
+    // The rhythm, as a rhythm spec.
+    pub rhythm: String,
+}
+
+impl ApiStatus {
+    // The status as a JSON object, with the volume out of 100 as on
+    // the command line.
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"tempo\":{},\"volume\":{},\"playing\":{},\"rhythm\":\"{}\"}}",
+            self.tempo,
+            self.volume * 100.0,
+            self.playing,
+            self.rhythm.escape_default()
+        )
+    }
+}
+
+// Subscriber that keeps the status the API reports up to date.
+pub struct StatusRecorder {
+    status: Arc<Mutex<ApiStatus>>,
+}
+
+impl StatusRecorder {
+    pub fn new(status: Arc<Mutex<ApiStatus>>) -> StatusRecorder {
+        StatusRecorder { status }
+    }
+}
+
+impl Subscriber for StatusRecorder {
+    fn notify(&mut self, event: &ModelEvent) {
+        let mut status = self.status.lock().unwrap();
+        match event {
+            ModelEvent::TempoChanged(tempo) => status.tempo = *tempo,
+            ModelEvent::VolumeChanged(volume) => status.volume = *volume,
+            ModelEvent::Transport(Transport::Stop) => status.playing = false,
+            ModelEvent::Transport(_) => status.playing = true,
+            ModelEvent::RhythmChanged { rhythm, .. } => {
+                status.rhythm = rhythm.reduce().to_rhythmspec()
+            }
+            _ => {}
+        }
+    }
+}
+
+// The parts of an HTTP request the API looks at.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+struct Request {
+    method: String,
+    path: String,
+
+    // The token given in the Authorization header, if any.
+    token: Option<String>,
+
+    body: String,
+}
+
+// The answer to a request: the HTTP status code and JSON body to send
+// back, and the command the request asked for, if any.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+struct Response {
+    code: u16,
+    body: String,
+    msg: Option<ControllerMsg>,
+}
+
+impl Response {
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    fn error(code: u16, message: &str) -> Response {
+        Response {
+            code,
+            body: format!("{{\"error\":\"{}\"}}", message),
+            msg: None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    fn command(msg: ControllerMsg) -> Response {
+        Response {
+            code: 200,
+            body: "{\"ok\":true}".to_string(),
+            msg: Some(msg),
+        }
+    }
+}
+
+// Works out the answer to a request, given the token requests must
+// carry and the metronome's current status.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn respond(request: &Request, token: &str, status: &ApiStatus) -> Response {
+    if !request
+        .token
+        .as_deref()
+        .is_some_and(|t| same_token(t, token))
+    {
+        return Response::error(401, "Missing or wrong token");
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => Response {
+            code: 200,
+            body: status.to_json(),
+            msg: None,
+        },
+        ("POST", "/tempo") => match json_number(&request.body, "tempo") {
+            Some(tempo) if tempo > 0.0 && tempo.is_finite() => {
+                Response::command(ControllerMsg::SetTempo(tempo))
+            }
+            _ => Response::error(400, "Expected a body like {\"tempo\": 120}"),
+        },
+        ("POST", "/play") => Response::command(ControllerMsg::Play),
+        ("POST", "/pause") => Response::command(ControllerMsg::Pause),
+        (_, "/status") | (_, "/tempo") | (_, "/play") | (_, "/pause") => {
+            Response::error(405, "Method not allowed")
+        }
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+// Compares a token with the expected one, taking as long whichever
+// character they first differ at, so the token can't be guessed a
+// character at a time from how long requests take.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Finds the number given for `key` in a flat JSON object, e.g. 120
+// for "tempo" in {"tempo": 120}.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn json_number(body: &str, key: &str) -> Option<f64> {
+    let quoted = format!("\"{}\"", key);
+    let rest = &body[body.find(&quoted)? + quoted.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+// Splits the head of an HTTP request (everything before the blank
+// line) into a Request with an empty body, and the length of the body
+// that follows it.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn parse_head(head: &str) -> Option<(Request, usize)> {
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next()?.split(' ');
+    let method = start.next()?.to_string();
+    let path = start.next()?.to_string();
+
+    let mut token = None;
+    let mut length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "authorization" => token = value.strip_prefix("Bearer ").map(str::to_string),
+            "content-length" => length = value.parse().ok()?,
+            _ => {}
+        }
+    }
+
+    let request = Request {
+        method,
+        path,
+        token,
+        body: String::new(),
+    };
+    Some((request, length))
+}
+
+// The standard phrase for each status code we send.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Error",
+    }
+}
+
+// Listens for HTTP requests on the given TCP port, and starts a
+// thread that accepts them, passing the commands among them on to the
+// given queue. Every request must carry the given token.
+#[cfg(feature = "http")]
+pub fn init_http_thread(
+    port: u16,
+    token: String,
+    status: Arc<Mutex<ApiStatus>>,
+    send: InputSender,
+) -> Result<()> {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(e) => bail!(format!("Can't listen for HTTP on port {}: {}", port, e)),
+    };
+
+    let token: Arc<str> = token.into();
+    let clients = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::debug!("Couldn't accept an HTTP connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Each client is answered on its own thread, so a slow one
+            // can't hold up the rest; there's a limit on how many, so
+            // that a flood of them can't either.
+            if clients.fetch_add(1, Ordering::SeqCst) >= constants::HTTP_MAX_CLIENTS {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                log::debug!("Too many HTTP clients; turning one away");
+                continue;
+            }
+            let (token, status, send, clients) =
+                (token.clone(), status.clone(), send.clone(), clients.clone());
+            thread::spawn(move || {
+                let msg = serve(stream, &token, &status);
+                clients.fetch_sub(1, Ordering::SeqCst);
+                match msg {
+                    Ok(Some(msg)) => {
+                        let _ = send.send(Input {
+                            source: InputSource::Http,
+                            event: InputEvent::Message(msg),
+                        });
+                    }
+                    Ok(None) => {}
+                    Err(e) => log::debug!("HTTP request failed: {}", e),
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+pub fn init_http_thread(
+    _port: u16,
+    _token: String,
+    _status: Arc<Mutex<ApiStatus>>,
+    _send: InputSender,
+) -> Result<()> {
+    bail!("This metronome was built without the HTTP API; rebuild it with --features http")
+}
+
+// Answers a single request, returning the command it asked for, if
+// any.
+#[cfg(feature = "http")]
+fn serve(
+    mut stream: TcpStream,
+    token: &str,
+    status: &Mutex<ApiStatus>,
+) -> io::Result<Option<ControllerMsg>> {
+    let timeout = Duration::from_millis(constants::HTTP_TIMEOUT);
+    stream.set_write_timeout(Some(timeout))?;
+    let response = match read_request(&mut stream, Instant::now() + timeout)? {
+        Some(request) => {
+            let response = respond(&request, token, &status.lock().unwrap());
+            log::debug!(
+                "HTTP {} {}: {}",
+                request.method,
+                request.path,
+                response.code
+            );
+            response
+        }
+        None => Response::error(400, "Malformed request"),
+    };
+
+    let reply = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.code,
+        reason(response.code),
+        response.body.len(),
+        response.body
+    );
+    stream.write_all(reply.as_bytes())?;
+    Ok(response.msg)
+}
+
+// Reads from the stream, giving up at the deadline however slowly the
+// data trickles in.
+#[cfg(feature = "http")]
+fn read_by(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Client was too slow to send its request",
+        ));
+    }
+    stream.set_read_timeout(Some(left))?;
+    stream.read(buf)
+}
+
+// Reads a request from the stream by the deadline, or None if it's
+// malformed or too big.
+#[cfg(feature = "http")]
+fn read_request(stream: &mut TcpStream, deadline: Instant) -> io::Result<Option<Request>> {
+    let mut data = vec![];
+    let mut buf = [0; 1024];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        let n = read_by(stream, &mut buf, deadline)?;
+        if n == 0 || data.len() + n > constants::HTTP_MAX_REQUEST {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+    let (mut request, length) = match parse_head(&head) {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    if head_end + 4 + length > constants::HTTP_MAX_REQUEST {
+        return Ok(None);
+    }
+
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        let n = read_by(stream, &mut buf, deadline)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(length);
+    request.body = String::from_utf8_lossy(&body).to_string();
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respond_test() {
+        let status = ApiStatus {
+            tempo: 120.0,
+            volume: 0.5,
+            playing: true,
+            rhythm: "0222!1222".to_string(),
+        };
+        let (request, length) = parse_head(
+            "POST /tempo HTTP/1.1\r\nHost: metronome\r\n\
+             Authorization: Bearer s3cret\r\nContent-Length: 15",
+        )
+        .unwrap();
+        assert_eq!(length, 15);
+        let request = Request {
+            body: "{\"tempo\": 96.5}".to_string(),
+            ..request
+        };
+        match respond(&request, "s3cret", &status).msg {
+            Some(ControllerMsg::SetTempo(tempo)) => assert_eq!(tempo, 96.5),
+            msg => panic!("Got {:?}", msg),
+        }
+
+        // Requests without the right token are turned away.
+        assert_eq!(respond(&request, "other", &status).code, 401);
+        let anonymous = Request {
+            token: None,
+            ..request
+        };
+        assert_eq!(respond(&anonymous, "s3cret", &status).code, 401);
+
+        let get = |path: &str| Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            token: Some("s3cret".to_string()),
+            body: String::new(),
+        };
+        let response = respond(&get("/status"), "s3cret", &status);
+        assert_eq!(
+            response.body,
+            "{\"tempo\":120,\"volume\":50,\"playing\":true,\"rhythm\":\"0222!1222\"}"
+        );
+        assert_eq!(respond(&get("/play"), "s3cret", &status).code, 405);
+        assert_eq!(respond(&get("/volume"), "s3cret", &status).code, 404);
+    }
+
+    #[test]
+    fn json_number_test() {
+        assert_eq!(json_number("{\"tempo\":120}", "tempo"), Some(120.0));
+        assert_eq!(
+            json_number("{ \"x\": 1, \"tempo\" : 9.5e1 }", "tempo"),
+            Some(95.0)
+        );
+        assert_eq!(json_number("{\"tempo\": \"fast\"}", "tempo"), None);
+        assert_eq!(json_number("{}", "tempo"), None);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn deadline_test() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A client trickling in a byte at a time, each well within the
+        // time allowed for the whole request, still runs out of time.
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for byte in b"GET /status HTTP/1.1\r\n".iter() {
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        match read_request(&mut stream, deadline) {
+            Err(e) => assert!(matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )),
+            Ok(_) => panic!("Read a request past its deadline"),
+        }
+        assert!(start.elapsed() < Duration::from_millis(300));
+        drop(stream);
+        client.join().unwrap();
+    }
+}
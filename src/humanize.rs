@@ -0,0 +1,119 @@
+// Click humanization: small random variations in the timing and
+// volume of clicks, so the metronome feels less mechanical.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// User settings for humanization.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Humanize {
+    // Most a click may be moved early or late; zero turns
+    // humanization off.
+    pub amount: Duration,
+
+    // Seed for the random variations, so a run can be repeated, or
+    // None to vary them from run to run.
+    pub seed: Option<u64>,
+}
+
+// Source of the random variations applied to clicks.
+#[derive(Debug, Clone)]
+pub struct Humanizer {
+    // Most a click may be moved, in seconds.
+    amount: f64,
+
+    // State of the xorshift generator; never zero.
+    state: u64,
+}
+
+impl Humanizer {
+    pub fn new(options: Humanize) -> Humanizer {
+        let seed = options.seed.unwrap_or_else(time_seed);
+        // Xorshift sticks at zero, so mix the seed up with a constant
+        // that's unlikely to cancel it out.
+        let state = match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => 1,
+            state => state,
+        };
+
+        Humanizer {
+            amount: options.amount.as_secs_f64(),
+            state,
+        }
+    }
+
+    // How far to move the next click, in seconds, from -amount to
+    // amount; negative values are early.
+    pub fn offset(&mut self) -> f64 {
+        self.amount * self.next()
+    }
+
+    // Factor to scale the next click's volume by.
+    pub fn gain(&mut self) -> f64 {
+        1.0 + constants::HUMANIZE_VOLUME * self.next()
+    }
+
+    // Generates a random number from -1 to 1.
+    fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state as f64 / u64::MAX as f64 * 2.0 - 1.0
+    }
+}
+
+// A seed that differs from run to run.
+fn time_seed() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_nanos() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn humanizer_test() {
+        let options = Humanize {
+            amount: Duration::from_millis(10),
+            seed: Some(42),
+        };
+        let mut a = Humanizer::new(options);
+        let mut b = Humanizer::new(options);
+        for _ in 0..1000 {
+            // The same seed gives the same variations.
+            let offset = a.offset();
+            assert_eq!(offset, b.offset());
+            assert!(offset.abs() <= 0.010);
+
+            let gain = a.gain();
+            assert_eq!(gain, b.gain());
+            assert!((gain - 1.0).abs() <= constants::HUMANIZE_VOLUME);
+        }
+
+        // A different seed gives different ones.
+        let mut c = Humanizer::new(Humanize {
+            seed: Some(43),
+            ..options
+        });
+        assert_ne!(a.offset(), c.offset());
+    }
+}
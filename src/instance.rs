@@ -0,0 +1,298 @@
+// Coordination between instances of the metronome: a lock file marks
+// the one running, and its control socket lets others send it commands.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::app_state::{Input, InputEvent, InputSender, InputSource};
+use crate::constants;
+use crate::errors::*;
+use crate::journal::{self, JournalEntry};
+use crate::locale;
+use crate::met_controller::ControllerMsg;
+use crate::model_event::{ModelEvent, Subscriber};
+use error_chain::bail;
+use std::env;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Directory for the lock file and control socket: under
+// $XDG_RUNTIME_DIR, or a directory of the user's own in /tmp. Only the
+// user may use it, since whoever can reach the socket can control the
+// metronome.
+fn runtime_dir() -> Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join(constants::NAME),
+        // SAFETY: getuid() can't fail, and touches no memory.
+        None => env::temp_dir().join(format!("{}-{}", constants::NAME, unsafe { libc::getuid() })),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+fn socket_path() -> Result<PathBuf> {
+    Ok(runtime_dir()?.join("control"))
+}
+
+// The lock marking the running instance, held until it exits. The
+// operating system lets go of it however the instance exits, so a
+// crash doesn't leave it stuck.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    // Takes the lock, or returns None if another instance holds it.
+    pub fn acquire() -> Result<Option<InstanceLock>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(runtime_dir()?.join("lock"))?;
+        // SAFETY: flock() only touches the file descriptor, which is
+        // open.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            bail!(format!("Couldn't lock the instance lock file: {}", e));
+        }
+
+        // Note who has it, for anyone looking.
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Some(InstanceLock { _file: file }))
+    }
+
+    // Takes the lock from the running instance, asking it to quit and
+    // waiting for it to. Returns the lock and the settings the other
+    // instance was running with, to carry on from.
+    pub fn take_over() -> Result<(InstanceLock, JournalEntry)> {
+        let reply = send("state")?;
+        let entry = match journal::parse_entry(&reply) {
+            Some(entry) => entry,
+            None => bail!(format!(
+                "Couldn't understand the running metronome's state: {}",
+                reply
+            )),
+        };
+        send("quit")?;
+
+        let wait = Duration::from_millis(constants::TAKEOVER_WAIT);
+        let start = Instant::now();
+        while start.elapsed() < wait {
+            if let Some(lock) = InstanceLock::acquire()? {
+                return Ok((lock, entry));
+            }
+            thread::sleep(Duration::from_millis(constants::TAKEOVER_POLL));
+        }
+        bail!("The running metronome didn't quit")
+    }
+}
+
+// Sends a command to the running instance over its control socket,
+// returning its reply.
+pub fn send(command: &str) -> Result<String> {
+    let path = socket_path()?;
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(e) => bail!(format!(
+            "No metronome is running ({}: {})",
+            path.display(),
+            e
+        )),
+    };
+    let timeout = Some(Duration::from_millis(constants::CONTROL_TIMEOUT));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    writeln!(stream, "{}", command.trim())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    let reply = reply.trim_end().to_string();
+    match reply.strip_prefix("error: ") {
+        Some(e) => bail!(e.to_string()),
+        None => Ok(reply),
+    }
+}
+
+// Subscriber that keeps the settings reported over the control socket
+// up to date.
+pub struct StateRecorder {
+    state: Arc<Mutex<JournalEntry>>,
+}
+
+impl StateRecorder {
+    pub fn new(state: Arc<Mutex<JournalEntry>>) -> StateRecorder {
+        StateRecorder { state }
+    }
+}
+
+impl Subscriber for StateRecorder {
+    fn notify(&mut self, event: &ModelEvent) {
+        self.state.lock().unwrap().update(event);
+    }
+}
+
+// Works out the reply to a command from another instance, given the
+// current settings, and the message for the model it asks for, if
+// any. Tempos and volumes may be given outright or, with a sign, as a
+// change, e.g. "tempo 120" or "volume -10".
+fn respond(command: &str, state: &JournalEntry) -> (String, Option<ControllerMsg>) {
+    let mut words = command.split_whitespace();
+    let (name, arg) = (words.next().unwrap_or(""), words.next());
+    if words.next().is_some() {
+        return (format!("error: Too many arguments to {}", name), None);
+    }
+    let relative = arg.is_some_and(|arg| arg.starts_with(['+', '-']));
+    // Non-finite numbers parse, but aren't tempos or volumes.
+    let number = arg.map(|arg| locale::parse(arg).ok().filter(|x| x.is_finite()));
+
+    let msg = match (name, number) {
+        ("state", None) => return (journal::format_entry(state), None),
+        ("play", None) => ControllerMsg::Play,
+        ("pause", None) => ControllerMsg::Pause,
+        ("toggle", None) => ControllerMsg::Toggle,
+        ("stop", None) => ControllerMsg::Stop,
+        ("sync", None) => ControllerMsg::Sync,
+        ("quit", None) => ControllerMsg::Quit,
+        ("tempo", Some(Some(x))) if relative => ControllerMsg::AdjustTempo(x),
+        ("tempo", Some(Some(x))) if x > 0.0 => ControllerMsg::SetTempo(x),
+        ("volume", Some(Some(x))) if relative => ControllerMsg::AdjustVolume(x / 100.0),
+        ("volume", Some(Some(x))) if x >= 0.0 => {
+            ControllerMsg::AdjustVolume(x / 100.0 - state.volume)
+        }
+        _ => return (format!("error: Unknown command {}", command.trim()), None),
+    };
+    ("ok".to_string(), Some(msg))
+}
+
+// Listens on the control socket, and starts a thread that answers the
+// commands sent to it, passing them on to the given queue.
+pub fn init_control_thread(state: Arc<Mutex<JournalEntry>>, send: InputSender) -> Result<()> {
+    // Having the lock, any socket left behind is from an instance that
+    // didn't get to clean up.
+    let path = socket_path()?;
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => bail!(format!("Can't listen on {}: {}", path.display(), e)),
+    };
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Couldn't accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let timeout = Some(Duration::from_millis(constants::CONTROL_TIMEOUT));
+            let command = stream
+                .set_read_timeout(timeout)
+                .and_then(|()| read_command(&stream));
+            let command = match command {
+                Ok(command) => command,
+                Err(e) => {
+                    log::warn!("Couldn't read control command: {}", e);
+                    continue;
+                }
+            };
+
+            let (reply, msg) = respond(&command, &state.lock().unwrap());
+            log::info!("Control command {:?}: {}", command.trim(), reply);
+            let _ = writeln!(stream, "{}", reply);
+            if let Some(msg) = msg {
+                let input = Input {
+                    source: InputSource::Control,
+                    event: InputEvent::Message(msg),
+                };
+                if send.send(input).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Reads a command, one line long, from a control connection.
+fn read_command(stream: &UnixStream) -> io::Result<String> {
+    let mut command = String::new();
+    BufReader::new(stream.take(constants::CONTROL_MAX_COMMAND)).read_line(&mut command)?;
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_spec::BeatSpec;
+    use crate::sound::ClickOutput;
+
+    #[test]
+    fn respond_test() {
+        let state = JournalEntry {
+            tempo: 120.0,
+            volume: 0.5,
+            rhythm: BeatSpec::from_subdiv(4, 1),
+            meter: None,
+            output: ClickOutput::Audio,
+        };
+        let msg = |command| respond(command, &state).1;
+
+        assert!(matches!(msg("play"), Some(ControllerMsg::Play)));
+        assert!(matches!(msg(" quit\n"), Some(ControllerMsg::Quit)));
+        assert!(matches!(msg("tempo 140"), Some(ControllerMsg::SetTempo(x)) if x == 140.0));
+        assert!(matches!(msg("tempo -5"), Some(ControllerMsg::AdjustTempo(x)) if x == -5.0));
+        assert!(matches!(msg("volume +10"), Some(ControllerMsg::AdjustVolume(x)) if x == 0.1));
+        assert!(
+            matches!(msg("volume 80"), Some(ControllerMsg::AdjustVolume(x)) if (x - 0.3).abs() < 1e-9)
+        );
+
+        // The state can be read back by a new instance taking over.
+        let (reply, msg) = respond("state", &state);
+        assert!(msg.is_none());
+        assert_eq!(journal::parse_entry(&reply), Some(state.clone()));
+
+        for bad in [
+            "",
+            "dance",
+            "tempo",
+            "tempo 0",
+            "tempo fast",
+            "tempo inf",
+            "tempo +nan",
+            "volume +nan",
+            "volume -inf",
+            "play now",
+            "state 1",
+        ] {
+            let (reply, msg) = respond(bad, &state);
+            assert!(reply.starts_with("error: "), "{}", bad);
+            assert!(msg.is_none());
+        }
+    }
+}
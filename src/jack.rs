@@ -0,0 +1,297 @@
+// JACK transport: follows the JACK server's transport, so the click
+// starts and stops with a DAW like Ardour and lines up with its
+// timeline. This needs libjack, and the `jack` feature.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::app_state::InputSender;
+use crate::errors::*;
+use crate::met_controller::ControllerMsg;
+use error_chain::bail;
+use std::time::Duration;
+
+#[cfg(feature = "jack")]
+use crate::app_state::{Input, InputEvent, InputSource};
+#[cfg(feature = "jack")]
+use crate::constants;
+#[cfg(feature = "jack")]
+use std::thread;
+
+// Bindings to the parts of libjack we use.
+#[cfg(feature = "jack")]
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    // JackNoStartServer: don't start a server if none is running.
+    pub const NO_START_SERVER: c_int = 0x01;
+
+    // JackTransportRolling and JackTransportLooping.
+    pub const TRANSPORT_ROLLING: c_int = 1;
+    pub const TRANSPORT_LOOPING: c_int = 2;
+
+    // JackPositionBBT: the bar, beat and tick fields are valid.
+    pub const POSITION_BBT: c_int = 0x10;
+
+    // jack_position_t, which JACK declares packed.
+    #[repr(C, packed)]
+    pub struct Position {
+        pub unique_1: u64,
+        pub usecs: u64,
+        pub frame_rate: u32,
+        pub frame: u32,
+        pub valid: c_int,
+        pub bar: i32,
+        pub beat: i32,
+        pub tick: i32,
+        pub bar_start_tick: f64,
+        pub beats_per_bar: f32,
+        pub beat_type: f32,
+        pub ticks_per_beat: f64,
+        pub beats_per_minute: f64,
+        pub frame_time: f64,
+        pub next_time: f64,
+        pub bbt_offset: u32,
+        pub audio_frames_per_video_frame: f32,
+        pub video_offset: u32,
+        pub tick_double: f64,
+        pub padding: [i32; 5],
+        pub unique_2: u64,
+    }
+
+    #[link(name = "jack")]
+    extern "C" {
+        pub fn jack_client_open(
+            client_name: *const c_char,
+            options: c_int,
+            status: *mut c_int,
+            ...
+        ) -> *mut c_void;
+        pub fn jack_activate(client: *mut c_void) -> c_int;
+        pub fn jack_transport_query(client: *const c_void, pos: *mut Position) -> c_int;
+    }
+}
+
+// What the JACK transport is doing, as far as the metronome cares.
+#[cfg_attr(not(feature = "jack"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportState {
+    pub rolling: bool,
+
+    // Where the transport is in the bar and how fast it's going, if
+    // the timebase master says.
+    pub bar: Option<BarPosition>,
+}
+
+#[cfg_attr(not(feature = "jack"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarPosition {
+    // Beats since the start of the bar, counting from 0.
+    pub beat: f64,
+    pub beats_per_bar: f64,
+    pub tempo: f64,
+}
+
+// Turns changes in the transport into messages for the metronome.
+#[cfg_attr(not(feature = "jack"), allow(dead_code))]
+struct Follower {
+    // What the transport was doing last we looked, or None if we
+    // haven't yet.
+    rolling: Option<bool>,
+    tempo: Option<f64>,
+}
+
+#[cfg_attr(not(feature = "jack"), allow(dead_code))]
+impl Follower {
+    fn new() -> Follower {
+        Follower {
+            rolling: None,
+            tempo: None,
+        }
+    }
+
+    // Works out what to tell the metronome about the transport's
+    // latest state: each message, with how long to wait before
+    // sending it.
+    fn update(&mut self, state: TransportState) -> Vec<(Duration, ControllerMsg)> {
+        let mut msgs = vec![];
+
+        if let Some(bar) = state.bar {
+            if self.tempo != Some(bar.tempo) {
+                self.tempo = Some(bar.tempo);
+                msgs.push((Duration::new(0, 0), ControllerMsg::SetTempo(bar.tempo)));
+            }
+        }
+
+        let was_rolling = self.rolling.replace(state.rolling);
+        if state.rolling && was_rolling != Some(true) {
+            // Come in on the next bar line, so our downbeats are the
+            // timeline's; without bars to go by, just start now.
+            let wait = match state.bar {
+                Some(bar) => until_next_bar(bar),
+                None => Duration::new(0, 0),
+            };
+            msgs.push((wait, ControllerMsg::HardSync));
+        } else if !state.rolling && was_rolling != Some(false) {
+            msgs.push((Duration::new(0, 0), ControllerMsg::Stop));
+        }
+
+        msgs
+    }
+}
+
+// Time from the given position to the start of the next bar.
+#[cfg_attr(not(feature = "jack"), allow(dead_code))]
+fn until_next_bar(bar: BarPosition) -> Duration {
+    let beats = bar.beats_per_bar - bar.beat;
+    Duration::from_secs_f64((beats * 60.0 / bar.tempo).max(0.0))
+}
+
+// Connection to a JACK server, for reading its transport.
+#[cfg(feature = "jack")]
+struct JackClient(*mut std::os::raw::c_void);
+
+// JACK clients may be used from any thread.
+#[cfg(feature = "jack")]
+unsafe impl Send for JackClient {}
+
+#[cfg(feature = "jack")]
+impl JackClient {
+    fn open() -> Result<JackClient> {
+        let name = b"metronome\0";
+        let mut status = 0;
+        // SAFETY: the name is NUL-terminated, and status is valid for
+        // JACK to write to.
+        let client = unsafe {
+            ffi::jack_client_open(name.as_ptr() as *const _, ffi::NO_START_SERVER, &mut status)
+        };
+        if client.is_null() {
+            bail!(format!("Couldn't connect to JACK (status {:#x})", status));
+        }
+        // SAFETY: client is a freshly opened JACK client.
+        if unsafe { ffi::jack_activate(client) } != 0 {
+            bail!("Couldn't activate the JACK client");
+        }
+        Ok(JackClient(client))
+    }
+
+    fn query(&self) -> TransportState {
+        // SAFETY: jack_position_t is plain data, for which all zeroes
+        // is a valid value, and JACK fills it in.
+        let (state, pos) = unsafe {
+            let mut pos: ffi::Position = std::mem::zeroed();
+            let state = ffi::jack_transport_query(self.0, &mut pos);
+            (state, pos)
+        };
+
+        let valid = pos.valid;
+        let bar = if valid & ffi::POSITION_BBT != 0 {
+            let (beat, tick, ticks_per_beat) = (pos.beat, pos.tick, pos.ticks_per_beat);
+            Some(BarPosition {
+                // JACK counts beats from 1.
+                beat: (beat - 1) as f64 + tick as f64 / ticks_per_beat,
+                beats_per_bar: pos.beats_per_bar as f64,
+                tempo: pos.beats_per_minute,
+            })
+        } else {
+            None
+        };
+
+        TransportState {
+            rolling: state == ffi::TRANSPORT_ROLLING || state == ffi::TRANSPORT_LOOPING,
+            bar,
+        }
+    }
+}
+
+// Connects to the running JACK server, and starts a thread that makes
+// the metronome follow its transport.
+#[cfg(feature = "jack")]
+pub fn init_jack_thread(send: InputSender) -> Result<()> {
+    let client = JackClient::open()?;
+    let poll = Duration::from_millis(constants::JACK_POLL);
+    thread::spawn(move || {
+        let mut follower = Follower::new();
+        loop {
+            for (wait, msg) in follower.update(client.query()) {
+                thread::sleep(wait);
+                log::debug!("Following JACK transport: {:?}", msg);
+                let input = Input {
+                    source: InputSource::Jack,
+                    event: InputEvent::Message(msg),
+                };
+                if send.send(input).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(poll);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(feature = "jack"))]
+pub fn init_jack_thread(_send: InputSender) -> Result<()> {
+    bail!("This metronome was built without JACK support; rebuild it with --features jack")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follower_test() {
+        let mut follower = Follower::new();
+        let bar = BarPosition {
+            beat: 3.0,
+            beats_per_bar: 4.0,
+            tempo: 120.0,
+        };
+
+        // Stopped to begin with: take the tempo, and wait.
+        let msgs = follower.update(TransportState {
+            rolling: false,
+            bar: Some(bar),
+        });
+        assert_eq!(msgs.len(), 2);
+        assert!(matches!(msgs[0].1, ControllerMsg::SetTempo(t) if t == 120.0));
+        assert!(matches!(msgs[1].1, ControllerMsg::Stop));
+
+        // Rolling from the last beat of the bar: come in half a second
+        // later, on the next bar line.
+        let msgs = follower.update(TransportState {
+            rolling: true,
+            bar: Some(bar),
+        });
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].0, Duration::from_millis(500));
+        assert!(matches!(msgs[0].1, ControllerMsg::HardSync));
+
+        // Nothing new while it keeps rolling.
+        let msgs = follower.update(TransportState {
+            rolling: true,
+            bar: Some(bar),
+        });
+        assert!(msgs.is_empty());
+
+        let msgs = follower.update(TransportState {
+            rolling: false,
+            bar: None,
+        });
+        assert!(matches!(msgs[..], [(_, ControllerMsg::Stop)]));
+    }
+}
@@ -0,0 +1,442 @@
+// Journal of the metronome's settings, so that a session can be
+// picked back up with --resume after a crash or power loss.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Meter};
+use crate::config;
+use crate::constants;
+use crate::errors::*;
+use crate::model_event::{ModelEvent, Subscriber};
+use crate::sound::ClickOutput;
+use error_chain::bail;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+// Version of the journal's entry format, written as each entry's first
+// field. Version 1 entries had no version field.
+const JOURNAL_VERSION: u32 = 2;
+
+// The settings recorded in each journal entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub tempo: f64,
+    pub volume: f64,
+    pub rhythm: BeatSpec,
+    pub meter: Option<Meter>,
+    pub output: ClickOutput,
+}
+
+impl JournalEntry {
+    // Brings the entry up to date with a change published on the bus.
+    // Returns false for events that don't touch the recorded settings.
+    pub fn update(&mut self, event: &ModelEvent) -> bool {
+        match event {
+            ModelEvent::Tick { .. }
+            | ModelEvent::CountIn(_)
+            | ModelEvent::SongPosition { .. }
+            | ModelEvent::TimbreChanged(_)
+            | ModelEvent::AccentOnlyChanged(_)
+            | ModelEvent::LockChanged(_)
+            | ModelEvent::Transport(_) => return false,
+            ModelEvent::TempoChanged(tempo) => self.tempo = *tempo,
+            ModelEvent::VolumeChanged(volume) => self.volume = *volume,
+            ModelEvent::RhythmChanged { rhythm, meter } => {
+                self.rhythm = rhythm.clone();
+                self.meter = meter.clone();
+            }
+            ModelEvent::OutputChanged(output) => self.output = *output,
+        }
+        true
+    }
+}
+
+// Append-only file of JournalEntries, one per line. Each entry is a
+// complete snapshot, so only the last intact line matters when
+// resuming; a line torn by a crash mid-write is simply skipped.
+#[derive(Clone)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    // Opens the journal at its default location, under
+    // $XDG_STATE_HOME or ~/.local/state.
+    pub fn new() -> Result<Journal> {
+        let dir = match (env::var_os("XDG_STATE_HOME"), env::var_os("HOME")) {
+            (Some(state), _) => PathBuf::from(state),
+            (None, Some(home)) => PathBuf::from(home).join(".local").join("state"),
+            (None, None) => bail!("Can't find a directory for the state journal"),
+        };
+        Ok(Journal::at(dir.join(constants::NAME).join("journal")))
+    }
+
+    // Opens the journal at the given path.
+    pub fn at(path: PathBuf) -> Journal {
+        Journal { path }
+    }
+
+    // Appends an entry to the journal. If `sync` is set, the entry is
+    // flushed to disk before returning, so that it survives a power
+    // loss as well as a crash.
+    pub fn record(&self, entry: &JournalEntry, sync: bool) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let line = format!("{}\n", format_entry(entry));
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size + line.len() as u64 > constants::JOURNAL_MAX_SIZE {
+            // Start over with just this entry. Write it alongside the
+            // journal and rename it into place, so that there's always
+            // an intact journal on disk.
+            let tmp = self.path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(line.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Reads the most recent intact entry from the journal. Entries
+    // from a newer version of the metronome are an error, rather than
+    // being skipped in favor of older ones.
+    pub fn last_entry(&self) -> Result<JournalEntry> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) => bail!(format!("Can't read journal {}: {}", self.path.display(), e)),
+        };
+        for line in text.lines().rev() {
+            let version = entry_version(line);
+            if version > JOURNAL_VERSION {
+                bail!(format!(
+                    "Journal {} was written by a newer version of the metronome (format {}; this one reads up to {})",
+                    self.path.display(),
+                    version,
+                    JOURNAL_VERSION
+                ));
+            }
+            if let Some(entry) = parse_entry(line) {
+                return Ok(entry);
+            }
+        }
+        bail!("No saved state to resume from")
+    }
+}
+
+// Subscriber that keeps the journal up to date with the settings as
+// they change. The entries are written in the background, so the disk
+// doesn't hold up the metronome.
+pub struct JournalRecorder {
+    entry: JournalEntry,
+
+    // Feeds entries, and whether to sync them, to the thread that
+    // writes them; None once the recorder is being dropped.
+    writer: Option<Sender<(JournalEntry, bool)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl JournalRecorder {
+    // Creates a recorder starting from the given settings, which are
+    // recorded straight away.
+    pub fn new(journal: Journal, entry: JournalEntry) -> JournalRecorder {
+        let (send, recv) = channel();
+        let thread = thread::spawn(move || run_writer(journal, recv));
+        let recorder = JournalRecorder {
+            entry,
+            writer: Some(send),
+            thread: Some(thread),
+        };
+        recorder.record(true);
+        recorder
+    }
+
+    fn record(&self, sync: bool) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send((self.entry.clone(), sync));
+        }
+    }
+}
+
+impl Subscriber for JournalRecorder {
+    fn notify(&mut self, event: &ModelEvent) {
+        if !self.entry.update(event) {
+            return;
+        }
+
+        // Syncing on every tempo or volume step would be too slow.
+        self.record(event.is_significant());
+    }
+}
+
+impl Drop for JournalRecorder {
+    // Waits for the last entries to be written, so that quitting
+    // doesn't lose them.
+    fn drop(&mut self) {
+        self.writer = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Writes entries one at a time as they come in. Only the last entry
+// matters when resuming, so the entries that come in during a slow
+// write are skipped in favor of the latest, which is synced if any of
+// them were meant to be.
+fn run_writer(journal: Journal, recv: Receiver<(JournalEntry, bool)>) {
+    while let Ok(first) = recv.recv() {
+        let (entry, sync) = latest(&recv, first);
+        if let Err(e) = journal.record(&entry, sync) {
+            log::warn!("Couldn't write to the state journal: {}", e);
+        }
+    }
+}
+
+// The last of the entries waiting on a channel, or `first` if there
+// are none, to be synced if any of them were.
+fn latest(
+    recv: &Receiver<(JournalEntry, bool)>,
+    first: (JournalEntry, bool),
+) -> (JournalEntry, bool) {
+    recv.try_iter()
+        .fold(first, |(_, synced), (entry, sync)| (entry, synced || sync))
+}
+
+// Formats an entry as a line of space-separated key=value fields.
+pub fn format_entry(entry: &JournalEntry) -> String {
+    let meter = match &entry.meter {
+        Some(meter) => meter.to_string(),
+        None => "-".to_string(),
+    };
+    let output = match entry.output {
+        ClickOutput::Audio => "audio",
+        ClickOutput::Midi => "midi",
+        ClickOutput::Both => "both",
+        ClickOutput::Silent => "silent",
+    };
+
+    format!(
+        "version={} tempo={} volume={} rhythm={} meter={} output={} end",
+        JOURNAL_VERSION,
+        entry.tempo,
+        entry.volume,
+        entry.rhythm.reduce().to_rhythmspec(),
+        meter,
+        output
+    )
+}
+
+// The version of the format a line was written in.
+fn entry_version(line: &str) -> u32 {
+    match line
+        .split(' ')
+        .next()
+        .and_then(|f| f.strip_prefix("version="))
+    {
+        Some(version) => version.parse().unwrap_or(0),
+        None => 1,
+    }
+}
+
+// Parses a line written by format_entry, in this or an older version
+// of the format, returning None if it's malformed or incomplete.
+pub fn parse_entry(line: &str) -> Option<JournalEntry> {
+    let mut fields = line.split(' ');
+    // Versions 1 and 2 differ only in the version field.
+    if entry_version(line) != 1 {
+        fields.next();
+    }
+    let mut field = |key: &str| {
+        let (k, v) = fields.next()?.split_once('=')?;
+        if k == key {
+            Some(v)
+        } else {
+            None
+        }
+    };
+
+    let tempo = config::parse_tempo(field("tempo")?).ok()?;
+    let volume = field("volume")?.parse().ok()?;
+    config::check_volume(volume).ok()?;
+    let rhythm = BeatSpec::from_rhythmspec(field("rhythm")?).ok()?;
+    let meter = match field("meter")? {
+        "-" => None,
+        meter => Some(config::parse_meter(meter).ok()?),
+    };
+    let output = match field("output")? {
+        "audio" => ClickOutput::Audio,
+        "midi" => ClickOutput::Midi,
+        "both" => ClickOutput::Both,
+        "silent" => ClickOutput::Silent,
+        _ => return None,
+    };
+
+    // The trailing marker tells us the line wasn't cut short.
+    if fields.next() != Some("end") {
+        return None;
+    }
+
+    Some(JournalEntry {
+        tempo,
+        volume,
+        rhythm,
+        meter,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> JournalEntry {
+        JournalEntry {
+            tempo: 132.0,
+            volume: 0.7,
+            rhythm: BeatSpec::from_groups(&[2, 2, 3], 1),
+            meter: Some(Meter {
+                beats: 7,
+                subdiv: 1,
+                groups: vec![2, 2, 3],
+            }),
+            output: ClickOutput::Silent,
+        }
+    }
+
+    #[test]
+    fn entry_round_trip_test() {
+        let entry = entry();
+        assert_eq!(parse_entry(&format_entry(&entry)), Some(entry));
+
+        for output in [ClickOutput::Audio, ClickOutput::Midi, ClickOutput::Both] {
+            let plain = JournalEntry {
+                meter: None,
+                output,
+                ..self::entry()
+            };
+            assert_eq!(parse_entry(&format_entry(&plain)), Some(plain));
+        }
+
+        // Torn lines are rejected.
+        let line = format_entry(&self::entry());
+        assert_eq!(parse_entry(&line[..line.len() - 2]), None);
+        assert_eq!(parse_entry(""), None);
+
+        // So are settings the metronome can't play.
+        for (good, bad) in [
+            ("tempo=132", "tempo=0"),
+            ("volume=0.7", "volume=NaN"),
+            ("meter=7/1/2+2+3", "meter=7/0/2+2+3"),
+        ] {
+            assert!(line.contains(good));
+            assert_eq!(parse_entry(&line.replace(good, bad)), None);
+        }
+    }
+
+    #[test]
+    fn version_test() {
+        // Version 1 entries, without a version field, still load.
+        let old = "tempo=132 volume=0.7 rhythm=0..1.1..!2.. meter=- output=audio end";
+        assert_eq!(entry_version(old), 1);
+        assert_eq!(parse_entry(old).unwrap().tempo, 132.0);
+        assert_eq!(entry_version(&format_entry(&entry())), JOURNAL_VERSION);
+
+        // Entries from a newer version stop a resume, rather than
+        // falling back on an older entry.
+        let dir = env::temp_dir().join(format!("metronome-version-{}", std::process::id()));
+        let journal = Journal::at(dir.join("journal"));
+        journal.record(&entry(), false).unwrap();
+        let future = "version=99 tempo=90 end\n";
+        let mut file = OpenOptions::new().append(true).open(&journal.path).unwrap();
+        file.write_all(future.as_bytes()).unwrap();
+        let err = journal.last_entry().unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn journal_test() {
+        let dir = env::temp_dir().join(format!("metronome-journal-{}", std::process::id()));
+        let journal = Journal::at(dir.join("journal"));
+        assert!(journal.last_entry().is_err());
+
+        let mut entry = entry();
+        for n in 0..1000 {
+            entry.tempo = n as f64;
+            journal.record(&entry, n % 100 == 0).unwrap();
+        }
+        assert_eq!(journal.last_entry().unwrap(), entry);
+        assert!(fs::metadata(dir.join("journal")).unwrap().len() <= constants::JOURNAL_MAX_SIZE);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn latest_test() {
+        let (send, recv) = channel();
+        let mut entry = entry();
+        assert_eq!(
+            latest(&recv, (entry.clone(), false)),
+            (entry.clone(), false)
+        );
+
+        // A sync asked for by a skipped entry carries over to the
+        // latest.
+        for (tempo, sync) in [(100.0, true), (110.0, false), (120.0, false)] {
+            entry.tempo = tempo;
+            send.send((entry.clone(), sync)).unwrap();
+        }
+        assert_eq!(latest(&recv, (self::entry(), false)), (entry, true));
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn recorder_test() {
+        let dir = env::temp_dir().join(format!("metronome-recorder-{}", std::process::id()));
+        let journal = Journal::at(dir.join("journal"));
+
+        // Dropping the recorder waits for everything it was told to be
+        // written.
+        let mut recorder = JournalRecorder::new(journal.clone(), entry());
+        for n in 1..=50 {
+            recorder.notify(&ModelEvent::TempoChanged(n as f64 * 2.0));
+        }
+        drop(recorder);
+        let mut expected = entry();
+        expected.tempo = 100.0;
+        assert_eq!(journal.last_entry().unwrap(), expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
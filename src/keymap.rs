@@ -0,0 +1,411 @@
+// Key binding engine shared by the application's modes, each of which
+// has its own keymap on top of a few global bindings.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use crate::errors::*;
+use error_chain::bail;
+use std::fmt;
+use std::time::Instant;
+
+// Messages that every mode's keymap must be able to produce, for the
+// global bindings.
+pub trait GlobalMsg {
+    // Exit the program.
+    fn quit() -> Self;
+}
+
+// A mapping from a key (represented as a set of characters, [u8]) to
+// some functionality, and optionally to something else when the key
+// is pressed twice in quick succession, along with the label it's
+// shown with in the hint bar, if it's shown there at all.
+pub struct Binding<M>(
+    Vec<u8>,
+    fn() -> Option<M>,
+    Option<fn() -> Option<M>>,
+    Option<&'static str>,
+);
+
+impl<M> PartialEq for Binding<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<M> fmt::Debug for Binding<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Binding").field(&self.0).finish()
+    }
+}
+
+// Creates a Binding from a key sequence to an action.
+pub fn bind<M>(key: &[u8], action: fn() -> Option<M>) -> Binding<M> {
+    Binding(key.to_vec(), action, None, None)
+}
+
+// Creates a Binding with a separate action for double presses. The
+// first press always runs the single-press action straight away; a
+// second press within DOUBLE_PRESS_TIME runs the double-press action
+// instead of the single one.
+pub fn bind_double<M>(
+    key: &[u8],
+    action: fn() -> Option<M>,
+    double: fn() -> Option<M>,
+) -> Binding<M> {
+    Binding(key.to_vec(), action, Some(double), None)
+}
+
+impl<M> Binding<M> {
+    // Labels the binding, so that it's listed in the hint bar.
+    pub fn hint(self, label: &'static str) -> Binding<M> {
+        Binding(self.0, self.1, self.2, Some(label))
+    }
+}
+
+// The name a key sequence is shown with in the hint bar.
+fn key_name(key: &[u8]) -> String {
+    match key {
+        b" " => "Spc".to_string(),
+        b"\x1B[A" => "\u{2191}".to_string(),
+        b"\x1B[B" => "\u{2193}".to_string(),
+        b"\x1B[C" => "\u{2192}".to_string(),
+        b"\x1B[D" => "\u{2190}".to_string(),
+        [c @ 0x01..=0x1A] => format!("^{}", (c + b'@') as char),
+        _ => String::from_utf8_lossy(key).into_owned(),
+    }
+}
+
+// The bindings that work the same way in every mode. These are
+// checked before a mode's own bindings, so they can't be shadowed.
+fn global_bindings<M: GlobalMsg>() -> Vec<Binding<M>> {
+    vec![bind(b"\x03", || {
+        // Control-C
+        Some(M::quit())
+    })]
+}
+
+// A mode's keymap, along with the state of the key sequence being
+// typed.
+pub struct Keymap<M> {
+    // The mapping from key events to commands.
+    mapping: Vec<Binding<M>>,
+
+    // Action for keys that aren't bound to anything, if any.
+    fallback: Option<fn() -> Option<M>>,
+
+    // The fallback's label in the hint bar, if it's shown there.
+    fallback_hint: Option<&'static str>,
+
+    // Partial key combination entered. This is only used for parsing
+    // multi-byte escape codes at the moment, and should not be used
+    // for actual series of keystrokes (a la Emacs).
+    partial: Vec<u8>,
+
+    // The last key pressed that has a double-press binding, and when,
+    // if no other key has been pressed since.
+    last_press: Option<(Vec<u8>, Instant)>,
+}
+
+impl<M: GlobalMsg> Keymap<M> {
+    // Creates a keymap from a mode's bindings, plus the global ones.
+    pub fn new(bindings: Vec<Binding<M>>) -> Keymap<M> {
+        let mut mapping = global_bindings();
+        mapping.extend(bindings);
+        Keymap {
+            mapping,
+            fallback: None,
+            fallback_hint: None,
+            partial: vec![],
+            last_press: None,
+        }
+    }
+
+    // Sets the action for keys that aren't bound to anything.
+    pub fn with_fallback(self, fallback: fn() -> Option<M>) -> Keymap<M> {
+        Keymap {
+            fallback: Some(fallback),
+            ..self
+        }
+    }
+
+    // Labels the fallback action, so that it's listed in the hint bar.
+    pub fn with_fallback_hint(self, label: &'static str) -> Keymap<M> {
+        Keymap {
+            fallback_hint: Some(label),
+            ..self
+        }
+    }
+
+    // The keys to list in the hint bar, with their labels. Where
+    // several keys do the same thing, only the first one bound is
+    // listed; keys shadowed by an earlier binding aren't listed at
+    // all.
+    pub fn hints(&self) -> Vec<(String, &'static str)> {
+        let mut hints: Vec<(String, &'static str)> = vec![];
+        for (n, b) in self.mapping.iter().enumerate() {
+            if let Some(label) = b.3 {
+                let shadowed = self.mapping[..n].iter().any(|e| e.0 == b.0);
+                if !shadowed && !hints.iter().any(|&(_, l)| l == label) {
+                    hints.push((key_name(&b.0), label));
+                }
+            }
+        }
+        if let Some(label) = self.fallback_hint {
+            hints.push(("Any key".to_string(), label));
+        }
+        hints
+    }
+
+    // Sends a byte received from the keyboard to the keymap, which
+    // processes it and may or may not produce a message directing
+    // what to do.
+    pub fn send(&mut self, key: u8) -> Option<M> {
+        self.send_at(key, Instant::now())
+    }
+
+    // Sends a byte received from the keyboard at the given time.
+    fn send_at(&mut self, key: u8, now: Instant) -> Option<M> {
+        self.partial.push(key);
+        match get_binding(&self.partial, &self.mapping) {
+            BindingState::Invalid => {
+                self.partial = vec![];
+                self.last_press = None;
+                self.fallback.and_then(|f| f())
+            }
+            BindingState::Start => None,
+            BindingState::Complete(b) => {
+                let keys = std::mem::take(&mut self.partial);
+                let double = match (b.2, self.last_press.take()) {
+                    (Some(double), Some((last, time)))
+                        if last == keys
+                            && (now - time).as_secs_f64() <= constants::DOUBLE_PRESS_TIME =>
+                    {
+                        Some(double)
+                    }
+                    (Some(_), _) => {
+                        self.last_press = Some((keys, now));
+                        None
+                    }
+                    (None, _) => None,
+                };
+
+                match double {
+                    Some(double) => double(),
+                    None => b.1(),
+                }
+            }
+        }
+    }
+}
+
+// Possible states of the key binding engine.
+#[derive(PartialEq, Debug)]
+enum BindingState<'a, M> {
+    // The characters in the queue are not a valid key binding, nor
+    // are they the first part of valid key binding.
+    Invalid,
+
+    // The characters in the queue form the beginning of one or more
+    // keybindings, but we don't have a complete key binding yet.
+    Start,
+
+    // The characters in the queue are a perfect match for a key
+    // binding.
+    Complete(&'a Binding<M>),
+}
+
+// Calculates the state of the key binding engine, given a set of
+// characters that have already been received.
+fn get_binding<'a, M>(queue: &[u8], bindings: &'a [Binding<M>]) -> BindingState<'a, M> {
+    let mut is_prefix = false;
+    for b in bindings {
+        if b.0 == queue {
+            return BindingState::Complete(b);
+        }
+
+        if b.0.starts_with(queue) {
+            is_prefix = true;
+        }
+    }
+
+    match is_prefix {
+        true => BindingState::Start,
+        false => BindingState::Invalid,
+    }
+}
+
+// Keyboard layouts, used to put the directional keys in the same
+// place on the keyboard whatever letters are printed there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyboardLayout {
+    Qwerty,
+    Dvorak,
+    Colemak,
+    Azerty,
+}
+
+impl KeyboardLayout {
+    // Parses a layout's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<KeyboardLayout> {
+        Ok(match name {
+            "qwerty" => KeyboardLayout::Qwerty,
+            "dvorak" => KeyboardLayout::Dvorak,
+            "colemak" => KeyboardLayout::Colemak,
+            "azerty" => KeyboardLayout::Azerty,
+            _ => bail!(format!("Unknown keyboard layout {}", name)),
+        })
+    }
+
+    // The three letter rows of the layout, top to bottom.
+    fn rows(self) -> [&'static [u8]; 3] {
+        match self {
+            KeyboardLayout::Qwerty => [b"qwertyuiop", b"asdfghjkl;", b"zxcvbnm,./"],
+            KeyboardLayout::Dvorak => [b"',.pyfgcrl", b"aoeuidhtns", b";qjkxbmwvz"],
+            KeyboardLayout::Colemak => [b"qwfpgjluy;", b"arstdhneio", b"zxcvbkm,./"],
+            KeyboardLayout::Azerty => [b"azertyuiop", b"qsdfghjklm", b"wxcvbn,;:!"],
+        }
+    }
+
+    // The key in this layout at the position of the given key on a
+    // QWERTY keyboard.
+    pub fn key_at(self, qwerty: u8) -> u8 {
+        let qwerty_rows = KeyboardLayout::Qwerty.rows();
+        for (row, layout_row) in qwerty_rows.iter().zip(self.rows().iter()) {
+            if let Some(n) = row.iter().position(|&k| k == qwerty) {
+                return layout_row[n];
+            }
+        }
+
+        qwerty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    enum TestMsg {
+        Left,
+        Other,
+        Quit,
+    }
+
+    impl GlobalMsg for TestMsg {
+        fn quit() -> TestMsg {
+            TestMsg::Quit
+        }
+    }
+
+    #[test]
+    fn multichar_binding_test() {
+        let bindings = vec![
+            bind(b"\x1B[D", || Some(TestMsg::Left)),
+            bind(b"\x1B[C", || None),
+        ];
+
+        // Type in the left arrow key, character by character.
+        let mut left = vec![];
+        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
+        left.push(b'\x1B');
+        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
+        left.push(91);
+        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
+        left.push(b'X');
+        assert_eq!(get_binding(&left, &bindings), BindingState::Invalid);
+        left.pop();
+        left.push(b'D');
+        match get_binding(&left, &bindings) {
+            BindingState::Complete(_) => (),
+            _ => panic!("Didn't recognize binding"),
+        };
+    }
+
+    #[test]
+    fn keymap_test() {
+        // Global bindings can't be shadowed by a mode's bindings.
+        let mut keymap = Keymap::new(vec![bind(b"\x03", || Some(TestMsg::Left))]);
+        assert_eq!(keymap.send(b'\x03'), Some(TestMsg::Quit));
+        assert_eq!(keymap.send(b'x'), None);
+
+        // The fallback catches unbound keys, but not prefixes of bound
+        // ones.
+        let mut keymap = Keymap::new(vec![bind(b"\x1B[D", || Some(TestMsg::Left))])
+            .with_fallback(|| Some(TestMsg::Other));
+        assert_eq!(keymap.send(b'\x1B'), None);
+        assert_eq!(keymap.send(b'['), None);
+        assert_eq!(keymap.send(b'D'), Some(TestMsg::Left));
+        assert_eq!(keymap.send(b'x'), Some(TestMsg::Other));
+    }
+
+    #[test]
+    fn double_press_test() {
+        let mut keymap = Keymap::new(vec![
+            bind_double(b" ", || Some(TestMsg::Left), || Some(TestMsg::Other)),
+            bind(b"x", || None),
+        ]);
+        let start = Instant::now();
+        let soon = Duration::from_secs_f64(constants::DOUBLE_PRESS_TIME / 2.0);
+        let late = Duration::from_secs_f64(constants::DOUBLE_PRESS_TIME * 4.0);
+
+        // A quick second press is a double press; a third starts over.
+        assert_eq!(keymap.send_at(b' ', start), Some(TestMsg::Left));
+        assert_eq!(keymap.send_at(b' ', start + soon), Some(TestMsg::Other));
+        assert_eq!(keymap.send_at(b' ', start + soon * 2), Some(TestMsg::Left));
+
+        // Slow presses, or presses with another key between, aren't.
+        let start = start + late;
+        assert_eq!(keymap.send_at(b' ', start), Some(TestMsg::Left));
+        assert_eq!(keymap.send_at(b'x', start), None);
+        assert_eq!(keymap.send_at(b' ', start + soon), Some(TestMsg::Left));
+    }
+
+    #[test]
+    fn hints_test() {
+        // Only labelled keys are listed, and only the first key for
+        // each label.
+        let keymap = Keymap::new(vec![
+            bind(b"h", || Some(TestMsg::Left)).hint("Left"),
+            bind(b"\x1B[D", || Some(TestMsg::Left)).hint("Left"),
+            bind(b"\x10", || Some(TestMsg::Other)).hint("Other"),
+            bind(b" ", || Some(TestMsg::Other)),
+            bind(b"h", || Some(TestMsg::Other)).hint("Shadowed"),
+        ])
+        .with_fallback(|| Some(TestMsg::Other))
+        .with_fallback_hint("Back");
+        assert_eq!(
+            keymap.hints(),
+            vec![
+                ("h".to_string(), "Left"),
+                ("^P".to_string(), "Other"),
+                ("Any key".to_string(), "Back"),
+            ]
+        );
+        assert_eq!(key_name(b"\x1B[D"), "\u{2190}");
+        assert_eq!(key_name(b" "), "Spc");
+    }
+
+    #[test]
+    fn layout_test() {
+        assert_eq!(KeyboardLayout::Qwerty.key_at(b'k'), b'k');
+        assert_eq!(KeyboardLayout::Dvorak.key_at(b'k'), b't');
+        assert_eq!(KeyboardLayout::Colemak.key_at(b'j'), b'n');
+        assert_eq!(KeyboardLayout::Azerty.key_at(b'h'), b'h');
+    }
+}
@@ -0,0 +1,267 @@
+// LED ring or strip display, driven over a serial line or OSC, so
+// the beat can be seen from a music stand instead of a laptop.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Event};
+use crate::constants;
+use crate::errors::*;
+use crate::met_view::ViewBackend;
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use crate::osc;
+use crate::tick_clock::TickClock;
+use error_chain::bail;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::UdpSocket;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+use termios::Termios;
+
+// Address the frames are sent to over OSC.
+const LEDS_ADDRESS: &str = "/metronome/leds";
+
+// Color of an LED, as red, green and blue.
+type Rgb = [u8; 3];
+
+const OFF: Rgb = [0, 0, 0];
+const MARKER: Rgb = [constants::LED_MARKER; 3];
+const COUNT_IN: Rgb = [255, 160, 0];
+
+// Colors of the clicks, by emphasis: downbeats red, group accents
+// orange, beats green and subdivisions blue.
+const ACCENT_COLORS: [Rgb; 4] = [[255, 0, 0], [255, 96, 0], [0, 255, 0], [0, 64, 255]];
+
+// Where the frames go.
+enum LedLink {
+    // A serial line to a microcontroller running an Adalight sketch.
+    Serial(File),
+
+    // An OSC receiver, which gets a `/metronome/leds` message with
+    // one 0xRRGGBB integer per LED.
+    Osc(UdpSocket),
+}
+
+// Shows the measure on a ring (or strip) of LEDs: dim markers where
+// the beats fall, and the current position lit in the color of the
+// latest click's accent.
+pub struct LedView {
+    link: LedLink,
+    leds: usize,
+
+    // Number of ticks in the measure, and per beat.
+    ticks: usize,
+    beat_len: usize,
+
+    // Color of the latest click, or None after a rest or once the
+    // metronome stops.
+    color: Option<Rgb>,
+
+    // Beats left in the count-in, while one is going on.
+    counting: Option<u32>,
+
+    // Where the metronome is in the measure, so the position can
+    // move along between ticks.
+    clock: TickClock,
+
+    // The last frame sent, so unchanged frames aren't sent again.
+    sent: Vec<Rgb>,
+}
+
+impl LedView {
+    // Sets up to drive `leds` LEDs through the given serial device
+    // (a path) or OSC host:port, showing the given rhythm.
+    pub fn new(target: &str, leds: usize, rhythm: &BeatSpec) -> Result<LedView> {
+        let link = if target.starts_with('/') {
+            LedLink::Serial(open_serial(target)?)
+        } else {
+            LedLink::Osc(osc::connect(target)?)
+        };
+
+        Ok(LedView {
+            link,
+            leds,
+            ticks: rhythm.get_ticks().len(),
+            beat_len: rhythm.get_beat_len() as usize,
+            color: None,
+            counting: None,
+            clock: TickClock::new(),
+            sent: vec![],
+        })
+    }
+
+    // The colors of the LEDs at the given time.
+    fn frame(&self, now: Instant) -> Vec<Rgb> {
+        let mut frame = vec![OFF; self.leds];
+        if self.ticks == 0 || self.beat_len == 0 {
+            return frame;
+        }
+
+        // Markers on the beats count down through a count-in.
+        for (n, tick) in (0..self.ticks).step_by(self.beat_len).enumerate() {
+            frame[tick * self.leds / self.ticks] = match self.counting {
+                Some(left) if (n as u32) < left => COUNT_IN,
+                _ => MARKER,
+            };
+        }
+
+        if let (Some(color), None) = (self.color, self.counting) {
+            let led = (self.clock.measure_progress(now) * self.leds as f64) as usize;
+            frame[led.min(self.leds - 1)] = color;
+        }
+        frame
+    }
+}
+
+impl ViewBackend for LedView {
+    fn draw(&mut self) {
+        let frame = self.frame(Instant::now());
+        if frame == self.sent {
+            return;
+        }
+
+        let result = match &mut self.link {
+            LedLink::Serial(file) => file.write_all(&adalight(&frame)),
+            LedLink::Osc(socket) => socket.send(&osc_frame(&frame)).map(|_| ()),
+        };
+        // Keep going if the LEDs are unplugged; they'll catch up with
+        // the next frame once they're back.
+        if let Err(e) = result {
+            log::debug!("Couldn't update the LEDs: {}", e);
+        }
+        self.sent = frame;
+    }
+}
+
+impl Subscriber for LedView {
+    fn notify(&mut self, event: &ModelEvent) {
+        self.clock.notify(event, Instant::now());
+        match event {
+            ModelEvent::Tick {
+                ticks,
+                beat_len,
+                event,
+                ..
+            } => {
+                self.ticks = *ticks;
+                self.beat_len = *beat_len as usize;
+                self.counting = None;
+                self.color = match event {
+                    Event::Beep(emph) => {
+                        Some(ACCENT_COLORS[(*emph as usize).min(ACCENT_COLORS.len() - 1)])
+                    }
+                    Event::Rest => None,
+                };
+            }
+            ModelEvent::RhythmChanged { rhythm, .. } => {
+                self.ticks = rhythm.get_ticks().len();
+                self.beat_len = rhythm.get_beat_len() as usize;
+            }
+            ModelEvent::CountIn(left) => self.counting = Some(*left),
+            ModelEvent::Transport(Transport::Stop) => self.color = None,
+            _ => {}
+        }
+    }
+}
+
+// Opens a serial device for writing frames to. Anything that isn't a
+// terminal (a pipe, say, for testing) is written to as it is.
+fn open_serial(path: &str) -> Result<File> {
+    let file = match OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) => bail!(format!("Can't open LED device {}: {}", path, e)),
+    };
+
+    if let Ok(mut tty) = Termios::from_fd(file.as_raw_fd()) {
+        termios::cfmakeraw(&mut tty);
+        tty.c_cflag |= termios::CLOCAL;
+        termios::cfsetspeed(&mut tty, termios::os::target::B115200)?;
+        termios::tcsetattr(file.as_raw_fd(), termios::TCSANOW, &tty)?;
+    }
+    Ok(file)
+}
+
+// Encodes a frame in the Adalight protocol: "Ada", the number of
+// LEDs less one as two bytes, a checksum of those, then the colors.
+fn adalight(frame: &[Rgb]) -> Vec<u8> {
+    let count = frame.len().saturating_sub(1);
+    let (hi, lo) = ((count >> 8) as u8, count as u8);
+    let mut msg = vec![b'A', b'd', b'a', hi, lo, hi ^ lo ^ 0x55];
+    msg.extend(frame.iter().flatten());
+    msg
+}
+
+// Encodes a frame as an OSC message.
+fn osc_frame(frame: &[Rgb]) -> Vec<u8> {
+    let colors: Vec<i32> = frame
+        .iter()
+        .map(|&[r, g, b]| i32::from_be_bytes([0, r, g, b]))
+        .collect();
+    osc::encode(LEDS_ADDRESS, &colors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn led_frame_test() {
+        let rhythm = BeatSpec::from_subdiv(4, 2);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = socket.local_addr().unwrap().to_string();
+        let mut view = LedView::new(&target, 8, &rhythm).unwrap();
+        let now = Instant::now();
+
+        // Before the first tick, only the beats are marked.
+        let frame = view.frame(now);
+        assert_eq!(frame.iter().filter(|&&led| led == MARKER).count(), 4);
+        assert_eq!(frame[2], MARKER);
+        assert_eq!(frame[1], OFF);
+
+        // The count-in lights up the beats still to come.
+        view.notify(&ModelEvent::CountIn(2));
+        let frame = view.frame(now);
+        assert_eq!(&frame[..4], &[COUNT_IN, OFF, COUNT_IN, OFF]);
+        assert_eq!(frame[4], MARKER);
+
+        // A click lights its place in the measure.
+        view.notify(&ModelEvent::TempoChanged(60.0));
+        view.notify(&ModelEvent::Tick {
+            tick: 3,
+            ticks: 8,
+            beat_len: 2,
+            event: Event::Beep(3),
+            hand: None,
+        });
+        assert_eq!(view.frame(Instant::now())[3], ACCENT_COLORS[3]);
+        assert_eq!(view.frame(Instant::now())[0], MARKER);
+
+        view.draw();
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        // The address and type tags, padded, then an integer per LED.
+        assert_eq!(n, 16 + 12 + 8 * 4);
+
+        let msg = adalight(&[[1, 2, 3], [4, 5, 6]]);
+        assert_eq!(msg, [b'A', b'd', b'a', 0, 1, 0x54, 1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,206 @@
+// Ableton Link support: joins a Link session on the local network,
+// sharing tempo and beat phase with the other apps in it. This needs
+// Link's abl_link C library, and the `link` feature.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use std::time::Instant;
+
+#[cfg(not(feature = "link"))]
+use error_chain::bail;
+#[cfg(not(feature = "link"))]
+use std::convert::Infallible;
+#[cfg(feature = "link")]
+use std::time::Duration;
+
+// Bindings to the parts of abl_link we use.
+#[cfg(feature = "link")]
+mod ffi {
+    use std::os::raw::c_void;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct AblLink {
+        pub inner: *mut c_void,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct AblLinkSessionState {
+        pub inner: *mut c_void,
+    }
+
+    #[link(name = "abl_link")]
+    #[link(name = "stdc++")]
+    extern "C" {
+        pub fn abl_link_create(bpm: f64) -> AblLink;
+        pub fn abl_link_destroy(link: AblLink);
+        pub fn abl_link_enable(link: AblLink, enable: bool);
+        pub fn abl_link_clock_micros(link: AblLink) -> i64;
+        pub fn abl_link_create_session_state() -> AblLinkSessionState;
+        pub fn abl_link_destroy_session_state(state: AblLinkSessionState);
+        pub fn abl_link_capture_app_session_state(link: AblLink, state: AblLinkSessionState);
+        pub fn abl_link_commit_app_session_state(link: AblLink, state: AblLinkSessionState);
+        pub fn abl_link_tempo(state: AblLinkSessionState) -> f64;
+        pub fn abl_link_set_tempo(state: AblLinkSessionState, bpm: f64, at_time: i64);
+        pub fn abl_link_beat_at_time(state: AblLinkSessionState, time: i64, quantum: f64) -> f64;
+        pub fn abl_link_phase_at_time(state: AblLinkSessionState, time: i64, quantum: f64) -> f64;
+        pub fn abl_link_time_at_beat(state: AblLinkSessionState, beat: f64, quantum: f64) -> i64;
+    }
+}
+
+// Membership of a Link session. Only the thread that created it may
+// use it, since it shares one session state between calls.
+pub struct Link {
+    #[cfg(feature = "link")]
+    link: ffi::AblLink,
+    #[cfg(feature = "link")]
+    state: ffi::AblLinkSessionState,
+
+    // Without Link support a session can't be created at all.
+    #[cfg(not(feature = "link"))]
+    never: Infallible,
+}
+
+#[cfg(feature = "link")]
+impl Link {
+    // Joins the Link session on the local network, or starts one at
+    // the given tempo if there's none yet. If there is one, its tempo
+    // wins.
+    pub fn new(tempo: f64) -> Result<Link> {
+        unsafe {
+            let link = ffi::abl_link_create(tempo);
+            ffi::abl_link_enable(link, true);
+            Ok(Link {
+                link,
+                state: ffi::abl_link_create_session_state(),
+            })
+        }
+    }
+
+    // The session's tempo, in beats per minute.
+    pub fn tempo(&self) -> f64 {
+        unsafe {
+            ffi::abl_link_capture_app_session_state(self.link, self.state);
+            ffi::abl_link_tempo(self.state)
+        }
+    }
+
+    // Changes the session's tempo, for everyone in it.
+    pub fn set_tempo(&self, tempo: f64) {
+        unsafe {
+            ffi::abl_link_capture_app_session_state(self.link, self.state);
+            let now = ffi::abl_link_clock_micros(self.link);
+            ffi::abl_link_set_tempo(self.state, tempo, now);
+            ffi::abl_link_commit_app_session_state(self.link, self.state);
+        }
+    }
+
+    // Finds the next tick to play, in a measure of `ticks` ticks with
+    // `beat_len` to a beat, lined up with the session's beat grid, and
+    // when to play it.
+    pub fn next_tick(&self, ticks: usize, beat_len: u32) -> (usize, Instant) {
+        let quantum = ticks as f64 / beat_len as f64;
+        unsafe {
+            ffi::abl_link_capture_app_session_state(self.link, self.state);
+            let now = ffi::abl_link_clock_micros(self.link);
+            let beat = ffi::abl_link_beat_at_time(self.state, now, quantum);
+            let phase = ffi::abl_link_phase_at_time(self.state, now, quantum);
+            let (tick, ahead) = next_tick(phase, ticks, beat_len);
+            let time = ffi::abl_link_time_at_beat(self.state, beat + ahead, quantum);
+            (tick, self.instant_at(time))
+        }
+    }
+
+    // Converts a time on Link's clock, in microseconds, to an Instant.
+    fn instant_at(&self, micros: i64) -> Instant {
+        let now = Instant::now();
+        let from_now = micros - unsafe { ffi::abl_link_clock_micros(self.link) };
+        if from_now >= 0 {
+            now + Duration::from_micros(from_now as u64)
+        } else {
+            let ago = Duration::from_micros(from_now.unsigned_abs());
+            now.checked_sub(ago).unwrap_or(now)
+        }
+    }
+}
+
+#[cfg(feature = "link")]
+impl Drop for Link {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::abl_link_destroy_session_state(self.state);
+            ffi::abl_link_destroy(self.link);
+        }
+    }
+}
+
+#[cfg(not(feature = "link"))]
+impl Link {
+    pub fn new(_tempo: f64) -> Result<Link> {
+        bail!("This metronome was built without Ableton Link support; rebuild it with --features link")
+    }
+
+    pub fn tempo(&self) -> f64 {
+        match self.never {}
+    }
+
+    pub fn set_tempo(&self, _tempo: f64) {
+        match self.never {}
+    }
+
+    pub fn next_tick(&self, _ticks: usize, _beat_len: u32) -> (usize, Instant) {
+        match self.never {}
+    }
+}
+
+// Finds the tick that follows the one nearest `phase` beats into a
+// measure of `ticks` ticks, with `beat_len` to a beat, and how many
+// beats after `phase` it falls. The nearest tick counts as the one
+// just played, so a tick that played a little late isn't played again.
+#[cfg_attr(not(feature = "link"), allow(dead_code))]
+fn next_tick(phase: f64, ticks: usize, beat_len: u32) -> (usize, f64) {
+    let beat_len = beat_len as f64;
+    let next = (phase * beat_len).round() + 1.0;
+    (next as usize % ticks, next / beat_len - phase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_tick_test() {
+        // Four beats of two ticks each.
+        let (tick, ahead) = next_tick(0.0, 8, 2);
+        assert_eq!(tick, 1);
+        assert!((ahead - 0.5).abs() < 1e-9);
+
+        // A little late or early for tick 3, so tick 4 is next.
+        let (tick, ahead) = next_tick(1.55, 8, 2);
+        assert_eq!(tick, 4);
+        assert!((ahead - 0.45).abs() < 1e-9);
+        assert_eq!(next_tick(1.45, 8, 2).0, 4);
+
+        // Near the end of the measure, the next tick is the second of
+        // the next measure.
+        let (tick, ahead) = next_tick(3.9, 8, 2);
+        assert_eq!(tick, 1);
+        assert!((ahead - 0.6).abs() < 1e-9);
+    }
+}
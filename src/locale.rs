@@ -0,0 +1,146 @@
+// Locale-aware numbers: decimal commas in what the user types, and
+// optionally the locale's separators in what we show.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use error_chain::bail;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+// How numbers are written: the decimal separator, and the separator
+// between groups of thousands, if any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumberFormat {
+    pub decimal: char,
+    pub thousands: Option<char>,
+}
+
+// The plain format numbers are shown in unless --locale-numbers is
+// given.
+const PLAIN: NumberFormat = NumberFormat {
+    decimal: '.',
+    thousands: None,
+};
+
+// The format numbers are shown in, once chosen at startup.
+static FORMAT: OnceLock<NumberFormat> = OnceLock::new();
+
+// Shows numbers in the user's locale's format from now on. Must be
+// called before any numbers are shown.
+pub fn use_locale() {
+    let format = locale_format();
+    log::debug!("Showing numbers as {:?}", format);
+    let _ = FORMAT.set(format);
+}
+
+// Formats a number the way the user wants to see it.
+pub fn format(x: f64) -> String {
+    FORMAT.get().unwrap_or(&PLAIN).format(x)
+}
+
+// Parses a number typed by the user, who may have used a comma as the
+// decimal point whatever their locale.
+pub fn parse(arg: &str) -> Result<f64> {
+    match arg.replacen(',', ".", 1).parse() {
+        Ok(x) => Ok(x),
+        Err(_) => bail!(format!("Invalid number {}", arg)),
+    }
+}
+
+impl NumberFormat {
+    pub fn format(&self, x: f64) -> String {
+        let plain = x.to_string();
+        let (sign, plain) = match plain.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", &plain[..]),
+        };
+        let (int, frac) = match plain.split_once('.') {
+            Some((int, frac)) => (int, Some(frac)),
+            None => (plain, None),
+        };
+
+        let mut out = sign.to_string();
+        for (n, digit) in int.chars().enumerate() {
+            if let Some(sep) = self.thousands {
+                if n > 0 && (int.len() - n).is_multiple_of(3) {
+                    out.push(sep);
+                }
+            }
+            out.push(digit);
+        }
+        if let Some(frac) = frac {
+            out.push(self.decimal);
+            out.push_str(frac);
+        }
+        out
+    }
+}
+
+// Reads the number format of the locale set in the environment.
+fn locale_format() -> NumberFormat {
+    // SAFETY: we only switch LC_NUMERIC for as long as it takes to
+    // copy the separators out, before any other threads are started,
+    // and switch it back so C code in the libraries we use isn't
+    // surprised.
+    unsafe {
+        if libc::setlocale(libc::LC_NUMERIC, b"\0".as_ptr() as *const libc::c_char).is_null() {
+            return PLAIN;
+        }
+        let conv = &*libc::localeconv();
+        let first_char = |s: *const libc::c_char| {
+            if s.is_null() {
+                None
+            } else {
+                CStr::from_ptr(s).to_str().ok()?.chars().next()
+            }
+        };
+        let format = NumberFormat {
+            decimal: first_char(conv.decimal_point).unwrap_or('.'),
+            thousands: first_char(conv.thousands_sep),
+        };
+        libc::setlocale(libc::LC_NUMERIC, b"C\0".as_ptr() as *const libc::c_char);
+        format
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_test() {
+        assert_eq!(PLAIN.format(1234567.5), "1234567.5");
+        let german = NumberFormat {
+            decimal: ',',
+            thousands: Some('.'),
+        };
+        assert_eq!(german.format(57.5), "57,5");
+        assert_eq!(german.format(1234567.25), "1.234.567,25");
+        assert_eq!(german.format(-1000.0), "-1.000");
+        assert_eq!(german.format(999.0), "999");
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("57,5").unwrap(), 57.5);
+        assert_eq!(parse("57.5").unwrap(), 57.5);
+        assert_eq!(parse("120").unwrap(), 120.0);
+        assert!(parse("1,2,3").is_err());
+        assert!(parse("fast").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// Optional logging of the program's activity to a file, for
+// diagnosing problems after the fact.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use crate::locale;
+use crate::model_event::{ModelEvent, Subscriber};
+use error_chain::bail;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Logger that appends each message to a file, stamped with the time
+// since the program started.
+struct FileLogger {
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let elapsed = self.start.elapsed();
+        let mut file = self.file.lock().unwrap();
+        // There's nowhere to report a failure to log, so ignore it.
+        let _ = writeln!(
+            file,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            elapsed.as_secs(),
+            elapsed.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+// Starts logging to the given file, which is appended to, at the
+// given verbosity.
+pub fn init(path: &str, level: LevelFilter) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let logger = FileLogger {
+        file: Mutex::new(file),
+        start: Instant::now(),
+    };
+
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        bail!("Logging was already started");
+    }
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+// Subscriber that logs changes to the metronome's state.
+pub struct EventLogger;
+
+impl Subscriber for EventLogger {
+    fn notify(&mut self, event: &ModelEvent) {
+        match event {
+            ModelEvent::Tick { tick, .. } => log::trace!("Tick {}", tick),
+            ModelEvent::TempoChanged(tempo) => {
+                log::debug!("Tempo set to {}", locale::format(*tempo))
+            }
+            ModelEvent::VolumeChanged(volume) => {
+                log::debug!("Volume set to {}", locale::format(*volume))
+            }
+            ModelEvent::RhythmChanged { rhythm, meter } => log::info!(
+                "Rhythm set to {} (meter {:?})",
+                rhythm.reduce().to_rhythmspec(),
+                meter
+            ),
+            ModelEvent::OutputChanged(output) => log::info!("Click output set to {:?}", output),
+            ModelEvent::TimbreChanged(timbre) => log::info!("Timbre set to {:?}", timbre),
+            ModelEvent::AccentOnlyChanged(on) => log::info!("Accent-only mode set to {}", on),
+            ModelEvent::LockChanged(on) => log::info!("Keyboard lock set to {}", on),
+            ModelEvent::CountIn(left) => log::debug!("Counting in, {} beats left", left),
+            ModelEvent::SongPosition {
+                section,
+                measure,
+                measures,
+            } => log::debug!(
+                "Song section {}, measure {} of {}",
+                section,
+                measure,
+                measures
+            ),
+            ModelEvent::Transport(transport) => log::debug!("Transport: {:?}", transport),
+        }
+    }
+}
@@ -16,29 +16,80 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
+extern crate colorful;
 extern crate getopts;
 extern crate termios;
-extern crate colorful;
 pub mod app_state;
+pub mod audio_clock;
 pub mod beat_spec;
+pub mod conductor_view;
 pub mod config;
 pub mod constants;
+pub mod hint_view;
+pub mod hooks;
+pub mod http_api;
+pub mod humanize;
+pub mod instance;
+pub mod jack;
+pub mod journal;
+pub mod keymap;
+pub mod led_view;
+pub mod link;
+pub mod locale;
+pub mod logger;
 pub mod met_controller;
 pub mod met_model;
 pub mod met_view;
+pub mod midi;
+pub mod model_event;
+pub mod osc;
+pub mod poly_view;
+pub mod preset;
+pub mod profile;
+pub mod realtime;
+pub mod render;
+pub mod score_view;
+pub mod signal_input;
+pub mod smf;
+pub mod song;
 pub mod sound;
+pub mod sound_browser;
+pub mod spec_model;
+pub mod spec_view;
+pub mod sticking;
+pub mod system_volume;
 pub mod tap_model;
+pub mod tap_stats;
 pub mod tap_view;
+pub mod tempo_calc;
+pub mod tempo_detect;
+pub mod tempo_map;
 pub mod termios_handler;
+pub mod tick_clock;
+pub mod timing;
+pub mod wav;
 
-use app_state::state_loop;
+use app_state::{init_kbd_thread, state_loop, InputQueue, StdinClose};
 use config::Config;
-use met_model::MetronomeState;
-use sound::AudioConfig;
+use hooks::ExecHooks;
+use http_api::{ApiStatus, StatusRecorder};
+use instance::{InstanceLock, StateRecorder};
+use journal::{Journal, JournalEntry, JournalRecorder};
+use led_view::LedView;
+use link::Link;
+use met_model::{MetronomeSettings, MetronomeState};
+use met_view::Views;
+use midi::{MidiClock, MidiOut};
+use model_event::EventBus;
+use osc::OscSender;
+use sound::{AudioConfig, Bell, ClickOutput, NoAudio, SoundBackend};
 use std::env;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use tempo_map::TempoRecorder;
 use termios_handler::TermiosHandler;
 
-use error_chain::{error_chain, quick_main};
+use error_chain::{bail, error_chain, quick_main};
 mod errors {
     use super::*;
     error_chain! {
@@ -73,15 +124,253 @@ fn run() -> Result<()> {
 
     let cfg = Config::new(&args_ref)?;
     if let config::ConfigResult::Run(cfg) = cfg {
-        let _termios = TermiosHandler::set_stdin_raw()?;
+        if cfg.locale_numbers {
+            locale::use_locale();
+        }
+        if let Some(path) = &cfg.log_file {
+            logger::init(path, cfg.log_level)?;
+        }
+        log::info!("Starting {} version {}", constants::NAME, constants::VER);
+
+        // The journal is best-effort; a metronome that can't save its
+        // state is still worth running.
+        let journal = match Journal::new() {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                log::warn!("Not keeping a state journal: {}", e);
+                None
+            }
+        };
 
-        let rhythm = cfg
-            .rhythm
-            .make_divisible(constants::MEAS_INDIC_WIDTH as u32);
-        let acfg = AudioConfig::new()?;
-        let init_state = MetronomeState::new(&rhythm, acfg, cfg.volume, cfg.tempo);
+        let mut cfg = *cfg;
 
-        let s = state_loop(Box::new(init_state));
+        // Only one metronome plays at a time, so that a second one
+        // doesn't start clashing with the first by accident; if we
+        // can't tell whether one is running, carry on regardless.
+        let (lock, taken_over) = match InstanceLock::acquire() {
+            Ok(Some(lock)) => (Some(lock), None),
+            Ok(None) if cfg.takeover => {
+                let (lock, entry) = InstanceLock::take_over()?;
+                log::info!("Took over from the running metronome: {:?}", entry);
+                (Some(lock), Some(entry))
+            }
+            Ok(None) => bail!(
+                "A metronome is already running; use --takeover to replace it, \
+                 or --send <command> to control it"
+            ),
+            Err(e) => {
+                log::warn!("Not coordinating with other instances: {}", e);
+                (None, None)
+            }
+        };
+
+        let mut output = ClickOutput::Audio;
+        let resumed = match (taken_over, cfg.resume, &journal) {
+            (Some(entry), _, _) => Some(entry),
+            (None, true, Some(journal)) => Some(journal.last_entry()?),
+            (None, true, None) => bail!("No state journal to resume from"),
+            (None, false, _) => None,
+        };
+        if let Some(entry) = resumed {
+            log::info!("Resuming from: {:?}", entry);
+            cfg.tempo = entry.tempo;
+            cfg.volume = entry.volume;
+            cfg.rhythm = entry.rhythm;
+            cfg.meter = entry.meter;
+            cfg.poly = None;
+            output = entry.output;
+        }
+
+        // The HTTP API is no use to anyone without a token to let them
+        // in.
+        let http = match (cfg.http_listen, cfg.http_token.take()) {
+            (Some(port), Some(token)) => Some((port, token)),
+            (Some(_), None) => bail!("The HTTP API needs a token; give one with --http-token"),
+            (None, _) => None,
+        };
+
+        // Without an audio device, the metronome still runs, just
+        // silently.
+        let mut audio_clock = None;
+        let realtime = cfg.sound.realtime;
+        // Flams hold every click back by their spacing, whatever it's
+        // played on, and the display has to wait that out too.
+        let flam = cfg.sound.flam;
+        let latency = cfg.latency + flam.unwrap_or_default();
+        // The model's emphases follow its rhythm for the sound too.
+        let emphasis = cfg.sound.emphasis.clone();
+        let mut sound: Rc<dyn SoundBackend> = if let Some(level) = cfg.bell {
+            Rc::new(Bell::new(level, emphasis.clone(), flam))
+        } else if cfg.no_audio {
+            Rc::new(NoAudio)
+        } else {
+            match AudioConfig::new(cfg.sound) {
+                Ok(acfg) => {
+                    if cfg.audio_clock {
+                        audio_clock = Some(acfg.clock());
+                    }
+                    Rc::new(acfg)
+                }
+                Err(e) => {
+                    log::warn!("Running without audio: {}", e);
+                    eprintln!("No audio ({}); running without sound.", e);
+                    cfg.no_audio = true;
+                    Rc::new(NoAudio)
+                }
+            }
+        };
+        let midi_clock = cfg.midi.clock_device.take();
+        let midi = cfg.midi.device.is_some();
+        if midi {
+            sound = Rc::new(MidiOut::new(sound, cfg.midi, flam)?);
+        }
+        let output = output.within(!cfg.no_audio, midi);
+        let outputs = ClickOutput::cycle(!cfg.no_audio, midi);
+
+        // If we can carry on without a terminal, we can start without
+        // one too.
+        let _termios = match cfg.stdin_close {
+            StdinClose::Quit => Some(TermiosHandler::set_stdin_raw()?),
+            _ => TermiosHandler::set_stdin_raw().ok(),
+        };
+
+        // With system volume control, the volume keys start from the
+        // system's current volume rather than ours.
+        let volume = if cfg.system_volume {
+            system_volume::get_system_volume()?
+        } else {
+            cfg.volume
+        };
+
+        let link = if cfg.link {
+            Some(Rc::new(Link::new(cfg.tempo)?))
+        } else {
+            None
+        };
+
+        let bus = EventBus::new();
+        bus.subscribe(Box::new(logger::EventLogger));
+        if let Some(device) = &midi_clock {
+            bus.subscribe(Box::new(MidiClock::new(device, cfg.tempo)?));
+        }
+        let mut http_status = None;
+        if http.is_some() {
+            let status = Arc::new(Mutex::new(ApiStatus {
+                tempo: cfg.tempo,
+                volume,
+                playing: false,
+                rhythm: cfg.rhythm.reduce().to_rhythmspec(),
+            }));
+            bus.subscribe(Box::new(StatusRecorder::new(status.clone())));
+            http_status = Some(status);
+        }
+        if let Some(target) = &cfg.osc_send {
+            bus.subscribe(Box::new(OscSender::new(target)?));
+        }
+        if let Some(path) = &cfg.record_tempo {
+            bus.subscribe(Box::new(TempoRecorder::new(path, cfg.tempo)?));
+        }
+        let views = Views::new();
+        if let Some(target) = &cfg.leds {
+            views.add(Box::new(LedView::new(target, cfg.led_count, &cfg.rhythm)?));
+        }
+        if cfg.exec_on_start.is_some() || cfg.exec_on_stop.is_some() {
+            bus.subscribe(Box::new(ExecHooks::new(
+                cfg.exec_on_start.take(),
+                cfg.exec_on_stop.take(),
+            )));
+        }
+        let entry = JournalEntry {
+            tempo: cfg.tempo,
+            volume,
+            rhythm: cfg.rhythm.clone(),
+            meter: cfg.meter.clone(),
+            output,
+        };
+        // Other instances can ask the running one for its settings,
+        // to take over from it.
+        let mut control_state = None;
+        if lock.is_some() {
+            let state = Arc::new(Mutex::new(entry.clone()));
+            bus.subscribe(Box::new(StateRecorder::new(state.clone())));
+            control_state = Some(state);
+        }
+        if let Some(journal) = journal {
+            bus.subscribe(Box::new(JournalRecorder::new(journal, entry)));
+        }
+
+        let init_state = MetronomeState::new(MetronomeSettings {
+            rhythm: cfg.rhythm,
+            meter: cfg.meter,
+            poly: cfg.poly,
+            song: cfg.song,
+            tempo_map: cfg.tempo_map,
+            sound,
+            volume,
+            system_volume: cfg.system_volume,
+            volume_db: cfg.volume_db,
+            tempo: cfg.tempo,
+            output,
+            outputs,
+            conductor: cfg.conductor,
+            indicator: cfg.indicator,
+            score: cfg.score,
+            hints: cfg.hints,
+            confirm: cfg.confirm,
+            count_in: cfg.count_in,
+            sticking: cfg.sticking,
+            humanize: cfg.humanize,
+            swing: cfg.swing,
+            strict_timing: cfg.strict_timing,
+            stdin_close: cfg.stdin_close,
+            audio_clock,
+            link,
+            latency,
+            tap_latency: cfg.tap_latency,
+            timbre: cfg.timbre,
+            accent_only: cfg.accent_only,
+            accent_level: cfg.accent_level,
+            emphasis,
+            layout: cfg.layout,
+            tempo_accel: cfg.tempo_accel,
+            bus,
+            views,
+        });
+
+        let inputs = InputQueue::new();
+        init_kbd_thread(inputs.sender());
+        signal_input::init_signal_thread(inputs.sender(), cfg.stdin_close == StdinClose::Quit)?;
+        if let Some(port) = cfg.osc_listen {
+            osc::init_osc_thread(port, inputs.sender())?;
+        }
+        if cfg.jack_transport {
+            jack::init_jack_thread(inputs.sender())?;
+        }
+        if let (Some((port, token)), Some(status)) = (http, http_status) {
+            http_api::init_http_thread(port, token, status, inputs.sender())?;
+        }
+        if let Some(state) = control_state {
+            instance::init_control_thread(state, inputs.sender())?;
+        }
+
+        // The main loop ticks the metronome, so it gets real-time
+        // priority too; without permission for it, we carry on as
+        // usual.
+        if realtime {
+            match realtime::promote(constants::REALTIME_TICK_PRIORITY) {
+                Ok(()) => log::info!("Main loop running with real-time priority"),
+                Err(e) => {
+                    log::warn!("Couldn't get real-time priority: {}", e);
+                    eprintln!("No real-time priority ({}); running without it.", e);
+                }
+            }
+        }
+
+        let s = state_loop(Box::new(init_state), inputs);
+        match &s {
+            Ok(()) => log::info!("Exiting normally"),
+            Err(e) => log::error!("Exiting with error: {}", e),
+        }
         return s;
     }
 
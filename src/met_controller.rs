@@ -17,7 +17,10 @@
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::constants;
-use std::fmt;
+use crate::errors::*;
+use crate::keymap::{bind, bind_double, Binding, GlobalMsg, KeyboardLayout, Keymap};
+use error_chain::bail;
+use std::time::Instant;
 
 // Messages passed from the controller to the model, indicating user
 // requests.
@@ -34,17 +37,53 @@ pub enum ControllerMsg {
     // Toggle the metronome between a playing and paused state.
     Toggle,
 
+    // Pause the metronome and rewind it to the start of the measure.
+    Stop,
+
     // Increase the volume by the given amount (volume is on a scale
     // from 0.0 to 1.0).
     AdjustVolume(f64),
 
+    // Increase the volume by the given number of decibels.
+    AdjustVolumeDb(f64),
+
     // Increase the tempo by the given number of beats per measure.
     AdjustTempo(f64),
 
+    // Set the tempo to the given number of beats per minute.
+    SetTempo(f64),
+
+    // Switches to the next click output (audio or silent).
+    CycleOutput,
+
+    // Switches to the next click timbre.
+    CycleTimbre,
+
+    // Switches between playing every click and only the accents.
+    ToggleAccentOnly,
+
+    // Locks or unlocks the keyboard. The controller keeps track of
+    // the lock itself, and has already switched it by the time the
+    // model sees this.
+    ToggleLock,
+
     // Synchronizes the metronome, so a downbeat occurs the instant
     // this message is received.
     Sync,
 
+    // Like Sync, but skips the count-in.
+    HardSync,
+
+    // Switches to the next accent grouping for the current meter.
+    CycleGrouping,
+
+    // In the polyrhythm explorer, changes one of the two crossbeats
+    // (0 for the beat, 1 for the one against it) by the given amount.
+    AdjustCrossbeat(usize, i32),
+
+    // Opens the editor for the rhythm spec string.
+    EditSpec,
+
     // Enters tap mode.
     TapMode,
 
@@ -52,187 +91,390 @@ pub enum ControllerMsg {
     Quit,
 }
 
+impl GlobalMsg for ControllerMsg {
+    fn quit() -> ControllerMsg {
+        ControllerMsg::Quit
+    }
+}
+
+// How holding down a tempo key speeds up the tempo change: the
+// change per key repeat ramps linearly from TEMPO_ADJUST up to
+// `max_step` beats per minute over `ramp` seconds of holding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoAccel {
+    pub max_step: f64,
+    pub ramp: f64,
+}
+
+impl Default for TempoAccel {
+    fn default() -> TempoAccel {
+        TempoAccel {
+            max_step: constants::TEMPO_ACCEL_MAX,
+            ramp: constants::TEMPO_ACCEL_RAMP,
+        }
+    }
+}
+
+impl TempoAccel {
+    // Parses an acceleration of the form "<max>[:<seconds>]", e.g.
+    // "5:1" for up to 5 BPM per repeat after a second of holding.
+    pub fn parse(code: &str) -> Result<TempoAccel> {
+        let mut accel = TempoAccel::default();
+        let (max_step, ramp) = match code.split_once(':') {
+            Some((max_step, ramp)) => (max_step, Some(ramp)),
+            None => (code, None),
+        };
+
+        accel.max_step = max_step.parse()?;
+        if let Some(ramp) = ramp {
+            accel.ramp = ramp.parse()?;
+        }
+
+        if !(accel.max_step >= constants::TEMPO_ADJUST && accel.max_step.is_finite()) {
+            bail!(format!(
+                "Tempo acceleration must be at least {} BPM per press",
+                constants::TEMPO_ADJUST
+            ));
+        }
+        if !(accel.ramp >= 0.0 && accel.ramp.is_finite()) {
+            bail!("Tempo acceleration ramp must be a non-negative number of seconds");
+        }
+        Ok(accel)
+    }
+
+    // The tempo change for a press of a tempo key that's been held
+    // for the given number of seconds.
+    fn step(&self, held: f64) -> f64 {
+        let ramped = if self.ramp > 0.0 {
+            (held / self.ramp).min(1.0)
+        } else {
+            1.0
+        };
+        constants::TEMPO_ADJUST + (self.max_step - constants::TEMPO_ADJUST) * ramped
+    }
+}
+
+// The metronome mode's controller, which turns key presses into
+// ControllerMsgs.
 pub struct ControllerState {
-    // The mapping from key events to commands.
-    mapping: Vec<Binding>,
+    keymap: Keymap<ControllerMsg>,
+
+    accel: TempoAccel,
 
-    // Partial key combination entered. This is only used for parsing
-    // multi-byte escape codes at the moment, and should not be used
-    // for actual series of keystrokes (a la Emacs).
-    partial: Vec<u8>,
+    // While a tempo key is being held down: the direction it moves
+    // the tempo, when it started repeating, and when it last
+    // repeated.
+    held_tempo: Option<(f64, Instant, Instant)>,
+
+    // Whether the keyboard is locked, so that stray key presses
+    // mid-performance can't change anything. While it is, only the
+    // keys to unlock it, to play and pause, and Control-C do
+    // anything.
+    locked: bool,
+
+    // Whether the volume keys step the volume in decibels.
+    volume_db: bool,
 }
 
 impl ControllerState {
-    // Creates a new ControllerState.
-    pub fn new() -> ControllerState {
+    // Creates a new ControllerState, with the directional keys placed
+    // for the given keyboard layout.
+    pub fn new(layout: KeyboardLayout, accel: TempoAccel) -> ControllerState {
         ControllerState {
-            mapping: init_keybindings(),
-            partial: vec![],
+            keymap: Keymap::new(init_keybindings(layout)),
+            accel,
+            held_tempo: None,
+            locked: false,
+            volume_db: false,
         }
     }
 
+    // Makes the volume keys step the volume by VOL_ADJUST_DB
+    // decibels, rather than by VOL_ADJUST.
+    pub fn with_volume_db(mut self, volume_db: bool) -> ControllerState {
+        self.volume_db = volume_db;
+        self
+    }
+
+    // Whether the keyboard is locked.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    // The keys to list in the hint bar, with their labels.
+    pub fn hints(&self) -> Vec<(String, &'static str)> {
+        self.keymap.hints()
+    }
+
     // Sends a byte received from the keyboard to the controller,
     // which processes it according to the keymap and may or may not
     // produce a message directing what to do.
     pub fn send(&mut self, key: u8) -> Option<ControllerMsg> {
-        self.partial.push(key);
-        match get_binding(&self.partial, &self.mapping) {
-            BindingState::Invalid => {
-                self.partial = vec![];
-                None
-            }
-            BindingState::Start => None,
-            BindingState::Complete(b) => {
-                self.partial = vec![];
-                b.1()
+        let msg = self.keymap.send(key);
+        let msg = self.lock(key, msg);
+        let msg = match msg {
+            Some(ControllerMsg::AdjustVolume(x)) if self.volume_db => Some(
+                ControllerMsg::AdjustVolumeDb(x.signum() * constants::VOL_ADJUST_DB),
+            ),
+            msg => msg,
+        };
+        self.accelerate(msg, Instant::now())
+    }
+
+    // Switches the lock on the keyboard, and drops any message not
+    // let through while it's locked.
+    fn lock(&mut self, key: u8, msg: Option<ControllerMsg>) -> Option<ControllerMsg> {
+        match msg {
+            Some(ControllerMsg::ToggleLock) => {
+                self.locked = !self.locked;
+                msg
             }
+            Some(ControllerMsg::Pause | ControllerMsg::Toggle | ControllerMsg::Stop) => msg,
+            // Control-C still quits; "q" doesn't.
+            Some(ControllerMsg::Quit) if key == b'\x03' => msg,
+            _ if self.locked => None,
+            _ => msg,
         }
     }
-}
 
-// A mapping from a key (represented as a set of characters, [u8]) to
-// some functionality.
-struct Binding(&'static [u8], &'static dyn Fn() -> Option<ControllerMsg>);
+    // Speeds up tempo changes from a tempo key that's being held
+    // down, so it auto-repeats faster than the tempo keys are
+    // usually pressed.
+    fn accelerate(&mut self, msg: Option<ControllerMsg>, now: Instant) -> Option<ControllerMsg> {
+        let dir = match msg {
+            Some(ControllerMsg::AdjustTempo(x)) => x.signum(),
+            None => return None,
+            msg => {
+                self.held_tempo = None;
+                return msg;
+            }
+        };
 
-impl PartialEq for Binding {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
-    }
-}
+        let start = match self.held_tempo {
+            Some((held_dir, start, last))
+                if held_dir == dir && (now - last).as_secs_f64() <= constants::TEMPO_REPEAT_GAP =>
+            {
+                start
+            }
+            _ => now,
+        };
+        self.held_tempo = Some((dir, start, now));
 
-impl fmt::Debug for Binding {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("Binding").field(&self.0).finish()
+        let held = (now - start).as_secs_f64();
+        Some(ControllerMsg::AdjustTempo(dir * self.accel.step(held)))
     }
 }
 
-// Sets up the vector of key mapings used by the program.
-fn init_keybindings() -> Vec<Binding> {
-    // TODO: Clean this up with a helper function of some sort.
+// Sets up the vector of key mapings used by the program, with the
+// directional keys placed for the given keyboard layout.
+fn init_keybindings(layout: KeyboardLayout) -> Vec<Binding<ControllerMsg>> {
     let mut keys = vec![];
-    keys.push(Binding(b"p", &|| Some(ControllerMsg::Pause)));
-    keys.push(Binding(b"P", &|| Some(ControllerMsg::Play)));
-    keys.push(Binding(b" ", &|| Some(ControllerMsg::Toggle)));
-    keys.push(Binding(b".", &|| Some(ControllerMsg::Sync)));
-    keys.push(Binding(b",", &|| Some(ControllerMsg::TapMode)));
+
+    // Vim-like directional keys. These go by position rather than by
+    // letter, so they come first and take precedence over any
+    // mnemonic key they land on in other layouts.
+    keys.push(
+        bind(&[layout.key_at(b'h')], || {
+            Some(ControllerMsg::AdjustTempo(-constants::TEMPO_ADJUST))
+        })
+        .hint("Slower"),
+    );
+    keys.push(
+        bind(&[layout.key_at(b'l')], || {
+            Some(ControllerMsg::AdjustTempo(constants::TEMPO_ADJUST))
+        })
+        .hint("Faster"),
+    );
+    keys.push(
+        bind(&[layout.key_at(b'j')], || {
+            Some(ControllerMsg::AdjustVolume(-constants::VOL_ADJUST))
+        })
+        .hint("Quieter"),
+    );
+    keys.push(
+        bind(&[layout.key_at(b'k')], || {
+            Some(ControllerMsg::AdjustVolume(constants::VOL_ADJUST))
+        })
+        .hint("Louder"),
+    );
+
+    keys.push(bind(b"p", || Some(ControllerMsg::Pause)));
+    keys.push(bind(b"P", || Some(ControllerMsg::Play)));
+    keys.push(
+        bind_double(
+            b" ",
+            || Some(ControllerMsg::Toggle),
+            || Some(ControllerMsg::Stop),
+        )
+        .hint("Play/Pause"),
+    );
+    keys.push(
+        bind_double(
+            b".",
+            || Some(ControllerMsg::Sync),
+            || Some(ControllerMsg::HardSync),
+        )
+        .hint("Sync"),
+    );
+    keys.push(bind(b",", || Some(ControllerMsg::TapMode)).hint("Tap"));
+    keys.push(bind(b"a", || Some(ControllerMsg::CycleGrouping)));
+    keys.push(bind(b"[", || Some(ControllerMsg::AdjustCrossbeat(0, -1))));
+    keys.push(bind(b"]", || Some(ControllerMsg::AdjustCrossbeat(0, 1))));
+    keys.push(bind(b"{", || Some(ControllerMsg::AdjustCrossbeat(1, -1))));
+    keys.push(bind(b"}", || Some(ControllerMsg::AdjustCrossbeat(1, 1))));
+    keys.push(bind(b":", || Some(ControllerMsg::EditSpec)).hint("Rhythm"));
+    keys.push(bind(b"m", || Some(ControllerMsg::CycleOutput)).hint("Output"));
+    keys.push(bind(b"t", || Some(ControllerMsg::CycleTimbre)).hint("Sound"));
+    keys.push(bind(b"o", || Some(ControllerMsg::ToggleAccentOnly)).hint("Accents"));
+    keys.push(bind(b"L", || Some(ControllerMsg::ToggleLock)).hint("Lock"));
 
     // Arrow keys
-    keys.push(Binding(b"\x1B[A", &|| {
+    keys.push(bind(b"\x1B[A", || {
         // Up
         Some(ControllerMsg::AdjustVolume(constants::VOL_ADJUST))
     }));
-    keys.push(Binding(b"\x1B[B", &|| {
+    keys.push(bind(b"\x1B[B", || {
         // Down
         Some(ControllerMsg::AdjustVolume(-constants::VOL_ADJUST))
     }));
-    keys.push(Binding(b"\x1B[C", &|| {
+    keys.push(bind(b"\x1B[C", || {
         // Right
         Some(ControllerMsg::AdjustTempo(constants::TEMPO_ADJUST))
     }));
-    keys.push(Binding(b"\x1B[D", &|| {
+    keys.push(bind(b"\x1B[D", || {
         // Left
         Some(ControllerMsg::AdjustTempo(-constants::TEMPO_ADJUST))
     }));
 
-    // Vim-like directional keys
-    keys.push(Binding(b"k", &|| {
-        Some(ControllerMsg::AdjustVolume(constants::VOL_ADJUST))
-    }));
-    keys.push(Binding(b"j", &|| {
-        Some(ControllerMsg::AdjustVolume(-constants::VOL_ADJUST))
-    }));
-    keys.push(Binding(b"l", &|| {
-        Some(ControllerMsg::AdjustTempo(constants::TEMPO_ADJUST))
-    }));
-    keys.push(Binding(b"h", &|| {
-        Some(ControllerMsg::AdjustTempo(-constants::TEMPO_ADJUST))
-    }));
-
     // Emacs-like directional keys
-    keys.push(Binding(b"\x10", &|| {
+    keys.push(bind(b"\x10", || {
         // C-p
         Some(ControllerMsg::AdjustVolume(constants::VOL_ADJUST))
     }));
-    keys.push(Binding(b"\x0E", &|| {
+    keys.push(bind(b"\x0E", || {
         // C-n
         Some(ControllerMsg::AdjustVolume(-constants::VOL_ADJUST))
     }));
-    keys.push(Binding(b"\x06", &|| {
+    keys.push(bind(b"\x06", || {
         // C-f
         Some(ControllerMsg::AdjustTempo(constants::TEMPO_ADJUST))
     }));
-    keys.push(Binding(b"\x02", &|| {
+    keys.push(bind(b"\x02", || {
         // C-b
         Some(ControllerMsg::AdjustTempo(-constants::TEMPO_ADJUST))
     }));
 
-    keys.push(Binding(b"q", &|| Some(ControllerMsg::Quit)));
-    keys.push(Binding(b"\x03", &|| {
-        // Control-C
-        Some(ControllerMsg::Quit)
-    }));
+    // Control-C quits from the global bindings.
+    keys.push(bind(b"q", || Some(ControllerMsg::Quit)).hint("Quit"));
 
     keys
 }
 
-// Possible states of the key binding engine.
-#[derive(PartialEq, Debug)]
-enum BindingState<'a> {
-    // The characters in the queue are not a valid key binding, nor
-    // are they the first part of valid key binding.
-    Invalid,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn layout_bindings_test() {
+        // Directional keys move with the layout, and take precedence
+        // over the letters they land on.
+        let mut controller = ControllerState::new(KeyboardLayout::Dvorak, TempoAccel::default());
+        match controller.send(b't') {
+            Some(ControllerMsg::AdjustVolume(_)) => (),
+            msg => panic!("Got {:?}", msg),
+        }
 
-    // The characters in the queue form the beginning of one or more
-    // keybindings, but we don't have a complete key binding yet.
-    Start,
+        // The hint bar follows suit.
+        let hints = controller.hints();
+        assert!(hints.contains(&("t".to_string(), "Louder")));
+        assert!(!hints.iter().any(|&(_, label)| label == "Sound"));
 
-    // The characters in the queue are a perfect match for a key
-    // binding.
-    Complete(&'a Binding),
-}
+        let mut controller = ControllerState::new(KeyboardLayout::Qwerty, TempoAccel::default());
+        match controller.send(b't') {
+            Some(ControllerMsg::CycleTimbre) => (),
+            msg => panic!("Got {:?}", msg),
+        }
+    }
 
-// Calculates the state of the key binding engine, given a set of
-// characters that have already been received.
-fn get_binding<'a>(queue: &[u8], bindings: &'a [Binding]) -> BindingState<'a> {
-    let mut is_prefix = false;
-    for b in bindings {
-        if b.0 == queue {
-            return BindingState::Complete(&b);
+    #[test]
+    fn lock_test() {
+        let mut controller = ControllerState::new(KeyboardLayout::Qwerty, TempoAccel::default());
+        match controller.send(b'L') {
+            Some(ControllerMsg::ToggleLock) => (),
+            msg => panic!("Got {:?}", msg),
+        }
+        assert!(controller.locked());
+
+        // Only unlocking, playing and pausing, and Control-C get
+        // through.
+        assert!(controller.send(b'l').is_none());
+        assert!(controller.send(b'q').is_none());
+        match controller.send(b' ') {
+            Some(ControllerMsg::Toggle) => (),
+            msg => panic!("Got {:?}", msg),
+        }
+        match controller.send(b'\x03') {
+            Some(ControllerMsg::Quit) => (),
+            msg => panic!("Got {:?}", msg),
         }
 
-        if b.0.starts_with(queue) {
-            is_prefix = true;
+        controller.send(b'L');
+        assert!(!controller.locked());
+        match controller.send(b'q') {
+            Some(ControllerMsg::Quit) => (),
+            msg => panic!("Got {:?}", msg),
         }
     }
 
-    match is_prefix {
-        true => BindingState::Start,
-        false => BindingState::Invalid,
+    #[test]
+    fn volume_db_test() {
+        let mut controller = ControllerState::new(KeyboardLayout::Qwerty, TempoAccel::default())
+            .with_volume_db(true);
+        match controller.send(b'j') {
+            Some(ControllerMsg::AdjustVolumeDb(db)) => assert_eq!(db, -constants::VOL_ADJUST_DB),
+            msg => panic!("Got {:?}", msg),
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn multichar_binding_test() {
-        let bindings = init_keybindings();
-
-        // Type in the left arrow key, character by character.
-        let mut left = vec![];
-        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
-        left.push('\x1B' as u8);
-        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
-        left.push(91 as u8);
-        assert_eq!(get_binding(&left, &bindings), BindingState::Start);
-        left.push('X' as u8);
-        assert_eq!(get_binding(&left, &bindings), BindingState::Invalid);
-        left.pop();
-        left.push('D' as u8);
-        match get_binding(&left, &bindings) {
-            BindingState::Complete(_) => (),
-            _ => panic!("Didn't recognize binding"),
+    fn tempo_accel_test() {
+        let accel = TempoAccel::parse("5:1").unwrap();
+        assert_eq!(accel, TempoAccel::default());
+        assert_eq!(
+            TempoAccel::parse("3").unwrap().ramp,
+            constants::TEMPO_ACCEL_RAMP
+        );
+        assert!(TempoAccel::parse("0.5").is_err());
+        assert!(TempoAccel::parse("5:-1").is_err());
+
+        let mut controller = ControllerState::new(KeyboardLayout::Qwerty, accel);
+        let repeat = Duration::from_millis(30);
+        let start = Instant::now();
+        let mut step = |now| match controller.accelerate(Some(ControllerMsg::AdjustTempo(1.0)), now)
+        {
+            Some(ControllerMsg::AdjustTempo(x)) => x,
+            msg => panic!("Got {:?}", msg),
         };
+
+        // Separate taps stay at one BPM.
+        assert_eq!(step(start), 1.0);
+        assert_eq!(step(start + repeat * 10), 1.0);
+
+        // Holding the key ramps up to the maximum over a second of
+        // key repeats.
+        let mut now = start + repeat * 20;
+        assert_eq!(step(now), 1.0);
+        for _ in 0..16 {
+            now += repeat;
+            step(now);
+        }
+        let half = step(now + repeat);
+        assert!(half > 1.0 && half < 5.0);
+        for _ in 0..20 {
+            now += repeat;
+            step(now);
+        }
+        assert_eq!(step(now + repeat), 5.0);
     }
 }
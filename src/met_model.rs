@@ -17,155 +17,1135 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::app_state::{AppState, Keycode, StateTransition, TickCommand};
-use crate::beat_spec::{BeatSpec, Event};
+use crate::app_state::{AppState, InputSource, Keycode, StateTransition, StdinClose, TickCommand};
+use crate::audio_clock::{AudioClock, AudioSchedule};
+use crate::beat_spec::{BeatSpec, EmphasisPolicy, Event, Meter, Polyrhythm, Swing};
+use crate::conductor_view::ConductorView;
 use crate::constants;
-use crate::met_controller::{ControllerMsg, ControllerState};
-use crate::met_view::MetronomeView;
-use crate::sound::{beep, AudioConfig};
+use crate::hint_view::HintBar;
+use crate::humanize::{Humanize, Humanizer};
+use crate::keymap::KeyboardLayout;
+use crate::link::Link;
+use crate::locale;
+use crate::met_controller::{ControllerMsg, ControllerState, TempoAccel};
+use crate::met_view::{IndicatorStyle, MetronomeView, Views};
+use crate::model_event::{EventBus, ModelEvent, Subscriber, Transport};
+use crate::poly_view::PolyView;
+use crate::score_view::ScoreView;
+use crate::song::Song;
+use crate::sound::{self, AccentLevel, ClickOutput, Route, SoundBackend, Timbre};
+use crate::spec_model::{SpecEntry, SpecEntryResult};
+use crate::sticking::Sticking;
+use crate::system_volume::set_system_volume;
 use crate::tap_model::TapState;
-use std::time::Duration;
+use crate::tempo_map::TempoMap;
+use crate::termios_handler;
+use crate::timing::{TimingMonitor, Watchdog};
+use colorful::Color;
+use colorful::Colorful;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Settings that carry over when the metronome switches to another
+// state (e.g. tap mode) and back.
+#[derive(Clone)]
+pub struct MetronomeSettings {
+    // The rhythm the metronome is beating out.
+    pub rhythm: BeatSpec,
+
+    // The meter the rhythm was built from, if any; used to regroup
+    // the rhythm's accents at run time.
+    pub meter: Option<Meter>,
+
+    // The song being played, in song mode, whose sections take over
+    // from the rhythm.
+    pub song: Option<Song>,
+
+    // Tempo changes to replay as their beats come up, if any.
+    pub tempo_map: Option<TempoMap>,
+
+    // The two crossbeats being played, in the polyrhythm explorer;
+    // None when the explorer is off.
+    pub poly: Option<Polyrhythm>,
+
+    // What clicks are played on.
+    pub sound: Rc<dyn SoundBackend>,
+
+    // The current volume and tempo settings.
+    pub volume: f64,
+    pub tempo: f64,
+
+    // Whether `volume` is the system's output volume rather than our
+    // own gain.
+    pub system_volume: bool,
+
+    // Whether the volume is shown and stepped in decibels.
+    pub volume_db: bool,
+
+    // Where clicks are currently being sent, and the outputs the user
+    // can switch between.
+    pub output: ClickOutput,
+    pub outputs: Vec<ClickOutput>,
+
+    // The sound clicks are made with.
+    pub timbre: Timbre,
+
+    // Whether only the accents are being played, and which ones.
+    pub accent_only: bool,
+    pub accent_level: AccentLevel,
+
+    // Which emphasis each click has, which decides which are accents.
+    pub emphasis: EmphasisPolicy,
+
+    // The keyboard layout, which decides where the directional keys
+    // are.
+    pub layout: KeyboardLayout,
+
+    // How fast held tempo keys speed up.
+    pub tempo_accel: TempoAccel,
+
+    // Whether to show the full-screen conductor display instead of
+    // the usual one-line display.
+    pub conductor: bool,
+
+    // How the measure progress indicator looks.
+    pub indicator: IndicatorStyle,
+
+    // Whether to show the score line under the usual display.
+    pub score: bool,
+
+    // Whether to list the most useful keys at the bottom of the
+    // display.
+    pub hints: bool,
+
+    // Whether to play a blip when the tempo is changed by something
+    // other than the keyboard.
+    pub confirm: bool,
+
+    // Number of beats to count in with when starting, or after a
+    // sync.
+    pub count_in: u32,
+
+    // Whether to label clicks with alternating hands, for a sticking
+    // drill.
+    pub sticking: bool,
+
+    // How much to vary the timing and volume of clicks.
+    pub humanize: Humanize,
+
+    // How far to swing the subdivisions.
+    pub swing: Swing,
+
+    // The Ableton Link session we share tempo and beats with, if any.
+    pub link: Option<Rc<Link>>,
+
+    // Whether to warn when clicks keep being played late.
+    pub strict_timing: bool,
+
+    // What to do when stdin closes.
+    pub stdin_close: StdinClose,
+
+    // The audio device's clock, if ticks are to be timed by it rather
+    // than by the system clock.
+    pub audio_clock: Option<Arc<AudioClock>>,
+
+    // How long after a click is played it's actually heard; the
+    // display is held back by this much to line up with the sound.
+    pub latency: Duration,
+
+    // How long after a key is struck the key press reaches us, which
+    // tap mode allows for.
+    pub tap_latency: Duration,
+
+    // Bus that changes to the metronome's state are published on.
+    pub bus: EventBus,
+
+    // Displays to draw the metronome on besides the terminal.
+    pub views: Views,
+}
+
+// Whether the metronome is playing, as far as devices following it
+// are concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlayState {
+    // Not playing; the next tick starts from the top.
+    Stopped,
+
+    // Not playing; the next tick continues from where we left off.
+    Paused,
+
+    // Playing.
+    Playing,
+}
 
 // State of the metronome at any given time.
 pub struct MetronomeState {
-    // The rhythm the metronome is beating out.
-    rhythm: BeatSpec,
+    // Settings shared with the other states.
+    settings: MetronomeSettings,
 
     // The index of the next tick to be played by the metronome.
     tick_number: usize,
 
-    // The audio device configuration.
-    cfg: AudioConfig,
+    // Number of measures played since the last sync.
+    measure: usize,
 
-    // The current volume and tempo settings.
-    volume: f64,
-    tempo: f64,
+    // The section of the song being played, in song mode; None until
+    // the first measure.
+    section: Option<usize>,
+
+    // Beats played since the top, not counting the count-in, and the
+    // next change in the tempo map to replay.
+    beats: f64,
+    map_next: usize,
+
+    // Hands to play each click with, if we're drilling sticking.
+    sticking: Option<Sticking>,
+
+    // Source of the variations in humanized clicks, if humanization
+    // is on.
+    humanizer: Option<Humanizer>,
+
+    // How far, in seconds, the next tick is humanized away from its
+    // scheduled time; negative values are early.
+    nudge: f64,
+
+    // Watches how late ticks are played, in strict timing mode.
+    timing: Option<TimingMonitor>,
+
+    // Counts the ticks played late, and logs what held them up.
+    watchdog: Watchdog,
+
+    // Whether we're playing. The tick manager knows this too, but we
+    // keep track ourselves to tell followers when it changes.
+    play_state: PlayState,
+
+    // Beats of count-in left to play before the next tick.
+    count_in: u32,
+
+    // The time at which the next tick is meant to play, or None if
+    // the schedule should restart from the next tick (e.g. after a
+    // pause).
+    scheduled: Option<Instant>,
+
+    // Keeps the schedule on the audio device's clock, if ticks are
+    // timed by it.
+    audio_schedule: Option<AudioSchedule>,
+
+    // A rhythm entered in the spec editor, waiting to take effect at
+    // the start of the next measure.
+    pending: Option<BeatSpec>,
+
+    // Events for clicks that have been played but not yet heard, with
+    // the time each will be heard, when there's a latency offset.
+    delayed: VecDeque<(Instant, ModelEvent)>,
+
+    // The spec editor, if it's open.
+    entry: Option<SpecEntry>,
 
     // State of the view and controller subsystems.
     view: MetronomeView,
+    conductor: Option<ConductorView>,
+    score: Option<ScoreView>,
+    explorer: Option<PolyView>,
     controller: ControllerState,
 }
 
 impl MetronomeState {
-    pub fn new(rhythm: &BeatSpec, cfg: AudioConfig, volume: f64, tempo: f64) -> MetronomeState {
+    pub fn new(settings: MetronomeSettings) -> MetronomeState {
+        let mut view = MetronomeView::new(
+            settings.rhythm.get_ticks().len() as f64 / settings.rhythm.get_beat_len() as f64,
+            settings.indicator.clone(),
+        );
+        view.set_volume_db(settings.volume_db);
+        // The view starts out knowing nothing of the settings, so
+        // catch it up.
+        view.notify(&ModelEvent::TempoChanged(settings.tempo));
+        view.notify(&ModelEvent::VolumeChanged(settings.volume));
+        view.notify(&ModelEvent::OutputChanged(settings.output));
+        view.notify(&ModelEvent::AccentOnlyChanged(settings.accent_only));
+        settings
+            .views
+            .notify(&ModelEvent::TempoChanged(settings.tempo));
+        settings.sound.set_tempo(settings.tempo);
+        settings.sound.set_output(settings.output);
+        settings.emphasis.follow(&settings.rhythm);
+        let conductor = if settings.conductor {
+            let mut conductor = ConductorView::new();
+            conductor.notify(&ModelEvent::TempoChanged(settings.tempo));
+            Some(conductor)
+        } else {
+            None
+        };
+        let score = if settings.score && !settings.conductor {
+            Some(ScoreView::new(&settings.rhythm))
+        } else {
+            None
+        };
+
+        let explorer = match settings.poly {
+            Some(poly) if !settings.conductor => Some(PolyView::new(poly)),
+            _ => None,
+        };
+
+        let sticking = if settings.sticking {
+            Some(Sticking::new(&settings.rhythm))
+        } else {
+            None
+        };
+
+        let humanizer = if settings.humanize.amount.is_zero() {
+            None
+        } else {
+            Some(Humanizer::new(settings.humanize))
+        };
+
+        let timing = if settings.strict_timing {
+            Some(TimingMonitor::new())
+        } else {
+            None
+        };
+
+        let audio_schedule = settings.audio_clock.clone().map(AudioSchedule::new);
+
         MetronomeState {
-            rhythm: rhythm.clone(),
+            count_in: settings.count_in,
+            controller: ControllerState::new(settings.layout, settings.tempo_accel)
+                .with_volume_db(settings.volume_db),
+            settings,
             tick_number: 0,
-            cfg,
-            volume,
-            tempo,
-            view: MetronomeView::new(
-                rhythm.get_ticks().len() as f64 / rhythm.get_beat_len() as f64,
-            ),
-            controller: ControllerState::new(),
+            measure: 0,
+            section: None,
+            beats: 0.0,
+            map_next: 0,
+            sticking,
+            humanizer,
+            nudge: 0.0,
+            timing,
+            watchdog: Watchdog::new(),
+            play_state: PlayState::Stopped,
+            scheduled: None,
+            audio_schedule,
+            view,
+            conductor,
+            score,
+            explorer,
+            pending: None,
+            delayed: VecDeque::new(),
+            entry: None,
         }
     }
-}
 
-impl AppState for MetronomeState {
-    fn tick(&mut self) -> (StateTransition, TickCommand) {
-        let ticks = &self.rhythm.get_ticks();
-        let tick = &ticks[self.tick_number];
-        play_event(tick, &self.cfg, self.volume);
-
-        self.view
-            .set_progress(self.tick_number as f64 / ticks.len() as f64);
-        self.view.set_tempo(self.tempo);
-        self.view.set_volume(self.volume);
+    // Starts playing from the beat with the given number (counting
+    // from the top of the measure, and wrapping around it) at the
+    // given time, without a count-in.
+    pub fn come_in(&mut self, at: Instant, beat: usize) {
+        let beat_len = self.settings.rhythm.get_beat_len() as usize;
+        self.tick_number = beat * beat_len % self.settings.rhythm.get_ticks().len();
+        self.count_in = 0;
+        self.scheduled = Some(at);
+    }
+
+    // Tells the views and the bus's subscribers about a change to the
+    // metronome's state.
+    fn publish(&mut self, event: ModelEvent) {
+        self.view.notify(&event);
+        if let Some(conductor) = &mut self.conductor {
+            conductor.notify(&event);
+        }
+        if let Some(score) = &mut self.score {
+            score.notify(&event);
+        }
+        if let Some(explorer) = &mut self.explorer {
+            explorer.notify(&event);
+        }
+        self.settings.views.notify(&event);
+        self.settings.bus.publish(&event);
+    }
+
+    // Switches to a new rhythm, keeping our place in the measure as
+    // near as we can.
+    fn set_rhythm(&mut self, rhythm: &BeatSpec, meter: Option<Meter>) {
+        let old_len = self.settings.rhythm.get_ticks().len();
+        self.settings.rhythm = rhythm.clone();
+        self.settings.emphasis.follow(rhythm);
+        self.settings.meter = meter;
+        self.tick_number = self.tick_number * rhythm.get_ticks().len() / old_len;
+        if self.sticking.is_some() {
+            self.sticking = Some(Sticking::new(&self.settings.rhythm));
+        }
+        self.publish(ModelEvent::RhythmChanged {
+            rhythm: self.settings.rhythm.clone(),
+            meter: self.settings.meter.clone(),
+        });
+    }
+
+    // At the top of a measure in song mode, switches to the rhythm of
+    // the section the measure falls in, and shows where we are in it.
+    fn follow_song(&mut self) {
+        let (n, measure, section) = match &self.settings.song {
+            Some(song) => {
+                let (n, measure) = song.locate(self.measure);
+                (n, measure, song.sections()[n].clone())
+            }
+            None => return,
+        };
+
+        if self.section != Some(n) {
+            self.section = Some(n);
+            self.set_rhythm(&section.rhythm, None);
+        }
+        self.publish_heard(ModelEvent::SongPosition {
+            section: section.name,
+            measure: measure + 1,
+            measures: section.measures,
+        });
+    }
+
+    // Sets the tempo of any changes in the tempo map that are due by
+    // the tick about to be played.
+    fn follow_tempo_map(&mut self) {
+        let tempo = match &self.settings.tempo_map {
+            Some(map) => map.due(self.beats, &mut self.map_next),
+            None => return,
+        };
+        if let Some(tempo) = tempo {
+            self.apply_tempo(tempo);
+        }
+    }
+
+    // Stops playing, telling followers if we were playing; `state`
+    // says whether the next tick starts from the top or continues.
+    fn halt(&mut self, state: PlayState) {
+        if self.play_state == PlayState::Playing {
+            self.publish(ModelEvent::Transport(Transport::Stop));
+            self.settings.sound.set_playing(false);
+        }
+        self.play_state = state;
+    }
+
+    // Number of lines the display takes up below the main one-line
+    // view; the spec editor goes below these, and the hint bar below
+    // that.
+    fn extra_lines(&self) -> usize {
+        let score = if self.score.is_some() { 1 } else { 0 };
+        let explorer = if self.explorer.is_some() { 2 } else { 0 };
+        score + explorer
+    }
+
+    // Draws the metronome, along with the spec editor below it if the
+    // editor is open. The terminal cursor rests on the editor's line
+    // if it's open, and on the main view's otherwise.
+    fn draw(&mut self) {
+        self.settings.views.draw();
+        let hints = self.hint_bar();
+        if let Some(conductor) = &mut self.conductor {
+            conductor.draw();
+            if let Some(hints) = &hints {
+                hints.draw_bottom();
+            }
+            if let Some(entry) = &self.entry {
+                entry.draw();
+            }
+            return;
+        }
+
+        let extra = self.extra_lines();
+        if self.entry.is_some() {
+            print!("\x1B[{}A", extra + 1);
+        }
         self.view.draw();
+        let mut below = 0;
+        if let Some(score) = &self.score {
+            print!("\r\n");
+            score.draw();
+            below += 1;
+        }
+        if let Some(explorer) = &self.explorer {
+            print!("\r\n");
+            explorer.draw();
+            below += 2;
+        }
+        if self.entry.is_some() {
+            print!("\r\n");
+            below += 1;
+        }
+        if let Some(hints) = &hints {
+            print!("\r\n");
+            hints.draw();
+            below += 1;
+        }
+        match &self.entry {
+            Some(entry) => {
+                if hints.is_some() {
+                    print!("\x1B[A");
+                }
+                entry.draw();
+            }
+            None if below > 0 => print!("\x1B[{}A", below),
+            None => (),
+        }
+    }
 
-        self.tick_number = (self.tick_number + 1) % ticks.len();
+    // The hint bar for whichever of the metronome and the spec editor
+    // has the keyboard, if hints are turned on.
+    fn hint_bar(&self) -> Option<HintBar> {
+        if !self.settings.hints {
+            return None;
+        }
+        Some(HintBar::new(match self.entry {
+            Some(_) => SpecEntry::hints(),
+            None => self.controller.hints(),
+        }))
+    }
 
-        (
-            StateTransition::NoChange,
-            TickCommand::Set(get_delay(&self.rhythm, self.tempo)),
-        )
+    // Sets the volume, within the range it's allowed to take.
+    fn set_volume(&mut self, volume: f64) {
+        if !volume.is_finite() {
+            log::debug!("Ignoring volume {}", volume);
+            return;
+        }
+
+        // The system volume can't be boosted past 100%.
+        let max = if self.settings.system_volume {
+            1.0
+        } else {
+            constants::VOL_MAX
+        };
+
+        self.settings.volume = volume.clamp(constants::VOL_MIN, max);
+        if self.settings.system_volume {
+            set_system_volume(self.settings.volume);
+        }
+
+        self.publish(ModelEvent::VolumeChanged(self.settings.volume));
+        self.draw();
     }
 
-    fn keypress(&mut self, key: Keycode, _time: Duration) -> (StateTransition, TickCommand) {
-        let cmd = if let Keycode::Key(key) = key {
-            self.controller.send(key)
+    // Gain to play clicks at. When the volume keys control the system
+    // volume, our own gain stays at 100%.
+    fn click_gain(&self) -> f64 {
+        if self.settings.system_volume {
+            1.0
         } else {
-            // stdin closed, quit the program.
-            return (StateTransition::Exit, TickCommand::None);
+            self.settings.volume
+        }
+    }
+
+    // Plays a short, high blip, distinct from any click, to confirm a
+    // change the user didn't make from the keyboard.
+    fn confirm_blip(&self) {
+        if self.settings.output.audio() {
+            self.settings.sound.beep(
+                constants::CONFIRM_PITCH,
+                Duration::from_millis(constants::CONFIRM_LEN),
+                Timbre::Sine,
+                self.click_gain(),
+                Route::All,
+            );
+        }
+    }
+
+    // Plays a beat of the count-in: a plain click every beat, with the
+    // number of beats left shown in place of the usual display.
+    fn count_in_tick(&mut self) {
+        let now = Instant::now();
+        let beat_len = get_delay(&self.settings.rhythm, self.settings.tempo)
+            * self.settings.rhythm.get_beat_len();
+        let scheduled = self.scheduled.unwrap_or(now);
+
+        if self.settings.output != ClickOutput::Silent {
+            // The count-in counts up from the first beat, however
+            // many beats are left.
+            let beat = (self.settings.count_in - self.count_in) as usize;
+            self.settings.sound.play_event(
+                &Event::Beep(1),
+                Some(beat),
+                None,
+                self.settings.timbre,
+                self.click_gain(),
+                beat_len,
+            );
+        }
+        self.publish_heard(ModelEvent::CountIn(self.count_in));
+        self.count_in -= 1;
+
+        self.scheduled = Some(self.advance(scheduled, beat_len));
+        self.nudge = 0.0;
+    }
+
+    // Plays the next tick of the rhythm, and schedules the one after.
+    fn play_tick(&mut self) {
+        let due = self.play_time();
+
+        // If we were held up for a long time (e.g. the process was
+        // stopped), skip the ticks we missed rather than playing them
+        // all at once, so we come back in on the right beat.
+        let now = Instant::now();
+        let ticks = self.settings.rhythm.get_ticks().len();
+        let tick_len = get_delay(&self.settings.rhythm, self.settings.tempo);
+        let scheduled = self.scheduled.unwrap_or(now);
+        let missed = missed_ticks(now.saturating_duration_since(scheduled), tick_len, ticks);
+        if missed > 0 {
+            log::warn!(
+                "Stalled; skipping {} missed ticks",
+                locale::format(missed as f64)
+            );
+        }
+        // The measures skipped count as played, so the song and the
+        // sticking keep their place.
+        let skipped = self.tick_number + missed as usize;
+        let top = self.tick_number == 0 || skipped >= ticks;
+        self.measure += skipped / ticks;
+        self.tick_number = skipped % ticks;
+        self.beats += missed as f64 / self.settings.rhythm.get_beat_len() as f64;
+        let scheduled = scheduled + tick_len * missed;
+
+        // Anything waiting for the top of a measure happens at the
+        // first tick played after it, even if that tick was skipped.
+        if top {
+            if let Some(rhythm) = self.pending.take() {
+                self.set_rhythm(&rhythm, None);
+            }
+            self.follow_song();
+        }
+        self.follow_tempo_map();
+        let tick_len = get_delay(&self.settings.rhythm, self.settings.tempo);
+        let beat_len = self.settings.rhythm.get_beat_len() as f64;
+
+        match self.play_state {
+            PlayState::Stopped => self.publish(ModelEvent::Transport(Transport::Start)),
+            PlayState::Paused => self.publish(ModelEvent::Transport(Transport::Continue)),
+            PlayState::Playing => {}
+        }
+        if self.play_state != PlayState::Playing {
+            self.settings.sound.set_playing(true);
+        }
+        self.play_state = PlayState::Playing;
+
+        let n_ticks = self.settings.rhythm.get_ticks().len();
+        let tick = &self.settings.rhythm.get_ticks()[self.tick_number];
+        let hand = match &self.sticking {
+            Some(sticking) => sticking.hand(self.measure, self.tick_number),
+            None => None,
         };
+        let mut gain = self.click_gain();
+        if let (Some(humanizer), true) = (&mut self.humanizer, is_humanized(tick)) {
+            gain *= humanizer.gain();
+        }
+        let silenced = self.settings.accent_only
+            && !self
+                .settings
+                .accent_level
+                .keeps(self.settings.emphasis.of(tick));
+        if self.settings.output != ClickOutput::Silent && !silenced {
+            let beat_len = self.settings.rhythm.get_beat_len() as usize;
+            let beat = if self.tick_number.is_multiple_of(beat_len) {
+                Some(self.tick_number / beat_len)
+            } else {
+                None
+            };
+            // Swing moves the next tick nearer or further away.
+            let swing = self.settings.swing;
+            let swung = 1.0 + swing.delay(self.tick_number + 1, beat_len as u32)
+                - swing.delay(self.tick_number, beat_len as u32);
+            self.settings.sound.play_event(
+                tick,
+                beat,
+                hand,
+                self.settings.timbre,
+                gain,
+                tick_len.mul_f64(swung),
+            );
+        }
 
-        if let Some(cmd) = cmd {
-            return match cmd {
-                ControllerMsg::Pause => (StateTransition::NoChange, TickCommand::Pause),
-                ControllerMsg::Play => (StateTransition::NoChange, TickCommand::Resume),
-                ControllerMsg::Toggle => (StateTransition::NoChange, TickCommand::Toggle),
-                ControllerMsg::AdjustVolume(x) => {
-                    self.volume += x;
-                    if self.volume < 0.0 {
-                        self.volume = 0.0;
-                    } else if self.volume > 1.0 {
-                        self.volume = 1.0;
-                    }
+        let event = tick.clone();
 
-                    self.view.set_volume(self.volume);
-                    self.view.draw();
+        // Stalls long enough to skip ticks have been warned about
+        // already.
+        let worst = match (&mut self.timing, due) {
+            (Some(timing), Some(due)) if missed == 0 => timing.record(due.elapsed()),
+            _ => None,
+        };
+        if let Some(worst) = worst {
+            self.warn_late(worst);
+        }
+        if let Some(report) = due.and_then(|due| self.watchdog.check(due, Instant::now(), missed)) {
+            log::warn!("{}", report);
+            self.view.set_missed(self.watchdog.missed());
+        }
+
+        self.publish_heard(ModelEvent::Tick {
+            tick: self.tick_number,
+            ticks: n_ticks,
+            beat_len: self.settings.rhythm.get_beat_len(),
+            event,
+            hand,
+        });
 
-                    (StateTransition::NoChange, TickCommand::None)
+        // Schedule the next tick relative to when this one was meant
+        // to happen, not when it actually did, so we don't drift. In a
+        // Link session, the session's beats decide instead.
+        match &self.settings.link {
+            Some(link) => {
+                let beat_len = self.settings.rhythm.get_beat_len();
+                let (next, at) = link.next_tick(n_ticks, beat_len);
+                if next != (self.tick_number + 1) % n_ticks {
+                    log::debug!(
+                        "Moving from tick {} to {} to follow Link",
+                        self.tick_number,
+                        next
+                    );
                 }
-                ControllerMsg::AdjustTempo(x) => {
-                    self.tempo += x;
-                    if self.tempo < constants::TEMPO_MIN {
-                        self.tempo = constants::TEMPO_MIN;
-                    } else if self.tempo > constants::TEMPO_MAX {
-                        self.tempo = constants::TEMPO_MAX;
-                    }
+                if next <= self.tick_number {
+                    self.measure += 1;
+                }
+                self.tick_number = next;
+                self.scheduled = Some(at);
+            }
+            None => {
+                self.tick_number = (self.tick_number + 1) % n_ticks;
+                if self.tick_number == 0 {
+                    self.measure += 1;
+                }
+                self.scheduled = Some(self.advance(scheduled, tick_len));
+            }
+        }
+        self.beats += 1.0 / beat_len;
 
-                    self.view.set_tempo(self.tempo);
-                    self.view.draw();
+        // Humanization moves the next tick off the schedule, but by
+        // no more than a quarter of a tick, so clicks stay in order;
+        // swing holds back every second subdivision as well.
+        let next = &self.settings.rhythm.get_ticks()[self.tick_number];
+        let humanized = match (&mut self.humanizer, is_humanized(next)) {
+            (Some(humanizer), true) => {
+                let limit = tick_len.as_secs_f64() / 4.0;
+                humanizer.offset().clamp(-limit, limit)
+            }
+            _ => 0.0,
+        };
+        let swing = self
+            .settings
+            .swing
+            .delay(self.tick_number, self.settings.rhythm.get_beat_len());
+        self.nudge = humanized + swing * tick_len.as_secs_f64();
+    }
+
+    // The time `by` after `from`, by whichever clock the metronome
+    // keeps time by.
+    fn advance(&mut self, from: Instant, by: Duration) -> Instant {
+        match &mut self.audio_schedule {
+            Some(schedule) => schedule.advance(from, by),
+            None => from + by,
+        }
+    }
+
+    // When the next tick should actually play: its scheduled time,
+    // moved by any humanization. None if the schedule is restarting.
+    fn play_time(&self) -> Option<Instant> {
+        let scheduled = self.scheduled?;
+        let nudge = seconds(self.nudge.abs());
+        if self.nudge >= 0.0 {
+            Some(scheduled + nudge)
+        } else {
+            Some(scheduled.checked_sub(nudge).unwrap_or(scheduled))
+        }
+    }
+
+    // Changes the tempo, within the allowed range, at the request of
+    // the given input source.
+    fn set_tempo(&mut self, tempo: f64, source: InputSource) {
+        self.apply_tempo(tempo);
+        if self.settings.confirm && source != InputSource::Keyboard {
+            self.confirm_blip();
+        }
+    }
+
+    // Sets the tempo, within the range it's allowed to take, and
+    // tells everyone. A tempo that isn't a number at all is ignored.
+    fn apply_tempo(&mut self, tempo: f64) {
+        if !tempo.is_finite() {
+            log::debug!("Ignoring tempo {}", tempo);
+            return;
+        }
+        self.settings.tempo = tempo;
+        if self.settings.tempo < constants::TEMPO_MIN {
+            self.settings.tempo = constants::TEMPO_MIN;
+        } else if self.settings.tempo > constants::TEMPO_MAX {
+            self.settings.tempo = constants::TEMPO_MAX;
+        }
+
+        if let Some(link) = &self.settings.link {
+            link.set_tempo(self.settings.tempo);
+        }
+        self.settings.sound.set_tempo(self.settings.tempo);
+        self.publish(ModelEvent::TempoChanged(self.settings.tempo));
+        self.draw();
+    }
+
+    // Warns that clicks keep being played late, by up to `worst`,
+    // on a line of its own above the display.
+    fn warn_late(&mut self, worst: Duration) {
+        let msg = format!(
+            "Clicks are playing up to {} ms late. Try a different --buffer-size, \
+             closing busy programs, or another audio output.",
+            worst.as_millis()
+        );
+        log::warn!("{}", msg);
+        if self.conductor.is_none() {
+            print!("\r\x1B[K{}\r\n", msg.color(Color::LightRed));
+            self.draw();
+        }
+    }
+
+    // Picks up tempo changes made by other apps in the Link session.
+    fn follow_link(&mut self) {
+        let tempo = match &self.settings.link {
+            Some(link) => link.tempo(),
+            None => return,
+        };
+        if (tempo - self.settings.tempo).abs() > 1e-6 {
+            log::debug!("Link tempo changed to {}", locale::format(tempo));
+            self.settings.tempo = tempo;
+            self.settings.sound.set_tempo(tempo);
+            self.publish(ModelEvent::TempoChanged(tempo));
+            self.draw();
+        }
+    }
+
+    // Publishes an event for a click that's just been played, once
+    // the click can actually be heard: straight away, or after the
+    // latency offset if there is one.
+    fn publish_heard(&mut self, event: ModelEvent) {
+        if self.settings.latency == Duration::new(0, 0) {
+            self.publish(event);
+            self.draw();
+        } else {
+            let heard = Instant::now() + self.settings.latency;
+            self.delayed.push_back((heard, event));
+        }
+    }
+
+    // Publishes the events held back by publish_heard whose clicks
+    // have now been heard, returning whether there were any.
+    fn show_heard(&mut self) -> bool {
+        let now = Instant::now();
+        let mut shown = false;
+        while let Some((heard, _)) = self.delayed.front() {
+            if *heard > now {
+                break;
+            }
+            let (_, event) = self.delayed.pop_front().unwrap();
+            self.publish(event);
+            shown = true;
+        }
+        if shown {
+            self.draw();
+        }
+        shown
+    }
+
+    // Handles a key press while the spec editor is open.
+    fn entry_keypress(&mut self, key: u8) -> (StateTransition, TickCommand) {
+        let entry = self.entry.as_mut().unwrap();
+        match entry.send(key) {
+            SpecEntryResult::Continue => entry.draw(),
+            SpecEntryResult::Cancel => self.close_entry(),
+            SpecEntryResult::Submit(rhythm) => {
+                self.pending = Some(rhythm);
+                self.close_entry();
+            }
+            SpecEntryResult::Quit => return (StateTransition::Exit, TickCommand::None),
+        }
+
+        (StateTransition::NoChange, TickCommand::None)
+    }
+
+    // Quits, or carries on without a terminal, once stdin has closed.
+    fn stdin_closed(&mut self) -> (StateTransition, TickCommand) {
+        if self.settings.stdin_close == StdinClose::Quit {
+            self.halt(PlayState::Stopped);
+            return (StateTransition::Exit, TickCommand::None);
+        }
 
-                    (StateTransition::NoChange, TickCommand::None)
+        log::info!("Stdin closed; carrying on without a terminal");
+        if let Err(e) = termios_handler::detach_stdout() {
+            log::warn!("Couldn't detach from the terminal: {}", e);
+        }
+        match self.settings.stdin_close {
+            StdinClose::Pause => self.message(ControllerMsg::Pause, InputSource::Keyboard),
+            _ => (StateTransition::NoChange, TickCommand::None),
+        }
+    }
+
+    // Closes the spec editor and clears its line, along with the hint
+    // bar below it.
+    fn close_entry(&mut self) {
+        self.entry = None;
+        print!("\r\x1B[J\x1B[{}A", self.extra_lines() + 1);
+        self.draw();
+    }
+
+    // Carries out a request from one of the frontends.
+    fn handle_message(
+        &mut self,
+        msg: ControllerMsg,
+        source: InputSource,
+    ) -> (StateTransition, TickCommand) {
+        match msg {
+            ControllerMsg::Pause => {
+                self.halt(PlayState::Paused);
+                self.scheduled = None;
+                (StateTransition::NoChange, TickCommand::Pause)
+            }
+            ControllerMsg::Play => {
+                self.scheduled = None;
+                (StateTransition::NoChange, TickCommand::Resume)
+            }
+            ControllerMsg::Toggle => {
+                // If we weren't playing, the next tick says so.
+                if self.play_state == PlayState::Playing {
+                    self.halt(PlayState::Paused);
                 }
-                ControllerMsg::Sync => {
-                    self.tick_number = 0;
-                    (
-                        StateTransition::NoChange,
-                        TickCommand::Set(Duration::new(0, 0)),
-                    )
+                self.scheduled = None;
+                (StateTransition::NoChange, TickCommand::Toggle)
+            }
+            ControllerMsg::Stop => {
+                self.halt(PlayState::Stopped);
+                self.delayed.clear();
+                self.tick_number = 0;
+                self.measure = 0;
+                self.beats = 0.0;
+                self.map_next = 0;
+                self.count_in = self.settings.count_in;
+                self.scheduled = None;
+                (StateTransition::NoChange, TickCommand::Pause)
+            }
+            ControllerMsg::AdjustVolume(x) => {
+                self.set_volume(self.settings.volume + x);
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::AdjustVolumeDb(db) => {
+                self.set_volume(sound::volume_from_db(
+                    sound::volume_db(self.settings.volume) + db,
+                ));
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::AdjustTempo(x) => {
+                self.set_tempo(self.settings.tempo + x, source);
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::SetTempo(tempo) => {
+                self.set_tempo(tempo, source);
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::Sync => {
+                self.halt(PlayState::Stopped);
+                self.delayed.clear();
+                self.tick_number = 0;
+                self.measure = 0;
+                self.beats = 0.0;
+                self.map_next = 0;
+                self.count_in = self.settings.count_in;
+                self.scheduled = None;
+                (
+                    StateTransition::NoChange,
+                    TickCommand::Set(Duration::new(0, 0)),
+                )
+            }
+            ControllerMsg::HardSync => {
+                self.halt(PlayState::Stopped);
+                self.delayed.clear();
+                self.tick_number = 0;
+                self.measure = 0;
+                self.beats = 0.0;
+                self.map_next = 0;
+                self.count_in = 0;
+                self.scheduled = None;
+                (
+                    StateTransition::NoChange,
+                    TickCommand::Set(Duration::new(0, 0)),
+                )
+            }
+            ControllerMsg::CycleGrouping => {
+                if let Some(meter) = &self.settings.meter {
+                    let meter = meter.next_grouping();
+                    self.set_rhythm(&meter.to_beatspec(), Some(meter));
+                }
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::AdjustCrossbeat(which, by) => {
+                if let Some(poly) = self.settings.poly {
+                    let poly = poly.adjust(which, by);
+                    self.set_rhythm(&poly.to_beatspec(), None);
+                    self.settings.poly = Some(poly);
+                    if let Some(explorer) = &mut self.explorer {
+                        explorer.set_poly(poly);
+                    }
+                    self.draw();
                 }
-                ControllerMsg::TapMode => (
-                    StateTransition::To(Box::new(TapState::new(
-                        self.rhythm.clone(),
-                        self.cfg.clone(),
-                        self.volume,
-                    ))),
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            // The explorer's rhythm is always its two crossbeats.
+            ControllerMsg::EditSpec if self.settings.poly.is_some() => {
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::EditSpec => {
+                let entry = SpecEntry::new(&self.settings.rhythm.reduce().to_rhythmspec());
+                print!("{}", "\r\n".repeat(self.extra_lines() + 1));
+                self.entry = Some(entry);
+                self.draw();
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::CycleOutput => {
+                self.settings.output = self.settings.output.next(&self.settings.outputs);
+                self.settings.sound.set_output(self.settings.output);
+                self.publish(ModelEvent::OutputChanged(self.settings.output));
+                self.draw();
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::CycleTimbre => {
+                self.settings.timbre = self.settings.timbre.next();
+                self.publish(ModelEvent::TimbreChanged(self.settings.timbre));
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::ToggleAccentOnly => {
+                self.settings.accent_only = !self.settings.accent_only;
+                self.publish(ModelEvent::AccentOnlyChanged(self.settings.accent_only));
+                self.draw();
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::ToggleLock => {
+                self.publish(ModelEvent::LockChanged(self.controller.locked()));
+                self.draw();
+
+                (StateTransition::NoChange, TickCommand::None)
+            }
+            ControllerMsg::TapMode => {
+                self.halt(PlayState::Stopped);
+                (
+                    StateTransition::To(Box::new(TapState::new(self.settings.clone()))),
                     TickCommand::None,
-                ),
-                ControllerMsg::Quit => (StateTransition::Exit, TickCommand::None),
-            };
-        } else {
-            (StateTransition::NoChange, TickCommand::None)
+                )
+            }
+            ControllerMsg::Quit => {
+                self.halt(PlayState::Stopped);
+                (StateTransition::Exit, TickCommand::None)
+            }
         }
     }
 }
 
-// Plays a single BeatSpec event with the given configuration and
-// volume.
-fn play_event(evt: &Event, cfg: &AudioConfig, vol: f64) {
-    match *evt {
-        Event::Rest => {}
-        Event::Beep(emph) => beep(
-            constants::BEEP_PITCH / (emph + 1) as f64,
-            Duration::from_millis(constants::BEAT_LEN),
-            cfg,
-            vol,
-        ),
+impl AppState for MetronomeState {
+    fn tick(&mut self) -> (StateTransition, TickCommand) {
+        // We also wake up between ticks to show the ones that have
+        // just been heard, with a latency offset, and to move the
+        // display along, so only play a tick if it's due.
+        let started = Instant::now();
+        self.follow_link();
+
+        let due = match self.play_time() {
+            Some(time) => Instant::now() >= time,
+            None => true,
+        };
+        if due {
+            if self.count_in > 0 {
+                self.count_in_tick();
+            } else {
+                self.play_tick();
+            }
+        }
+        if !self.show_heard() && !due {
+            self.draw();
+        }
+        self.watchdog
+            .done(if due { "playing a tick" } else { "drawing" }, started);
+
+        let frame = Instant::now() + Duration::from_millis(constants::FRAME_INTERVAL);
+        let mut next = self.play_time().unwrap().min(frame);
+        if let Some((heard, _)) = self.delayed.front() {
+            next = next.min(*heard);
+        }
+        (
+            StateTransition::NoChange,
+            TickCommand::Set(next.saturating_duration_since(Instant::now())),
+        )
+    }
+
+    fn keypress(&mut self, key: Keycode, _time: Duration) -> (StateTransition, TickCommand) {
+        let cmd = if let Keycode::Key(key) = key {
+            if self.entry.is_some() {
+                return self.entry_keypress(key);
+            }
+            self.controller.send(key)
+        } else {
+            return self.stdin_closed();
+        };
+
+        match cmd {
+            Some(cmd) => self.message(cmd, InputSource::Keyboard),
+            None => (StateTransition::NoChange, TickCommand::None),
+        }
+    }
+
+    fn message(
+        &mut self,
+        msg: ControllerMsg,
+        source: InputSource,
+    ) -> (StateTransition, TickCommand) {
+        let started = Instant::now();
+        let result = self.handle_message(msg, source);
+        self.watchdog.done(activity(source), started);
+        result
     }
 }
 
+// Whether humanization may vary a tick: anything but a rest or the
+// strongest accent, which stays exact to anchor the measure.
+fn is_humanized(tick: &Event) -> bool {
+    matches!(tick, Event::Beep(emph) if *emph > 0)
+}
+
 // Gets the time delay between two ticks of the given BeatSpec.
-fn get_delay(bs: &BeatSpec, tempo: f64) -> Duration {
+pub fn get_delay(bs: &BeatSpec, tempo: f64) -> Duration {
     let beat_time = 60.0 / tempo;
     let tick_time = beat_time / bs.get_beat_len() as f64;
 
     seconds(tick_time)
 }
 
+// What the main loop is doing while it handles a request from the
+// given frontend, for the watchdog's reports.
+fn activity(source: InputSource) -> &'static str {
+    match source {
+        InputSource::Keyboard => "handling a key press",
+        InputSource::Signal => "handling a signal",
+        InputSource::Jack => "following the JACK transport",
+        InputSource::Osc => "handling an OSC command",
+        InputSource::Http => "handling an HTTP request",
+        InputSource::Control => "handling a command from another instance",
+    }
+}
+
+// Calculates how many ticks to skip when a tick comes in the given
+// amount of time late. Lateness of up to a measure is tolerated, and
+// the ticks are played late; beyond that, every whole tick missed is
+// skipped, which keeps the metronome on its original schedule.
+fn missed_ticks(lateness: Duration, tick_len: Duration, ticks_per_measure: usize) -> u32 {
+    if tick_len.is_zero() || lateness <= tick_len * ticks_per_measure as u32 {
+        0
+    } else {
+        (lateness.as_nanos() / tick_len.as_nanos()) as u32
+    }
+}
+
 // Creates a Duration from the given number of seconds.
 fn seconds(secs: f64) -> Duration {
     let s = secs as u64;
@@ -174,3 +1154,110 @@ fn seconds(secs: f64) -> Duration {
 
     Duration::from_secs(s) + Duration::from_nanos(ns)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::StdinClose;
+    use crate::tick_clock::TickClock;
+
+    // Settings for a metronome that makes no sound, and holds back
+    // the ticks it plays (for an hour) rather than showing them.
+    fn quiet_settings(rhythm: BeatSpec) -> MetronomeSettings {
+        MetronomeSettings {
+            rhythm,
+            meter: None,
+            song: None,
+            tempo_map: None,
+            poly: None,
+            sound: Rc::new(sound::NoAudio),
+            volume: 1.0,
+            tempo: 120.0,
+            system_volume: false,
+            volume_db: false,
+            output: ClickOutput::Audio,
+            outputs: vec![ClickOutput::Audio],
+            timbre: Timbre::Sine,
+            accent_only: false,
+            accent_level: AccentLevel::Accents,
+            emphasis: EmphasisPolicy::default(),
+            layout: KeyboardLayout::Qwerty,
+            tempo_accel: TempoAccel::default(),
+            conductor: false,
+            indicator: IndicatorStyle::default(),
+            score: false,
+            hints: false,
+            confirm: false,
+            count_in: 0,
+            sticking: false,
+            humanize: Humanize::default(),
+            swing: Swing::default(),
+            link: None,
+            strict_timing: false,
+            stdin_close: StdinClose::default(),
+            audio_clock: None,
+            latency: Duration::from_secs(3600),
+            tap_latency: Duration::new(0, 0),
+            bus: EventBus::new(),
+            views: Views::new(),
+        }
+    }
+
+    // Follows the ticks a metronome has played so far.
+    fn heard(state: &mut MetronomeState, clock: &mut TickClock) {
+        for (_, event) in state.delayed.drain(..) {
+            clock.notify(&event, Instant::now());
+        }
+    }
+
+    #[test]
+    fn stall_test() {
+        // 4/4 in eighth notes at 120 BPM, so 250ms to a tick and 8
+        // ticks to a measure.
+        let mut state = MetronomeState::new(quiet_settings(BeatSpec::from_subdiv(4, 2)));
+        let mut clock = TickClock::new();
+
+        // Held up for 4.6s on the fourth tick of the first measure:
+        // the 18 whole ticks missed take us through two measures to
+        // the sixth tick of the third, on the offbeat of beat 3.
+        let start = Instant::now();
+        state.tick_number = 3;
+        state.scheduled = Some(start - Duration::from_millis(4600));
+        state.play_tick();
+        heard(&mut state, &mut clock);
+        assert_eq!(clock.beat(Instant::now()), Some((2, 0.5)));
+        assert_eq!(state.measure, 2);
+        assert_eq!(state.tick_number, 6);
+
+        // And the tick after comes back on the original grid.
+        let next = state.scheduled.unwrap();
+        assert!(next > start && next < start + Duration::from_millis(250));
+
+        // A rhythm waiting for the next measure takes over when a
+        // stall skips past its top, keeping our place in the measure.
+        state.pending = Some(BeatSpec::from_subdiv(3, 2));
+        state.scheduled = Some(Instant::now() - Duration::from_millis(2100));
+        state.play_tick();
+        heard(&mut state, &mut clock);
+        assert!(state.pending.is_none());
+        assert_eq!(state.settings.rhythm.get_ticks().len(), 6);
+        assert_eq!(clock.beat(Instant::now()), Some((2, 0.0)));
+        assert_eq!(state.measure, 3);
+    }
+
+    #[test]
+    fn missed_ticks_test() {
+        let tick = Duration::from_millis(100);
+
+        // Small delays within a measure are played late, not skipped.
+        assert_eq!(missed_ticks(Duration::new(0, 0), tick, 4), 0);
+        assert_eq!(missed_ticks(Duration::from_millis(250), tick, 4), 0);
+        assert_eq!(missed_ticks(Duration::from_millis(400), tick, 4), 0);
+
+        // Longer stalls skip every whole tick missed, so the next tick
+        // lands back on the original grid.
+        assert_eq!(missed_ticks(Duration::from_millis(401), tick, 4), 4);
+        assert_eq!(missed_ticks(Duration::from_millis(1050), tick, 4), 10);
+        assert_eq!(missed_ticks(Duration::from_secs(60), tick, 4), 600);
+    }
+}
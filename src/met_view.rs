@@ -16,11 +16,125 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::beat_spec::Event;
 use crate::constants;
+use crate::errors::*;
+use crate::locale;
+use crate::model_event::{ModelEvent, Subscriber};
+use crate::sound::{self, ClickOutput};
+use crate::sticking::Hand;
+use crate::termios_handler::terminal_size;
+use crate::tick_clock::TickClock;
 use colorful::Color;
 use colorful::Colorful;
+use error_chain::bail;
+use std::cell::RefCell;
 use std::fmt::Display;
 use std::io::{stdout, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+// How strongly the most recent click was accented, which colors the
+// progress indicator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Accent {
+    // The strongest accent, normally the downbeat.
+    Downbeat,
+
+    // Any other click on a beat.
+    Beat,
+
+    // A click between beats.
+    Subdivision,
+}
+
+impl Accent {
+    // How strongly the given event, at the given tick, is accented;
+    // None for rests.
+    fn of(event: &Event, tick: usize, beat_len: u32) -> Option<Accent> {
+        match event {
+            Event::Rest => None,
+            Event::Beep(0) => Some(Accent::Downbeat),
+            Event::Beep(_) if tick.is_multiple_of(beat_len as usize) => Some(Accent::Beat),
+            Event::Beep(_) => Some(Accent::Subdivision),
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Accent::Downbeat => Color::LightRed,
+            Accent::Beat => Color::Green,
+            Accent::Subdivision => Color::DarkGray,
+        }
+    }
+}
+
+// How the measure progress indicator looks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndicatorStyle {
+    // Width of the indicator, or None to fill the rest of the
+    // terminal.
+    pub width: Option<usize>,
+
+    // The glyph that bounces across the indicator, and the one it
+    // moves over.
+    pub marker: char,
+    pub track: char,
+}
+
+impl Default for IndicatorStyle {
+    fn default() -> IndicatorStyle {
+        IndicatorStyle {
+            width: Some(constants::DEF_MEAS_INDIC_WIDTH),
+            marker: '*',
+            track: ' ',
+        }
+    }
+}
+
+impl IndicatorStyle {
+    // Parses an indicator width: a number of columns, or "auto".
+    pub fn parse_width(arg: &str) -> Result<Option<usize>> {
+        if arg == "auto" {
+            return Ok(None);
+        }
+        match arg.parse() {
+            Ok(width) if width >= constants::MIN_MEAS_INDIC_WIDTH => Ok(Some(width)),
+            _ => bail!(format!(
+                "Indicator width must be \"auto\" or at least {}, got {}",
+                constants::MIN_MEAS_INDIC_WIDTH,
+                arg
+            )),
+        }
+    }
+
+    // Parses the indicator's glyphs: the marker, optionally followed
+    // by the track it moves over.
+    pub fn parse_glyphs(arg: &str) -> Result<(char, char)> {
+        let mut chars = arg.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some(marker), None, _) => Ok((marker, ' ')),
+            (Some(marker), Some(track), None) => Ok((marker, track)),
+            _ => bail!(format!(
+                "Indicator glyphs must be one or two characters, got {}",
+                arg
+            )),
+        }
+    }
+
+    // The indicator's width in columns, sizing it to the terminal if
+    // it's set to fill it.
+    pub fn columns(&self) -> usize {
+        match self.width {
+            Some(width) => width,
+            None => {
+                let (cols, _) = terminal_size();
+                cols.saturating_sub(constants::MEAS_INDIC_MARGIN)
+                    .max(constants::MIN_MEAS_INDIC_WIDTH)
+            }
+        }
+    }
+}
 
 // Direction of movement for the metronome indicator.
 enum Direction {
@@ -31,8 +145,16 @@ enum Direction {
 // State of the view module; this represents exactly which numbers and
 // indicators are visible on the screen.
 pub struct MetronomeView {
-    // Current progress through a measure, on a scale from 0 to 1.
-    progress: f64,
+    // Where the metronome is in the measure, which the progress
+    // indicator moves smoothly along with.
+    clock: TickClock,
+
+    // Accent of the most recent click; rests leave this alone.
+    accent: Accent,
+
+    // The hand to play the most recent click with, in a sticking
+    // drill.
+    hand: Option<Hand>,
 
     // The tempo in bpm.
     tempo: f64,
@@ -40,62 +162,97 @@ pub struct MetronomeView {
     // The volume from 0 to 1.
     volume: f64,
 
+    // Where clicks are being sent.
+    output: ClickOutput,
+
+    // Whether only the accents are being played.
+    accent_only: bool,
+
+    // Whether the keyboard is locked.
+    locked: bool,
+
+    // Whether to show the volume in decibels.
+    volume_db: bool,
+
+    // Number of ticks the watchdog has caught playing late.
+    missed: u32,
+
     // The number of beats per measure.
     beats_per_measure: f64,
+
+    // Beats left in the count-in, if we're counting in.
+    countdown: Option<u32>,
+
+    // The section of the song being played, and the measure within
+    // it out of its length, in song mode.
+    song: Option<(String, u32, u32)>,
+
+    // How the progress indicator looks.
+    style: IndicatorStyle,
 }
 
 impl MetronomeView {
-    pub fn new(beats_per_measure: f64) -> Self {
+    pub fn new(beats_per_measure: f64, style: IndicatorStyle) -> Self {
         Self {
-            progress: 0.0,
+            clock: TickClock::new(),
+            accent: Accent::Beat,
+            hand: None,
             tempo: constants::DEF_TEMPO,
             volume: constants::DEF_VOLUME,
+            output: ClickOutput::Audio,
+            accent_only: false,
+            locked: false,
+            volume_db: false,
+            missed: 0,
             beats_per_measure,
+            countdown: None,
+            song: None,
+            style,
         }
     }
 
-    // Sets the progress through the measure.
-    pub fn set_progress(&mut self, progress: f64) {
-        self.progress = progress;
-    }
-
-    // Sets the tempo in beats per minute.
-    pub fn set_tempo(&mut self, tempo: f64) {
-        self.tempo = tempo;
-    }
-
-    // Sets the volume from 0 to 1.
-    pub fn set_volume(&mut self, volume: f64) {
-        self.volume = volume;
-    }
-
-    // Visual indicator string for the tempo marking.
+    // Visual indicator string for the tempo marking. Fractional
+    // tempos get a decimal place, so 57.5 doesn't pass for 57.
     fn tempo_indicator(&self) -> String {
-        format!("{:1$}", self.tempo as u32, constants::NUM_INDIC_WIDTH)
+        let tempo = if self.tempo.fract() == 0.0 {
+            self.tempo
+        } else {
+            (self.tempo * 10.0).round() / 10.0
+        };
+        format!("{:>1$}", locale::format(tempo), constants::NUM_INDIC_WIDTH)
     }
 
     // Visual indicator for the progress through the measure. In this
-    // implementation, we use an asterisk that bounces back and forth
-    // across the fixed-width indicator.
-    fn progress_indicator(&self) -> String {
+    // implementation, we use a marker that bounces back and forth
+    // across the indicator, given the progress through the measure
+    // from 0 to 1.
+    fn progress_indicator(&self, progress: f64) -> String {
+        let width = self.style.columns();
+
+        // During a count-in, show the countdown instead.
+        if let Some(left) = self.countdown {
+            return format!("{:^1$}", left, width);
+        }
+
         let mut indicator = String::new();
-        indicator.reserve(constants::MEAS_INDIC_WIDTH as usize);
+        indicator.reserve(width);
 
-        let total_spaces = constants::MEAS_INDIC_WIDTH - 1;
-        let leading_spaces = (total_spaces as f64 * self.progress_indicator_pos()) as usize;
+        let total_spaces = width - 1;
+        let leading_spaces = (total_spaces as f64 * self.progress_indicator_pos(progress)) as usize;
         let trailing_spaces = total_spaces - leading_spaces;
 
-        indicator.push_str(&" ".repeat(leading_spaces));
-        indicator.push('*');
-        indicator.push_str(&" ".repeat(trailing_spaces));
+        let track = self.style.track.to_string();
+        indicator.push_str(&track.repeat(leading_spaces));
+        indicator.push(self.style.marker);
+        indicator.push_str(&track.repeat(trailing_spaces));
 
         indicator
     }
 
     // Calculates the position of the progress indicator, where 0 is
     // the left side of the indicator and 1 is the right side.
-    fn progress_indicator_pos(&self) -> f64 {
-        let beat_fl = self.beats_per_measure * self.progress;
+    fn progress_indicator_pos(&self, progress: f64) -> f64 {
+        let beat_fl = self.beats_per_measure * progress;
         let n_beats = beat_fl as u32;
         let dir = if n_beats % 2 == 0 {
             Direction::Right
@@ -110,13 +267,71 @@ impl MetronomeView {
         }
     }
 
+    // Name of the song section being played and how far into it we
+    // are, in song mode.
+    fn song_indicator(&self) -> String {
+        match &self.song {
+            Some((section, measure, measures)) => {
+                format!(" {}: {}/{}", section, measure, measures)
+            }
+            None => String::new(),
+        }
+    }
+
+    // Label for the hand to play the most recent click with, if
+    // we're drilling sticking.
+    fn hand_indicator(&self) -> String {
+        match self.hand {
+            Some(hand) => format!(" {}", hand.label()),
+            None => String::new(),
+        }
+    }
+
+    // Marker shown while only the accents are being played.
+    fn accent_only_indicator(&self) -> &'static str {
+        if self.accent_only {
+            " A"
+        } else {
+            ""
+        }
+    }
+
+    // Padlock shown while the keyboard is locked.
+    fn lock_indicator(&self) -> &'static str {
+        if self.locked {
+            " \u{1F512}"
+        } else {
+            ""
+        }
+    }
+
+    // Count of late ticks, shown once there have been any.
+    fn missed_indicator(&self) -> String {
+        if self.missed == 0 {
+            String::new()
+        } else {
+            format!(" {} missed", locale::format(self.missed as f64))
+        }
+    }
+
+    // Shows the volume in decibels rather than percent.
+    pub fn set_volume_db(&mut self, volume_db: bool) {
+        self.volume_db = volume_db;
+    }
+
+    pub fn set_missed(&mut self, missed: u32) {
+        self.missed = missed;
+    }
+
     // Visual indicator for the volume level.
     fn volume_indicator(&self) -> String {
-        format!(
-            "{:1$}%",
-            (self.volume * 100.0) as u32,
-            constants::NUM_INDIC_WIDTH
-        )
+        match self.output {
+            ClickOutput::Silent => return format!("{:>1$}", "off", constants::NUM_INDIC_WIDTH + 1),
+            ClickOutput::Midi => return format!("{:>1$}", "midi", constants::NUM_INDIC_WIDTH + 1),
+            ClickOutput::Audio | ClickOutput::Both => {}
+        }
+
+        volume_text(self.volume, self.volume_db)
     }
 
     // Draws the MetronomeView on the screen.
@@ -131,20 +346,184 @@ impl MetronomeView {
     }
 }
 
+impl ViewBackend for MetronomeView {
+    fn draw(&mut self) {
+        MetronomeView::draw(self);
+    }
+}
+
+// Something the metronome can be shown on, which follows the model's
+// events like any subscriber and is redrawn every frame, so it can
+// move along between ticks.
+pub trait ViewBackend: Subscriber {
+    fn draw(&mut self);
+}
+
+// The displays the metronome drives besides the terminal, e.g. an LED
+// ring. Clones share the same displays, so they carry over from state
+// to state along with the settings.
+#[derive(Clone, Default)]
+pub struct Views {
+    views: Rc<RefCell<Vec<Box<dyn ViewBackend>>>>,
+}
+
+impl Views {
+    pub fn new() -> Views {
+        Views::default()
+    }
+
+    // Adds a display, which will be notified of all later events.
+    pub fn add(&self, view: Box<dyn ViewBackend>) {
+        self.views.borrow_mut().push(view);
+    }
+
+    pub fn notify(&self, event: &ModelEvent) {
+        for view in self.views.borrow_mut().iter_mut() {
+            view.notify(event);
+        }
+    }
+
+    pub fn draw(&self) {
+        for view in self.views.borrow_mut().iter_mut() {
+            view.draw();
+        }
+    }
+}
+
+// Color of a volume indicator, which stands out when the volume is
+// boosted past 100%.
+pub fn volume_color(volume: f64) -> Color {
+    if volume > 1.0 {
+        Color::Magenta
+    } else {
+        Color::LightRed
+    }
+}
+
+// Text of a volume indicator, in percent or in decibels.
+pub fn volume_text(volume: f64, volume_db: bool) -> String {
+    if !volume_db {
+        format!(
+            "{:1$}%",
+            (volume * 100.0) as u32,
+            constants::NUM_INDIC_WIDTH
+        )
+    } else if volume <= 0.0 {
+        format!("{:>5} dB", "-inf")
+    } else {
+        format!("{:5.1} dB", sound::volume_db(volume))
+    }
+}
+
+impl Subscriber for MetronomeView {
+    fn notify(&mut self, event: &ModelEvent) {
+        self.clock.notify(event, Instant::now());
+        match event {
+            ModelEvent::Tick {
+                tick,
+                beat_len,
+                event,
+                hand,
+                ..
+            } => {
+                if let Some(accent) = Accent::of(event, *tick, *beat_len) {
+                    self.accent = accent;
+                }
+                if hand.is_some() {
+                    self.hand = *hand;
+                }
+                self.countdown = None;
+            }
+            ModelEvent::TempoChanged(tempo) => self.tempo = *tempo,
+            ModelEvent::VolumeChanged(volume) => self.volume = *volume,
+            ModelEvent::RhythmChanged { rhythm, .. } => {
+                self.beats_per_measure =
+                    rhythm.get_ticks().len() as f64 / rhythm.get_beat_len() as f64
+            }
+            ModelEvent::OutputChanged(output) => self.output = *output,
+            ModelEvent::AccentOnlyChanged(on) => self.accent_only = *on,
+            ModelEvent::LockChanged(on) => self.locked = *on,
+            ModelEvent::CountIn(left) => self.countdown = Some(*left),
+            ModelEvent::SongPosition {
+                section,
+                measure,
+                measures,
+            } => self.song = Some((section.clone(), *measure, *measures)),
+            ModelEvent::TimbreChanged(_) | ModelEvent::Transport(_) => {}
+        }
+    }
+}
+
 impl Display for MetronomeView {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}{}{} {}{}{} {}{}{}",
+            "{}{}{} {}{}{}{}{}{}{}{} {}{}{}",
             "[".color(Color::Yellow),
             self.tempo_indicator().color(Color::LightBlue),
             "]".color(Color::Yellow),
             "[".color(Color::Yellow),
-            self.progress_indicator().color(Color::Green),
+            self.progress_indicator(self.clock.measure_progress(Instant::now()))
+                .color(self.accent.color()),
             "]".color(Color::Yellow),
+            self.song_indicator().color(Color::White),
+            self.hand_indicator().color(Color::White),
+            self.accent_only_indicator().color(Color::LightRed),
+            self.lock_indicator(),
+            self.missed_indicator().color(Color::LightRed),
             "(".color(Color::Yellow),
-            self.volume_indicator().color(Color::LightRed),
+            self.volume_indicator().color(volume_color(self.volume)),
             ")".color(Color::Yellow),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accent_test() {
+        assert_eq!(Accent::of(&Event::Beep(0), 0, 2), Some(Accent::Downbeat));
+        assert_eq!(Accent::of(&Event::Beep(1), 2, 2), Some(Accent::Beat));
+        assert_eq!(Accent::of(&Event::Beep(2), 3, 2), Some(Accent::Subdivision));
+        assert_eq!(Accent::of(&Event::Rest, 1, 2), None);
+
+        // Rests keep the color of the click before them.
+        let mut view = MetronomeView::new(4.0, IndicatorStyle::default());
+        for (tick, event) in [Event::Beep(0), Event::Rest].iter().enumerate() {
+            view.notify(&ModelEvent::Tick {
+                tick,
+                ticks: 8,
+                beat_len: 2,
+                event: event.clone(),
+                hand: None,
+            });
+        }
+        assert_eq!(view.accent, Accent::Downbeat);
+    }
+
+    #[test]
+    fn indicator_style_test() {
+        assert_eq!(IndicatorStyle::parse_width("auto").unwrap(), None);
+        assert_eq!(IndicatorStyle::parse_width("20").unwrap(), Some(20));
+        assert!(IndicatorStyle::parse_width("2").is_err());
+        assert_eq!(IndicatorStyle::parse_glyphs("o").unwrap(), ('o', ' '));
+        assert_eq!(
+            IndicatorStyle::parse_glyphs("\u{25cf}-").unwrap(),
+            ('\u{25cf}', '-')
+        );
+        assert!(IndicatorStyle::parse_glyphs("").is_err());
+        assert!(IndicatorStyle::parse_glyphs("abc").is_err());
+
+        // Halfway through the first of two beats, the marker is in
+        // the middle of the track.
+        let style = IndicatorStyle {
+            width: Some(9),
+            marker: 'o',
+            track: '-',
+        };
+        let view = MetronomeView::new(2.0, style);
+        assert_eq!(view.progress_indicator(0.25), "----o----");
+    }
+}
@@ -0,0 +1,428 @@
+// MIDI output: sends a note for every click to a raw MIDI device, to
+// drive a drum module or a DAW from the metronome, and MIDI clock for
+// other gear to follow.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::Event;
+use crate::constants;
+use crate::errors::*;
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use crate::sound::{ClickOutput, DelayedOutput, Route, SoundBackend, Timbre};
+use crate::sticking::Hand;
+use error_chain::bail;
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// MIDI clock pulses per beat, fixed by the MIDI spec.
+const CLOCK_PPQN: u32 = 24;
+
+// MIDI real-time messages.
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+// User settings for MIDI output.
+#[derive(Debug, Clone)]
+pub struct MidiOptions {
+    // Raw MIDI device to write to (e.g. /dev/snd/midiC1D0), if any.
+    pub device: Option<String>,
+
+    // MIDI channel to send notes on, from 1 to 16.
+    pub channel: u8,
+
+    // Note to send for each emphasis level; levels past the end of the
+    // list use its last note.
+    pub notes: Vec<u8>,
+
+    // Raw MIDI device to send clock to, if any.
+    pub clock_device: Option<String>,
+}
+
+impl Default for MidiOptions {
+    fn default() -> MidiOptions {
+        MidiOptions {
+            device: None,
+            channel: constants::MIDI_CHANNEL,
+            notes: constants::MIDI_NOTES.to_vec(),
+            clock_device: None,
+        }
+    }
+}
+
+impl MidiOptions {
+    // Parses a channel number, from 1 to 16.
+    pub fn parse_channel(arg: &str) -> Result<u8> {
+        match arg.parse() {
+            Ok(channel @ 1..=16) => Ok(channel),
+            _ => bail!(format!("MIDI channel must be from 1 to 16, got {}", arg)),
+        }
+    }
+
+    // Parses a comma-separated list of note numbers, from 0 to 127.
+    pub fn parse_notes(arg: &str) -> Result<Vec<u8>> {
+        let mut notes = vec![];
+        for note in arg.split(',') {
+            match note.parse() {
+                Ok(note @ 0..=127) => notes.push(note),
+                _ => bail!(format!("MIDI notes must be from 0 to 127, got {}", note)),
+            }
+        }
+        Ok(notes)
+    }
+
+    // The note to send for a click of the given emphasis.
+    pub fn note(&self, emph: u32) -> u8 {
+        let n = (emph as usize).min(self.notes.len() - 1);
+        self.notes[n]
+    }
+}
+
+// Sound backend that sends a MIDI note for every click, on top of
+// whatever another backend plays.
+pub struct MidiOut {
+    inner: Rc<dyn SoundBackend>,
+    port: File,
+    options: MidiOptions,
+
+    // Holds the notes back by the flam spacing, if there is one, to
+    // line them up with the audio.
+    delayed: Option<DelayedOutput>,
+
+    // The note still sounding from the last click, which is released
+    // when the next one is played, or when the metronome stops.
+    sounding: Cell<Option<u8>>,
+
+    // Whether clicks go to `inner`, as notes, or both.
+    output: Cell<ClickOutput>,
+}
+
+impl MidiOut {
+    // Opens the MIDI device given in the options, sending clicks to it
+    // as well as to `inner`, held back by the flam spacing if any.
+    pub fn new(
+        inner: Rc<dyn SoundBackend>,
+        options: MidiOptions,
+        flam: Option<Duration>,
+    ) -> Result<MidiOut> {
+        let device = match &options.device {
+            Some(device) => device,
+            None => bail!("No MIDI device given"),
+        };
+        let port = open_port(device)?;
+        let delayed = match flam {
+            Some(flam) => {
+                let port = port.try_clone()?;
+                Some(DelayedOutput::new(flam, move |msg| send(&port, msg)))
+            }
+            None => None,
+        };
+
+        Ok(MidiOut {
+            inner,
+            port,
+            options,
+            delayed,
+            sounding: Cell::new(None),
+            output: Cell::new(ClickOutput::Both),
+        })
+    }
+
+    fn send(&self, msg: &[u8]) {
+        match &self.delayed {
+            Some(delayed) => delayed.write(msg),
+            None => send(&self.port, msg),
+        }
+    }
+
+    // Releases the note still sounding from the last click, if any.
+    fn release(&self) {
+        if let Some(note) = self.sounding.take() {
+            self.send(&note_off(self.options.channel, note));
+        }
+    }
+}
+
+impl SoundBackend for MidiOut {
+    fn play_event(
+        &self,
+        evt: &Event,
+        beat: Option<usize>,
+        hand: Option<Hand>,
+        timbre: Timbre,
+        vol: f64,
+        tick_len: Duration,
+    ) {
+        let output = self.output.get();
+        if output.audio() {
+            self.inner
+                .play_event(evt, beat, hand, timbre, vol, tick_len);
+        }
+
+        if let (Event::Beep(emph), true) = (evt, output.midi()) {
+            self.release();
+            let note = self.options.note(*emph);
+            self.send(&note_on(self.options.channel, note, velocity(vol)));
+            self.sounding.set(Some(note));
+        }
+    }
+
+    fn beep(&self, frequency: f64, length: Duration, timbre: Timbre, vol: f64, route: Route) {
+        self.inner.beep(frequency, length, timbre, vol, route);
+    }
+
+    fn set_tempo(&self, tempo: f64) {
+        self.inner.set_tempo(tempo);
+    }
+
+    fn set_playing(&self, playing: bool) {
+        if !playing {
+            self.release();
+        }
+        self.inner.set_playing(playing);
+    }
+
+    fn set_output(&self, output: ClickOutput) {
+        if !output.midi() {
+            self.release();
+        }
+        self.output.set(output);
+    }
+}
+
+// Leaves no note hanging on the other end when we quit.
+impl Drop for MidiOut {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+// Subscriber that sends MIDI clock at the metronome's tempo, along
+// with Start, Stop and Continue as playback starts and stops, so that
+// other gear can follow along. The pulses come from a thread of their
+// own, since there are 24 of them to every beat.
+pub struct MidiClock {
+    clock: Sender<ClockMsg>,
+}
+
+// A change the clock thread needs to know about.
+enum ClockMsg {
+    Tempo(f64),
+    Transport(Transport),
+}
+
+impl MidiClock {
+    // Opens the given MIDI device and starts the clock thread, at the
+    // given starting tempo. The clock doesn't run until playback
+    // starts.
+    pub fn new(device: &str, tempo: f64) -> Result<MidiClock> {
+        let port = open_port(device)?;
+        let (clock, msgs) = channel();
+        thread::spawn(move || run_clock(port, msgs, tempo));
+
+        Ok(MidiClock { clock })
+    }
+}
+
+impl Subscriber for MidiClock {
+    fn notify(&mut self, event: &ModelEvent) {
+        let msg = match event {
+            ModelEvent::TempoChanged(tempo) => ClockMsg::Tempo(*tempo),
+            ModelEvent::Transport(transport) => ClockMsg::Transport(*transport),
+            _ => return,
+        };
+        // If the thread's gone, it's already logged why.
+        let _ = self.clock.send(msg);
+    }
+}
+
+// Sends clock pulses to `port` while playing, following the changes
+// sent on `msgs`, until the sender goes away.
+fn run_clock(port: File, msgs: Receiver<ClockMsg>, tempo: f64) {
+    let mut interval = pulse_interval(tempo);
+
+    // When the next pulse is due, if we're playing.
+    let mut next: Option<Instant> = None;
+    loop {
+        let msg = match next {
+            Some(next) => match msgs.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                Ok(msg) => Some(msg),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match msgs.recv() {
+                Ok(msg) => Some(msg),
+                Err(_) => return,
+            },
+        };
+
+        match msg {
+            // Pulses are scheduled relative to when the last one was
+            // due, so the clock doesn't drift from the clicks.
+            None => {
+                send(&port, &[CLOCK]);
+                next = next.map(|next| next + interval);
+            }
+            Some(ClockMsg::Tempo(tempo)) => interval = pulse_interval(tempo),
+            Some(ClockMsg::Transport(transport)) => {
+                send(&port, &[transport_message(transport)]);
+                next = match transport {
+                    // The first pulse after a Start or Continue marks
+                    // the beat it starts on.
+                    Transport::Start | Transport::Continue => {
+                        send(&port, &[CLOCK]);
+                        Some(Instant::now() + interval)
+                    }
+                    Transport::Stop => None,
+                };
+            }
+        }
+    }
+}
+
+// Time between clock pulses at the given tempo.
+fn pulse_interval(tempo: f64) -> Duration {
+    Duration::from_secs_f64(60.0 / tempo / CLOCK_PPQN as f64)
+}
+
+// The real-time message for a change in playback.
+fn transport_message(transport: Transport) -> u8 {
+    match transport {
+        Transport::Start => START,
+        Transport::Stop => STOP,
+        Transport::Continue => CONTINUE,
+    }
+}
+
+// Opens a raw MIDI device for writing.
+fn open_port(device: &str) -> Result<File> {
+    match OpenOptions::new().write(true).open(device) {
+        Ok(port) => Ok(port),
+        Err(e) => bail!(format!("Couldn't open MIDI device {}: {}", device, e)),
+    }
+}
+
+// Writes a message to a device. MIDI is best-effort: a message that
+// can't be sent is logged and dropped.
+fn send(port: &File, msg: &[u8]) {
+    let mut port = port;
+    if let Err(e) = port.write_all(msg) {
+        log::error!("Couldn't send MIDI message: {}", e);
+    }
+}
+
+// Note-on message for the given channel (1 to 16), note and velocity.
+pub fn note_on(channel: u8, note: u8, velocity: u8) -> [u8; 3] {
+    [0x90 | (channel - 1), note, velocity]
+}
+
+// Note-off message for the given channel (1 to 16) and note.
+pub fn note_off(channel: u8, note: u8) -> [u8; 3] {
+    [0x80 | (channel - 1), note, 0]
+}
+
+// MIDI velocity for a click at the given volume. Velocity 0 would be
+// read as a note-off, so the quietest click gets 1.
+pub fn velocity(vol: f64) -> u8 {
+    (vol.min(1.0) * 127.0).round().max(1.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_test() {
+        assert_eq!(note_on(10, 76, 100), [0x99, 76, 100]);
+        assert_eq!(note_off(1, 60), [0x80, 60, 0]);
+        assert_eq!(velocity(1.5), 127);
+        assert_eq!(velocity(0.0), 1);
+    }
+
+    #[test]
+    fn options_test() {
+        assert_eq!(MidiOptions::parse_channel("16").unwrap(), 16);
+        assert!(MidiOptions::parse_channel("0").is_err());
+        assert!(MidiOptions::parse_channel("17").is_err());
+
+        let options = MidiOptions {
+            notes: MidiOptions::parse_notes("76,77").unwrap(),
+            ..MidiOptions::default()
+        };
+        assert_eq!(options.note(0), 76);
+        assert_eq!(options.note(3), 77);
+        assert!(MidiOptions::parse_notes("76,128").is_err());
+        assert!(MidiOptions::parse_notes("").is_err());
+    }
+
+    #[test]
+    fn clock_test() {
+        assert_eq!(pulse_interval(60.0).as_micros(), 41_666);
+        assert_eq!(pulse_interval(150.0).as_micros(), 16_666);
+        assert_eq!(transport_message(Transport::Start), 0xFA);
+        assert_eq!(transport_message(Transport::Continue), 0xFB);
+        assert_eq!(transport_message(Transport::Stop), 0xFC);
+    }
+
+    #[test]
+    fn release_test() {
+        let path = std::env::temp_dir().join(format!("metronome-midi-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let options = MidiOptions {
+            device: Some(path.to_string_lossy().into_owned()),
+            ..MidiOptions::default()
+        };
+        let out = MidiOut::new(Rc::new(crate::sound::NoAudio), options.clone(), None).unwrap();
+        let click = |out: &MidiOut| {
+            let len = Duration::from_millis(100);
+            out.play_event(&Event::Beep(0), None, None, Timbre::Sine, 1.0, len);
+        };
+
+        // Stopping releases the last note, as does quitting; clicks
+        // sent to audio only send no notes.
+        click(&out);
+        out.set_playing(false);
+        out.set_output(ClickOutput::Audio);
+        click(&out);
+        out.set_output(ClickOutput::Midi);
+        click(&out);
+        drop(out);
+
+        let sent = std::fs::read(&path).unwrap();
+        let (on, off) = (note_on(10, 76, 127), note_off(10, 76));
+        assert_eq!(sent, [on, off, on, off].concat());
+
+        // With flams on, notes wait out the flam spacing like the
+        // audio does, but still all arrive before we quit.
+        File::create(&path).unwrap();
+        let flam = Some(Duration::from_millis(200));
+        let out = MidiOut::new(Rc::new(crate::sound::NoAudio), options, flam).unwrap();
+        click(&out);
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        drop(out);
+
+        let sent = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent, [on, off].concat());
+    }
+}
@@ -0,0 +1,167 @@
+// Events describing changes to the metronome's state, and the bus
+// that carries them to whatever is interested in them.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Event, Meter};
+use crate::sound::{ClickOutput, Timbre};
+use crate::sticking::Hand;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// A change to the metronome's state.
+#[derive(Debug, Clone)]
+pub enum ModelEvent {
+    // A tick was played; `tick` is its index among the `ticks` ticks
+    // in the measure, with `beat_len` ticks per beat, and `event` is
+    // what it played. `hand` is the hand to play it with, in a
+    // sticking drill.
+    Tick {
+        tick: usize,
+        ticks: usize,
+        beat_len: u32,
+        event: Event,
+        hand: Option<Hand>,
+    },
+
+    // The tempo changed, in beats per minute.
+    TempoChanged(f64),
+
+    // The volume changed.
+    VolumeChanged(f64),
+
+    // The rhythm changed, along with the meter it was built from, if
+    // any.
+    RhythmChanged {
+        rhythm: BeatSpec,
+        meter: Option<Meter>,
+    },
+
+    // Clicks are now being sent to a different output.
+    OutputChanged(ClickOutput),
+
+    // Clicks are now made with a different timbre.
+    TimbreChanged(Timbre),
+
+    // Only the accents are now being played, or every click is again.
+    AccentOnlyChanged(bool),
+
+    // The keyboard was locked or unlocked.
+    LockChanged(bool),
+
+    // A beat of a count-in was played, with the given number of beats
+    // left before the downbeat, counting this one.
+    CountIn(u32),
+
+    // A measure of a song started: the `measure`th (counting from 1)
+    // of the `measures` in the section with the given name.
+    SongPosition {
+        section: String,
+        measure: u32,
+        measures: u32,
+    },
+
+    // Playback started, stopped or picked up again, for devices that
+    // follow the metronome.
+    Transport(Transport),
+}
+
+// A change in whether the metronome is playing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    // Playing from the top of the measure.
+    Start,
+
+    // Stopped or paused.
+    Stop,
+
+    // Playing again from where it was paused.
+    Continue,
+}
+
+impl ModelEvent {
+    // Whether this event changes a setting that would be a pain to
+    // lose, as opposed to the tempo and volume, which change often
+    // and in small steps, or ticks, which don't change settings.
+    pub fn is_significant(&self) -> bool {
+        matches!(
+            self,
+            ModelEvent::RhythmChanged { .. } | ModelEvent::OutputChanged(_)
+        )
+    }
+}
+
+// Something that wants to hear about changes to the metronome's
+// state.
+pub trait Subscriber {
+    fn notify(&mut self, event: &ModelEvent);
+}
+
+// Carries ModelEvents to their subscribers. Clones of a bus share the
+// same subscribers, so a bus can be handed from state to state.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Rc<RefCell<Vec<Box<dyn Subscriber>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    // Adds a subscriber, which will be notified of all later events.
+    pub fn subscribe(&self, subscriber: Box<dyn Subscriber>) {
+        self.subscribers.borrow_mut().push(subscriber);
+    }
+
+    // Notifies all the subscribers of an event.
+    pub fn publish(&self, event: &ModelEvent) {
+        for subscriber in self.subscribers.borrow_mut().iter_mut() {
+            subscriber.notify(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Subscriber that counts the tempo changes it sees.
+    struct Counter(Rc<RefCell<u32>>);
+
+    impl Subscriber for Counter {
+        fn notify(&mut self, event: &ModelEvent) {
+            if let ModelEvent::TempoChanged(_) = event {
+                *self.0.borrow_mut() += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn bus_test() {
+        let count = Rc::new(RefCell::new(0));
+        let bus = EventBus::new();
+        bus.subscribe(Box::new(Counter(count.clone())));
+
+        // Clones of the bus reach the same subscribers.
+        let clone = bus.clone();
+        bus.publish(&ModelEvent::TempoChanged(100.0));
+        clone.publish(&ModelEvent::TempoChanged(101.0));
+        clone.publish(&ModelEvent::VolumeChanged(0.5));
+        assert_eq!(*count.borrow(), 2);
+    }
+}
@@ -0,0 +1,314 @@
+// Open Sound Control: broadcasts every tick, so lighting rigs and
+// visual software can follow the click, and takes remote control
+// commands, e.g. from a tablet on stage.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::app_state::{Input, InputEvent, InputSender, InputSource};
+use crate::beat_spec::Event;
+use crate::errors::*;
+use crate::met_controller::ControllerMsg;
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use error_chain::bail;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::thread;
+
+// Address the tick messages are sent to.
+const BEAT_ADDRESS: &str = "/metronome/beat";
+
+// Subscriber that sends a `/metronome/beat` message for every tick,
+// with the measure number, the tick's index in the measure and its
+// emphasis (-1 for rests).
+pub struct OscSender {
+    socket: UdpSocket,
+
+    // Number of measures since the metronome started or was synced,
+    // and the last tick sent, to tell when a new measure starts.
+    measure: i32,
+    last_tick: Option<usize>,
+}
+
+impl OscSender {
+    // Sets up to send to the given host:port.
+    pub fn new(target: &str) -> Result<OscSender> {
+        Ok(OscSender {
+            socket: connect(target)?,
+            measure: 0,
+            last_tick: None,
+        })
+    }
+}
+
+// Opens a socket for sending OSC messages to the given host:port.
+pub fn connect(target: &str) -> Result<UdpSocket> {
+    let addr = match target.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        _ => bail!(format!("Can't find OSC target {}", target)),
+    };
+    let local = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+impl Subscriber for OscSender {
+    fn notify(&mut self, event: &ModelEvent) {
+        let (tick, emphasis) = match event {
+            ModelEvent::Tick { tick, event, .. } => match event {
+                Event::Beep(emph) => (*tick, *emph as i32),
+                Event::Rest => (*tick, -1),
+            },
+            ModelEvent::Transport(Transport::Start) => {
+                self.measure = 0;
+                self.last_tick = None;
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(last) = self.last_tick {
+            if tick <= last {
+                self.measure += 1;
+            }
+        }
+        self.last_tick = Some(tick);
+
+        let msg = encode(BEAT_ADDRESS, &[self.measure, tick as i32, emphasis]);
+        // OSC is fire-and-forget; there's probably just nothing
+        // listening yet.
+        if let Err(e) = self.socket.send(&msg) {
+            log::debug!("Couldn't send OSC message: {}", e);
+        }
+    }
+}
+
+// Listens for OSC messages on the given port, and starts a thread
+// that passes the commands among them on to the given queue.
+pub fn init_osc_thread(port: u16, send: InputSender) -> Result<()> {
+    let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(socket) => socket,
+        Err(e) => bail!(format!("Can't listen for OSC on port {}: {}", port, e)),
+    };
+
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        loop {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::error!("Couldn't receive OSC message: {}", e);
+                    return;
+                }
+            };
+            let msg = match decode(&buf[..n]) {
+                Some((address, args)) => command(&address, &args),
+                None => {
+                    log::debug!("Ignoring malformed OSC message");
+                    continue;
+                }
+            };
+            if let Some(msg) = msg {
+                let input = Input {
+                    source: InputSource::Osc,
+                    event: InputEvent::Message(msg),
+                };
+                if send.send(input).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Works out which command an OSC message is. Buttons on control
+// surfaces send 1 when pressed and 0 when released, so only presses
+// trigger a command. Anyone on the network can send these, so numbers
+// that make no sense as a tempo or volume are ignored.
+fn command(address: &str, args: &[f64]) -> Option<ControllerMsg> {
+    let pressed = args.first().is_none_or(|&x| x != 0.0);
+    let msg = match (address, args) {
+        ("/metronome/tempo", &[tempo]) if tempo > 0.0 && tempo.is_finite() => {
+            ControllerMsg::SetTempo(tempo)
+        }
+        ("/metronome/tempo/adjust", &[x]) if x.is_finite() => ControllerMsg::AdjustTempo(x),
+        ("/metronome/volume/adjust", &[x]) if x.is_finite() => {
+            ControllerMsg::AdjustVolume(x / 100.0)
+        }
+        (_, _) if !pressed => return None,
+        ("/metronome/play", _) => ControllerMsg::Play,
+        ("/metronome/pause", _) => ControllerMsg::Pause,
+        ("/metronome/toggle", _) => ControllerMsg::Toggle,
+        ("/metronome/stop", _) => ControllerMsg::Stop,
+        ("/metronome/sync", _) => ControllerMsg::Sync,
+        _ => {
+            log::debug!("Ignoring unknown OSC message {} {:?}", address, args);
+            return None;
+        }
+    };
+    Some(msg)
+}
+
+// Decodes an OSC message into its address and numeric arguments.
+// Returns None for malformed messages and for ones with arguments
+// that aren't numbers.
+fn decode(msg: &[u8]) -> Option<(String, Vec<f64>)> {
+    let (address, rest) = pull_string(msg)?;
+    let (tags, mut rest) = pull_string(rest)?;
+    let tags = tags.strip_prefix(',')?;
+
+    let mut args = vec![];
+    for tag in tags.chars() {
+        if rest.len() < 4 {
+            return None;
+        }
+        let bytes = [rest[0], rest[1], rest[2], rest[3]];
+        args.push(match tag {
+            'i' => i32::from_be_bytes(bytes) as f64,
+            'f' => f32::from_be_bytes(bytes) as f64,
+            _ => return None,
+        });
+        rest = &rest[4..];
+    }
+
+    Some((address, args))
+}
+
+// Reads an OSC string from the start of `msg`, returning it and the
+// rest of the message after its padding.
+fn pull_string(msg: &[u8]) -> Option<(String, &[u8])> {
+    let len = msg.iter().position(|&b| b == 0)?;
+    let s = String::from_utf8(msg[..len].to_vec()).ok()?;
+    let padded = (len + 4) / 4 * 4;
+    Some((s, msg.get(padded..)?))
+}
+
+// Encodes an OSC message with the given address and integer
+// arguments.
+pub fn encode(address: &str, args: &[i32]) -> Vec<u8> {
+    let mut msg = vec![];
+    push_string(&mut msg, address);
+    push_string(&mut msg, &format!(",{}", "i".repeat(args.len())));
+    for arg in args {
+        msg.extend_from_slice(&arg.to_be_bytes());
+    }
+    msg
+}
+
+// Appends an OSC string: NUL-terminated, and padded with NULs to a
+// multiple of four bytes.
+fn push_string(msg: &mut Vec<u8>, s: &str) {
+    msg.extend_from_slice(s.as_bytes());
+    msg.push(0);
+    while !msg.len().is_multiple_of(4) {
+        msg.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_test() {
+        let msg = encode("/beat", &[1, -1]);
+        assert_eq!(&msg[..8], b"/beat\0\0\0");
+        assert_eq!(&msg[8..12], b",ii\0");
+        assert_eq!(&msg[12..], &[0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff]);
+
+        // A string that fills its four bytes still gets a NUL.
+        assert_eq!(encode("/abc", &[]), b"/abc\0\0\0\0,\0\0\0");
+    }
+
+    #[test]
+    fn decode_test() {
+        let msg = encode("/metronome/tempo/adjust", &[-2]);
+        assert_eq!(
+            decode(&msg),
+            Some(("/metronome/tempo/adjust".to_string(), vec![-2.0]))
+        );
+
+        let mut msg = vec![];
+        push_string(&mut msg, "/metronome/tempo");
+        push_string(&mut msg, ",f");
+        msg.extend_from_slice(&96.5f32.to_be_bytes());
+        let (address, args) = decode(&msg).unwrap();
+        assert!(matches!(command(&address, &args), Some(ControllerMsg::SetTempo(t)) if t == 96.5));
+
+        // Truncated messages and string arguments are rejected.
+        assert_eq!(decode(&msg[..msg.len() - 1]), None);
+        assert_eq!(decode(b"/abc\0\0\0\0,s\0\0abc\0"), None);
+    }
+
+    #[test]
+    fn command_test() {
+        assert!(matches!(
+            command("/metronome/play", &[]),
+            Some(ControllerMsg::Play)
+        ));
+        assert!(matches!(
+            command("/metronome/pause", &[1.0]),
+            Some(ControllerMsg::Pause)
+        ));
+        // Button releases are ignored.
+        assert!(command("/metronome/pause", &[0.0]).is_none());
+        assert!(matches!(
+            command("/metronome/volume/adjust", &[10.0]),
+            Some(ControllerMsg::AdjustVolume(x)) if (x - 0.1).abs() < 1e-9
+        ));
+        assert!(command("/metronome/explode", &[]).is_none());
+        assert!(command("/metronome/tempo", &[]).is_none());
+        for bad in [0.0, -60.0, f64::NAN, f64::INFINITY] {
+            assert!(command("/metronome/tempo", &[bad]).is_none());
+        }
+        assert!(command("/metronome/tempo/adjust", &[f64::NAN]).is_none());
+        assert!(command("/metronome/volume/adjust", &[f64::NEG_INFINITY]).is_none());
+    }
+
+    #[test]
+    fn measure_test() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let mut sender = OscSender::new(&target).unwrap();
+
+        let tick = |tick| ModelEvent::Tick {
+            tick,
+            ticks: 2,
+            beat_len: 1,
+            event: Event::Beep(tick as u32),
+            hand: None,
+        };
+        let mut buf = [0; 64];
+        let mut measures = vec![];
+        for event in [tick(0), tick(1), tick(0), tick(1)] {
+            sender.notify(&event);
+            let n = listener.recv(&mut buf).unwrap();
+            measures.push(buf[n - 9]);
+        }
+        assert_eq!(measures, [0, 0, 1, 1]);
+
+        sender.notify(&ModelEvent::Transport(Transport::Start));
+        sender.notify(&tick(0));
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[n - 12..n], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
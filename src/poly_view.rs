@@ -0,0 +1,128 @@
+// View for the polyrhythm explorer, showing each of the two
+// crossbeats on a line of its own, with the points they meet.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::Polyrhythm;
+use crate::model_event::{ModelEvent, Subscriber};
+use crate::termios_handler::terminal_size;
+use colorful::Color;
+use colorful::Colorful;
+use std::io::{stdout, Write};
+
+pub struct PolyView {
+    poly: Polyrhythm,
+
+    // The current tick.
+    current: usize,
+}
+
+impl PolyView {
+    pub fn new(poly: Polyrhythm) -> Self {
+        Self { poly, current: 0 }
+    }
+
+    // Switches to a new polyrhythm.
+    pub fn set_poly(&mut self, poly: Polyrhythm) {
+        self.poly = poly;
+        self.current %= poly.ticks() as usize;
+    }
+
+    // The line for one of the crossbeats (0 for the beat, 1 for the
+    // one against it), squeezed into at most `width` glyphs: 'X'
+    // where both crossbeats fall, 'x' where only this one does and
+    // '-' elsewhere. When there are more ticks than glyphs, each glyph
+    // stands for several ticks, and shows the strongest of them.
+    fn glyphs(&self, which: usize, width: usize) -> Vec<char> {
+        let ticks = self.poly.ticks() as usize;
+        let width = width.clamp(1, ticks);
+        let mut glyphs = vec!['-'; width];
+        for tick in 0..ticks {
+            let (beats, against) = self.poly.hits(tick as u32);
+            let hit = if which == 0 { beats } else { against };
+            let glyph = &mut glyphs[tick * width / ticks];
+            *glyph = match (hit, beats && against) {
+                (true, true) => 'X',
+                (true, false) if *glyph != 'X' => 'x',
+                _ => *glyph,
+            };
+        }
+
+        glyphs
+    }
+
+    // Draws the PolyView on the current line and the one below it,
+    // leaving the cursor on the second.
+    pub fn draw(&self) {
+        let (cols, _) = terminal_size();
+        let ticks = self.poly.ticks() as usize;
+        for (which, count) in [self.poly.beats, self.poly.against].iter().enumerate() {
+            if which > 0 {
+                print!("\r\n");
+            }
+            let label = format!("{:>2} ", count);
+            let glyphs = self.glyphs(which, cols.saturating_sub(label.len() + 1));
+            let current = self.current * glyphs.len() / ticks;
+
+            print!("\r\x1B[K{}", label);
+            for (n, &g) in glyphs.iter().enumerate() {
+                let glyph = g.to_string();
+                if n == current {
+                    print!("{}", glyph.color(Color::Black).bg_color(Color::Green));
+                } else if g == 'X' {
+                    print!("{}", glyph.color(Color::LightRed));
+                } else if g == 'x' {
+                    print!("{}", glyph.color(Color::LightBlue));
+                } else {
+                    print!("{}", glyph.color(Color::DarkGray));
+                }
+            }
+        }
+
+        stdout().flush().unwrap();
+    }
+}
+
+impl Subscriber for PolyView {
+    fn notify(&mut self, event: &ModelEvent) {
+        if let ModelEvent::Tick { tick, .. } = event {
+            self.current = *tick;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyphs_test() {
+        let view = PolyView::new(Polyrhythm {
+            beats: 3,
+            against: 4,
+        });
+        let line = |which, width| view.glyphs(which, width).iter().collect::<String>();
+        assert_eq!(line(0, 80), "X---x---x---");
+        assert_eq!(line(1, 80), "X--x--x--x--");
+
+        // Squeezed lines share glyphs between ticks, and a meeting
+        // point wins over the hits sharing its glyph.
+        assert_eq!(line(0, 6), "X-x-x-");
+        assert_eq!(line(1, 6), "Xx-xx-");
+        assert_eq!(line(1, 2), "Xx");
+    }
+}
@@ -0,0 +1,284 @@
+// Compact encoding of the metronome's settings as short strings, for
+// sharing exact setups by pasting them into a chat.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Swing};
+use crate::config::{self, Config};
+use crate::errors::*;
+use crate::locale;
+use crate::sound::Timbre;
+use error_chain::bail;
+
+// Version of the preset format, written as the first field so that
+// old presets can still be read if the format changes. Version 2 added
+// the timbre, and version 3 the swing.
+const PRESET_VERSION: u32 = 3;
+
+// Alphabet for the URL-safe flavor of base64, which survives being
+// pasted into chats and URLs without quoting.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// Encodes the preset-worthy parts of a configuration (rhythm, tempo,
+// volume, timbre, swing and sound options) as a string.
+pub fn encode(cfg: &Config) -> String {
+    let meter = match &cfg.meter {
+        Some(meter) => meter.to_string(),
+        None => "-".to_string(),
+    };
+    let flam = match cfg.sound.flam {
+        Some(flam) => flam.as_millis().to_string(),
+        None => "-".to_string(),
+    };
+    let swing = if cfg.swing == Swing::default() {
+        "-".to_string()
+    } else {
+        cfg.swing.0.to_string()
+    };
+    let text = format!(
+        "{};{};{};{};{};{};{};{}",
+        PRESET_VERSION,
+        cfg.tempo,
+        cfg.volume,
+        cfg.rhythm.reduce().to_rhythmspec(),
+        meter,
+        flam,
+        cfg.timbre.name(),
+        swing
+    );
+
+    to_base64(text.as_bytes())
+}
+
+// Decodes a preset string, applying its settings to the given
+// configuration.
+pub fn decode(preset: &str, cfg: &mut Config) -> Result<()> {
+    let text = match from_base64(preset.trim()).map(String::from_utf8) {
+        Some(Ok(text)) => text,
+        _ => bail!(format!("Malformed preset {}", preset)),
+    };
+    let fields = match migrate(text.split(';').collect())? {
+        Some(fields) => fields,
+        None => bail!(format!("Malformed preset {}", preset)),
+    };
+
+    // Presets are pasted from elsewhere, so they get the same checks
+    // as the command line.
+    cfg.tempo = config::parse_tempo(fields[1])?;
+    cfg.volume = fields[2].parse()?;
+    config::check_volume(cfg.volume)?;
+    cfg.rhythm = BeatSpec::from_rhythmspec(fields[3])?;
+    cfg.meter = match fields[4] {
+        "-" => None,
+        meter => Some(config::parse_meter(meter)?),
+    };
+    cfg.sound.flam = match fields[5] {
+        "-" => None,
+        flam => Some(config::parse_flam(flam)?),
+    };
+    cfg.timbre = Timbre::parse(fields[6])?;
+    cfg.swing = match fields[7] {
+        "-" => Swing::default(),
+        swing => Swing::parse(swing)?,
+    };
+
+    Ok(())
+}
+
+// Brings the fields of a preset in any version of the format up to
+// the current one. Returns None if the fields don't fit their version,
+// and an error if the preset is from a newer version of the metronome
+// than this one.
+fn migrate(mut fields: Vec<&str>) -> Result<Option<Vec<&str>>> {
+    let mut version: u32 = match fields[0].parse() {
+        Ok(version) => version,
+        Err(_) => return Ok(None),
+    };
+    if version > PRESET_VERSION {
+        bail!(format!(
+            "Preset is from a newer version of the metronome (format {}; this one reads up to {})",
+            version, PRESET_VERSION
+        ));
+    }
+
+    let lengths = [0, 6, 7, 8];
+    if version == 0 || fields.len() != lengths[version as usize] {
+        return Ok(None);
+    }
+    while version < PRESET_VERSION {
+        match version {
+            // Version 1 presets played with the default timbre.
+            1 => fields.push(Timbre::Sine.name()),
+            // Version 2 presets played straight.
+            2 => fields.push("-"),
+            _ => unreachable!(),
+        }
+        version += 1;
+    }
+
+    Ok(Some(fields))
+}
+
+// Prints the settings in a configuration in human-readable form.
+pub fn describe(cfg: &Config) {
+    println!("Tempo:  {}", locale::format(cfg.tempo));
+    println!("Rhythm: {}", cfg.rhythm.reduce().to_rhythmspec());
+    if let Some(meter) = &cfg.meter {
+        if !meter.groups.is_empty() {
+            let groups: Vec<String> = meter.groups.iter().map(|g| g.to_string()).collect();
+            println!("Accent grouping: {}", groups.join("+"));
+        }
+    }
+    println!("Volume: {}", locale::format(cfg.volume));
+    if let Some(flam) = cfg.sound.flam {
+        println!("Flam:   {} ms", flam.as_millis());
+    }
+    println!("Timbre: {}", cfg.timbre.name());
+    if cfg.swing != Swing::default() {
+        println!("Swing:  {}%", locale::format(cfg.swing.0));
+    }
+}
+
+// Encodes bytes as unpadded base64.
+fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+
+    out
+}
+
+// Decodes unpadded base64, returning None if the string isn't valid.
+fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .bytes()
+        .map(|c| ALPHABET.iter().position(|&a| a == c).map(|d| d as u32))
+        .collect::<Option<Vec<u32>>>()?;
+
+    let mut out = vec![];
+    for chunk in digits.chunks(4) {
+        if chunk.len() < 2 {
+            return None;
+        }
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &d)| n | d << (18 - 6 * i));
+        for i in 0..chunk.len() - 1 {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_spec::Meter;
+    use std::time::Duration;
+
+    #[test]
+    fn base64_test() {
+        for text in ["", "a", "ab", "abc", "abcd", "1;120;0.5;0111!;4/1/;-"] {
+            let encoded = to_base64(text.as_bytes());
+            assert_eq!(from_base64(&encoded).unwrap(), text.as_bytes());
+        }
+        assert_eq!(to_base64(b"Man"), "TWFu");
+        assert_eq!(to_base64(b"Ma"), "TWE");
+        assert_eq!(from_base64("T"), None);
+        assert_eq!(from_base64("TW=="), None);
+    }
+
+    #[test]
+    fn preset_test() {
+        let mut cfg = Config {
+            tempo: 96.5,
+            volume: 0.8,
+            rhythm: BeatSpec::from_groups(&[2, 2, 3], 2),
+            meter: Some(Meter {
+                beats: 7,
+                subdiv: 2,
+                groups: vec![2, 2, 3],
+            }),
+            ..Config::default()
+        };
+        cfg.sound.flam = Some(Duration::from_millis(25));
+        cfg.swing = Swing(62.5);
+
+        let mut decoded = Config::default();
+        decode(&encode(&cfg), &mut decoded).unwrap();
+        assert_eq!(decoded.tempo, cfg.tempo);
+        assert_eq!(decoded.volume, cfg.volume);
+        assert_eq!(decoded.rhythm, cfg.rhythm);
+        assert_eq!(decoded.meter, cfg.meter);
+        assert_eq!(decoded.sound.flam, cfg.sound.flam);
+        assert_eq!(decoded.swing, cfg.swing);
+
+        assert!(decode("not a preset!", &mut decoded).is_err());
+        assert!(decode(&to_base64(b"9;1;2"), &mut decoded).is_err());
+
+        // Settings the metronome can't play are errors, as they would be
+        // on the command line.
+        for bad in [
+            "2;0;0.5;0111!;-;-;sine",
+            "2;96;NaN;0111!;-;-;sine",
+            "2;96;3;0111!;-;-;sine",
+            "2;96;0.5;0111!;4/0/;-;sine",
+            "2;96;0.5;0111!;4/1/3+2;-;sine",
+            "2;96;0.5;0111!;-;5000;sine",
+            "3;96;0.5;0111!;-;-;sine;40",
+        ] {
+            assert!(decode(&to_base64(bad.as_bytes()), &mut decoded).is_err());
+        }
+        decode(&to_base64(b"2;96;0.5;0111!;4/1/2+2;50;sine"), &mut decoded).unwrap();
+    }
+
+    #[test]
+    fn migrate_test() {
+        // Version 1 presets are upgraded, with the default timbre,
+        // and version 2 ones play straight.
+        let mut cfg = Config {
+            timbre: Timbre::HiHat,
+            swing: Swing(60.0),
+            ..Config::default()
+        };
+        decode(&to_base64(b"1;96;0.5;0111!;-;-"), &mut cfg).unwrap();
+        assert_eq!(cfg.tempo, 96.0);
+        assert_eq!(cfg.timbre, Timbre::Sine);
+        assert_eq!(cfg.swing, Swing::default());
+        cfg.swing = Swing(60.0);
+        decode(&to_base64(b"2;96;0.5;0111!;-;-;square"), &mut cfg).unwrap();
+        assert_eq!(cfg.timbre, Timbre::Square);
+        assert_eq!(cfg.swing, Swing::default());
+
+        // Fields have to fit their version.
+        assert!(decode(&to_base64(b"1;96;0.5;0111!;-;-;sine"), &mut cfg).is_err());
+        assert!(decode(&to_base64(b"0;96"), &mut cfg).is_err());
+
+        // Presets from the future get a clear error.
+        let err = decode(&to_base64(b"4;96;0.5;0111!;-;-;sine;-;x"), &mut cfg).unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+    }
+}
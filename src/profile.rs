@@ -0,0 +1,90 @@
+// Named profiles: sets of default command-line options, such as one
+// per instrument, kept in files under the user's config directory.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use crate::errors::*;
+use error_chain::bail;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Directory the metronome's configuration is kept in, under
+// $XDG_CONFIG_HOME or ~/.config.
+pub fn config_dir() -> Result<PathBuf> {
+    let dir = match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(config), _) => PathBuf::from(config),
+        (None, Some(home)) => PathBuf::from(home).join(".config"),
+        (None, None) => bail!("Can't find a configuration directory"),
+    };
+    Ok(dir.join(constants::NAME))
+}
+
+// Directory profiles are kept in.
+fn profile_dir() -> Result<PathBuf> {
+    Ok(config_dir()?.join("profiles"))
+}
+
+// Loads the profile with the given name, returning the command-line
+// arguments it contains.
+pub fn load(name: &str) -> Result<Vec<String>> {
+    if name.is_empty() || name.contains('/') {
+        bail!(format!("Invalid profile name {}", name));
+    }
+
+    let path = profile_dir()?.join(name);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => bail!(format!(
+            "Couldn't read profile {} from {}: {}",
+            name,
+            path.display(),
+            e
+        )),
+    };
+    Ok(parse(&text))
+}
+
+// Splits the text of a profile into command-line arguments. Arguments
+// are separated by whitespace, and may be spread over any number of
+// lines; everything after a '#' on a line is a comment.
+fn parse(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| match line.find('#') {
+            Some(n) => &line[..n],
+            None => line,
+        })
+        .flat_map(|line| line.split_whitespace())
+        .map(|arg| arg.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let text = "# Drum kit\n160:4:4 --timbre woodblock  # loud\n\n--split-channels\n";
+        assert_eq!(
+            parse(text),
+            vec!["160:4:4", "--timbre", "woodblock", "--split-channels"]
+        );
+        assert!(parse("# nothing\n").is_empty());
+    }
+}
@@ -0,0 +1,41 @@
+// Real-time scheduling for the threads the click's timing depends on,
+// so a busy machine can't hold them up.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+
+// Moves the calling thread into the real-time FIFO scheduling class,
+// at the given priority. This typically fails for lack of permission
+// (an rtprio limit, or CAP_SYS_NICE on Linux), in which case the
+// thread carries on as it was.
+pub fn promote(priority: i32) -> io::Result<()> {
+    // SAFETY: sched_param is plain old data, for which all zeroes is
+    // valid; some platforms have fields besides the priority.
+    let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+    param.sched_priority = priority;
+
+    // SAFETY: pthread_self is always a valid handle to this thread,
+    // and param outlives the call.
+    let err =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(err))
+    }
+}
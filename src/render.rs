@@ -0,0 +1,185 @@
+// Offline rendering of the metronome's clicks to a WAV file, for
+// dropping a click track into a recording project.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::Event;
+use crate::config::Config;
+use crate::constants;
+use crate::errors::*;
+use crate::met_model::get_delay;
+use crate::sound::Mixdown;
+use crate::wav;
+use error_chain::bail;
+use std::time::Duration;
+
+// Options for the render and export subcommands, on top of the usual
+// ones.
+#[derive(Debug, PartialEq)]
+pub struct RenderOptions {
+    // File to write the click track to.
+    pub out: String,
+
+    // Number of measures to render, not counting the count-in.
+    pub measures: u32,
+}
+
+// Splits the given subcommand's own options out of the arguments
+// following it, leaving the rest to configure the metronome as usual.
+pub fn parse_args<'a>(command: &str, args: &[&'a str]) -> Result<(RenderOptions, Vec<&'a str>)> {
+    let usage = format!(
+        "Usage: {} --out <file> [--measures <count>] [<options> ...] [<tempo>[:<beats>[:<subdiv>]]]",
+        command
+    );
+    let mut out = None;
+    let mut measures = constants::DEF_RENDER_MEASURES;
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        if name != "--out" && name != "--measures" {
+            rest.push(arg);
+            continue;
+        }
+
+        let value = match value.or_else(|| args.next().copied()) {
+            Some(value) => value,
+            None => bail!(usage),
+        };
+        if name == "--out" {
+            out = Some(value.to_string());
+        } else {
+            measures = match value.parse() {
+                Ok(n) if n > 0 => n,
+                _ => bail!("Number of measures must be a positive integer"),
+            };
+        }
+    }
+
+    match out {
+        Some(out) => Ok((RenderOptions { out, measures }, rest)),
+        None => bail!(usage),
+    }
+}
+
+// Renders the configured rhythm, at the configured tempo and with the
+// configured sound, to a WAV file, without opening an audio device.
+pub fn render(cfg: &Config, options: &RenderOptions) -> Result<()> {
+    let ticks = cfg.rhythm.get_ticks();
+    let beat_len = cfg.rhythm.get_beat_len() as usize;
+    let tick_len = get_delay(&cfg.rhythm, cfg.tempo);
+    let mut mixdown = Mixdown::new(cfg.sound.clone(), constants::RENDER_SAMPLE_RATE);
+    let emphasis = &cfg.sound.emphasis;
+    emphasis.follow(&cfg.rhythm);
+    let mut at = Duration::new(0, 0);
+
+    // The count-in is a plain click every beat, as when playing live.
+    for beat in 0..cfg.count_in as usize {
+        let len = tick_len * beat_len as u32;
+        mixdown.add_event(at, &Event::Beep(1), Some(beat), cfg.timbre, cfg.volume, len);
+        at += len;
+    }
+
+    for measure in 0..options.measures as usize {
+        for (n, tick) in ticks.iter().enumerate() {
+            // A tempo map changes the tempo as its beats come up.
+            let tempo = match &cfg.tempo_map {
+                Some(map) => {
+                    let beats = (measure * ticks.len() + n) as f64 / beat_len as f64;
+                    map.tempo_at(beats).unwrap_or(cfg.tempo)
+                }
+                None => cfg.tempo,
+            };
+            let tick_len = get_delay(&cfg.rhythm, tempo);
+            mixdown.set_tempo(tempo);
+            let beat = if n.is_multiple_of(beat_len) {
+                Some(n / beat_len)
+            } else {
+                None
+            };
+            if !cfg.accent_only || cfg.accent_level.keeps(emphasis.of(tick)) {
+                let late = cfg.swing.delay(n, beat_len as u32);
+                let room = 1.0 + cfg.swing.delay(n + 1, beat_len as u32) - late;
+                mixdown.add_event(
+                    at + tick_len.mul_f64(late),
+                    tick,
+                    beat,
+                    cfg.timbre,
+                    cfg.volume,
+                    tick_len.mul_f64(room),
+                );
+            }
+            at += tick_len;
+        }
+    }
+
+    wav::write(
+        &options.out,
+        constants::RENDER_SAMPLE_RATE,
+        constants::RENDER_CHANNELS as u16,
+        &mixdown.finish(at),
+    )?;
+    println!(
+        "Rendered {} measures ({:.1} seconds) to {}",
+        options.measures,
+        at.as_secs_f64(),
+        options.out
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_args_test() {
+        let (options, rest) = parse_args(
+            "render",
+            &[
+                "metronome",
+                "--out",
+                "click.wav",
+                "-t",
+                "hihat",
+                "--measures=4",
+                "90",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            RenderOptions {
+                out: "click.wav".to_string(),
+                measures: 4,
+            }
+        );
+        assert_eq!(rest, vec!["metronome", "-t", "hihat", "90"]);
+
+        let (options, _) = parse_args("render", &["metronome", "--out=click.wav"]).unwrap();
+        assert_eq!(options.measures, constants::DEF_RENDER_MEASURES);
+
+        assert!(parse_args("render", &["metronome", "120"]).is_err());
+        assert!(parse_args("render", &["metronome", "--out"]).is_err());
+        let zero = ["metronome", "--out", "x.wav", "--measures", "0"];
+        assert!(parse_args("render", &zero).is_err());
+    }
+}
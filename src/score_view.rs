@@ -0,0 +1,148 @@
+// View showing the whole measure as a row of fixed glyphs, with the
+// current tick highlighted.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Event};
+use crate::model_event::{ModelEvent, Subscriber};
+use crate::termios_handler::terminal_size;
+use colorful::Color;
+use colorful::Colorful;
+use std::io::{stdout, Write};
+
+pub struct ScoreView {
+    // The events in the measure, in the rhythm's simplest form.
+    events: Vec<Event>,
+
+    // Number of ticks per beat, in the simplest form.
+    beat_len: usize,
+
+    // Number of the rhythm's ticks per event in `events`; a rhythm
+    // given with extra rests padding out its beats is shown without
+    // them.
+    ticks_per_event: usize,
+
+    // Index in `events` of the current tick.
+    current: usize,
+}
+
+impl ScoreView {
+    // Creates a ScoreView for the given rhythm.
+    pub fn new(rhythm: &BeatSpec) -> Self {
+        let mut view = Self {
+            events: vec![],
+            beat_len: 1,
+            ticks_per_event: 1,
+            current: 0,
+        };
+        view.set_rhythm(rhythm);
+        view
+    }
+
+    fn set_rhythm(&mut self, rhythm: &BeatSpec) {
+        let reduced = rhythm.reduce();
+        self.events = reduced.get_ticks().to_vec();
+        self.beat_len = reduced.get_beat_len() as usize;
+        self.ticks_per_event = rhythm.get_ticks().len() / self.events.len();
+        self.current = 0;
+    }
+
+    // The score as plain glyphs, one per event, with a space between
+    // beats, along with the index in the string of each event's
+    // glyph.
+    fn glyphs(&self) -> (Vec<char>, Vec<usize>) {
+        let mut glyphs = vec![];
+        let mut positions = vec![];
+        for (n, event) in self.events.iter().enumerate() {
+            if n > 0 && n.is_multiple_of(self.beat_len) {
+                glyphs.push(' ');
+            }
+            positions.push(glyphs.len());
+            glyphs.push(glyph(event));
+        }
+
+        (glyphs, positions)
+    }
+
+    // Draws the ScoreView on the current line.
+    pub fn draw(&self) {
+        let (glyphs, positions) = self.glyphs();
+        let (cols, _) = terminal_size();
+        let current = positions[self.current];
+
+        print!("\r\x1B[K");
+        for (n, &g) in glyphs.iter().enumerate().take(cols - 1) {
+            if n == current {
+                print!(
+                    "{}",
+                    g.to_string().color(Color::Black).bg_color(Color::Green)
+                );
+            } else {
+                print!("{}", g.to_string().color(Color::LightBlue));
+            }
+        }
+
+        stdout().flush().unwrap();
+    }
+}
+
+impl Subscriber for ScoreView {
+    fn notify(&mut self, event: &ModelEvent) {
+        match event {
+            ModelEvent::Tick { tick, .. } => self.current = tick / self.ticks_per_event,
+            ModelEvent::RhythmChanged { rhythm, .. } => self.set_rhythm(rhythm),
+            _ => {}
+        }
+    }
+}
+
+// Glyph for a single event: heavier for stronger accents, and a
+// period for rests, as in rhythm specs.
+fn glyph(event: &Event) -> char {
+    match event {
+        Event::Rest => '.',
+        Event::Beep(0) => 'X',
+        Event::Beep(1) => 'x',
+        Event::Beep(2) => 'o',
+        Event::Beep(_) => '+',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyphs_test() {
+        let rhythm = BeatSpec::from_rhythmspec("0.3.1.3.!2.3.1.3...3.1.3.").unwrap();
+        let mut view = ScoreView::new(&rhythm);
+        let (glyphs, positions) = view.glyphs();
+        assert_eq!(glyphs.iter().collect::<String>(), "X+x+ o+x+ .+x+");
+        assert_eq!(positions[4], 5);
+
+        // Padding ticks map back onto the event they follow.
+        let ticks = view.ticks_per_event * view.events.len();
+        view.notify(&ModelEvent::Tick {
+            tick: view.ticks_per_event * 5 + 1,
+            ticks,
+            beat_len: 4,
+            event: Event::Rest,
+            hand: None,
+        });
+        assert_eq!(view.current, 5);
+    }
+}
@@ -0,0 +1,92 @@
+// Frontend that turns termination signals into requests to quit, so
+// that the terminal is restored when the metronome is killed.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::app_state::{Input, InputEvent, InputSender, InputSource};
+use crate::errors::*;
+use crate::met_controller::ControllerMsg;
+use error_chain::bail;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+
+// Write end of the pipe the signal handler reports signals through.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// The signals that ask the metronome to quit. SIGHUP only does so if
+// the metronome quits when its terminal goes away.
+const QUIT_SIGNALS: &[libc::c_int] = &[libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn handle_signal(sig: libc::c_int) {
+    // Only async-signal-safe calls are allowed here, so just pass the
+    // signal number on to the thread reading the pipe.
+    let byte = sig as u8;
+    // SAFETY: write() is async-signal-safe, and the buffer is valid
+    // for the duration of the call.
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
+
+// Installs handlers for the termination signals, and starts a thread
+// that sends a Quit message to the given queue when one arrives. If
+// `hangup` is false, SIGHUP is ignored instead, as with nohup.
+pub fn init_signal_thread(send: InputSender, hangup: bool) -> Result<()> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe() writes two file descriptors to the given array.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        bail!("Couldn't create signal pipe");
+    }
+    SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+
+    for &sig in QUIT_SIGNALS {
+        // SAFETY: handle_signal only makes async-signal-safe calls.
+        let handler = if sig == libc::SIGHUP && !hangup {
+            libc::SIG_IGN
+        } else {
+            handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t
+        };
+        if unsafe { libc::signal(sig, handler) } == libc::SIG_ERR {
+            bail!(format!("Couldn't install handler for signal {}", sig));
+        }
+    }
+
+    // SAFETY: fds[0] is a freshly opened pipe that nothing else owns.
+    let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+    thread::spawn(move || {
+        let mut buf = [0];
+        while pipe.read_exact(&mut buf).is_ok() {
+            log::info!("Received signal {}", buf[0]);
+            let input = Input {
+                source: InputSource::Signal,
+                event: InputEvent::Message(ControllerMsg::Quit),
+            };
+            if send.send(input).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+// Export of the metronome's rhythm as a standard MIDI file, for
+// dropping the click into a sequencer.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::Event;
+use crate::config::Config;
+use crate::constants;
+use crate::errors::*;
+use crate::midi::{note_off, note_on, velocity};
+use crate::render::RenderOptions;
+use error_chain::bail;
+use std::fs;
+
+// Writes the configured rhythm, at the configured tempo, to a Type-0
+// standard MIDI file, with a note for every click.
+pub fn export(cfg: &Config, options: &RenderOptions) -> Result<()> {
+    let bytes = encode(cfg, options.measures);
+    if let Err(e) = fs::write(&options.out, bytes) {
+        bail!(format!("Can't write {}: {}", options.out, e));
+    }
+    println!(
+        "Exported {} measures at {} BPM to {}",
+        options.measures, cfg.tempo, options.out
+    );
+    Ok(())
+}
+
+// Encodes the given number of measures of the configured rhythm,
+// after the count-in, as a Type-0 standard MIDI file.
+fn encode(cfg: &Config, measures: u32) -> Vec<u8> {
+    let ticks = cfg.rhythm.get_ticks();
+    let beat_len = cfg.rhythm.get_beat_len() as u64;
+    let division = constants::SMF_DIVISION as u64;
+
+    // Every event, with its time in MIDI ticks from the start.
+    let mut events: Vec<(u64, Vec<u8>)> = vec![];
+    events.push((0, meta(0x03, b"Metronome")));
+    events.push((0, meta(0x51, &tempo_bytes(cfg.tempo))));
+    if let Some(sig) = time_signature(ticks.len() as u64, beat_len) {
+        events.push((0, meta(0x58, &sig)));
+    }
+
+    // The tempo map's changes come in at their beats, after the
+    // count-in; they're sorted in among the clicks below.
+    let count_in = cfg.count_in as u64;
+    let measure_len = ticks.len() as u64 * division;
+    let end = (count_in * division * beat_len + measures as u64 * measure_len) / beat_len;
+    if let Some(map) = &cfg.tempo_map {
+        for &(beat, tempo) in map.points() {
+            let at = count_in * division + (beat * division as f64).round() as u64;
+            if at < end {
+                events.push((at, meta(0x51, &tempo_bytes(tempo))));
+            }
+        }
+    }
+
+    let channel = cfg.midi.channel;
+    let velocity = velocity(cfg.volume);
+    let mut sounding: Option<u8> = None;
+    let mut click = |at: u64, emph: u32, events: &mut Vec<(u64, Vec<u8>)>| {
+        if let Some(note) = sounding.take() {
+            events.push((at, note_off(channel, note).to_vec()));
+        }
+        let note = cfg.midi.note(emph);
+        events.push((at, note_on(channel, note, velocity).to_vec()));
+        sounding = Some(note);
+    };
+
+    // The count-in is a plain click every beat, as when playing live.
+    for beat in 0..count_in {
+        click(beat * division, 1, &mut events);
+    }
+
+    // Tick times are worked out from the start of each measure, so
+    // rounding them to MIDI ticks doesn't drift.
+    cfg.sound.emphasis.follow(&cfg.rhythm);
+    for measure in 0..measures as u64 {
+        let start = count_in * division * beat_len + measure * measure_len;
+        for (n, tick) in ticks.iter().enumerate() {
+            if cfg.accent_only && !cfg.accent_level.keeps(cfg.sound.emphasis.of(tick)) {
+                continue;
+            }
+            if let Event::Beep(emph) = *tick {
+                let late = (cfg.swing.delay(n, beat_len as u32) * division as f64) as u64;
+                let at = (start + n as u64 * division + late + beat_len / 2) / beat_len;
+                click(at, emph, &mut events);
+            }
+        }
+    }
+
+    if let Some(note) = sounding {
+        events.push((end, note_off(channel, note).to_vec()));
+    }
+    events.push((end, meta(0x2F, &[])));
+    // Stable, so events at the same time stay in the order pushed.
+    events.sort_by_key(|(at, _)| *at);
+
+    let mut track = vec![];
+    let mut last = 0;
+    for (at, event) in events {
+        track.extend(var_len(at - last));
+        track.extend(event);
+        last = at;
+    }
+
+    let mut bytes = b"MThd".to_vec();
+    bytes.extend(&6u32.to_be_bytes());
+    bytes.extend(&0u16.to_be_bytes());
+    bytes.extend(&1u16.to_be_bytes());
+    bytes.extend(&constants::SMF_DIVISION.to_be_bytes());
+    bytes.extend(b"MTrk");
+    bytes.extend(&(track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    bytes
+}
+
+// A meta event of the given type and contents.
+fn meta(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0xFF, kind];
+    bytes.extend(var_len(data.len() as u64));
+    bytes.extend(data);
+    bytes
+}
+
+// The contents of a tempo meta event: microseconds per quarter note,
+// taking each beat to be a quarter note.
+fn tempo_bytes(tempo: f64) -> [u8; 3] {
+    let micros = (60_000_000.0 / tempo).round().min(0xFF_FFFF as f64) as u32;
+    let bytes = micros.to_be_bytes();
+    [bytes[1], bytes[2], bytes[3]]
+}
+
+// The contents of a time signature meta event for a measure of the
+// given number of ticks, each 1/beat_len of a quarter note long; the
+// measure is written in the longest note value that gives it a whole
+// number of beats, or None if no note value down to a 32nd does.
+fn time_signature(ticks: u64, beat_len: u64) -> Option<[u8; 4]> {
+    (2..=5).find_map(|denom: u32| {
+        // Number of notes of value 1/2^denom in the measure.
+        let notes = ticks << denom;
+        if !notes.is_multiple_of(beat_len * 4) || notes / (beat_len * 4) > 0xFF {
+            return None;
+        }
+        let clocks = 96 >> denom;
+        Some([(notes / (beat_len * 4)) as u8, denom as u8, clocks, 8])
+    })
+}
+
+// A number in the variable-length format used for delta times and
+// meta event lengths: seven bits per byte, most significant first,
+// with the top bit set on every byte but the last.
+fn var_len(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![(n & 0x7F) as u8];
+    n >>= 7;
+    while n > 0 {
+        bytes.push((n & 0x7F) as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_spec::BeatSpec;
+
+    #[test]
+    fn var_len_test() {
+        assert_eq!(var_len(0), vec![0x00]);
+        assert_eq!(var_len(0x7F), vec![0x7F]);
+        assert_eq!(var_len(0x80), vec![0x81, 0x00]);
+        assert_eq!(var_len(0x0FFF_FFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn time_signature_test() {
+        // 4/4, and 7/8 when the measure is 3.5 beats long.
+        assert_eq!(time_signature(8, 2), Some([4, 2, 24, 8]));
+        assert_eq!(time_signature(7, 2), Some([7, 3, 12, 8]));
+        assert_eq!(time_signature(5, 3), None);
+    }
+
+    #[test]
+    fn encode_test() {
+        let cfg = Config {
+            rhythm: BeatSpec::from_subdiv(2, 1),
+            tempo: 120.0,
+            ..Config::default()
+        };
+        let bytes = encode(&cfg, 1);
+        assert_eq!(&bytes[..8], b"MThd\0\0\0\x06");
+        assert_eq!(&bytes[8..14], &[0, 0, 0, 1, 0x01, 0xE0]);
+        assert_eq!(&bytes[14..18], b"MTrk");
+
+        let track = &bytes[22..];
+        let len = u32::from_be_bytes([bytes[18], bytes[19], bytes[20], bytes[21]]);
+        assert_eq!(track.len(), len as usize);
+
+        // 500,000 microseconds per beat at 120 BPM.
+        let tempo = [0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20];
+        assert!(track.windows(tempo.len()).any(|w| w == tempo));
+
+        // The downbeat and then the second beat, a beat later.
+        let channel = cfg.midi.channel - 1;
+        let downbeat = [0x00, 0x90 | channel, cfg.midi.note(0)];
+        assert!(track.windows(downbeat.len()).any(|w| w == downbeat));
+        let beat = [0x83, 0x60, 0x80 | channel, cfg.midi.note(0), 0x00, 0x00];
+        assert!(track.windows(beat.len()).any(|w| w == beat));
+        assert!(track.ends_with(&[0xFF, 0x2F, 0x00]));
+    }
+}
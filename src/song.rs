@@ -0,0 +1,118 @@
+// Song mode: a sequence of sections in different time signatures,
+// each played for a number of measures.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::BeatSpec;
+use crate::errors::*;
+use error_chain::bail;
+
+// A section of a song: a rhythm played for a number of measures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    // What the section is called in the display; its label if it
+    // has one, or its time signature.
+    pub name: String,
+
+    pub rhythm: BeatSpec,
+    pub measures: u32,
+}
+
+// The sections of a song, which loops back to the first after the
+// last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Song {
+    sections: Vec<Section>,
+}
+
+impl Song {
+    // Parses a list of sections separated by commas, each a time
+    // signature followed by an optional "x<n>" or "*<n>" repeat
+    // count, and optionally labeled with "<label>=", e.g.
+    // "A=7/8x2,B=4/4x4".
+    pub fn parse(spec: &str) -> Result<Song> {
+        let mut sections = vec![];
+        for section in spec.split(',') {
+            let (label, rest) = match section.split_once('=') {
+                Some((label, rest)) => (Some(label.trim()), rest),
+                None => (None, section),
+            };
+            let (sig, measures) = match rest.rsplit_once(['x', '*']) {
+                Some((sig, count)) => match count.trim().parse() {
+                    Ok(n) if n >= 1 => (sig.trim(), n),
+                    _ => bail!(format!(
+                        "Expected a positive repeat count in song section {}",
+                        section.trim()
+                    )),
+                },
+                None => (rest.trim(), 1),
+            };
+
+            sections.push(Section {
+                name: label.unwrap_or(sig).to_string(),
+                rhythm: BeatSpec::from_signature(sig)?,
+                measures,
+            });
+        }
+
+        Ok(Song { sections })
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    // The section playing in the given measure of the song, counting
+    // from 0 and looping around, and the measure within it.
+    pub fn locate(&self, measure: usize) -> (usize, u32) {
+        let total: usize = self.sections.iter().map(|s| s.measures as usize).sum();
+        let mut measure = (measure % total) as u32;
+        for (n, section) in self.sections.iter().enumerate() {
+            if measure < section.measures {
+                return (n, measure);
+            }
+            measure -= section.measures;
+        }
+
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_test() {
+        let song = Song::parse("A=7/8x2, 4/4 *3,5/4").unwrap();
+        let names: Vec<&str> = song.sections().iter().map(|s| &s.name[..]).collect();
+        assert_eq!(names, ["A", "4/4", "5/4"]);
+        assert_eq!(song.sections()[0].rhythm.get_ticks().len(), 7);
+        assert_eq!(song.sections()[1].measures, 3);
+
+        assert_eq!(song.locate(0), (0, 0));
+        assert_eq!(song.locate(1), (0, 1));
+        assert_eq!(song.locate(4), (1, 2));
+        assert_eq!(song.locate(5), (2, 0));
+        assert_eq!(song.locate(6), (0, 0));
+
+        assert!(Song::parse("7/8x0").is_err());
+        assert!(Song::parse("7/8,").is_err());
+        assert!(Song::parse("7/8xx2").is_err());
+        assert!(Song::parse("A=").is_err());
+    }
+}
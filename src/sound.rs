@@ -16,13 +16,27 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::audio_clock::AudioClock;
+use crate::beat_spec::{Emphasis, EmphasisPolicy, Event};
+use crate::constants;
 use crate::errors::*;
-use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, StreamConfig};
+use crate::profile;
+use crate::realtime;
+use crate::sticking::Hand;
+use crate::wav::Sample;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, BuildStreamError, Device, StreamConfig, StreamError, SupportedBufferSize};
+use error_chain::bail;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
 use std::ops::Deref;
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 // Since AudioConfigInternal is not Clone (because Device is not
 // Clone), we use reference counting to ensure its data can be passed
@@ -30,16 +44,23 @@ use std::time::Duration;
 #[derive(Clone)]
 pub struct AudioConfig {
     // Note that this struct implements Deref, so you can write
-    // "audio_config.device" rather than needing to spell out the
-    // literal path "(*audio_config.cfg).device".
+    // "audio_config.options" rather than needing to spell out the
+    // literal path "(*audio_config.cfg).options".
     cfg: Arc<AudioConfigInternal>,
 }
 
 impl AudioConfig {
-    pub fn new() -> Result<Self> {
-        Ok(AudioConfig {
-            cfg: Arc::new(AudioConfigInternal::new()?),
-        })
+    pub fn new(options: SoundOptions) -> Result<Self> {
+        let cfg = AudioConfig {
+            cfg: Arc::new(AudioConfigInternal::new(options)?),
+        };
+        run_output(&cfg);
+        Ok(cfg)
+    }
+
+    // The clock of the device the clicks are played on.
+    pub fn clock(&self) -> Arc<AudioClock> {
+        self.mixer.lock().unwrap().clock.clone()
     }
 }
 
@@ -55,14 +76,315 @@ impl Deref for AudioConfig {
 // prepared at the start of the program, and it should live for the
 // entire duration of the program.
 pub struct AudioConfigInternal {
+    // The device clicks are played on. This is swapped out for a new
+    // one if the device goes away.
+    output: Mutex<Output>,
+
+    // Set when the device has gone away, until it's reopened.
+    lost: AtomicBool,
+
+    // User-selected options for how clicks should sound.
+    pub options: SoundOptions,
+
+    // Mixes the sounds being played into the output stream.
+    mixer: Mutex<Mixer>,
+
+    // Whether the metronome is playing. While it isn't, and there's
+    // nothing left to mix, the stream is suspended to save power.
+    playing: AtomicBool,
+
+    // The thread running the output stream, to wake when there's
+    // something to play.
+    output_thread: OnceLock<thread::Thread>,
+}
+
+// User-adjustable settings for the sound engine, which stay fixed for
+// the duration of the program.
+#[derive(Debug, Clone, Default)]
+pub struct SoundOptions {
+    // If set, downbeats are played as a flam: a quieter grace note
+    // this long ahead of the main click. To leave room for the grace
+    // note, every click is held back by this much.
+    pub flam: Option<Duration>,
+
+    // Whether to play clicks on the beat in the left channel only, and
+    // subdivisions in the right channel only.
+    pub split_channels: bool,
+
+    // Whether to pan the clicks of a sticking drill to the side of the
+    // hand that plays them.
+    pub sticking_pan: bool,
+
+    // Size of the audio buffer to ask the device for, in frames, or
+    // None to leave it up to the device.
+    pub buffer_size: Option<u32>,
+
+    // A sound to play continuously under the clicks, if any, and its
+    // volume, which is separate from the clicks'.
+    pub background: Option<Background>,
+    pub background_volume: f64,
+
+    // Recordings of a voice counting, spoken on the beats in place of
+    // the clicks, if any.
+    pub voice: Option<VoiceSamples>,
+
+    // Recorded clicks played in place of the synthesized ones, if any.
+    pub theme: Option<SoundTheme>,
+
+    // Pitch of the strongest click, in Hz, which the other emphasis
+    // levels are pitched relative to; None for the default.
+    pub pitch: Option<f64>,
+
+    // Which emphasis each crossbeat's clicks are played with.
+    pub emphasis: EmphasisPolicy,
+
+    // Volume of subdivision clicks relative to the rest, from 0.0 to
+    // 1.0, or None for the default.
+    pub subdiv_volume: Option<f64>,
+
+    // How subdivision clicks fade out at fast tempos, if they do.
+    pub ghost: Option<Ghosting>,
+
+    // How primary clicks are pitched.
+    pub accent_tone: AccentTone,
+
+    // How long each click lasts, or None for the default. Clicks are
+    // cut shorter if need be to end before the next one.
+    pub click_length: Option<Duration>,
+
+    // Whether to match the loudness of the timbres and recordings to
+    // the sine click's, so switching sounds doesn't change the volume.
+    pub normalize: bool,
+
+    // Whether to give the audio thread real-time priority.
+    pub realtime: bool,
+
+    // The audio system to play through, or None for cpal's default.
+    pub backend: Option<AudioBackend>,
+}
+
+// Recordings of a voice counting "one", "two", "three" and so on.
+#[derive(Clone)]
+pub struct VoiceSamples {
+    counts: Vec<Arc<Sample>>,
+
+    // Gain bringing each count to the loudness of the default click.
+    gains: Vec<f64>,
+}
+
+impl VoiceSamples {
+    // Loads the counts from a directory holding 1.wav, 2.wav and so
+    // on, up to the first number that's missing.
+    pub fn load(dir: &str) -> Result<VoiceSamples> {
+        let mut counts = vec![];
+        loop {
+            let path = Path::new(dir).join(format!("{}.wav", counts.len() + 1));
+            if !path.exists() {
+                break;
+            }
+            counts.push(Arc::new(Sample::read(&path.to_string_lossy())?));
+        }
+
+        if counts.is_empty() {
+            bail!(format!("No voice samples (1.wav, 2.wav, ...) in {}", dir));
+        }
+        let reference = click_loudness(Timbre::Sine);
+        let gains = counts
+            .iter()
+            .map(|count| loudness_gain(reference, loudness(&count.data)))
+            .collect();
+        Ok(VoiceSamples { counts, gains })
+    }
+
+    // The recording of the count for the given beat, counting from 0,
+    // if there is one, and the gain that matches its loudness to the
+    // default click's.
+    fn count(&self, beat: usize) -> Option<(&Arc<Sample>, f64)> {
+        Some((self.counts.get(beat)?, self.gains[beat]))
+    }
+
+    // The highest count there's a recording of.
+    pub fn highest(&self) -> usize {
+        self.counts.len()
+    }
+}
+
+impl fmt::Debug for VoiceSamples {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VoiceSamples({} counts)", self.counts.len())
+    }
+}
+
+// A set of recorded clicks, one for each emphasis.
+#[derive(Clone)]
+pub struct SoundTheme {
+    accent: Arc<Sample>,
+    beat: Arc<Sample>,
+    subdiv: Arc<Sample>,
+
+    // Gain bringing the accent to the loudness of the default click.
+    // The other clicks get the same gain, keeping the theme's own
+    // balance between them.
+    gain: f64,
+}
+
+impl SoundTheme {
+    // Loads the theme with the given name, from accent.wav, beat.wav
+    // and subdiv.wav in its directory under the configuration
+    // directory's "sounds", or from the given directory if the name
+    // is a path.
+    pub fn load(name: &str) -> Result<SoundTheme> {
+        let dir = if name.contains('/') {
+            PathBuf::from(name)
+        } else {
+            profile::config_dir()?.join("sounds").join(name)
+        };
+        if name.is_empty() || !dir.is_dir() {
+            bail!(format!(
+                "No sound theme {} (looked for {})",
+                name,
+                dir.display()
+            ));
+        }
+
+        let read = |file| Sample::read(&dir.join(file).to_string_lossy()).map(Arc::new);
+        let accent = read("accent.wav")?;
+        let gain = loudness_gain(click_loudness(Timbre::Sine), loudness(&accent.data));
+        Ok(SoundTheme {
+            accent,
+            beat: read("beat.wav")?,
+            subdiv: read("subdiv.wav")?,
+            gain,
+        })
+    }
+
+    // The recording of the click with the given emphasis, and the gain
+    // that matches the theme's loudness to the default click's.
+    fn click(&self, emphasis: Emphasis) -> (&Arc<Sample>, f64) {
+        let sample = match emphasis {
+            Emphasis::Primary => &self.accent,
+            Emphasis::Secondary => &self.beat,
+            Emphasis::Subdivision => &self.subdiv,
+        };
+        (sample, self.gain)
+    }
+}
+
+impl fmt::Debug for SoundTheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SoundTheme")
+    }
+}
+
+// A continuous sound played under the clicks, to practice picking the
+// click out of a dense mix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    // Pink noise, which has equal energy per octave, like a band.
+    Noise,
+
+    // A sine drone at the given pitch, in Hz.
+    Drone(f64),
+}
+
+impl Background {
+    // Parses a background sound as given on the command line: either
+    // "noise", or the pitch of a drone in Hz.
+    pub fn parse(arg: &str) -> Result<Background> {
+        if arg == "noise" {
+            return Ok(Background::Noise);
+        }
+        match arg.parse::<f64>() {
+            Ok(pitch) if pitch > 0.0 => Ok(Background::Drone(pitch)),
+            _ => bail!(format!(
+                "Background must be noise or a drone pitch in Hz, got {}",
+                arg
+            )),
+        }
+    }
+}
+
+// Which of the output's channels a beep plays in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    All,
+    Left,
+    Right,
+}
+
+impl Route {
+    // Whether a beep with this route plays in channel n of an output
+    // with the given number of channels. Mono outputs play everything,
+    // as there's nothing to split.
+    fn plays_on(self, n: usize, channels: usize) -> bool {
+        match self {
+            _ if channels == 1 => true,
+            Route::All => true,
+            Route::Left => n == 0,
+            Route::Right => n == 1,
+        }
+    }
+}
+
+// An audio device, along with the configuration to open streams on it
+// with.
+struct Output {
     device: Device,
     stream_config: StreamConfig,
 }
 
 impl AudioConfigInternal {
-    pub fn new() -> Result<Self> {
-        let host = cpal::default_host();
-        let device = match host.default_output_device() {
+    pub fn new(options: SoundOptions) -> Result<Self> {
+        let output = Output::open(&options)?;
+        let mixer = Mixer::new(&options, output.stream_config.sample_rate.0);
+        Ok(Self {
+            output: Mutex::new(output),
+            lost: AtomicBool::new(false),
+            options,
+            mixer: Mutex::new(mixer),
+            playing: AtomicBool::new(false),
+            output_thread: OnceLock::new(),
+        })
+    }
+
+    // Wakes the output stream's thread, to start the stream again if
+    // it's been suspended.
+    fn wake(&self) {
+        if let Some(thread) = self.output_thread.get() {
+            thread.unpark();
+        }
+    }
+
+    // Marks the device as gone, e.g. because it was unplugged.
+    fn lose_device(&self) {
+        if !self.lost.swap(true, Ordering::Relaxed) {
+            log::warn!("Lost the audio device");
+        }
+    }
+
+    // Tries to open the default device again after the old one went
+    // away. Clicks are silent until this succeeds; everything else
+    // carries on as usual.
+    fn reopen(&self) {
+        match Output::open(&self.options) {
+            Ok(output) => {
+                *self.output.lock().unwrap() = output;
+                self.lost.store(false, Ordering::Relaxed);
+                log::info!("Reopened the audio device");
+            }
+            Err(e) => log::debug!("Couldn't reopen the audio device: {}", e),
+        }
+    }
+}
+
+impl Output {
+    // Opens the output device of the chosen backend, or cpal's default.
+    fn open(options: &SoundOptions) -> Result<Output> {
+        let device = match options.backend {
+            Some(backend) => backend.device(&backend.host()?),
+            None => cpal::default_host().default_output_device(),
+        };
+        let device = match device {
             Some(dev) => dev,
             None => {
                 return Err(ErrorKind::AudioConfig("No audio device found".to_string()).into());
@@ -71,44 +393,2075 @@ impl AudioConfigInternal {
 
         let mut supported_cfg_range = device.supported_output_configs()?;
 
-        let stream_config = match supported_cfg_range.next() {
-            Some(cfg) => cfg.with_max_sample_rate().config(),
+        let mut stream_config = match supported_cfg_range.next() {
+            Some(cfg) => {
+                if let Some(frames) = options.buffer_size {
+                    check_buffer_size(frames, cfg.buffer_size())?;
+                }
+                cfg.with_max_sample_rate().config()
+            }
             None => {
                 return Err(
                     ErrorKind::AudioConfig("No supported configurations".to_string()).into(),
                 );
             }
         };
+        if let Some(frames) = options.buffer_size {
+            stream_config.buffer_size = BufferSize::Fixed(frames);
+        }
 
-        Ok(Self {
+        Ok(Output {
             device,
             stream_config,
         })
     }
 }
 
-// Plays a beep at the given frequency, for the given length of time
-// and at the given volume. The sound is played in another thread, so
-// this function does not block.
-pub fn beep(frequency: f64, length: Duration, cfg: &AudioConfig, vol: f64) {
-    let cfg = cfg.clone();
-    thread::spawn(move || {
-        let omega = frequency * std::f64::consts::TAU / cfg.stream_config.sample_rate.0 as f64;
-        let mut theta: f64 = 0.0;
-        let stream = cfg.device.build_output_stream(
-            &cfg.stream_config,
-            move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
-                for el in data {
-                    *el = (theta.sin() * vol) as f32;
-                    theta += omega;
+// The sounds being mixed into the output stream. Everything the
+// metronome plays goes through a single stream, so overlapping clicks
+// are summed and limited together rather than each fighting for the
+// device in a stream of its own.
+struct Mixer {
+    // Sample rate of the output stream.
+    sample_rate: u32,
+
+    // Clicks waiting for their time to start, with that time.
+    pending: Vec<(Instant, Voice)>,
+
+    // Clicks playing, oldest first.
+    playing: VecDeque<Voice>,
+
+    // Clicks already rendered at the stream's sample rate, with the
+    // source and length they were rendered from, oldest first.
+    clicks: VecDeque<(Source, Duration, Arc<[f32]>)>,
+
+    // The sound played under the clicks, if any, and its gain.
+    background: Option<BackgroundGenerator>,
+    background_gain: f64,
+
+    // Keeps the sum of everything playing within full scale.
+    limiter: Limiter,
+
+    // Counts the frames mixed, as a clock for the metronome to keep
+    // time by.
+    clock: Arc<AudioClock>,
+
+    // Whether the thread mixing for the stream is to be given
+    // real-time priority, and whether it's been tried yet.
+    realtime: bool,
+    promoted: bool,
+}
+
+// A click being mixed.
+struct Voice {
+    // The click's rendered samples, and how many of them have been
+    // mixed.
+    click: Arc<[f32]>,
+    position: usize,
+
+    gain: f64,
+    route: Route,
+
+    // Frames still to wait before the click starts, once it's playing.
+    wait: usize,
+
+    // Length in frames of the fade out when the click is stolen to
+    // make room for another, and the frames of it left once it has
+    // been.
+    release: usize,
+    fade: Option<usize>,
+}
+
+impl Voice {
+    // Whether the click has finished.
+    fn done(&self) -> bool {
+        self.position >= self.click.len() || self.fade == Some(0)
+    }
+
+    // Cuts the click short, fading it out from where it's got to
+    // rather than stopping dead.
+    fn steal(&mut self) {
+        self.fade = Some(self.release);
+    }
+
+    // Mixes as much of the click as fits into a buffer of interleaved
+    // samples with the given number of channels.
+    fn mix(&mut self, data: &mut [f32], channels: usize) {
+        let skip = self.wait.min(data.len() / channels);
+        self.wait -= skip;
+        let frames = data.chunks_mut(channels).skip(skip);
+        for (frame, &sample) in frames.zip(&self.click[self.position..]) {
+            let gain = match &mut self.fade {
+                Some(0) => break,
+                Some(left) => {
+                    *left -= 1;
+                    self.gain * *left as f64 / self.release as f64
+                }
+                None => self.gain,
+            };
+            self.position += 1;
+            let sample = (sample as f64 * gain) as f32;
+            for (n, el) in frame.iter_mut().enumerate() {
+                if self.route.plays_on(n, channels) {
+                    *el += sample;
+                }
+            }
+        }
+    }
+}
+
+impl Mixer {
+    fn new(options: &SoundOptions, sample_rate: u32) -> Mixer {
+        let clock = Arc::new(AudioClock::new());
+        clock.restart(sample_rate);
+        Mixer {
+            sample_rate,
+            pending: vec![],
+            playing: VecDeque::new(),
+            clicks: VecDeque::new(),
+            background: options
+                .background
+                .map(|background| BackgroundGenerator::new(background, sample_rate)),
+            background_gain: perceptual_gain(options.background_volume),
+            limiter: Limiter::new(sample_rate),
+            clock,
+            realtime: options.realtime,
+            promoted: false,
+        }
+    }
+
+    // Starts mixing for a new stream at the given sample rate. The
+    // background starts over, fading in again, and clicks left over
+    // from the old stream are dropped.
+    fn open(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.clock.restart(sample_rate);
+        self.limiter = Limiter::new(sample_rate);
+        self.promoted = false;
+        self.pending.clear();
+        self.playing.clear();
+        self.clicks.clear();
+        self.background = self
+            .background
+            .take()
+            .map(|gen| BackgroundGenerator::new(gen.background, sample_rate));
+    }
+
+    // Starts the clock over after the stream has been suspended, as the
+    // frames it counted no longer line up with the time.
+    fn resume(&mut self) {
+        self.clock.restart(self.sample_rate);
+    }
+
+    // Whether there's nothing to mix, so the stream can be suspended.
+    fn idle(&self) -> bool {
+        self.background.is_none() && self.pending.is_empty() && self.playing.is_empty()
+    }
+
+    // Queues a sound, already rendered at the stream's sample rate, to
+    // start playing at the given time.
+    fn queue(&mut self, sound: Sound, click: Arc<[f32]>, start: Instant) {
+        let voice = Voice {
+            click,
+            position: 0,
+            gain: sound.gain,
+            route: sound.route,
+            wait: 0,
+            release: ((constants::ENVELOPE_RELEASE * self.sample_rate as f64) as usize).max(1),
+            fade: None,
+        };
+        self.pending.push((start + sound.delay, voice));
+    }
+
+    // The rendered samples of a sound, if it's been played before.
+    // Clicks are rendered as they're queued, which keeps the work out
+    // of the audio thread, which only has to mix the samples.
+    fn cached(&self, sound: &Sound) -> Option<Arc<[f32]>> {
+        self.clicks
+            .iter()
+            .find(|(source, length, _)| *source == sound.source && *length == sound.length)
+            .map(|(_, _, click)| click.clone())
+    }
+
+    // Keeps a sound's rendered samples for reuse, dropping the oldest
+    // kept if there are too many.
+    fn keep(&mut self, sound: &Sound, click: Arc<[f32]>) {
+        if self.cached(sound).is_some() {
+            return;
+        }
+        if self.clicks.len() == constants::CLICK_CACHE_SIZE {
+            self.clicks.pop_front();
+        }
+        self.clicks
+            .push_back((sound.source.clone(), sound.length, click));
+    }
+
+    // Moves the clicks due to start within the next `frames` frames
+    // from the queue to the mix, where `now` is the time of the first
+    // frame. Clicks that should have started long ago are dropped, so
+    // a stream that's been held up doesn't play them all at once. If
+    // that makes more than MAX_VOICES playing, the oldest are faded
+    // out early to make room.
+    fn start_due(&mut self, frames: usize, now: Instant) {
+        let rate = self.sample_rate as f64;
+        let end = now + Duration::from_secs_f64(frames as f64 / rate);
+        let stale = now.checked_sub(Duration::from_millis(constants::STALE_CLICK));
+        let mut n = 0;
+        while n < self.pending.len() {
+            if self.pending[n].0 >= end {
+                n += 1;
+                continue;
+            }
+            let (start, mut voice) = self.pending.swap_remove(n);
+            if stale.is_some_and(|stale| start < stale) {
+                log::debug!("Dropping a click {:?} late", now - start);
+                continue;
+            }
+            voice.wait = (start.saturating_duration_since(now).as_secs_f64() * rate) as usize;
+            self.playing.push_back(voice);
+        }
+
+        let mut live = self
+            .playing
+            .iter()
+            .filter(|voice| voice.fade.is_none())
+            .count();
+        for voice in self.playing.iter_mut() {
+            if live <= constants::MAX_VOICES {
+                break;
+            }
+            if voice.fade.is_none() {
+                voice.steal();
+                live -= 1;
+                log::debug!("Stealing the oldest of {} voices", live + 1);
+            }
+        }
+    }
+
+    // Mixes the next buffer of interleaved samples, with the given
+    // number of channels, where `now` is the time of its first frame.
+    fn fill(&mut self, data: &mut [f32], channels: usize, now: Instant) {
+        // The stream's thread is only ours to change from inside it.
+        if self.realtime && !self.promoted {
+            self.promoted = true;
+            match realtime::promote(constants::REALTIME_AUDIO_PRIORITY) {
+                Ok(()) => log::info!("Audio thread running with real-time priority"),
+                Err(e) => log::warn!("Couldn't give the audio thread real-time priority: {}", e),
+            }
+        }
+        self.clock.mixed(data.len() / channels, now);
+        self.start_due(data.len() / channels, now);
+
+        match &mut self.background {
+            Some(gen) => {
+                for frame in data.chunks_mut(channels) {
+                    frame.fill((gen.next_sample() * self.background_gain) as f32);
+                }
+            }
+            None => data.fill(0.0),
+        }
+        for voice in self.playing.iter_mut() {
+            voice.mix(data, channels);
+        }
+        for frame in data.chunks_mut(channels) {
+            self.limiter.limit(frame);
+        }
+
+        self.playing.retain(|voice| !voice.done());
+    }
+}
+
+// Checks that a buffer size, in frames, is within the range the
+// device supports. Some devices don't say what they support, in which
+// case we try it anyway.
+fn check_buffer_size(frames: u32, supported: &SupportedBufferSize) -> Result<()> {
+    match *supported {
+        SupportedBufferSize::Range { min, max } if !(min..=max).contains(&frames) => {
+            Err(ErrorKind::AudioConfig(format!(
+                "Buffer size must be between {} and {} frames on this device, got {}",
+                min, max, frames
+            ))
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// An audio system the clicks can be played through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioBackend {
+    // ALSA, straight to the sound card where possible, rather than
+    // through whatever sound server ALSA's default device leads to.
+    Alsa,
+
+    // PulseAudio, through its ALSA plugin, as cpal has no host of its
+    // own for it.
+    Pulse,
+
+    // A JACK server; only available if cpal was built with JACK
+    // support.
+    Jack,
+}
+
+impl AudioBackend {
+    // Parses a backend's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<AudioBackend> {
+        Ok(match name {
+            "alsa" => AudioBackend::Alsa,
+            "pulse" => AudioBackend::Pulse,
+            "jack" => AudioBackend::Jack,
+            _ => bail!(format!("Unknown audio backend {}", name)),
+        })
+    }
+
+    // Name of the cpal host the backend is reached through.
+    fn host_name(self) -> &'static str {
+        match self {
+            AudioBackend::Alsa | AudioBackend::Pulse => "ALSA",
+            AudioBackend::Jack => "JACK",
+        }
+    }
+
+    // Start of the name of the device on the host to play through, or
+    // None for the host's default.
+    fn device_prefix(self) -> Option<&'static str> {
+        match self {
+            AudioBackend::Alsa => Some("sysdefault"),
+            AudioBackend::Pulse => Some("pulse"),
+            AudioBackend::Jack => None,
+        }
+    }
+
+    // Opens the backend's host.
+    fn host(self) -> Result<cpal::Host> {
+        let id = cpal::available_hosts()
+            .into_iter()
+            .find(|id| id.name() == self.host_name());
+        match id.map(cpal::host_from_id) {
+            Some(Ok(host)) => Ok(host),
+            _ => Err(ErrorKind::AudioConfig(format!(
+                "{} isn't available on this system",
+                self.host_name()
+            ))
+            .into()),
+        }
+    }
+
+    // Finds the backend's output device on its host, falling back to
+    // the host's default if there's no device by the right name.
+    fn device(self, host: &cpal::Host) -> Option<Device> {
+        let named = self.device_prefix().and_then(|prefix| {
+            host.output_devices()
+                .ok()?
+                .find(|dev| dev.name().is_ok_and(|n| n.starts_with(prefix)))
+        });
+        named.or_else(|| host.default_output_device())
+    }
+}
+
+// Where the metronome's clicks are sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClickOutput {
+    // Play clicks through the audio device.
+    Audio,
+
+    // Send clicks as MIDI notes only.
+    Midi,
+
+    // Play clicks through the audio device and send them as MIDI
+    // notes.
+    Both,
+
+    // Don't make any sound; the metronome is visual only.
+    Silent,
+}
+
+impl ClickOutput {
+    // The outputs the user steps through at run time, given whether
+    // there's an audio device and a MIDI device to send clicks to.
+    pub fn cycle(audio: bool, midi: bool) -> Vec<ClickOutput> {
+        [
+            ClickOutput::Audio,
+            ClickOutput::Both,
+            ClickOutput::Midi,
+            ClickOutput::Silent,
+        ]
+        .iter()
+        .copied()
+        .filter(|output| (audio || !output.audio()) && (midi || !output.midi()))
+        .collect()
+    }
+
+    // Whether clicks are played through the audio device.
+    pub fn audio(self) -> bool {
+        matches!(self, ClickOutput::Audio | ClickOutput::Both)
+    }
+
+    // Whether clicks are sent as MIDI notes.
+    pub fn midi(self) -> bool {
+        matches!(self, ClickOutput::Midi | ClickOutput::Both)
+    }
+
+    // The next output in the given cycle.
+    pub fn next(self, cycle: &[ClickOutput]) -> ClickOutput {
+        let n = cycle.iter().position(|&output| output == self);
+        cycle[n.map_or(0, |n| n + 1) % cycle.len()]
+    }
+
+    // The output nearest this one of those that can be used, e.g. for
+    // one restored from a session that had other devices. With only
+    // MIDI to send to, audio clicks are sent there instead.
+    pub fn within(self, audio: bool, midi: bool) -> ClickOutput {
+        match (self.audio() && audio, self.midi() && midi) {
+            (true, true) => ClickOutput::Both,
+            (true, false) => ClickOutput::Audio,
+            (false, true) => ClickOutput::Midi,
+            (false, false) if self == ClickOutput::Audio && midi => ClickOutput::Midi,
+            (false, false) => ClickOutput::Silent,
+        }
+    }
+}
+
+// Which clicks are still played when the metronome is set to play
+// only its accents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccentLevel {
+    // Only the downbeat.
+    Downbeat,
+
+    // The primary and secondary clicks, which are the beats unless
+    // --emphasis says otherwise.
+    Accents,
+}
+
+impl AccentLevel {
+    // Parses an accent level's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<AccentLevel> {
+        Ok(match name {
+            "downbeat" => AccentLevel::Downbeat,
+            "accents" => AccentLevel::Accents,
+            _ => bail!(format!("Unknown accent level {}", name)),
+        })
+    }
+
+    // Whether a click of the given emphasis is still played at this
+    // level. Rests, with no emphasis, make no sound either way.
+    pub fn keeps(self, emphasis: Option<Emphasis>) -> bool {
+        matches!(
+            (self, emphasis),
+            (_, None)
+                | (_, Some(Emphasis::Primary))
+                | (AccentLevel::Accents, Some(Emphasis::Secondary))
+        )
+    }
+}
+
+// The sound a click is made with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timbre {
+    // A plain sine wave.
+    Sine,
+
+    // A square wave, which has a harsher, buzzier tone.
+    Square,
+
+    // A triangle wave, between sine and square.
+    Triangle,
+
+    // High-passed noise with a fast decay, like a closed hi-hat; the
+    // frequency is ignored.
+    HiHat,
+
+    // A sine wave with a fast decay, like a woodblock.
+    Woodblock,
+}
+
+impl Timbre {
+    // The next timbre in the cycle the user steps through at run
+    // time.
+    pub fn next(self) -> Timbre {
+        match self {
+            Timbre::Sine => Timbre::Square,
+            Timbre::Square => Timbre::Triangle,
+            Timbre::Triangle => Timbre::HiHat,
+            Timbre::HiHat => Timbre::Woodblock,
+            Timbre::Woodblock => Timbre::Sine,
+        }
+    }
+
+    // Parses a timbre's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<Timbre> {
+        Ok(match name {
+            "sine" => Timbre::Sine,
+            "square" => Timbre::Square,
+            "triangle" => Timbre::Triangle,
+            "hihat" => Timbre::HiHat,
+            "woodblock" => Timbre::Woodblock,
+            _ => bail!(format!("Unknown timbre {}", name)),
+        })
+    }
+
+    // The timbre's name, as given on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Timbre::Sine => "sine",
+            Timbre::Square => "square",
+            Timbre::Triangle => "triangle",
+            Timbre::HiHat => "hihat",
+            Timbre::Woodblock => "woodblock",
+        }
+    }
+}
+
+// How primary clicks (the downbeats, by default) are pitched, to make
+// them easier to pick out over a loud band.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccentTone {
+    // A single note, like the other clicks.
+    #[default]
+    Plain,
+
+    // Two notes at once, a fifth apart.
+    Chord,
+
+    // A quick glide down an octave, onto the usual pitch.
+    Sweep,
+}
+
+impl AccentTone {
+    // Parses an accent tone's name, as given on the command line.
+    pub fn parse(name: &str) -> Result<AccentTone> {
+        Ok(match name {
+            "plain" => AccentTone::Plain,
+            "chord" => AccentTone::Chord,
+            "sweep" => AccentTone::Sweep,
+            _ => bail!(format!("Unknown accent tone {}", name)),
+        })
+    }
+
+    // The pitch of a primary click at the given frequency.
+    fn pitch(self, frequency: f64) -> Pitch {
+        match self {
+            AccentTone::Plain => Pitch::Note(frequency),
+            AccentTone::Chord => Pitch::Chord(frequency, frequency * constants::ACCENT_CHORD_RATIO),
+            AccentTone::Sweep => Pitch::Sweep(frequency * constants::ACCENT_SWEEP_RATIO, frequency),
+        }
+    }
+}
+
+// Fading out of subdivision clicks as the tempo rises past a
+// threshold, and back in as it falls, so a ramp up to a fast tempo
+// doesn't turn into a blur of clicks. Clones share the tempo they're
+// driven to.
+#[derive(Debug, Clone)]
+pub struct Ghosting {
+    // Tempo, in BPM, above which subdivisions start to fade, and how
+    // many BPM faster they're gone entirely.
+    pub above: f64,
+    pub over: f64,
+
+    // Volume subdivisions are played at, from 1 down to 0, as the
+    // bits of an f64.
+    level: Arc<AtomicU64>,
+}
+
+impl Ghosting {
+    // Ghosting that starts at `above` BPM and is complete `over` BPM
+    // later; subdivisions start out at full volume.
+    pub fn new(above: f64, over: f64) -> Ghosting {
+        Ghosting {
+            above,
+            over,
+            level: Arc::new(AtomicU64::new(1.0f64.to_bits())),
+        }
+    }
+
+    // Follows a change of tempo. Subdivisions are at full volume up
+    // to `above`, and fade out evenly to nothing by `above + over`.
+    pub fn drive(&self, tempo: f64) {
+        let level = if tempo <= self.above {
+            1.0
+        } else if tempo >= self.above + self.over {
+            0.0
+        } else {
+            1.0 - (tempo - self.above) / self.over
+        };
+        self.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    // The gain to play subdivisions at.
+    fn gain(&self) -> f64 {
+        perceptual_gain(f64::from_bits(self.level.load(Ordering::Relaxed)))
+    }
+}
+
+// The pitch of a tone, with frequencies in Hz.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pitch {
+    // A single note.
+    Note(f64),
+
+    // Two notes at once.
+    Chord(f64, f64),
+
+    // A glide from the first frequency to the second over the length
+    // of the tone.
+    Sweep(f64, f64),
+}
+
+impl Pitch {
+    // The frequencies sounding the given fraction of the way through
+    // the tone: one note, and another if it's a chord.
+    fn at(self, progress: f64) -> (f64, Option<f64>) {
+        match self {
+            Pitch::Note(frequency) => (frequency, None),
+            Pitch::Chord(low, high) => (low, Some(high)),
+            // Glide evenly in musical terms, i.e. exponentially.
+            Pitch::Sweep(from, to) => (from * (to / from).powf(progress.clamp(0.0, 1.0)), None),
+        }
+    }
+}
+
+// Generates the samples of a single click.
+struct Generator {
+    timbre: Timbre,
+
+    // A recording to play instead of a waveform of the timbre, if
+    // any.
+    sample: Option<Arc<Sample>>,
+
+    // The pitch of the tone, and the phase of each of its notes, in
+    // cycles.
+    pitch: Pitch,
+    phases: [f64; 2],
+
+    // Length of a sample, and of the whole click, in seconds.
+    sample_len: f64,
+    length: f64,
+
+    // Number of samples generated so far.
+    n: u64,
+
+    // State of the noise generator, and its previous output for the
+    // high-pass filter.
+    noise: u32,
+    last_noise: f64,
+}
+
+impl Generator {
+    fn new(timbre: Timbre, pitch: Pitch, length: Duration, sample_rate: u32) -> Generator {
+        Generator {
+            timbre,
+            sample: None,
+            pitch,
+            phases: [0.0; 2],
+            sample_len: 1.0 / sample_rate as f64,
+            length: length.as_secs_f64(),
+            n: 0,
+            noise: 0x2545_f491,
+            last_noise: 0.0,
+        }
+    }
+
+    // Creates a Generator that plays a recording through.
+    fn from_sample(sample: Arc<Sample>, sample_rate: u32) -> Generator {
+        let length = sample.duration();
+        Generator {
+            sample: Some(sample),
+            ..Generator::new(Timbre::Sine, Pitch::Note(0.0), length, sample_rate)
+        }
+    }
+
+    // Whether the click has finished.
+    fn done(&self) -> bool {
+        self.n as f64 * self.sample_len >= self.length
+    }
+
+    // Generates the next sample, from -1 to 1.
+    fn next_sample(&mut self) -> f64 {
+        let t = self.n as f64 * self.sample_len;
+        self.wave(t) * envelope(t, self.length)
+    }
+
+    // Generates the next sample of the raw waveform, without the
+    // envelope.
+    fn wave(&mut self, t: f64) -> f64 {
+        self.n += 1;
+        if let Some(sample) = &self.sample {
+            return sample.at(t);
+        }
+
+        let (low, high) = self.pitch.at(t / self.length);
+        let wave = self.oscillate(0, low, t);
+        // Noise has no pitch, so there's no chord to play.
+        match high {
+            Some(high) if self.timbre != Timbre::HiHat => (wave + self.oscillate(1, high, t)) / 2.0,
+            _ => wave,
+        }
+    }
+
+    // Generates the next sample of the waveform of one of the tone's
+    // notes, at the given frequency.
+    fn oscillate(&mut self, note: usize, frequency: f64, t: f64) -> f64 {
+        let phase = self.phases[note];
+        self.phases[note] = (phase + frequency * self.sample_len).fract();
+
+        match self.timbre {
+            Timbre::Sine => (phase * std::f64::consts::TAU).sin(),
+            Timbre::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Timbre::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Timbre::HiHat => {
+                // Differencing white noise removes its low end.
+                let noise = self.white_noise();
+                let sample = (noise - self.last_noise) / 2.0;
+                self.last_noise = noise;
+                sample * (-t / constants::HIHAT_DECAY).exp()
+            }
+            Timbre::Woodblock => {
+                (phase * std::f64::consts::TAU).sin() * (-t / constants::WOODBLOCK_DECAY).exp()
+            }
+        }
+    }
+
+    fn white_noise(&mut self) -> f64 {
+        white_noise(&mut self.noise)
+    }
+}
+
+// Generates the samples of the background sound, which goes on
+// forever.
+struct BackgroundGenerator {
+    background: Background,
+    sample_len: f64,
+    n: u64,
+
+    // State of the noise generator, and of the filters that turn its
+    // white noise pink.
+    noise: u32,
+    pink: [f64; 3],
+}
+
+impl BackgroundGenerator {
+    fn new(background: Background, sample_rate: u32) -> BackgroundGenerator {
+        BackgroundGenerator {
+            background,
+            sample_len: 1.0 / sample_rate as f64,
+            n: 0,
+            noise: 0x2545_f491,
+            pink: [0.0; 3],
+        }
+    }
+
+    // Generates the next sample, fading in at the start.
+    fn next_sample(&mut self) -> f64 {
+        let t = self.n as f64 * self.sample_len;
+        self.n += 1;
+        let sample = match self.background {
+            Background::Noise => self.pink_noise(),
+            Background::Drone(pitch) => (t * pitch * std::f64::consts::TAU).sin(),
+        };
+        sample * envelope(t, f64::INFINITY)
+    }
+
+    // Generates pink noise by summing white noise through three
+    // low-pass filters (Paul Kellet's economy method).
+    fn pink_noise(&mut self) -> f64 {
+        let white = white_noise(&mut self.noise);
+        self.pink[0] = 0.99765 * self.pink[0] + white * 0.0990460;
+        self.pink[1] = 0.96300 * self.pink[1] + white * 0.2965164;
+        self.pink[2] = 0.57000 * self.pink[2] + white * 1.0526913;
+        let pink = self.pink.iter().sum::<f64>() + white * 0.1848;
+        pink * constants::PINK_NOISE_GAIN
+    }
+}
+
+// Generates white noise from -1 to 1, with a xorshift generator whose
+// state is kept in `state`.
+fn white_noise(state: &mut u32) -> f64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state as f64 / u32::MAX as f64 * 2.0 - 1.0
+}
+
+// Gain of the envelope at time t into a click of the given length:
+// a linear fade in at the start, and a linear fade out at the end.
+fn envelope(t: f64, length: f64) -> f64 {
+    let attack = (t / constants::ENVELOPE_ATTACK).min(1.0);
+    let release = ((length - t) / constants::ENVELOPE_RELEASE).clamp(0.0, 1.0);
+    attack * release
+}
+
+// Something the metronome's clicks can be played on.
+pub trait SoundBackend {
+    // Plays a single BeatSpec event with the given timbre and volume.
+    // `beat` is the number of the beat in the measure (from 0) if the
+    // event falls on a beat rather than a subdivision, and `hand`
+    // says which hand plays it in a sticking drill, for picking its
+    // sound and channel. `tick_len` is the time until the next event,
+    // which the click is kept from running into.
+    fn play_event(
+        &self,
+        evt: &Event,
+        beat: Option<usize>,
+        hand: Option<Hand>,
+        timbre: Timbre,
+        vol: f64,
+        tick_len: Duration,
+    );
+
+    // Plays a beep at the given frequency, for the given length of
+    // time and at the given volume, in the given channels.
+    fn beep(&self, frequency: f64, length: Duration, timbre: Timbre, vol: f64, route: Route);
+
+    // Follows a change of tempo, which drives the fading of
+    // subdivisions with --ghost-above; called whenever the tempo is
+    // set, by hand or by the tempo map.
+    fn set_tempo(&self, _tempo: f64) {}
+
+    // Follows the metronome starting and stopping, or pausing.
+    fn set_playing(&self, _playing: bool) {}
+
+    // Follows a change of where clicks are sent, for backends that send
+    // them to more than one place.
+    fn set_output(&self, _output: ClickOutput) {}
+}
+
+impl SoundBackend for AudioConfig {
+    fn play_event(
+        &self,
+        evt: &Event,
+        beat: Option<usize>,
+        hand: Option<Hand>,
+        timbre: Timbre,
+        vol: f64,
+        tick_len: Duration,
+    ) {
+        for sound in event_sounds(&self.options, evt, beat, hand, timbre, vol, tick_len) {
+            play(self, sound);
+        }
+    }
+
+    fn set_tempo(&self, tempo: f64) {
+        if let Some(ghost) = &self.options.ghost {
+            ghost.drive(tempo);
+        }
+    }
+
+    fn set_playing(&self, playing: bool) {
+        self.playing.store(playing, Ordering::Relaxed);
+        self.wake();
+    }
+
+    fn beep(&self, frequency: f64, length: Duration, timbre: Timbre, vol: f64, route: Route) {
+        play(
+            self,
+            Sound {
+                delay: Duration::new(0, 0),
+                length,
+                gain: perceptual_gain(vol),
+                route,
+                source: Source::Tone(timbre, Pitch::Note(frequency)),
+            },
+        );
+    }
+}
+
+// Backend for machines with no audio device, which plays nothing, so
+// the metronome is purely visual.
+pub struct NoAudio;
+
+impl SoundBackend for NoAudio {
+    fn play_event(
+        &self,
+        _evt: &Event,
+        _beat: Option<usize>,
+        _hand: Option<Hand>,
+        _timbre: Timbre,
+        _vol: f64,
+        _tick_len: Duration,
+    ) {
+    }
+
+    fn beep(&self, _frequency: f64, _length: Duration, _timbre: Timbre, _vol: f64, _route: Route) {}
+}
+
+// Output written a fixed time after it's asked for, on a thread of its
+// own. Backends that would otherwise play straight away use this to
+// keep time with the audio, which holds every click back by the flam
+// spacing.
+pub struct DelayedOutput {
+    delay: Duration,
+
+    // Feeds the thread the bytes to write, and when; None once the
+    // output is being dropped.
+    send: Option<Sender<(Instant, Vec<u8>)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DelayedOutput {
+    // Starts a thread that passes the bytes given to `write` on to
+    // `output`, `delay` later.
+    pub fn new<F>(delay: Duration, mut output: F) -> DelayedOutput
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let (send, recv) = channel::<(Instant, Vec<u8>)>();
+        let thread = thread::spawn(move || {
+            while let Ok((due, bytes)) = recv.recv() {
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                output(&bytes);
+            }
+        });
+        DelayedOutput {
+            delay,
+            send: Some(send),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        if let Some(send) = &self.send {
+            let _ = send.send((Instant::now() + self.delay, bytes.to_vec()));
+        }
+    }
+}
+
+impl Drop for DelayedOutput {
+    // Waits for everything asked for to be written, so that e.g. a
+    // note-off isn't lost when we quit.
+    fn drop(&mut self) {
+        self.send = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Backend that rings the terminal bell instead of playing clicks, for
+// when there's no audio to be had but the terminal can still make a
+// sound (e.g. over SSH). The bell has no pitch or length of its own,
+// so it's only rung on the stronger clicks, to keep it from blurring
+// together at faster tempos.
+pub struct Bell {
+    // The weakest emphasis that still rings the bell, by its rank, with
+    // 0 for primary clicks only.
+    max_emph: u32,
+
+    // Which emphasis each click has.
+    emphasis: EmphasisPolicy,
+
+    // Holds the bell back by the flam spacing, if there is one.
+    delayed: Option<DelayedOutput>,
+}
+
+impl Bell {
+    pub fn new(max_emph: u32, emphasis: EmphasisPolicy, flam: Option<Duration>) -> Bell {
+        let delayed = flam.map(|flam| DelayedOutput::new(flam, write_terminal));
+        Bell {
+            max_emph,
+            emphasis,
+            delayed,
+        }
+    }
+
+    // Whether the given event rings the bell.
+    fn rings(&self, evt: &Event) -> bool {
+        self.emphasis
+            .of(evt)
+            .is_some_and(|emphasis| emphasis.rank() <= self.max_emph)
+    }
+}
+
+impl SoundBackend for Bell {
+    fn play_event(
+        &self,
+        evt: &Event,
+        _beat: Option<usize>,
+        _hand: Option<Hand>,
+        _timbre: Timbre,
+        vol: f64,
+        _tick_len: Duration,
+    ) {
+        if vol > 0.0 && self.rings(evt) {
+            match &self.delayed {
+                Some(delayed) => delayed.write(b"\x07"),
+                None => write_terminal(b"\x07"),
+            }
+        }
+    }
+
+    fn beep(&self, _frequency: f64, _length: Duration, _timbre: Timbre, _vol: f64, _route: Route) {}
+}
+
+// Writes to the terminal, for the bell. A bell that fails to ring
+// isn't worth stopping for.
+fn write_terminal(bytes: &[u8]) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(bytes);
+    let _ = stdout.flush();
+}
+
+// Loudness of a sound, as the RMS of its samples from the first to the
+// last within NORMALIZE_GATE of its peak, so silence around a
+// recording doesn't count against it.
+fn loudness(samples: &[f32]) -> f64 {
+    let peak = samples.iter().fold(0.0_f32, |max, x| max.max(x.abs()));
+    let gate = peak * constants::NORMALIZE_GATE as f32;
+    let first = samples.iter().position(|x| x.abs() >= gate);
+    let last = samples.iter().rposition(|x| x.abs() >= gate);
+    let heard = match (first, last) {
+        (Some(first), Some(last)) if peak > 0.0 => &samples[first..=last],
+        _ => return 0.0,
+    };
+
+    let squares: f64 = heard.iter().map(|&x| x as f64 * x as f64).sum();
+    (squares / heard.len() as f64).sqrt()
+}
+
+// Loudness of a default-length click of the given timbre, at the
+// default pitch.
+fn click_loudness(timbre: Timbre) -> f64 {
+    let length = Duration::from_millis(constants::BEAT_LEN);
+    let rate = constants::NORMALIZE_RATE;
+    let mut generator = Generator::new(timbre, Pitch::Note(constants::BEEP_PITCH), length, rate);
+    let frames = (length.as_secs_f64() * rate as f64) as usize;
+    let samples: Vec<f32> = (0..frames)
+        .map(|_| generator.next_sample() as f32)
+        .collect();
+    loudness(&samples)
+}
+
+// Gain that brings a sound of the given loudness up or down to the
+// reference loudness, boosting quiet sounds by no more than
+// NORMALIZE_MAX_GAIN.
+fn loudness_gain(reference: f64, loudness: f64) -> f64 {
+    if loudness > 0.0 {
+        (reference / loudness).min(constants::NORMALIZE_MAX_GAIN)
+    } else {
+        1.0
+    }
+}
+
+// Gain that brings the given timbre's clicks to the loudness of the
+// sine click's. They're worked out the first time they're needed.
+fn timbre_gain(timbre: Timbre) -> f64 {
+    static GAINS: OnceLock<Vec<(Timbre, f64)>> = OnceLock::new();
+    let gains = GAINS.get_or_init(|| {
+        let reference = click_loudness(Timbre::Sine);
+        let mut gains = vec![];
+        let mut timbre = Timbre::Sine;
+        loop {
+            gains.push((timbre, loudness_gain(reference, click_loudness(timbre))));
+            timbre = timbre.next();
+            if timbre == Timbre::Sine {
+                break gains;
+            }
+        }
+    });
+    gains
+        .iter()
+        .find(|&&(t, _)| t == timbre)
+        .map_or(1.0, |&(_, gain)| gain)
+}
+
+// Converts a volume as shown to the user into a gain to apply to
+// samples. Loudness is roughly logarithmic in amplitude, so volumes up
+// to 100% are spread evenly in decibels, making each volume step sound
+// like the same change; boosts above 100% stay linear, as the
+// limiter takes over there anyway.
+fn perceptual_gain(vol: f64) -> f64 {
+    if vol <= 0.0 {
+        0.0
+    } else {
+        10.0_f64.powf(volume_db(vol) / 20.0)
+    }
+}
+
+// The gain a volume is played at, in decibels relative to full scale.
+// Silence, at volume 0, comes out as the bottom of the dynamic range
+// rather than minus infinity, so it can be stepped up from.
+pub fn volume_db(vol: f64) -> f64 {
+    if vol >= 1.0 {
+        20.0 * vol.log10()
+    } else {
+        constants::VOL_DYNAMIC_RANGE * (vol - 1.0)
+    }
+}
+
+// The volume played at the given gain in decibels; the inverse of
+// volume_db.
+pub fn volume_from_db(db: f64) -> f64 {
+    if db >= 0.0 {
+        10.0_f64.powf(db / 20.0)
+    } else {
+        1.0 + db / constants::VOL_DYNAMIC_RANGE
+    }
+}
+
+// A single sound making up part of an event.
+struct Sound {
+    // When the sound starts, after the event itself.
+    delay: Duration,
+
+    length: Duration,
+
+    // Gain to scale the sound's samples by, and the channels it plays
+    // in.
+    gain: f64,
+    route: Route,
+
+    source: Source,
+}
+
+// What a Sound is made from.
+#[derive(Clone)]
+enum Source {
+    // A tone of the given timbre and pitch.
+    Tone(Timbre, Pitch),
+
+    // A recording, played through once.
+    Recording(Arc<Sample>),
+}
+
+// Sources are the same if they sound the same; recordings are only
+// compared by identity, since comparing every sample would cost more
+// than rendering them again.
+impl PartialEq for Source {
+    fn eq(&self, other: &Source) -> bool {
+        match (self, other) {
+            (Source::Tone(timbre, pitch), Source::Tone(other_timbre, other_pitch)) => {
+                timbre == other_timbre && pitch == other_pitch
+            }
+            (Source::Recording(sample), Source::Recording(other)) => Arc::ptr_eq(sample, other),
+            _ => false,
+        }
+    }
+}
+
+impl Sound {
+    // Creates the Generator for the sound's samples, at the given
+    // sample rate.
+    fn generator(&self, sample_rate: u32) -> Generator {
+        match &self.source {
+            Source::Tone(timbre, pitch) => {
+                Generator::new(*timbre, *pitch, self.length, sample_rate)
+            }
+            Source::Recording(sample) => Generator::from_sample(sample.clone(), sample_rate),
+        }
+    }
+
+    // Renders all of the sound's samples, at the given sample rate,
+    // without the gain.
+    fn render(&self, sample_rate: u32) -> Arc<[f32]> {
+        let mut generator = self.generator(sample_rate);
+        let mut samples =
+            Vec::with_capacity((self.length.as_secs_f64() * sample_rate as f64) as usize + 1);
+        while !generator.done() {
+            samples.push(generator.next_sample() as f32);
+        }
+        samples.into()
+    }
+}
+
+// Works out the sounds that make up a single BeatSpec event, as
+// played by SoundBackend::play_event.
+fn event_sounds(
+    options: &SoundOptions,
+    evt: &Event,
+    beat: Option<usize>,
+    hand: Option<Hand>,
+    timbre: Timbre,
+    vol: f64,
+    tick_len: Duration,
+) -> Vec<Sound> {
+    let route = match (hand, options.sticking_pan) {
+        (Some(Hand::Right), true) => Route::Right,
+        (Some(Hand::Left), true) => Route::Left,
+        _ => match (options.split_channels, beat.is_some()) {
+            (false, _) => Route::All,
+            (true, true) => Route::Left,
+            (true, false) => Route::Right,
+        },
+    };
+
+    let pitch = options.pitch.unwrap_or(constants::BEEP_PITCH) * hand.map_or(1.0, Hand::pitch);
+    let gain = perceptual_gain(vol);
+    let level = |normalized| if options.normalize { normalized } else { 1.0 };
+    let timbre_gain = level(timbre_gain(timbre));
+    // With flams on, every click is held back by the flam spacing, so
+    // that a downbeat's grace note can come before the beat and the
+    // main click still lands on it, in time with all the others.
+    let grace = Duration::new(0, 0);
+    let now = options.flam.unwrap_or(grace);
+    let length = options
+        .click_length
+        .unwrap_or(Duration::from_millis(constants::BEAT_LEN));
+    let room = tick_len.mul_f64(constants::CLICK_TICK_FRACTION);
+    let tone = |delay: Duration, pitch, gain| Sound {
+        delay,
+        length: length.min(room.saturating_sub(delay.saturating_sub(now))),
+        gain: gain * timbre_gain,
+        route,
+        source: Source::Tone(timbre, pitch),
+    };
+    let recording = |delay, (sample, sample_gain): (&Arc<Sample>, f64), gain| Sound {
+        delay,
+        length: sample.duration(),
+        gain: gain * level(sample_gain),
+        route,
+        source: Source::Recording(sample.clone()),
+    };
+
+    let spoken = match (&options.voice, beat) {
+        (Some(voice), Some(beat)) => voice.count(beat),
+        _ => None,
+    };
+    match (evt, spoken) {
+        (&Event::Rest, _) => vec![],
+        (&Event::Beep(_), Some(count)) => vec![recording(now, count, gain)],
+        // Downbeats are played as a flam if flams are enabled: a grace
+        // note at reduced volume the flam spacing ahead of the beat,
+        // then the main beep on it.
+        (&Event::Beep(layer), None) => {
+            let emphasis = options.emphasis.of(evt).unwrap_or(Emphasis::Primary);
+            let gain = gain
+                * match (emphasis, options.subdiv_volume, &options.theme) {
+                    (Emphasis::Subdivision, Some(vol), _) => perceptual_gain(vol),
+                    // A theme's recordings have their own balance
+                    // between the emphases.
+                    (_, _, Some(_)) => 1.0,
+                    _ => emphasis.gain(),
+                };
+            // Subdivisions fade out at fast tempos, with ghosting on.
+            let gain = match (emphasis, &options.ghost) {
+                (Emphasis::Subdivision, Some(ghost)) => gain * ghost.gain(),
+                _ => gain,
+            };
+            let frequency = pitch * emphasis.pitch();
+            let pitch = match emphasis {
+                Emphasis::Primary => options.accent_tone.pitch(frequency),
+                _ => Pitch::Note(frequency),
+            };
+            let click = |delay, gain| match &options.theme {
+                Some(theme) => recording(delay, theme.click(emphasis), gain),
+                None => tone(delay, pitch, gain),
+            };
+            match (layer, options.flam) {
+                (0, Some(_)) => vec![
+                    click(grace, gain * constants::FLAM_GRACE_VOLUME),
+                    click(now, gain),
+                ],
+                _ => vec![click(now, gain)],
+            }
+        }
+    }
+}
+
+// Queues a sound to be mixed into the output, so this function does
+// not block.
+fn play(cfg: &AudioConfig, sound: Sound) {
+    // There's no stream to play it on until the device is back.
+    if cfg.lost.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // A click that hasn't been played before is rendered without
+    // holding the mixer, which the audio thread needs to fill every
+    // buffer; if the stream changes sample rate meanwhile, it's
+    // rendered again for the new one.
+    let start = Instant::now();
+    loop {
+        let (rate, cached) = {
+            let mixer = cfg.mixer.lock().unwrap();
+            (mixer.sample_rate, mixer.cached(&sound))
+        };
+        let click = cached.unwrap_or_else(|| sound.render(rate));
+
+        let mut mixer = cfg.mixer.lock().unwrap();
+        if mixer.sample_rate == rate {
+            mixer.keep(&sound, click.clone());
+            mixer.queue(sound, click, start);
+            break;
+        }
+    }
+    cfg.wake();
+}
+
+// A click track being mixed down in memory, for writing to a file
+// rather than playing. Events are mixed in exactly as play_event
+// would play them.
+pub struct Mixdown {
+    options: SoundOptions,
+    sample_rate: u32,
+
+    // Interleaved samples, one per channel per frame, before
+    // limiting.
+    samples: Vec<f64>,
+}
+
+impl Mixdown {
+    pub fn new(options: SoundOptions, sample_rate: u32) -> Mixdown {
+        Mixdown {
+            options,
+            sample_rate,
+            samples: vec![],
+        }
+    }
+
+    // Follows a change of tempo, like SoundBackend::set_tempo.
+    pub fn set_tempo(&self, tempo: f64) {
+        if let Some(ghost) = &self.options.ghost {
+            ghost.drive(tempo);
+        }
+    }
+
+    // Mixes in a single BeatSpec event starting at the given time into
+    // the track, with the same meanings of the other arguments as for
+    // SoundBackend::play_event.
+    pub fn add_event(
+        &mut self,
+        at: Duration,
+        evt: &Event,
+        beat: Option<usize>,
+        timbre: Timbre,
+        vol: f64,
+        tick_len: Duration,
+    ) {
+        let channels = constants::RENDER_CHANNELS;
+        let rate = self.sample_rate as f64;
+        for sound in event_sounds(&self.options, evt, beat, None, timbre, vol, tick_len) {
+            let start = ((at + sound.delay).as_secs_f64() * rate).round() as usize;
+            let frames = (sound.length.as_secs_f64() * rate).ceil() as usize;
+            let end = (start + frames) * channels;
+            if self.samples.len() < end {
+                self.samples.resize(end, 0.0);
+            }
+
+            let mut generator = sound.generator(self.sample_rate);
+            let track = &mut self.samples[start * channels..end];
+            for frame in track.chunks_mut(channels) {
+                let sample = generator.next_sample() * sound.gain;
+                for (n, el) in frame.iter_mut().enumerate() {
+                    if sound.route.plays_on(n, channels) {
+                        *el += sample;
+                    }
                 }
+            }
+        }
+    }
+
+    // Finishes the track, cutting or padding it to the given length.
+    pub fn finish(mut self, length: Duration) -> Vec<f32> {
+        let frames = (length.as_secs_f64() * self.sample_rate as f64).round() as usize;
+        self.samples
+            .resize(frames * constants::RENDER_CHANNELS, 0.0);
+        let mut samples: Vec<f32> = self.samples.iter().map(|&x| x as f32).collect();
+        let mut limiter = Limiter::new(self.sample_rate);
+        for frame in samples.chunks_mut(constants::RENDER_CHANNELS) {
+            limiter.limit(frame);
+        }
+        samples
+    }
+}
+
+// Runs the output stream the mixer plays through, in another thread,
+// for as long as the program runs. If the device goes away, the
+// stream starts over on the new one once it's reopened. While the
+// metronome isn't playing and nothing's left to mix, the stream is
+// suspended, so the device can sleep.
+fn run_output(audio: &AudioConfig) {
+    let cfg = audio.clone();
+    let poll = Duration::from_millis(constants::OUTPUT_POLL);
+    let thread = thread::spawn(move || loop {
+        if cfg.lost.load(Ordering::Relaxed) {
+            cfg.reopen();
+            if cfg.lost.load(Ordering::Relaxed) {
+                thread::sleep(poll);
+                continue;
+            }
+        }
+
+        let output = cfg.output.lock().unwrap();
+        let channels = output.stream_config.channels as usize;
+        cfg.mixer
+            .lock()
+            .unwrap()
+            .open(output.stream_config.sample_rate.0);
+        let mix_cfg = cfg.clone();
+        let err_cfg = cfg.clone();
+        let stream = output.device.build_output_stream(
+            &output.stream_config,
+            move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
+                mix_cfg
+                    .mixer
+                    .lock()
+                    .unwrap()
+                    .fill(data, channels, Instant::now());
             },
-            move |_err| {
-                panic!("Stream error");
+            move |err| match err {
+                StreamError::DeviceNotAvailable => err_cfg.lose_device(),
+                err => log::error!("Audio stream error: {}", err),
             },
         );
+        drop(output);
+        match &stream {
+            Err(BuildStreamError::DeviceNotAvailable) => cfg.lose_device(),
+            Err(e) => {
+                // Try again on a fresh device, giving it a moment
+                // rather than spinning if the error's a lasting one.
+                log::error!("Couldn't open audio stream: {}", e);
+                cfg.lose_device();
+                thread::sleep(poll);
+            }
+            Ok(_) => {}
+        }
 
-        thread::sleep(length);
+        let mut suspended = false;
+        while !cfg.lost.load(Ordering::Relaxed) {
+            let idle = !cfg.playing.load(Ordering::Relaxed) && cfg.mixer.lock().unwrap().idle();
+            if let (Ok(stream), true) = (&stream, idle != suspended) {
+                if idle {
+                    if let Err(e) = stream.pause() {
+                        log::debug!("Couldn't suspend the audio stream: {}", e);
+                    }
+                    log::debug!("Suspended the audio stream");
+                } else {
+                    cfg.mixer.lock().unwrap().resume();
+                    if let Err(e) = stream.play() {
+                        log::error!("Couldn't resume the audio stream: {}", e);
+                        cfg.lose_device();
+                    }
+                    log::debug!("Resumed the audio stream");
+                }
+                suspended = idle;
+            }
+            thread::park_timeout(poll);
+        }
         drop(stream);
     });
+    audio.output_thread.set(thread.thread().clone()).ok();
+}
+
+// Keeps a mix within full scale without hard clipping, so boosted or
+// overlapping clicks get louder without harsh distortion. A frame that
+// would go over turns the gain down just far enough to fit, and the
+// gain then comes back up to unity over LIMITER_RELEASE. A mix that
+// stays within full scale passes through untouched.
+struct Limiter {
+    gain: f64,
+
+    // Fraction of the gain reduction left after each frame.
+    recovery: f64,
+}
+
+impl Limiter {
+    fn new(sample_rate: u32) -> Limiter {
+        Limiter {
+            gain: 1.0,
+            recovery: (-1.0 / (constants::LIMITER_RELEASE * sample_rate as f64)).exp(),
+        }
+    }
+
+    // Limits a frame of samples, one per channel, in place.
+    fn limit(&mut self, frame: &mut [f32]) {
+        let peak = frame
+            .iter()
+            .fold(0.0_f64, |peak, &x| peak.max((x as f64).abs()));
+        if peak * self.gain > 1.0 {
+            self.gain = 1.0 / peak;
+        }
+        if self.gain < 1.0 {
+            for el in frame.iter_mut() {
+                *el = (*el as f64 * self.gain) as f32;
+            }
+            self.gain = 1.0 - (1.0 - self.gain) * self.recovery;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bell_test() {
+        let bell = Bell::new(1, EmphasisPolicy::default(), None);
+        assert!(bell.rings(&Event::Beep(0)));
+        assert!(bell.rings(&Event::Beep(1)));
+        assert!(!bell.rings(&Event::Beep(2)));
+        assert!(!bell.rings(&Event::Rest));
+
+        // The bell goes by emphasis, not by crossbeat.
+        let bell = Bell::new(0, EmphasisPolicy::parse("secondary,primary").unwrap(), None);
+        assert!(!bell.rings(&Event::Beep(0)));
+        assert!(bell.rings(&Event::Beep(1)));
+    }
+
+    #[test]
+    fn mixdown_test() {
+        let rate = 1000;
+        let mut mixdown = Mixdown::new(
+            SoundOptions {
+                split_channels: true,
+                ..SoundOptions::default()
+            },
+            rate,
+        );
+        let at = Duration::from_millis(500);
+        mixdown.add_event(at, &Event::Beep(1), None, Timbre::Square, 1.0, at);
+        mixdown.add_event(at * 2, &Event::Rest, Some(0), Timbre::Square, 1.0, at);
+        let track = mixdown.finish(Duration::from_secs(1));
+        assert_eq!(track.len(), 2 * rate as usize);
+
+        // The subdivision plays in the right channel only, starting
+        // halfway through.
+        let frame = |n: usize| (track[2 * n], track[2 * n + 1]);
+        assert_eq!(frame(499), (0.0, 0.0));
+        assert_eq!(frame(550).0, 0.0);
+        assert!(frame(550).1.abs() > 0.5);
+        assert_eq!(frame(999), (0.0, 0.0));
+    }
+
+    #[test]
+    fn accent_level_test() {
+        use Emphasis::*;
+        let downbeat = AccentLevel::parse("downbeat").unwrap();
+        assert!(downbeat.keeps(Some(Primary)));
+        assert!(!downbeat.keeps(Some(Secondary)));
+        assert!(downbeat.keeps(None));
+
+        let accents = AccentLevel::parse("accents").unwrap();
+        assert!(accents.keeps(Some(Primary)));
+        assert!(accents.keeps(Some(Secondary)));
+        assert!(!accents.keeps(Some(Subdivision)));
+
+        assert!(AccentLevel::parse("beats").is_err());
+    }
+
+    #[test]
+    fn click_output_test() {
+        use ClickOutput::*;
+
+        // Only the outputs there are devices for are stepped through.
+        let all = ClickOutput::cycle(true, true);
+        assert_eq!(all, vec![Audio, Both, Midi, Silent]);
+        assert_eq!(ClickOutput::cycle(true, false), vec![Audio, Silent]);
+        assert_eq!(ClickOutput::cycle(false, true), vec![Midi, Silent]);
+        assert_eq!(Silent.next(&all), Audio);
+        assert_eq!(Both.next(&all), Midi);
+        assert_eq!(Both.next(&ClickOutput::cycle(true, false)), Audio);
+
+        // Outputs restored from elsewhere fall back on what's there.
+        assert_eq!(Both.within(true, false), Audio);
+        assert_eq!(Both.within(false, true), Midi);
+        assert_eq!(Audio.within(false, true), Midi);
+        assert_eq!(Midi.within(true, false), Silent);
+        assert_eq!(Audio.within(false, false), Silent);
+    }
+
+    #[test]
+    fn limiter_test() {
+        let mut limiter = Limiter::new(1000);
+
+        // A mix within full scale is untouched.
+        let mut frame = [1.0, -0.5];
+        limiter.limit(&mut frame);
+        assert_eq!(frame, [1.0, -0.5]);
+
+        // A peak over full scale is brought down to it, along with the
+        // other channels.
+        let mut frame = [-2.0, 0.5];
+        limiter.limit(&mut frame);
+        assert_eq!(frame, [-1.0, 0.25]);
+
+        // The gain then recovers gradually, rather than jumping back.
+        let mut frame = [0.5, 0.5];
+        limiter.limit(&mut frame);
+        assert!(frame[0] > 0.25 && frame[0] < 0.5);
+        for _ in 0..1000 {
+            limiter.limit(&mut [0.0, 0.0]);
+        }
+        let mut frame = [0.5, 0.5];
+        limiter.limit(&mut frame);
+        assert!((frame[0] - 0.5).abs() < 1e-6);
+
+        // Overlapping clicks that add up past full scale are limited
+        // in the mix, and in a rendered track.
+        let loud = || Sound {
+            delay: Duration::new(0, 0),
+            length: Duration::from_millis(100),
+            gain: 1.0,
+            route: Route::All,
+            source: Source::Tone(Timbre::Square, Pitch::Note(100.0)),
+        };
+        let now = Instant::now();
+        let mut mixer = Mixer::new(&SoundOptions::default(), 1000);
+        add(&mut mixer, loud(), now);
+        add(&mut mixer, loud(), now);
+        let mut data = vec![0.0; 2 * 50];
+        mixer.fill(&mut data, 2, now);
+        assert!(data.iter().all(|x| x.abs() <= 1.0 + 1e-6));
+        assert!(data.iter().any(|x| x.abs() > 0.99));
+    }
+
+    #[test]
+    fn timbre_test() {
+        let timbres = [
+            Timbre::Sine,
+            Timbre::Square,
+            Timbre::Triangle,
+            Timbre::HiHat,
+            Timbre::Woodblock,
+        ];
+        for &timbre in timbres.iter() {
+            let mut generator = Generator::new(
+                timbre,
+                Pitch::Note(880.0),
+                Duration::from_millis(100),
+                44100,
+            );
+            let samples: Vec<f64> = (0..44100 / 10).map(|_| generator.next_sample()).collect();
+            assert!(samples.iter().all(|s| s.abs() <= 1.0));
+
+            // Every timbre makes some sound.
+            assert!(samples.iter().any(|s| s.abs() > 0.1));
+        }
+
+        // The decaying timbres have died down well before the end of
+        // a click.
+        let mut generator = Generator::new(
+            Timbre::Woodblock,
+            Pitch::Note(880.0),
+            Duration::from_millis(200),
+            44100,
+        );
+        let tail = (0..44100 / 10).map(|_| generator.next_sample()).last();
+        assert!(tail.unwrap().abs() < 0.01);
+
+        assert_eq!(Timbre::parse("hihat").unwrap(), Timbre::HiHat);
+        for &timbre in timbres.iter() {
+            assert_eq!(Timbre::parse(timbre.name()).unwrap(), timbre);
+        }
+        assert!(Timbre::parse("cowbell").is_err());
+    }
+
+    #[test]
+    fn background_test() {
+        for &background in [Background::Noise, Background::Drone(110.0)].iter() {
+            let mut generator = BackgroundGenerator::new(background, 44100);
+            let samples: Vec<f64> = (0..44100).map(|_| generator.next_sample()).collect();
+            assert!(samples.iter().all(|s| s.abs() <= 1.0));
+            assert!(samples.iter().any(|s| s.abs() > 0.1));
+        }
+
+        assert_eq!(Background::parse("noise").unwrap(), Background::Noise);
+        assert_eq!(Background::parse("220").unwrap(), Background::Drone(220.0));
+        assert!(Background::parse("0").is_err());
+        assert!(Background::parse("rain").is_err());
+    }
+
+    #[test]
+    fn perceptual_gain_test() {
+        assert_eq!(perceptual_gain(0.0), 0.0);
+        assert_eq!(perceptual_gain(1.0), 1.0);
+        assert_eq!(perceptual_gain(1.5), 1.5);
+
+        // Each step below 100% changes the gain by the same ratio.
+        let step = |vol: f64| perceptual_gain(vol) / perceptual_gain(vol - constants::VOL_ADJUST);
+        assert!((step(0.3) - step(0.9)).abs() < 1e-9);
+        assert!(step(0.5) > 1.0);
+    }
+
+    #[test]
+    fn loudness_test() {
+        // Silence around a sound doesn't make it any quieter.
+        let tone = [0.5, -0.5, 0.5, -0.5];
+        let padded = [0.0, 0.0, 0.5, -0.5, 0.5, -0.5, 0.0];
+        assert_eq!(loudness(&tone), 0.5);
+        assert_eq!(loudness(&padded), 0.5);
+        assert_eq!(loudness(&[0.0; 4]), 0.0);
+
+        assert_eq!(loudness_gain(0.5, 0.25), 2.0);
+        assert_eq!(loudness_gain(0.5, 0.1), constants::NORMALIZE_MAX_GAIN);
+        assert_eq!(loudness_gain(0.5, 0.0), 1.0);
+
+        // Square waves carry more energy than sines, so they're turned
+        // down to match.
+        assert!((timbre_gain(Timbre::Sine) - 1.0).abs() < 1e-9);
+        assert!(timbre_gain(Timbre::Square) < 1.0);
+    }
+
+    #[test]
+    fn volume_db_test() {
+        assert_eq!(volume_db(1.0), 0.0);
+        assert_eq!(volume_db(0.0), -constants::VOL_DYNAMIC_RANGE);
+        assert!((volume_db(2.0) - 6.0206).abs() < 1e-4);
+        for &vol in &[0.0, 0.25, 0.5, 1.0, 1.5, 2.0] {
+            assert!((volume_from_db(volume_db(vol)) - vol).abs() < 1e-9);
+            if vol > 0.0 {
+                let gain = 10.0_f64.powf(volume_db(vol) / 20.0);
+                assert!((perceptual_gain(vol) - gain).abs() < 1e-9);
+            }
+        }
+    }
+
+    // Renders a sound as play() does, reusing the mixer's cached
+    // samples if there are any.
+    fn render(mixer: &mut Mixer, sound: &Sound) -> Arc<[f32]> {
+        let click = mixer
+            .cached(sound)
+            .unwrap_or_else(|| sound.render(mixer.sample_rate));
+        mixer.keep(sound, click.clone());
+        click
+    }
+
+    // Queues a sound on the mixer as play() does.
+    fn add(mixer: &mut Mixer, sound: Sound, start: Instant) {
+        let click = render(mixer, &sound);
+        mixer.queue(sound, click, start);
+    }
+
+    #[test]
+    fn mixer_test() {
+        let rate = 1000;
+        let ms = Duration::from_millis;
+        let click = || Sound {
+            delay: ms(0),
+            length: ms(100),
+            gain: 0.1,
+            route: Route::Left,
+            source: Source::Tone(Timbre::Square, Pitch::Note(100.0)),
+        };
+        let now = Instant::now();
+
+        let mut single = Mixer::new(&SoundOptions::default(), rate);
+        add(&mut single, click(), now);
+        let mut alone = vec![0.0; 2 * 50];
+        single.fill(&mut alone, 2, now);
+
+        // A click due partway into a buffer starts partway into it,
+        // and overlapping clicks add up.
+        let mut mixer = Mixer::new(&SoundOptions::default(), rate);
+        add(&mut mixer, click(), now);
+        add(&mut mixer, click(), now + ms(10));
+        let mut mixed = vec![0.0; 2 * 50];
+        mixer.fill(&mut mixed, 2, now);
+        for n in 0..50 {
+            let later = if n >= 10 { alone[2 * (n - 10)] } else { 0.0 };
+            assert!((mixed[2 * n] - (alone[2 * n] + later)).abs() < 1e-6);
+            assert_eq!(mixed[2 * n + 1], 0.0);
+        }
+
+        // Clicks past the end of the buffer wait for the next one, and
+        // finished clicks leave the mix.
+        add(&mut mixer, click(), now + ms(120));
+        mixer.fill(&mut mixed, 2, now + ms(50));
+        assert_eq!(mixer.pending.len(), 1);
+        assert_eq!(mixer.playing.len(), 1);
+
+        // One click too many steals the oldest, which fades out rather
+        // than stopping dead.
+        let mut mixer = Mixer::new(&SoundOptions::default(), rate);
+        for _ in 0..=constants::MAX_VOICES {
+            add(&mut mixer, click(), now);
+        }
+        let mut data = vec![0.0; 2];
+        mixer.fill(&mut data, 2, now);
+        assert_eq!(mixer.playing.len(), constants::MAX_VOICES + 1);
+        assert!(mixer.playing[0].fade.is_some());
+        assert!(mixer.playing[1].fade.is_none());
+
+        let mut data = vec![0.0; 2 * 50];
+        mixer.fill(&mut data, 2, now);
+        assert_eq!(mixer.playing.len(), constants::MAX_VOICES);
+        let mut data = vec![0.0; 2 * 100];
+        mixer.fill(&mut data, 2, now);
+        assert!(mixer.playing.is_empty());
+        assert!(mixer.idle());
+
+        // A new stream drops the clicks queued for the old one, and
+        // clicks long overdue by the time they're reached are dropped
+        // rather than played in a burst.
+        add(&mut mixer, click(), now + ms(500));
+        assert!(!mixer.idle());
+        mixer.open(rate);
+        assert!(mixer.idle());
+        let late = now + ms(constants::STALE_CLICK * 2);
+        add(&mut mixer, click(), now);
+        add(&mut mixer, click(), late - ms(10));
+        let mut data = vec![0.0; 2 * 10];
+        mixer.fill(&mut data, 2, late);
+        assert!(mixer.pending.is_empty());
+        assert_eq!(mixer.playing.len(), 1);
+    }
+
+    #[test]
+    fn click_cache_test() {
+        let ms = Duration::from_millis;
+        let click = |length, pitch| Sound {
+            delay: ms(0),
+            length: ms(length),
+            gain: 1.0,
+            route: Route::All,
+            source: Source::Tone(Timbre::Sine, Pitch::Note(pitch)),
+        };
+        let mut mixer = Mixer::new(&SoundOptions::default(), 1000);
+
+        // The same click is only rendered once, and sounds as the
+        // generator would have played it.
+        let first = render(&mut mixer, &click(50, 100.0));
+        assert!(Arc::ptr_eq(&first, &render(&mut mixer, &click(50, 100.0))));
+        let mut generator = click(50, 100.0).generator(1000);
+        assert_eq!(first.len(), 50);
+        for &sample in first.iter() {
+            assert_eq!(sample, generator.next_sample() as f32);
+        }
+
+        // Changing the length or pitch renders it again.
+        assert!(!Arc::ptr_eq(&first, &render(&mut mixer, &click(40, 100.0))));
+        assert!(!Arc::ptr_eq(&first, &render(&mut mixer, &click(50, 200.0))));
+        assert_eq!(mixer.clicks.len(), 3);
+
+        // Only so many are kept, and a new stream starts afresh.
+        for n in 0..constants::CLICK_CACHE_SIZE {
+            render(&mut mixer, &click(10, n as f64));
+        }
+        assert_eq!(mixer.clicks.len(), constants::CLICK_CACHE_SIZE);
+        mixer.open(2000);
+        assert!(mixer.clicks.is_empty());
+    }
+
+    #[test]
+    fn pitch_test() {
+        let options = SoundOptions {
+            pitch: Some(440.0),
+            ..SoundOptions::default()
+        };
+        let tick_len = Duration::from_secs(1);
+        let frequency =
+            |evt| match event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)[0]
+                .source
+            {
+                Source::Tone(_, Pitch::Note(frequency)) => frequency,
+                _ => panic!("Expected a tone"),
+            };
+
+        // Every emphasis level moves with the base pitch, and deep
+        // crossbeats don't keep diving below the subdivisions.
+        assert_eq!(frequency(Event::Beep(0)), 440.0);
+        assert_eq!(frequency(Event::Beep(1)), 220.0);
+        assert_eq!(frequency(Event::Beep(2)), 440.0 / 3.0);
+        assert_eq!(frequency(Event::Beep(5)), 440.0 / 3.0);
+    }
+
+    #[test]
+    fn accent_tone_test() {
+        let options = SoundOptions {
+            pitch: Some(440.0),
+            accent_tone: AccentTone::Chord,
+            ..SoundOptions::default()
+        };
+        let tick_len = Duration::from_secs(1);
+        let pitch =
+            |evt| match event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)[0]
+                .source
+            {
+                Source::Tone(_, pitch) => pitch,
+                _ => panic!("Expected a tone"),
+            };
+
+        // Only the primary clicks get the two-tone accent.
+        assert_eq!(pitch(Event::Beep(0)), Pitch::Chord(440.0, 660.0));
+        assert_eq!(pitch(Event::Beep(1)), Pitch::Note(220.0));
+
+        // Sweeps glide down an octave, evenly in pitch.
+        let sweep = AccentTone::Sweep.pitch(440.0);
+        assert_eq!(sweep.at(0.0), (880.0, None));
+        assert!((sweep.at(0.5).0 - 440.0 * 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(sweep.at(1.0), (440.0, None));
+
+        // Chords stay within full scale.
+        let length = Duration::from_millis(50);
+        let mut generator =
+            Generator::new(Timbre::Square, Pitch::Chord(440.0, 660.0), length, 44100);
+        while !generator.done() {
+            assert!(generator.next_sample().abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn subdiv_volume_test() {
+        let options = SoundOptions {
+            subdiv_volume: Some(0.4),
+            ..SoundOptions::default()
+        };
+        let tick_len = Duration::from_secs(1);
+        let gain =
+            |evt| event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)[0].gain;
+
+        // Only the subdivisions are turned down.
+        assert_eq!(gain(Event::Beep(0)), 1.0);
+        assert_eq!(gain(Event::Beep(1)), constants::SECONDARY_GAIN);
+        assert_eq!(gain(Event::Beep(2)), perceptual_gain(0.4));
+        assert!(gain(Event::Beep(2)) < 0.4);
+    }
+
+    #[test]
+    fn ghosting_test() {
+        let ghost = Ghosting::new(200.0, 40.0);
+        let options = SoundOptions {
+            ghost: Some(ghost.clone()),
+            ..SoundOptions::default()
+        };
+        let tick_len = Duration::from_secs(1);
+        let gain =
+            |evt| event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)[0].gain;
+        let subdiv = constants::SUBDIVISION_GAIN;
+
+        // Subdivisions fade out past the threshold, and back in below
+        // it; the beats are left alone.
+        ghost.drive(120.0);
+        assert_eq!(gain(Event::Beep(2)), subdiv);
+        ghost.drive(220.0);
+        assert_eq!(gain(Event::Beep(2)), subdiv * perceptual_gain(0.5));
+        assert_eq!(gain(Event::Beep(0)), 1.0);
+        ghost.drive(300.0);
+        assert_eq!(gain(Event::Beep(2)), 0.0);
+        ghost.drive(180.0);
+        assert_eq!(gain(Event::Beep(2)), subdiv);
+    }
+
+    #[test]
+    fn theme_test() {
+        let dir = std::env::temp_dir().join(format!("metronome-theme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, len) in &[("accent.wav", 300), ("beat.wav", 200), ("subdiv.wav", 100)] {
+            let path = dir.join(file);
+            crate::wav::write(&path.to_string_lossy(), 1000, 1, &vec![0.5; *len]).unwrap();
+        }
+        let theme = SoundTheme::load(&dir.to_string_lossy());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let options = SoundOptions {
+            theme: Some(theme.unwrap()),
+            ..SoundOptions::default()
+        };
+        let tick_len = Duration::from_secs(1);
+        let lengths = |evt| {
+            event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)
+                .iter()
+                .map(|sound| match sound.source {
+                    Source::Recording(ref sample) => sample.data.len(),
+                    _ => panic!("Expected a recording"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Each emphasis plays its own recording.
+        assert_eq!(lengths(Event::Beep(0)), vec![300]);
+        assert_eq!(lengths(Event::Beep(1)), vec![200]);
+        assert_eq!(lengths(Event::Beep(4)), vec![100]);
+
+        assert!(SoundTheme::load("/nonexistent/theme").is_err());
+    }
+
+    #[test]
+    fn click_length_test() {
+        let options = SoundOptions {
+            click_length: Some(Duration::from_millis(50)),
+            flam: Some(Duration::from_millis(20)),
+            ..SoundOptions::default()
+        };
+        let lengths = |evt, tick_len| {
+            event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)
+                .iter()
+                .map(|sound| sound.length.as_millis())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lengths(Event::Beep(1), Duration::from_millis(500)),
+            vec![50]
+        );
+
+        // Fast clicks are cut short to end before the next one, flams
+        // and all.
+        assert_eq!(lengths(Event::Beep(1), Duration::from_millis(50)), vec![40]);
+        assert_eq!(
+            lengths(Event::Beep(0), Duration::from_millis(50)),
+            vec![40, 40]
+        );
+    }
+
+    #[test]
+    fn flam_test() {
+        let delays = |flam, evt| {
+            let options = SoundOptions {
+                flam,
+                ..SoundOptions::default()
+            };
+            let tick_len = Duration::from_millis(500);
+            event_sounds(&options, &evt, None, None, Timbre::Sine, 1.0, tick_len)
+                .iter()
+                .map(|sound| sound.delay.as_millis())
+                .collect::<Vec<_>>()
+        };
+
+        // The grace note comes ahead of the beat, and the main click
+        // of a downbeat lands with the other clicks.
+        let flam = Some(Duration::from_millis(20));
+        assert_eq!(delays(flam, Event::Beep(0)), vec![0, 20]);
+        assert_eq!(delays(flam, Event::Beep(1)), vec![20]);
+        assert_eq!(delays(flam, Event::Beep(2)), vec![20]);
+        assert_eq!(delays(None, Event::Beep(0)), vec![0]);
+        assert_eq!(delays(None, Event::Beep(1)), vec![0]);
+    }
+
+    #[test]
+    fn route_test() {
+        assert!(Route::All.plays_on(1, 2));
+        assert!(Route::Left.plays_on(0, 2));
+        assert!(!Route::Left.plays_on(1, 2));
+        assert!(Route::Right.plays_on(1, 2));
+        assert!(!Route::Right.plays_on(0, 6));
+        assert!(!Route::Right.plays_on(2, 6));
+
+        // Mono outputs can't be split.
+        assert!(Route::Right.plays_on(0, 1));
+    }
+
+    #[test]
+    fn buffer_size_test() {
+        let supported = SupportedBufferSize::Range { min: 64, max: 4096 };
+        assert!(check_buffer_size(64, &supported).is_ok());
+        assert!(check_buffer_size(4096, &supported).is_ok());
+        assert!(check_buffer_size(32, &supported).is_err());
+        assert!(check_buffer_size(8192, &supported).is_err());
+        assert!(check_buffer_size(32, &SupportedBufferSize::Unknown).is_ok());
+    }
+
+    #[test]
+    fn envelope_test() {
+        // Beeps fade in from silence, and back out to it.
+        assert_eq!(envelope(0.0, 0.15), 0.0);
+        assert_eq!(envelope(0.15, 0.15), 0.0);
+        assert_eq!(envelope(0.05, 0.15), 1.0);
+        assert!(envelope(constants::ENVELOPE_ATTACK / 2.0, 0.15) < 1.0);
+
+        // Square waves start at full scale, but the envelope still
+        // keeps them from popping.
+        let mut generator = Generator::new(
+            Timbre::Square,
+            Pitch::Note(880.0),
+            Duration::from_millis(150),
+            44100,
+        );
+        assert_eq!(generator.next_sample(), 0.0);
+    }
 }
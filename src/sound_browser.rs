@@ -0,0 +1,208 @@
+// Browser for auditioning the sounds the metronome can click with, run
+// by the "sounds" subcommand.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::Event;
+use crate::config::Config;
+use crate::errors::*;
+use crate::hint_view::HintBar;
+use crate::keymap::{bind, Binding, GlobalMsg, KeyboardLayout, Keymap};
+use crate::sound::{AudioConfig, SoundBackend, SoundOptions, Timbre, VoiceSamples};
+use crate::termios_handler::TermiosHandler;
+use colorful::Colorful;
+use std::io::{stdin, stdout, Read, Write};
+use std::time::Duration;
+
+// Requests from the keyboard while browsing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BrowserMsg {
+    // Select the previous or next sound in the list.
+    Prev,
+    Next,
+
+    // Play the selected sound as a click of the given emphasis.
+    Play(u32),
+
+    // Leave the browser.
+    Quit,
+}
+
+impl GlobalMsg for BrowserMsg {
+    fn quit() -> BrowserMsg {
+        BrowserMsg::Quit
+    }
+}
+
+// A sound that can be auditioned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Preview {
+    // One of the built-in timbres.
+    Tone(Timbre),
+
+    // The recording of the given count (from 1) in the --voice
+    // directory.
+    Count(usize),
+}
+
+impl Preview {
+    // The name the sound is listed under.
+    fn label(self) -> String {
+        match self {
+            Preview::Tone(timbre) => timbre.name().to_string(),
+            Preview::Count(n) => format!("voice {}", n),
+        }
+    }
+}
+
+// Every sound there is to audition: the built-in timbres, then the
+// recorded counts if a voice was loaded.
+fn previews(voice: Option<&VoiceSamples>) -> Vec<Preview> {
+    let mut previews = vec![Preview::Tone(Timbre::Sine)];
+    let mut timbre = Timbre::Sine.next();
+    while timbre != Timbre::Sine {
+        previews.push(Preview::Tone(timbre));
+        timbre = timbre.next();
+    }
+    if let Some(voice) = voice {
+        previews.extend((1..=voice.highest()).map(Preview::Count));
+    }
+    previews
+}
+
+// The browser's key bindings, with the up and down keys placed for
+// the given keyboard layout.
+fn bindings(layout: KeyboardLayout) -> Vec<Binding<BrowserMsg>> {
+    vec![
+        bind(b"\x1B[A", || Some(BrowserMsg::Prev)).hint("Prev"),
+        bind(b"\x1B[B", || Some(BrowserMsg::Next)).hint("Next"),
+        bind(&[layout.key_at(b'k')], || Some(BrowserMsg::Prev)),
+        bind(&[layout.key_at(b'j')], || Some(BrowserMsg::Next)),
+        bind(b" ", || Some(BrowserMsg::Play(0))).hint("Downbeat"),
+        bind(b"\r", || Some(BrowserMsg::Play(0))),
+        bind(b"1", || Some(BrowserMsg::Play(0))),
+        bind(b"2", || Some(BrowserMsg::Play(1))).hint("Beat"),
+        bind(b"3", || Some(BrowserMsg::Play(2))).hint("Subdivision"),
+        bind(b"q", || Some(BrowserMsg::Quit)).hint("Quit"),
+    ]
+}
+
+// Lists the sounds, with the selected one highlighted and the current
+// timbre marked, followed by the hint bar. After the first time, the
+// list is drawn over the one drawn before.
+fn draw(previews: &[Preview], selected: usize, current: Timbre, hints: &HintBar, first: bool) {
+    if !first {
+        print!("\r\x1B[{}A", previews.len());
+    }
+    for (n, preview) in previews.iter().enumerate() {
+        let mark = if *preview == Preview::Tone(current) {
+            " (current)"
+        } else {
+            ""
+        };
+        let line = format!(" {}{} ", preview.label(), mark);
+        if n == selected {
+            print!("\r\x1B[K{}\r\n", line.reverse());
+        } else {
+            print!("\r\x1B[K{}\r\n", line);
+        }
+    }
+    hints.draw();
+
+    stdout().flush().unwrap();
+}
+
+// Lists the available sounds and plays whichever is selected on
+// request, until the user quits.
+pub fn browse(cfg: &Config) -> Result<()> {
+    // The browser auditions the timbres, which a sound theme would
+    // drown out.
+    let sound = AudioConfig::new(SoundOptions {
+        theme: None,
+        ..cfg.sound.clone()
+    })?;
+    let previews = previews(cfg.sound.voice.as_ref());
+    let mut keymap = Keymap::new(bindings(cfg.layout));
+    let hints = HintBar::new(keymap.hints());
+
+    let _termios = TermiosHandler::set_stdin_raw()?;
+    let mut selected = previews
+        .iter()
+        .position(|&preview| preview == Preview::Tone(cfg.timbre))
+        .unwrap_or(0);
+    draw(&previews, selected, cfg.timbre, &hints, true);
+
+    let mut input = stdin();
+    loop {
+        let mut buf = [0];
+        if input.read_exact(&mut buf).is_err() {
+            break;
+        }
+        match keymap.send(buf[0]) {
+            Some(BrowserMsg::Prev) => selected = selected.saturating_sub(1),
+            Some(BrowserMsg::Next) => selected = (selected + 1).min(previews.len() - 1),
+            // Nothing follows an audition, so it gets as much room as
+            // it wants.
+            Some(BrowserMsg::Play(emph)) => match previews[selected] {
+                Preview::Tone(timbre) => sound.play_event(
+                    &Event::Beep(emph),
+                    None,
+                    None,
+                    timbre,
+                    cfg.volume,
+                    Duration::from_secs(1),
+                ),
+                Preview::Count(n) => sound.play_event(
+                    &Event::Beep(emph),
+                    Some(n - 1),
+                    None,
+                    cfg.timbre,
+                    cfg.volume,
+                    Duration::from_secs(1),
+                ),
+            },
+            Some(BrowserMsg::Quit) => break,
+            None => continue,
+        }
+        draw(&previews, selected, cfg.timbre, &hints, false);
+    }
+
+    print!("\r\n");
+    if let Preview::Tone(timbre) = previews[selected] {
+        print!("To click with {0}, use --timbre {0}.\r\n", timbre.name());
+    }
+    stdout().flush().unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_test() {
+        let previews = previews(None);
+        assert_eq!(previews.len(), 5);
+        assert_eq!(previews[0], Preview::Tone(Timbre::Sine));
+        assert_eq!(previews[4].label(), "woodblock");
+        assert_eq!(Preview::Count(2).label(), "voice 2");
+
+        let mut keymap = Keymap::new(bindings(KeyboardLayout::Dvorak));
+        assert_eq!(keymap.send(b'h'), Some(BrowserMsg::Next));
+        assert_eq!(keymap.send(b'3'), Some(BrowserMsg::Play(2)));
+    }
+}
@@ -0,0 +1,179 @@
+// Line editor for entering a new rhythm specification string while
+// the metronome keeps running.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::BeatSpec;
+use crate::spec_view::SpecView;
+
+// Outcome of sending a key to the spec editor.
+pub enum SpecEntryResult {
+    // Keep editing.
+    Continue,
+
+    // The user gave up on editing; keep the old rhythm.
+    Cancel,
+
+    // The user entered a valid rhythm.
+    Submit(BeatSpec),
+
+    // The user asked to quit the program.
+    Quit,
+}
+
+pub struct SpecEntry {
+    // The spec string being edited.
+    text: Vec<char>,
+
+    // Position of the cursor within `text`.
+    cursor: usize,
+
+    // Partial escape sequence entered, as in ControllerState.
+    partial: Vec<u8>,
+
+    // The on-screen representation of the editor.
+    view: SpecView,
+}
+
+impl SpecEntry {
+    // Starts editing with the given initial spec string.
+    pub fn new(spec: &str) -> Self {
+        let text: Vec<char> = spec.chars().collect();
+        let cursor = text.len();
+        Self {
+            view: SpecView::new(&text, cursor),
+            text,
+            cursor,
+            partial: vec![],
+        }
+    }
+
+    // Processes a byte received from the keyboard.
+    pub fn send(&mut self, key: u8) -> SpecEntryResult {
+        if !self.partial.is_empty() || key == b'\x1B' {
+            self.partial.push(key);
+            match &self.partial[..] {
+                b"\x1B" | b"\x1B[" => {}
+                b"\x1B[C" => {
+                    // Right
+                    self.cursor = (self.cursor + 1).min(self.text.len());
+                    self.partial.clear();
+                }
+                b"\x1B[D" => {
+                    // Left
+                    self.cursor = self.cursor.saturating_sub(1);
+                    self.partial.clear();
+                }
+                _ => self.partial.clear(),
+            }
+            self.update_view();
+            return SpecEntryResult::Continue;
+        }
+
+        match key {
+            b'\r' | b'\n' => {
+                let spec: String = self.text.iter().collect();
+                match BeatSpec::from_rhythmspec(&spec) {
+                    Ok(bs) => return SpecEntryResult::Submit(bs),
+                    Err(e) => self.view.set_error(Some(e.to_string())),
+                }
+            }
+            b'\x7F' | b'\x08' if self.cursor > 0 => {
+                // Backspace
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            b'\x01' => {
+                // C-a
+                self.cursor = 0;
+            }
+            b'\x05' => {
+                // C-e
+                self.cursor = self.text.len();
+            }
+            b'\x07' => {
+                // C-g
+                return SpecEntryResult::Cancel;
+            }
+            b'\x03' => {
+                // Control-C
+                return SpecEntryResult::Quit;
+            }
+            b' '..=b'~' => {
+                self.text.insert(self.cursor, key as char);
+                self.cursor += 1;
+            }
+            _ => {}
+        }
+
+        self.update_view();
+        SpecEntryResult::Continue
+    }
+
+    // The keys to list in the hint bar while editing, with their
+    // labels.
+    pub fn hints() -> Vec<(String, &'static str)> {
+        vec![
+            ("Enter".to_string(), "Apply"),
+            ("^G".to_string(), "Cancel"),
+            ("^A".to_string(), "Start"),
+            ("^E".to_string(), "End"),
+        ]
+    }
+
+    // Copies the editor's state to its view.
+    fn update_view(&mut self) {
+        self.view.set_text(&self.text, self.cursor);
+    }
+
+    // Draws the editor on the current line.
+    pub fn draw(&self) {
+        self.view.draw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_str(entry: &mut SpecEntry, s: &[u8]) {
+        for c in s {
+            if let SpecEntryResult::Submit(_) = entry.send(*c) {
+                panic!("Submitted early");
+            }
+        }
+    }
+
+    #[test]
+    fn editing_test() {
+        let mut entry = SpecEntry::new("01");
+
+        // Move left once and insert, then delete the last character.
+        type_str(&mut entry, b"\x1B[D2\x05\x7F");
+        assert_eq!(entry.text.iter().collect::<String>(), "02");
+
+        // Invalid specs stay in the editor.
+        type_str(&mut entry, b"z\r");
+        assert_eq!(entry.text.iter().collect::<String>(), "02z");
+
+        type_str(&mut entry, b"\x7F");
+        match entry.send(b'\r') {
+            SpecEntryResult::Submit(bs) => assert_eq!(bs.get_ticks().len(), 2),
+            _ => panic!("Didn't submit valid spec"),
+        }
+    }
+}
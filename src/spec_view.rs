@@ -0,0 +1,73 @@
+// Display for the rhythm spec editor.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use colorful::Color;
+use colorful::Colorful;
+use std::io::{stdout, Write};
+
+pub struct SpecView {
+    // The spec string being edited.
+    text: String,
+
+    // Position of the cursor, in characters.
+    cursor: usize,
+
+    // Error from the last attempt to apply the spec, if any.
+    error: Option<String>,
+}
+
+impl SpecView {
+    pub fn new(text: &[char], cursor: usize) -> Self {
+        Self {
+            text: text.iter().collect(),
+            cursor,
+            error: None,
+        }
+    }
+
+    // Sets the text being edited and the cursor position. Editing the
+    // text clears any error message.
+    pub fn set_text(&mut self, text: &[char], cursor: usize) {
+        let text: String = text.iter().collect();
+        if text != self.text {
+            self.error = None;
+        }
+        self.text = text;
+        self.cursor = cursor;
+    }
+
+    // Sets the parse error to show next to the text.
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+    }
+
+    // Draws the SpecView on the current line, and leaves the terminal
+    // cursor at the editing position.
+    pub fn draw(&self) {
+        print!("\r\x1B[K:{}", self.text);
+        if let Some(e) = &self.error {
+            print!("  {}", e.as_str().color(Color::LightRed));
+        }
+
+        // Move back to the start of the line, then over to the
+        // cursor (past the leading ':').
+        print!("\r\x1B[{}C", self.cursor + 1);
+
+        stdout().flush().unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+// Sticking drills: labels each click of a rhythm with the hand that
+// plays it, alternating R L R L, for practicing rudiments.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::beat_spec::{BeatSpec, Event};
+use crate::constants;
+
+// A hand to play a click with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hand {
+    Right,
+    Left,
+}
+
+impl Hand {
+    // The other hand.
+    fn other(self) -> Hand {
+        match self {
+            Hand::Right => Hand::Left,
+            Hand::Left => Hand::Right,
+        }
+    }
+
+    // Label shown for the hand.
+    pub fn label(self) -> &'static str {
+        match self {
+            Hand::Right => "R",
+            Hand::Left => "L",
+        }
+    }
+
+    // Factor the pitch of clicks played with this hand is scaled by.
+    pub fn pitch(self) -> f64 {
+        match self {
+            Hand::Right => 1.0,
+            Hand::Left => constants::STICKING_LEFT_PITCH,
+        }
+    }
+}
+
+// The clicks of a BeatSpec, labeled with alternating hands. A measure
+// with an odd number of clicks starts on the other hand the next time
+// around, so the labels cover two measures.
+pub struct Sticking {
+    hands: Vec<Option<Hand>>,
+    ticks: usize,
+}
+
+impl Sticking {
+    pub fn new(rhythm: &BeatSpec) -> Sticking {
+        let ticks = rhythm.get_ticks();
+        let mut hand = Hand::Right;
+        let mut hands = vec![];
+        for ev in ticks.iter().chain(ticks.iter()) {
+            match ev {
+                Event::Rest => hands.push(None),
+                Event::Beep(_) => {
+                    hands.push(Some(hand));
+                    hand = hand.other();
+                }
+            }
+        }
+
+        Sticking {
+            hands,
+            ticks: ticks.len(),
+        }
+    }
+
+    // The hand that plays the given tick of the given measure, or None
+    // if the tick is a rest.
+    pub fn hand(&self, measure: usize, tick: usize) -> Option<Hand> {
+        self.hands[measure % 2 * self.ticks + tick]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sticking_test() {
+        // Three clicks a measure: R L R, then L R L.
+        let sticking = Sticking::new(&BeatSpec::from_rhythmspec("0.11!").unwrap());
+        assert_eq!(sticking.hand(0, 0), Some(Hand::Right));
+        assert_eq!(sticking.hand(0, 1), None);
+        assert_eq!(sticking.hand(0, 3), Some(Hand::Right));
+        assert_eq!(sticking.hand(1, 0), Some(Hand::Left));
+        assert_eq!(sticking.hand(2, 0), Some(Hand::Right));
+        assert_eq!(sticking.hand(3, 2), Some(Hand::Right));
+    }
+}
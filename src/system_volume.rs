@@ -0,0 +1,138 @@
+// Control of the operating system's output volume, for users who want
+// the volume keys to act like their computer's own.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use error_chain::bail;
+use std::process::Command;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+// Feeds volumes, as percentages, to the thread that sets them.
+static SETTER: OnceLock<Sender<u32>> = OnceLock::new();
+
+// Reads the system output volume, on a scale from 0 to 1.
+pub fn get_system_volume() -> Result<f64> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("osascript")
+            .args(["-e", "output volume of (get volume settings)"])
+            .output()?
+    } else {
+        // Works with both PulseAudio and PipeWire (through
+        // pipewire-pulse).
+        Command::new("pactl")
+            .args(["get-sink-volume", "@DEFAULT_SINK@"])
+            .output()?
+    };
+
+    if !output.status.success() {
+        bail!("Couldn't read the system volume");
+    }
+
+    match parse_percent(&String::from_utf8_lossy(&output.stdout)) {
+        Some(percent) => Ok(percent / 100.0),
+        None => bail!("Couldn't read the system volume"),
+    }
+}
+
+// Sets the system output volume, on a scale from 0 to 1. This runs in
+// the background, so it doesn't hold up the metronome; failures are
+// ignored, since there's nothing useful to do about them mid-session.
+pub fn set_system_volume(volume: f64) {
+    let percent = (volume * 100.0).round() as u32;
+    let setter = SETTER.get_or_init(|| {
+        let (send, recv) = channel();
+        thread::spawn(move || run_setter(recv));
+        send
+    });
+    let _ = setter.send(percent);
+}
+
+// Sets volumes one at a time as they're asked for. A volume key held
+// down asks faster than pactl can keep up, so the steps asked for
+// meanwhile are skipped in favor of the latest, which is where the
+// volume ends up.
+fn run_setter(recv: Receiver<u32>) {
+    while let Ok(percent) = recv.recv() {
+        let percent = latest(&recv, percent);
+        let _ = if cfg!(target_os = "macos") {
+            Command::new("osascript")
+                .args(["-e", &format!("set volume output volume {}", percent)])
+                .status()
+        } else {
+            Command::new("pactl")
+                .args([
+                    "set-sink-volume",
+                    "@DEFAULT_SINK@",
+                    &format!("{}%", percent),
+                ])
+                .status()
+        };
+    }
+}
+
+// The last of the values waiting on a channel, or `first` if there are
+// none.
+fn latest<T>(recv: &Receiver<T>, first: T) -> T {
+    recv.try_iter().last().unwrap_or(first)
+}
+
+// Finds the first volume percentage in the output of pactl (e.g.
+// "Volume: front-left: 32768 /  50% / -18.06 dB, ...") or osascript
+// (a bare number, e.g. "50").
+fn parse_percent(output: &str) -> Option<f64> {
+    let output = output.trim();
+    if let Ok(percent) = output.parse() {
+        return Some(percent);
+    }
+
+    output
+        .split_whitespace()
+        .find(|word| word.ends_with('%'))
+        .and_then(|word| word.trim_end_matches('%').parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_percent_test() {
+        assert_eq!(
+            parse_percent(
+                "Volume: front-left: 32768 /  50% / -18.06 dB,   \
+                 front-right: 32768 /  50% / -18.06 dB\n        balance 0.00\n"
+            ),
+            Some(50.0)
+        );
+        assert_eq!(parse_percent("75\n"), Some(75.0));
+        assert_eq!(parse_percent("No such sink"), None);
+    }
+
+    #[test]
+    fn latest_test() {
+        let (send, recv) = channel();
+        assert_eq!(latest(&recv, 40), 40);
+        for percent in [45, 50, 55] {
+            send.send(percent).unwrap();
+        }
+        assert_eq!(latest(&recv, 40), 55);
+        assert!(recv.try_recv().is_err());
+    }
+}
@@ -17,42 +17,99 @@
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::app_state::Keycode;
-use crate::app_state::{AppState, StateTransition, TickCommand};
-use crate::beat_spec::BeatSpec;
+use crate::app_state::{AppState, InputSource, StateTransition, TickCommand};
 use crate::constants;
-use crate::met_model::MetronomeState;
-use crate::sound::AudioConfig;
+use crate::hint_view::HintBar;
+use crate::keymap::{bind, GlobalMsg, Keymap};
+use crate::met_controller::ControllerMsg;
+use crate::met_model::{MetronomeSettings, MetronomeState};
+use crate::model_event::ModelEvent;
+use crate::tap_stats;
 use crate::tap_view::TapView;
 use std::time::{Duration, Instant};
 
+// Requests the user can make in tap mode.
+#[derive(Debug)]
+enum TapMsg {
+    // Registers a tap.
+    Tap,
+
+    // Goes back to the metronome, at the tapped tempo.
+    Leave,
+
+    // Exits the program.
+    Quit,
+}
+
+impl GlobalMsg for TapMsg {
+    fn quit() -> TapMsg {
+        TapMsg::Quit
+    }
+}
+
+// Sets up tap mode's keymap; any key without a binding of its own
+// leaves tap mode.
+fn tap_keymap() -> Keymap<TapMsg> {
+    Keymap::new(vec![bind(b",", || Some(TapMsg::Tap)).hint("Tap")])
+        .with_fallback(|| Some(TapMsg::Leave))
+        .with_fallback_hint("Done")
+}
+
 // State of the tap mode.
 pub struct TapState {
     // Times at which each tap occurred.
     times: Vec<Instant>,
 
-    // The rhythm to create when we go back to Metronome mode.
-    rhythm: BeatSpec,
-
-    // Configuration for producing tick sounds.
-    cfg: AudioConfig,
-
-    // The volume at which to produce tick sounds.
-    volume: f64,
+    // The settings to restore when we go back to Metronome mode.
+    settings: MetronomeSettings,
 
     // The on-screen representation of the TapState.
     view: TapView,
+
+    // Interprets key presses.
+    controller: Keymap<TapMsg>,
+
+    // The hint bar, if hints are turned on.
+    hints: Option<HintBar>,
 }
 
 impl TapState {
     // Constructs a new TapState given the previous MetronomeState.
-    pub fn new(rhythm: BeatSpec, cfg: AudioConfig, volume: f64) -> Self {
+    pub fn new(settings: MetronomeSettings) -> Self {
+        let controller = tap_keymap();
+        let hints = if settings.hints {
+            Some(HintBar::new(controller.hints()))
+        } else {
+            None
+        };
+        let mut view = TapView::new(settings.volume, settings.indicator.columns());
+        view.set_volume_db(settings.volume_db);
         Self {
             // The first tap occurs the moment this state is invoked.
             times: vec![Instant::now()],
-            rhythm,
-            cfg,
-            volume,
-            view: TapView::new(volume),
+            view,
+            controller,
+            hints,
+            settings,
+        }
+    }
+
+    // Draws tap mode's hint bar over the metronome's, if hints are
+    // turned on.
+    fn draw_hints(&self) {
+        let hints = match &self.hints {
+            Some(hints) => hints,
+            None => return,
+        };
+        if self.settings.conductor {
+            hints.draw_bottom();
+        } else {
+            // The hint bar goes under the score line, if there is
+            // one.
+            let down = if self.settings.score { 2 } else { 1 };
+            print!("\x1B[{}B", down);
+            hints.draw();
+            print!("\x1B[{}A", down);
         }
     }
 
@@ -86,40 +143,130 @@ impl TapState {
 
     // Leaves Tap mode and returns to Metronome mode.
     fn exit(&self) -> (StateTransition, TickCommand) {
-        (
-            StateTransition::To(Box::new(MetronomeState::new(
-                &self.rhythm,
-                self.cfg.clone(),
-                self.volume,
-                match self.calc_tempo() {
-                    None => constants::DEF_TEMPO,
-                    Some(x) => x,
-                },
-            ))),
-            TickCommand::Set(Duration::from_secs(0)),
-        )
+        log::info!(
+            "Leaving tap mode after {} taps, tempo {:?}",
+            self.times.len(),
+            self.calc_tempo()
+        );
+        let deviations = tap_stats::deviations(&self.times);
+        if !deviations.is_empty() {
+            log::info!(
+                "Tap intervals strayed {:.0} ms from the average: {}",
+                tap_stats::spread(&deviations) * 1000.0,
+                tap_stats::sparkline(&deviations, deviations.len())
+            );
+        }
+        let tempo = match self.calc_tempo() {
+            None => constants::DEF_TEMPO,
+            Some(x) => x,
+        };
+        if let Some(link) = &self.settings.link {
+            link.set_tempo(tempo);
+        }
+        self.settings.bus.publish(&ModelEvent::TempoChanged(tempo));
+        let mut state = MetronomeState::new(MetronomeSettings {
+            tempo,
+            ..self.settings.clone()
+        });
+
+        // Having tapped, come back in on the beat where the taps left
+        // off, taking the last tap as a downbeat, rather than starting
+        // a measure whenever the key to leave was pressed. The taps
+        // did the job of a count-in.
+        let now = Instant::now();
+        let wait = match (self.times.len(), self.times.last()) {
+            (2.., Some(&last)) => {
+                let last = last.checked_sub(self.settings.tap_latency).unwrap_or(last);
+                let beat = Duration::from_secs_f64(60.0 / tempo);
+                let (at, beats) = next_beat(last, beat, self.settings.latency, now);
+                state.come_in(at, beats as usize);
+                at.saturating_duration_since(now)
+            }
+            _ => Duration::from_secs(0),
+        };
+        (StateTransition::To(Box::new(state)), TickCommand::Set(wait))
     }
 }
 
+// The first beat after the last tap, at the given beat length, that
+// can still be heard on time when clicks take `latency` to be heard
+// after they're played. Returns when to play it, and how many beats
+// after the last tap it falls.
+fn next_beat(last_tap: Instant, beat: Duration, latency: Duration, now: Instant) -> (Instant, u32) {
+    let elapsed = (now + latency).saturating_duration_since(last_tap);
+    let beats = (elapsed.as_secs_f64() / beat.as_secs_f64()) as u32 + 1;
+    (last_tap + beat * beats - latency, beats)
+}
+
 impl AppState for TapState {
     fn tick(&mut self) -> (StateTransition, TickCommand) {
         self.view.draw();
+        self.draw_hints();
         (StateTransition::NoChange, TickCommand::None)
     }
 
     fn keypress(&mut self, key: Keycode, _time: Duration) -> (StateTransition, TickCommand) {
         // Tap controller is simple enough that it doesn't get its own
-        // file. (It's self-contained in this function here.)
-        match key {
-            Keycode::Key(b',') => {
+        // file; its keymap is set up in tap_keymap() above.
+        let key = match key {
+            Keycode::Key(key) => key,
+            Keycode::NoKey => return self.exit(),
+        };
+
+        match self.controller.send(key) {
+            Some(TapMsg::Tap) => {
                 self.times.push(Instant::now());
+                self.view.set_deviations(tap_stats::deviations(&self.times));
+                self.view.draw();
+                self.draw_hints();
                 (StateTransition::NoChange, TickCommand::None)
             }
-            Keycode::Key(b'\x03') => {
-                // Exit on Control-C
-                (StateTransition::Exit, TickCommand::None)
-            }
-            _ => self.exit(),
+            Some(TapMsg::Leave) => self.exit(),
+            Some(TapMsg::Quit) => (StateTransition::Exit, TickCommand::None),
+            None => (StateTransition::NoChange, TickCommand::None),
+        }
+    }
+
+    fn message(
+        &mut self,
+        msg: ControllerMsg,
+        _source: InputSource,
+    ) -> (StateTransition, TickCommand) {
+        // Taps only come from the keyboard, so the only request that
+        // makes sense here is to quit.
+        match msg {
+            ControllerMsg::Quit => (StateTransition::Exit, TickCommand::None),
+            _ => (StateTransition::NoChange, TickCommand::None),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_beat_test() {
+        let tap = Instant::now();
+        let ms = Duration::from_millis;
+        let beat = ms(500);
+
+        // Leaving partway through a beat comes in at the end of it.
+        assert_eq!(next_beat(tap, beat, ms(0), tap + ms(100)), (tap + beat, 1));
+        assert_eq!(
+            next_beat(tap, beat, ms(0), tap + ms(1200)),
+            (tap + ms(1500), 3)
+        );
+
+        // With output latency, clicks are played early, and a beat too
+        // close to be heard in time is skipped.
+        assert_eq!(
+            next_beat(tap, beat, ms(100), tap + ms(100)),
+            (tap + ms(400), 1)
+        );
+        assert_eq!(
+            next_beat(tap, beat, ms(100), tap + ms(450)),
+            (tap + ms(900), 2)
+        );
+    }
+}
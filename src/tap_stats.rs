@@ -0,0 +1,94 @@
+// Statistics on how steadily a series of taps was played, and a small
+// in-terminal graph of them.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Instant;
+
+// Block characters for the graph, from lowest to highest.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// How far each interval between consecutive taps strayed from the
+// average interval, in seconds: positive for a late tap, negative for
+// an early one.
+pub fn deviations(times: &[Instant]) -> Vec<f64> {
+    let intervals: Vec<f64> = times
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).as_secs_f64())
+        .collect();
+    if intervals.is_empty() {
+        return vec![];
+    }
+
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    intervals.iter().map(|interval| interval - mean).collect()
+}
+
+// The typical size of the deviations (their root mean square), in
+// seconds.
+pub fn spread(deviations: &[f64]) -> f64 {
+    if deviations.is_empty() {
+        return 0.0;
+    }
+    let squares: f64 = deviations.iter().map(|dev| dev * dev).sum();
+    (squares / deviations.len() as f64).sqrt()
+}
+
+// Draws the deviations as a sparkline, one character each, with an
+// interval right on the average at half height and the largest
+// deviation either way at the top or bottom. Only the last `width`
+// deviations are drawn.
+pub fn sparkline(deviations: &[f64], width: usize) -> String {
+    let shown = &deviations[deviations.len().saturating_sub(width)..];
+    let scale = shown.iter().fold(0.0_f64, |max, dev| max.max(dev.abs()));
+
+    shown
+        .iter()
+        .map(|dev| {
+            let height = if scale > 0.0 {
+                (dev / scale + 1.0) / 2.0
+            } else {
+                0.5
+            };
+            let level = (height * (BARS.len() - 1) as f64).round() as usize;
+            BARS[level.min(BARS.len() - 1)]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sparkline_test() {
+        let start = Instant::now();
+        let times: Vec<Instant> = [0, 500, 1000, 1600, 2000]
+            .iter()
+            .map(|&ms| start + Duration::from_millis(ms))
+            .collect();
+        let devs = deviations(&times);
+        assert_eq!(devs.len(), 4);
+        assert!(devs[0].abs() < 1e-9 && (devs[2] - 0.1).abs() < 1e-9);
+        assert!((spread(&devs) - 0.005f64.sqrt()).abs() < 1e-9);
+
+        assert_eq!(sparkline(&devs, 10), "▅▅█▁");
+        assert_eq!(sparkline(&devs, 2), "█▁");
+        assert_eq!(sparkline(&[0.0, 0.0], 10), "▅▅");
+    }
+}
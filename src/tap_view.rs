@@ -16,7 +16,8 @@
 // You should have received a copy of the GNU General Public License
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::constants;
+use crate::met_view::{volume_color, volume_text};
+use crate::tap_stats;
 use colorful::Color;
 use colorful::Colorful;
 use std::fmt::Display;
@@ -25,14 +26,40 @@ use std::io::{stdout, Write};
 pub struct TapView {
     // The volume from 0 to 1.
     volume: f64,
+
+    // Whether to show the volume in decibels.
+    volume_db: bool,
+
+    // Width of the measure progress indicator, which shows how
+    // steadily the user is tapping.
+    width: usize,
+
+    // How far each interval between taps so far strayed from the
+    // average, in seconds.
+    deviations: Vec<f64>,
 }
 
 // TODO: There's a lot of repeated and very similar code here from
 // met_view.rs. Make a shared trait or set of functions for drawing
 // "things that look look kind of like the metronome view".
 impl TapView {
-    pub fn new(volume: f64) -> Self {
-        Self { volume }
+    pub fn new(volume: f64, width: usize) -> Self {
+        Self {
+            volume,
+            volume_db: false,
+            width,
+            deviations: vec![],
+        }
+    }
+
+    // Shows the volume in decibels rather than percent.
+    pub fn set_volume_db(&mut self, volume_db: bool) {
+        self.volume_db = volume_db;
+    }
+
+    // Sets the deviations of the intervals between taps so far.
+    pub fn set_deviations(&mut self, deviations: Vec<f64>) {
+        self.deviations = deviations;
     }
 
     // Sets the volume level, on a scale from 0 to 1.
@@ -46,18 +73,31 @@ impl TapView {
     }
 
     // Visual indicator for the for the progress through the measure.
-    // In Tap mode, it's currently just kept blank.
+    // In Tap mode, it instead graphs how far each interval between
+    // taps strayed from the average, followed by the typical size of
+    // the deviations. It stays blank until there are a few taps to go
+    // on.
     fn progress_indicator(&self) -> String {
-        " ".repeat(constants::MEAS_INDIC_WIDTH)
+        if self.deviations.len() < 2 {
+            return " ".repeat(self.width);
+        }
+
+        let spread = format!(
+            " \u{b1}{} ms",
+            (tap_stats::spread(&self.deviations) * 1000.0).round()
+        );
+        let room = self.width.saturating_sub(spread.chars().count());
+        let graph = if room > 0 {
+            tap_stats::sparkline(&self.deviations, room) + &spread
+        } else {
+            tap_stats::sparkline(&self.deviations, self.width)
+        };
+        format!("{:1$}", graph, self.width)
     }
 
     // Visual indicator for the volume level.
     fn volume_indicator(&self) -> String {
-        format!(
-            "{:1$}%",
-            (self.volume * 100.0) as u32,
-            constants::NUM_INDIC_WIDTH,
-        )
+        volume_text(self.volume, self.volume_db)
     }
 
     // Draws the TapView on the screen.
@@ -84,7 +124,7 @@ impl Display for TapView {
             self.progress_indicator().color(Color::Green),
             "]".color(Color::Yellow),
             "(".color(Color::Yellow),
-            self.volume_indicator().color(Color::LightRed),
+            self.volume_indicator().color(volume_color(self.volume)),
             ")".color(Color::Yellow),
         )
     }
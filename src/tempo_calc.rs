@@ -0,0 +1,232 @@
+// Tempo calculator, run by the "calc" subcommand: converts between
+// tempos, note values, note lengths and frequencies.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use crate::locale;
+use error_chain::bail;
+
+// The note values in the table, with their lengths in quarter notes.
+const NOTES: &[(&str, f64)] = &[
+    ("1/1", 4.0),
+    ("1/2", 2.0),
+    ("1/4", 1.0),
+    ("1/8", 0.5),
+    ("1/16", 0.25),
+    ("1/32", 0.125),
+];
+
+// How each note value in the table is played: its name, and how much
+// that scales its length by.
+const FEELS: &[(&str, f64)] = &[("straight", 1.0), ("dotted", 1.5), ("triplet", 2.0 / 3.0)];
+
+// A question for the calculator.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    // The tempo, in quarter notes per minute.
+    pub tempo: f64,
+
+    // Length of the note value asked about, in quarter notes, if any.
+    pub note: Option<f64>,
+}
+
+// Parses a question for the calculator: a tempo, e.g. "120"; a note
+// value at a tempo, e.g. "16ths at 120"; or the length of a note value
+// at an unknown tempo, in milliseconds or as a frequency or rate, e.g.
+// "e=480", "e=480ms", "e=4hz" or "e=240bpm".
+pub fn parse_query(query: &str) -> Result<Query> {
+    let query = query.trim().to_lowercase();
+    if let Some((note, tempo)) = query.split_once(" at ") {
+        return Ok(Query {
+            tempo: parse_tempo(tempo)?,
+            note: Some(parse_note(note)?),
+        });
+    }
+
+    let (note, amount) = match query.split_once('=') {
+        Some(question) => question,
+        None => {
+            return Ok(Query {
+                tempo: parse_tempo(&query)?,
+                note: None,
+            })
+        }
+    };
+    let note = parse_note(note)?;
+    let amount = amount.trim();
+    let ms = if let Some(hz) = amount.strip_suffix("hz") {
+        1000.0 / parse_positive(hz, amount)?
+    } else if let Some(rate) = amount.strip_suffix("bpm") {
+        60000.0 / parse_positive(rate, amount)?
+    } else {
+        parse_positive(amount.strip_suffix("ms").unwrap_or(amount), amount)?
+    };
+    Ok(Query {
+        tempo: 60000.0 * note / ms,
+        note: Some(note),
+    })
+}
+
+// Parses a note value, e.g. "q", "8th", "16ths", "eighth" or "1/8",
+// optionally dotted ("e.", "dotted eighth") or a triplet ("8t",
+// "eighth triplets"). Returns its length in quarter notes.
+pub fn parse_note(name: &str) -> Result<f64> {
+    let mut note = name.trim();
+    let mut scale = 1.0;
+    if let Some(base) = note
+        .strip_prefix("dotted ")
+        .or_else(|| note.strip_suffix('.'))
+    {
+        note = base;
+        scale = 1.5;
+    } else if let Some(base) = note
+        .strip_suffix(" triplets")
+        .or_else(|| note.strip_suffix(" triplet"))
+        .or_else(|| note.strip_suffix('t'))
+    {
+        note = base;
+        scale = 2.0 / 3.0;
+    }
+    if note.len() > 1 {
+        note = note.strip_suffix('s').unwrap_or(note);
+    }
+    if note.starts_with(|c: char| c.is_ascii_digit()) {
+        note = note
+            .strip_suffix("th")
+            .or_else(|| note.strip_suffix("nd"))
+            .unwrap_or(note);
+    }
+    note = note.strip_prefix("1/").unwrap_or(note);
+
+    let length = match note {
+        "w" | "whole" | "1" => 4.0,
+        "h" | "half" | "halve" | "2" => 2.0,
+        "q" | "quarter" | "4" => 1.0,
+        "e" | "eighth" | "8" => 0.5,
+        "s" | "sixteenth" | "16" => 0.25,
+        "thirty-second" | "32" => 0.125,
+        _ => bail!(format!("Unknown note value {}", name.trim())),
+    };
+    Ok(length * scale)
+}
+
+// Parses a tempo in bpm, with or without the "bpm".
+fn parse_tempo(arg: &str) -> Result<f64> {
+    parse_positive(arg.trim().strip_suffix("bpm").unwrap_or(arg), arg)
+}
+
+// Parses a positive number, naming the whole of `arg` if it isn't one.
+fn parse_positive(number: &str, arg: &str) -> Result<f64> {
+    match locale::parse(number.trim()) {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(x),
+        _ => bail!(format!("Expected a positive number, got {}", arg.trim())),
+    }
+}
+
+// Length in milliseconds of a note of the given length, in quarter
+// notes, at the given tempo.
+pub fn note_ms(tempo: f64, length: f64) -> f64 {
+    60000.0 / tempo * length
+}
+
+// Describes a note's length in milliseconds and as a frequency.
+fn describe(ms: f64) -> String {
+    format!(
+        "{} ms, {} Hz",
+        locale::format((ms * 10.0).round() / 10.0),
+        locale::format((100000.0 / ms).round() / 100.0)
+    )
+}
+
+// Prints the answer to a question: the tempo, the note asked about,
+// and a table of the lengths of the common note values.
+pub fn print(query: &Query) {
+    println!(
+        "Tempo: {} bpm",
+        locale::format((query.tempo * 100.0).round() / 100.0)
+    );
+    if let Some(note) = query.note {
+        println!("Note:  {}", describe(note_ms(query.tempo, note)));
+    }
+    println!();
+
+    print!("{:<6}", "");
+    for (feel, _) in FEELS {
+        print!("{:>22}", feel);
+    }
+    println!();
+    for (name, length) in NOTES {
+        print!("{:<6}", name);
+        for (_, scale) in FEELS {
+            print!("{:>22}", describe(note_ms(query.tempo, length * scale)));
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_test() {
+        for name in ["q", "quarter", "4", "4th", "1/4", "quarters"] {
+            assert_eq!(parse_note(name).unwrap(), 1.0);
+        }
+        for name in ["s", "16ths", "sixteenth", "1/16"] {
+            assert_eq!(parse_note(name).unwrap(), 0.25);
+        }
+        assert_eq!(parse_note("e.").unwrap(), 0.75);
+        assert_eq!(parse_note("dotted eighth").unwrap(), 0.75);
+        assert_eq!(parse_note("8t").unwrap(), 1.0 / 3.0);
+        assert_eq!(parse_note("eighth triplets").unwrap(), 1.0 / 3.0);
+        assert_eq!(parse_note("halves").unwrap(), 2.0);
+        assert!(parse_note("5").is_err());
+        assert!(parse_note("").is_err());
+    }
+
+    #[test]
+    fn query_test() {
+        assert_eq!(
+            parse_query("120").unwrap(),
+            Query {
+                tempo: 120.0,
+                note: None
+            }
+        );
+        assert_eq!(
+            parse_query("16ths at 120 bpm").unwrap(),
+            Query {
+                tempo: 120.0,
+                note: Some(0.25)
+            }
+        );
+        assert_eq!(note_ms(120.0, 0.25), 125.0);
+
+        // A note's length gives the tempo, whichever way it's put.
+        for query in ["e=480", "E = 480ms", "e=125bpm", "e=480,0"] {
+            assert!((parse_query(query).unwrap().tempo - 62.5).abs() < 1e-9);
+        }
+        assert_eq!(parse_query("q.=2hz").unwrap().tempo, 180.0);
+
+        assert!(parse_query("e=0").is_err());
+        assert!(parse_query("e=fast").is_err());
+        assert!(parse_query("x at 120").is_err());
+        assert!(parse_query("-60").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+// Tempo detection for recordings, to set the click to the tempo of a
+// song being played along with.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use crate::errors::*;
+use crate::wav::Sample;
+use error_chain::bail;
+
+// Estimates the tempo of a recording, in beats per minute.
+//
+// The recording is cut into short frames, and each frame is given an
+// onset strength: how much louder it is than the frame before. The
+// onsets of a steady beat repeat every beat, so the tempo is found
+// from the lag at which the onset strengths best match up with
+// themselves. A steady beat matches up just as well at two or three
+// beats' lag, so tempos near DETECT_PRIOR_TEMPO are favored, to choose
+// between a tempo and its multiples and fractions; this folds most
+// tempos into the octave around it.
+pub fn detect_tempo(sample: &Sample) -> Result<f64> {
+    let frame_len = ((sample.rate as f64 * constants::DETECT_HOP).round() as usize).max(1);
+    let hop = frame_len as f64 / sample.rate as f64;
+    let onsets = onset_strengths(sample, frame_len);
+
+    let min_lag = (60.0 / (constants::DETECT_MAX_TEMPO * hop)).floor() as usize;
+    let max_lag = (60.0 / (constants::DETECT_MIN_TEMPO * hop)).ceil() as usize;
+    if onsets.len() < max_lag * 4 {
+        bail!("Recording is too short to find its tempo");
+    }
+
+    // Weighted autocorrelation of the onset strengths, for every lag
+    // in range, plus one either side for interpolating the peak.
+    let score = |lag: usize| {
+        let n = onsets.len() - lag;
+        let corr = (0..n).map(|i| onsets[i] * onsets[i + lag]).sum::<f64>() / n as f64;
+        let octaves = (60.0 / (lag as f64 * hop) / constants::DETECT_PRIOR_TEMPO).log2();
+        corr * (-0.5 * octaves * octaves).exp()
+    };
+    let scores: Vec<f64> = (min_lag - 1..=max_lag + 1).map(score).collect();
+
+    let (best, &peak) = scores[1..scores.len() - 1]
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    if peak <= 0.0 {
+        bail!("Couldn't find a steady beat in the recording");
+    }
+
+    // Fit a parabola through the peak and its neighbours, to find the
+    // lag more finely than a whole frame.
+    let (before, after) = (scores[best], scores[best + 2]);
+    let curve = before - 2.0 * peak + after;
+    let offset = if curve < 0.0 {
+        0.5 * (before - after) / curve
+    } else {
+        0.0
+    };
+    // Detection isn't precise past a tenth of a BPM, so don't pretend
+    // it is.
+    let lag = (min_lag + best) as f64 + offset;
+    Ok((600.0 / (lag * hop)).round() / 10.0)
+}
+
+// Reads a recording and estimates its tempo.
+pub fn detect_file_tempo(path: &str) -> Result<f64> {
+    let sample = Sample::read(path)?;
+    match detect_tempo(&sample) {
+        Ok(tempo) => Ok(tempo),
+        Err(e) => bail!(format!("{}: {}", path, e)),
+    }
+}
+
+// The onset strength of each `frame_len`-sample frame of a recording:
+// the rise in log energy from the frame before, or 0 if it got
+// quieter. The strengths are smoothed a little, since an onset may
+// straddle two frames, and the average is taken off so that they
+// center on 0.
+fn onset_strengths(sample: &Sample, frame_len: usize) -> Vec<f64> {
+    let energies: Vec<f64> = sample
+        .data
+        .chunks_exact(frame_len)
+        .map(|frame| {
+            let energy: f64 = frame.iter().map(|&x| (x as f64) * (x as f64)).sum();
+            (energy / frame_len as f64 + 1e-10).ln()
+        })
+        .collect();
+
+    let rises: Vec<f64> = energies
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect();
+    let smoothed: Vec<f64> = rises
+        .windows(3)
+        .map(|w| 0.25 * w[0] + 0.5 * w[1] + 0.25 * w[2])
+        .collect();
+    let mean = smoothed.iter().sum::<f64>() / smoothed.len().max(1) as f64;
+    smoothed.iter().map(|x| x - mean).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A recording of short noise bursts at the given tempo.
+    fn clicks(tempo: f64, seconds: f64) -> Sample {
+        let rate = 8000;
+        let beat = 60.0 / tempo;
+        let mut noise = 1u32;
+        let data = (0..(seconds * rate as f64) as usize)
+            .map(|n| {
+                let t = n as f64 / rate as f64;
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                let level = if t % beat < 0.02 { 0.5 } else { 0.001 };
+                level * (noise as f32 / u32::MAX as f32 - 0.5)
+            })
+            .collect();
+        Sample { rate, data }
+    }
+
+    #[test]
+    fn detect_test() {
+        for &tempo in &[72.0, 100.0, 128.0, 160.0] {
+            let detected = detect_tempo(&clicks(tempo, 20.0)).unwrap();
+            assert!((detected - tempo).abs() < 1.0, "{} for {}", detected, tempo);
+        }
+
+        assert!(detect_tempo(&clicks(100.0, 1.0)).is_err());
+        let silence = Sample {
+            rate: 8000,
+            data: vec![0.0; 8000 * 20],
+        };
+        assert!(detect_tempo(&silence).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+// Tempo maps: the tempo changes made while playing, recorded against
+// the beat they came in on, so they can be replayed or exported.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use crate::errors::*;
+use crate::model_event::{ModelEvent, Subscriber, Transport};
+use error_chain::bail;
+use std::fs;
+
+// A list of tempo changes, each at a number of beats from the top
+// (after any count-in), in order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TempoMap {
+    points: Vec<(f64, f64)>,
+}
+
+impl TempoMap {
+    // Reads a tempo map file: one "<beat> <tempo>" pair per line, in
+    // order, with blank lines and lines starting with '#' skipped.
+    pub fn load(path: &str) -> Result<TempoMap> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => bail!(format!("Can't read tempo map {}: {}", path, e)),
+        };
+        match TempoMap::parse(&text) {
+            Ok(map) => Ok(map),
+            Err(e) => bail!(format!("Bad tempo map {}: {}", path, e)),
+        }
+    }
+
+    fn parse(text: &str) -> Result<TempoMap> {
+        let mut map = TempoMap::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let point = match fields[..] {
+                [beat, tempo] => (beat.parse::<f64>(), tempo.parse::<f64>()),
+                _ => bail!(format!("line {} isn't \"<beat> <tempo>\"", n + 1)),
+            };
+            let last = map.points.last().map_or(0.0, |&(beat, _)| beat);
+            match point {
+                (Ok(beat), Ok(tempo))
+                    if beat >= last
+                        && (constants::TEMPO_MIN..=constants::TEMPO_MAX).contains(&tempo) =>
+                {
+                    map.points.push((beat, tempo))
+                }
+                _ => bail!(format!(
+                    "line {} must have a beat no earlier than the last, \
+                     and a tempo from {} to {}",
+                    n + 1,
+                    constants::TEMPO_MIN,
+                    constants::TEMPO_MAX
+                )),
+            }
+        }
+
+        if map.points.is_empty() {
+            bail!("no tempo changes in it");
+        }
+        Ok(map)
+    }
+
+    // Renders the map in the format load() reads.
+    fn format(&self) -> String {
+        let mut text = String::from("# beat tempo\n");
+        for (beat, tempo) in &self.points {
+            text.push_str(&format!("{} {}\n", beat, tempo));
+        }
+        text
+    }
+
+    pub fn points(&self) -> &[(f64, f64)] {
+        &self.points
+    }
+
+    // The tempo at the given beat, or None before the first change.
+    pub fn tempo_at(&self, beat: f64) -> Option<f64> {
+        self.points
+            .iter()
+            .take_while(|(at, _)| *at <= beat)
+            .last()
+            .map(|&(_, tempo)| tempo)
+    }
+
+    // The tempo to change to by the given beat, if any of the changes
+    // from the `next`th on are due by then; `next` is moved past them.
+    // Changes are only made as they come up, so the tempo can still
+    // be changed by hand between them.
+    pub fn due(&self, beat: f64, next: &mut usize) -> Option<f64> {
+        let mut tempo = None;
+        while let Some(&(at, to)) = self.points.get(*next) {
+            if at > beat {
+                break;
+            }
+            tempo = Some(to);
+            *next += 1;
+        }
+        tempo
+    }
+}
+
+// Subscriber that records every tempo change into a tempo map file,
+// against the beat of the next tick, which is the first played at the
+// new tempo. Each take from the top replaces the last.
+pub struct TempoRecorder {
+    path: String,
+    map: TempoMap,
+
+    // The tempo, and whether a take has started.
+    tempo: f64,
+    recording: bool,
+
+    // Beats since the top up to the latest tick, that tick's index,
+    // and the number of ticks per measure and per beat then.
+    beats: f64,
+    last_tick: Option<usize>,
+    ticks: usize,
+    beat_len: u32,
+}
+
+impl TempoRecorder {
+    // Sets up to record into the given file, starting at the given
+    // tempo. The file is created (or emptied) right away, so a path
+    // that can't be written is caught before playing.
+    pub fn new(path: &str, tempo: f64) -> Result<TempoRecorder> {
+        if let Err(e) = fs::write(path, "") {
+            bail!(format!("Can't write tempo map {}: {}", path, e));
+        }
+        Ok(TempoRecorder {
+            path: path.to_string(),
+            map: TempoMap::default(),
+            tempo,
+            recording: false,
+            beats: 0.0,
+            last_tick: None,
+            ticks: 1,
+            beat_len: 1,
+        })
+    }
+
+    // The beat a tempo change made now takes effect on.
+    fn next_beat(&self) -> f64 {
+        match self.last_tick {
+            Some(_) => self.beats + 1.0 / self.beat_len as f64,
+            None => 0.0,
+        }
+    }
+
+    // Records the current tempo at the given beat, in place of any
+    // change already recorded there.
+    fn record(&mut self, beat: f64) {
+        match self.map.points.last_mut() {
+            Some(last) if last.0 == beat => last.1 = self.tempo,
+            _ => self.map.points.push((beat, self.tempo)),
+        }
+        // The whole map is rewritten each time, so the file is always
+        // complete, even if we're killed.
+        if let Err(e) = fs::write(&self.path, self.map.format()) {
+            log::warn!("Couldn't write tempo map {}: {}", self.path, e);
+        }
+    }
+}
+
+impl Subscriber for TempoRecorder {
+    fn notify(&mut self, event: &ModelEvent) {
+        match event {
+            ModelEvent::Transport(Transport::Start) => {
+                self.map = TempoMap::default();
+                self.recording = true;
+                self.beats = 0.0;
+                self.last_tick = None;
+                self.record(0.0);
+            }
+            ModelEvent::Tick {
+                tick,
+                ticks,
+                beat_len,
+                ..
+            } => {
+                if let Some(last) = self.last_tick {
+                    // A measure of one tick comes back to the same one.
+                    let gap = if *tick > last {
+                        tick - last
+                    } else {
+                        tick + self.ticks - last
+                    };
+                    self.beats += gap as f64 / self.beat_len as f64;
+                }
+                self.last_tick = Some(*tick);
+                self.ticks = *ticks;
+                self.beat_len = *beat_len;
+            }
+            ModelEvent::TempoChanged(tempo) => {
+                self.tempo = *tempo;
+                if self.recording {
+                    self.record(self.next_beat());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_spec::Event;
+
+    #[test]
+    fn tempo_map_test() {
+        let map = TempoMap::parse("# beat tempo\n0 120\n\n8.5 100\n8.5 90\n16 132\n").unwrap();
+        assert_eq!(map.tempo_at(8.0), Some(120.0));
+        assert_eq!(map.tempo_at(8.5), Some(90.0));
+        assert_eq!(map.tempo_at(100.0), Some(132.0));
+        assert_eq!(TempoMap::parse(&map.format()).unwrap(), map);
+
+        // Changes come up once each, the last of any due at once
+        // winning.
+        let mut next = 0;
+        assert_eq!(map.due(0.0, &mut next), Some(120.0));
+        assert_eq!(map.due(1.0, &mut next), None);
+        assert_eq!(map.due(10.0, &mut next), Some(90.0));
+        assert_eq!(next, 3);
+
+        let late = TempoMap::parse("4 100").unwrap();
+        assert_eq!(late.tempo_at(2.0), None);
+
+        assert!(TempoMap::parse("").is_err());
+        assert!(TempoMap::parse("0 120\n-1 100").is_err());
+        assert!(TempoMap::parse("0 120\n4 100\n2 90").is_err());
+        assert!(TempoMap::parse("0 fast").is_err());
+        assert!(TempoMap::parse("0 120 4").is_err());
+    }
+
+    #[test]
+    fn recorder_test() {
+        let path = std::env::temp_dir().join(format!("metronome-tempo-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut recorder = TempoRecorder::new(path, 100.0).unwrap();
+        let tick = |tick| ModelEvent::Tick {
+            tick,
+            ticks: 4,
+            beat_len: 2,
+            event: Event::Beep(1),
+            hand: None,
+        };
+
+        // Changes before starting only set the tempo to start with.
+        recorder.notify(&ModelEvent::TempoChanged(110.0));
+        recorder.notify(&ModelEvent::Transport(Transport::Start));
+        for n in [0, 1, 2, 3, 0] {
+            recorder.notify(&tick(n));
+        }
+        recorder.notify(&ModelEvent::TempoChanged(112.0));
+        recorder.notify(&ModelEvent::TempoChanged(114.0));
+        recorder.notify(&tick(1));
+        recorder.notify(&ModelEvent::TempoChanged(120.0));
+
+        let map = TempoMap::load(path).unwrap();
+        assert_eq!(map.points(), &[(0.0, 110.0), (2.5, 114.0), (3.0, 120.0)]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -17,7 +17,9 @@
 // along with Metronome. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::errors::*;
-use std::io::stdin;
+use error_chain::bail;
+use std::fs::OpenOptions;
+use std::io::{stdin, stdout};
 use std::os::unix::io::{AsRawFd, RawFd};
 use termios::Termios;
 
@@ -39,7 +41,7 @@ impl TermiosHandler {
     // Sets stdin to raw mode.
     pub fn set_stdin_raw() -> Result<TermiosHandler> {
         let stdin_fd = stdin().as_raw_fd();
-        let mut t = Termios::from_fd(stdin_fd).unwrap();
+        let mut t = Termios::from_fd(stdin_fd)?;
         let orig_termios = t.clone();
 
         termios::cfmakeraw(&mut t);
@@ -51,10 +53,43 @@ impl TermiosHandler {
     }
 }
 
+// Points stdout at /dev/null, so the display can carry on drawing
+// once the terminal has gone away, without anyone to see it.
+pub fn detach_stdout() -> Result<()> {
+    let null = OpenOptions::new().write(true).open("/dev/null")?;
+    // SAFETY: dup2() only touches the two file descriptors, both of
+    // which are open.
+    if unsafe { libc::dup2(null.as_raw_fd(), stdout().as_raw_fd()) } < 0 {
+        bail!("Couldn't redirect stdout");
+    }
+    Ok(())
+}
+
+// Gets the size of the terminal on stdout, in columns and rows.
+// Falls back to 80x24 if stdout isn't a terminal.
+pub fn terminal_size() -> (usize, usize) {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: TIOCGWINSZ only writes a winsize to the given pointer,
+    // which is valid for the duration of the call.
+    let res = unsafe { libc::ioctl(stdout().as_raw_fd(), libc::TIOCGWINSZ, &mut size) };
+    if res != 0 || size.ws_col == 0 || size.ws_row == 0 {
+        return (80, 24);
+    }
+
+    (size.ws_col as usize, size.ws_row as usize)
+}
+
 impl Drop for TermiosHandler {
     // Restore the termios to its prior state when this structure goes
-    // out of scope.
+    // out of scope. The terminal may be gone by now, in which case
+    // there's nothing to restore.
     fn drop(&mut self) {
-        termios::tcsetattr(self.fd, termios::TCSANOW, &self.orig).unwrap();
+        let _ = termios::tcsetattr(self.fd, termios::TCSANOW, &self.orig);
     }
 }
@@ -0,0 +1,145 @@
+// Tracks the metronome's ticks as they're shown, so that views can move
+// smoothly between them however few ticks there are in a measure.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::model_event::{ModelEvent, Transport};
+use std::time::Instant;
+
+// Where the metronome is in the measure, worked out from the ticks
+// published on the bus and the time since the last of them.
+#[derive(Debug, Clone, Default)]
+pub struct TickClock {
+    // When the current tick was shown, or None if the metronome has
+    // stopped or hasn't ticked yet.
+    started: Option<Instant>,
+
+    // The current tick, and the number of ticks per measure and per
+    // beat; no ticks per measure until the first tick.
+    tick: usize,
+    ticks: usize,
+    beat_len: u32,
+
+    // The tempo in beats per minute, which sets the length of a tick.
+    tempo: f64,
+
+    // How far through the current tick the metronome stopped.
+    stopped_at: f64,
+}
+
+impl TickClock {
+    pub fn new() -> TickClock {
+        TickClock::default()
+    }
+
+    // Follows an event published at the given time.
+    pub fn notify(&mut self, event: &ModelEvent, now: Instant) {
+        match event {
+            ModelEvent::Tick {
+                tick,
+                ticks,
+                beat_len,
+                ..
+            } => {
+                self.started = Some(now);
+                self.tick = *tick;
+                self.ticks = *ticks;
+                self.beat_len = *beat_len;
+                self.stopped_at = 0.0;
+            }
+            ModelEvent::TempoChanged(tempo) => self.tempo = *tempo,
+            ModelEvent::Transport(Transport::Stop) => {
+                self.stopped_at = self.tick_progress(now);
+                self.started = None;
+            }
+            _ => {}
+        }
+    }
+
+    // How far through the current tick the metronome is, from 0 up
+    // to (but not reaching) 1. The next tick may be late, so this
+    // waits at the end of the tick rather than running past it.
+    fn tick_progress(&self, now: Instant) -> f64 {
+        let started = match self.started {
+            Some(started) => started,
+            None => return self.stopped_at,
+        };
+        if self.tempo <= 0.0 || self.beat_len == 0 {
+            return 0.0;
+        }
+
+        let tick_len = 60.0 / self.tempo / self.beat_len as f64;
+        let elapsed = now.saturating_duration_since(started).as_secs_f64();
+        (elapsed / tick_len).min(1.0 - f64::EPSILON)
+    }
+
+    // Progress through the measure, from 0 to 1.
+    pub fn measure_progress(&self, now: Instant) -> f64 {
+        if self.ticks == 0 {
+            return 0.0;
+        }
+        (self.tick as f64 + self.tick_progress(now)) / self.ticks as f64
+    }
+
+    // The current beat, counting from 0, and the progress through it
+    // from 0 to 1; None before the first tick.
+    pub fn beat(&self, now: Instant) -> Option<(usize, f64)> {
+        if self.ticks == 0 {
+            return None;
+        }
+        let beat_len = self.beat_len as usize;
+        let progress = ((self.tick % beat_len) as f64 + self.tick_progress(now)) / beat_len as f64;
+        Some((self.tick / beat_len, progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beat_spec::Event;
+    use std::time::Duration;
+
+    #[test]
+    fn tick_clock_test() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let tick = |tick| ModelEvent::Tick {
+            tick,
+            ticks: 4,
+            beat_len: 1,
+            event: Event::Beep(1),
+            hand: None,
+        };
+
+        // A beat at 120 BPM lasts half a second.
+        let mut clock = TickClock::new();
+        assert_eq!(clock.beat(start), None);
+        clock.notify(&ModelEvent::TempoChanged(120.0), start);
+        clock.notify(&tick(1), start);
+        assert_eq!(clock.measure_progress(at(250)), 0.375);
+        assert_eq!(clock.beat(at(250)), Some((1, 0.5)));
+
+        // A late tick doesn't run into the next one.
+        assert!(clock.measure_progress(at(900)) < 0.5);
+
+        // Stopping holds the clock where it was.
+        clock.notify(&ModelEvent::Transport(Transport::Stop), at(125));
+        assert_eq!(clock.measure_progress(at(5000)), 0.3125);
+        clock.notify(&tick(0), at(6000));
+        assert_eq!(clock.measure_progress(at(6000)), 0.0);
+    }
+}
@@ -0,0 +1,230 @@
+// Strict timing and the watchdog: watch how late clicks are played,
+// to warn the user when the machine can't keep up rather than letting
+// the click get sloppy unnoticed.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::constants;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Keeps track of how late recent ticks were played.
+pub struct TimingMonitor {
+    // How late each of the last STRICT_WINDOW ticks was, oldest
+    // first.
+    recent: VecDeque<Duration>,
+
+    // Whether we've warned about the ticks in the window already.
+    warned: bool,
+}
+
+impl TimingMonitor {
+    pub fn new() -> TimingMonitor {
+        TimingMonitor {
+            recent: VecDeque::new(),
+            warned: false,
+        }
+    }
+
+    // Records how late a tick was played. Returns the worst lateness
+    // in the window if too many recent ticks were late, the first time
+    // that happens; after that, we stay quiet until a whole window of
+    // ticks has been on time again.
+    pub fn record(&mut self, lateness: Duration) -> Option<Duration> {
+        if self.recent.len() == constants::STRICT_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(lateness);
+
+        let max_jitter = Duration::from_millis(constants::STRICT_MAX_JITTER);
+        let late = self.recent.iter().filter(|&&l| l > max_jitter).count();
+        if late == 0 {
+            self.warned = false;
+        }
+        if self.warned || late < constants::STRICT_LATE_TICKS {
+            return None;
+        }
+
+        self.warned = true;
+        self.recent.iter().max().copied()
+    }
+}
+
+impl Default for TimingMonitor {
+    fn default() -> TimingMonitor {
+        TimingMonitor::new()
+    }
+}
+
+// Something the main loop spent time on.
+struct Activity {
+    what: &'static str,
+    started: Instant,
+    ended: Instant,
+}
+
+// Counts the ticks played too late, and remembers what the main loop
+// was doing last, so each one can be logged with enough detail to
+// tell a busy loop from a late wakeup.
+pub struct Watchdog {
+    // Number of ticks played late or skipped so far.
+    missed: u32,
+
+    // The last thing the main loop did.
+    last: Option<Activity>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            missed: 0,
+            last: None,
+        }
+    }
+
+    // Number of ticks played late or skipped so far.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    // Records that the main loop spent from `started` until now on
+    // `what`.
+    pub fn done(&mut self, what: &'static str, started: Instant) {
+        self.last = Some(Activity {
+            what,
+            started,
+            ended: Instant::now(),
+        });
+    }
+
+    // Checks a tick that was due at `due` and played at `played`,
+    // after skipping `skipped` ticks in a stall. Returns what went
+    // wrong, for the log, if the tick was late.
+    pub fn check(&mut self, due: Instant, played: Instant, skipped: u32) -> Option<String> {
+        let lateness = played.saturating_duration_since(due);
+        if lateness <= Duration::from_millis(constants::WATCHDOG_LATE_MS) && skipped == 0 {
+            return None;
+        }
+        self.missed += 1 + skipped;
+
+        let mut report = format!("Tick played {} ms late", lateness.as_millis());
+        if skipped > 0 {
+            report += &format!(", after skipping {} ticks", skipped);
+        }
+        match &self.last {
+            Some(last) if last.ended > due => {
+                report += &format!(
+                    "; the loop was busy {} for {} ms, until {} ms after it was due",
+                    last.what,
+                    last.ended.duration_since(last.started).as_millis(),
+                    last.ended.duration_since(due).as_millis()
+                );
+            }
+            Some(last) => {
+                report += &format!(
+                    "; the loop had been idle since {} finished {} ms before it was due, \
+                     and woke up late",
+                    last.what,
+                    due.duration_since(last.ended).as_millis()
+                );
+            }
+            None => {}
+        }
+        Some(report)
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn monitor_test() {
+        let on_time = Duration::from_millis(1);
+        let late = Duration::from_millis(constants::STRICT_MAX_JITTER + 10);
+        let mut monitor = TimingMonitor::new();
+
+        // The odd late tick is forgiven.
+        for _ in 0..constants::STRICT_WINDOW * 2 {
+            assert_eq!(monitor.record(on_time), None);
+        }
+        for _ in 1..constants::STRICT_LATE_TICKS {
+            assert_eq!(monitor.record(late), None);
+        }
+
+        // One too many warns, once.
+        assert_eq!(monitor.record(late), Some(late));
+        assert_eq!(monitor.record(late), None);
+
+        // Once things have settled down for a whole window, it can
+        // warn again.
+        for _ in 0..constants::STRICT_WINDOW {
+            assert_eq!(monitor.record(on_time), None);
+        }
+        for _ in 1..constants::STRICT_LATE_TICKS {
+            assert_eq!(monitor.record(late), None);
+        }
+        assert_eq!(monitor.record(late), Some(late));
+    }
+
+    #[test]
+    fn watchdog_test() {
+        let mut watchdog = Watchdog::new();
+        let due = Instant::now();
+        let threshold = Duration::from_millis(constants::WATCHDOG_LATE_MS);
+
+        // On time, or near enough.
+        assert_eq!(watchdog.check(due, due, 0), None);
+        assert_eq!(watchdog.check(due, due + threshold, 0), None);
+        assert_eq!(watchdog.missed(), 0);
+
+        // Late, with nothing to blame.
+        let late = due + threshold * 2;
+        let report = watchdog.check(due, late, 0).unwrap();
+        assert!(report.starts_with(&format!(
+            "Tick played {} ms late",
+            (threshold * 2).as_millis()
+        )));
+        assert_eq!(watchdog.missed(), 1);
+
+        // Skipped ticks count as missed too.
+        let report = watchdog.check(due, late, 3).unwrap();
+        assert!(report.contains("after skipping 3 ticks"));
+        assert_eq!(watchdog.missed(), 5);
+
+        // Something that ran past the due time is blamed; something
+        // that finished before it isn't.
+        watchdog.done("drawing", due - threshold);
+        watchdog.last.as_mut().unwrap().ended = due + threshold;
+        assert!(watchdog
+            .check(due, late, 0)
+            .unwrap()
+            .contains("busy drawing"));
+        watchdog.last.as_mut().unwrap().ended = due - threshold;
+        assert!(watchdog
+            .check(due, late, 0)
+            .unwrap()
+            .contains("idle since drawing"));
+        assert_eq!(watchdog.missed(), 7);
+    }
+}
@@ -0,0 +1,235 @@
+// Reading and writing of WAV files, for playing recorded sounds in
+// place of the generated ones, and for rendering click tracks.
+// Copyright (c) 2021 by Alexander Bethel.
+
+// This file is part of Metronome.
+
+// Metronome is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published
+// by the Free Software Foundation, either version 3 of the License,
+// or (at your option) any later version.
+
+// Metronome is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Metronome. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::errors::*;
+use error_chain::bail;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+// A mono recording, with samples from -1 to 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    // Samples per second.
+    pub rate: u32,
+
+    pub data: Vec<f32>,
+}
+
+impl Sample {
+    // Reads a WAV file, mixing its channels down to mono.
+    pub fn read(path: &str) -> Result<Sample> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => bail!(format!("Can't read {}: {}", path, e)),
+        };
+        match parse(&bytes) {
+            Ok(sample) => Ok(sample),
+            Err(e) => bail!(format!("Can't read {}: {}", path, e)),
+        }
+    }
+
+    // Length of the recording.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.data.len() as f64 / self.rate as f64)
+    }
+
+    // The recording's level `t` seconds in, interpolating between
+    // samples; silence outside the recording.
+    pub fn at(&self, t: f64) -> f64 {
+        let pos = t * self.rate as f64;
+        if pos < 0.0 {
+            return 0.0;
+        }
+        let n = pos as usize;
+        let frac = pos - n as f64;
+        let get = |n: usize| self.data.get(n).map_or(0.0, |&x| x as f64);
+        get(n) * (1.0 - frac) + get(n + 1) * frac
+    }
+}
+
+// Writes interleaved samples from -1 to 1 to a 16-bit PCM WAV file.
+pub fn write(path: &str, rate: u32, channels: u16, samples: &[f32]) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header(rate, channels, samples.len()))?;
+    for &sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+// The header of a 16-bit PCM WAV file holding the given number of
+// samples, up to the start of the samples themselves.
+fn header(rate: u32, channels: u16, samples: usize) -> Vec<u8> {
+    let data_len = samples as u32 * 2;
+    let mut bytes = b"RIFF".to_vec();
+    bytes.extend(&(36 + data_len).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(&16u32.to_le_bytes());
+    bytes.extend(&1u16.to_le_bytes());
+    bytes.extend(&channels.to_le_bytes());
+    bytes.extend(&rate.to_le_bytes());
+    bytes.extend(&(rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend(&(channels * 2).to_le_bytes());
+    bytes.extend(&16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(&data_len.to_le_bytes());
+    bytes
+}
+
+// Reads a little-endian integer of `len` bytes from the start of
+// `bytes`.
+fn read_le(bytes: &[u8], len: usize) -> Result<u32> {
+    match bytes.get(..len) {
+        Some(bytes) => Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, &byte| (acc << 8) | byte as u32)),
+        None => bail!("file is cut short"),
+    }
+}
+
+// Parses the contents of a WAV file. PCM files of 8, 16, 24 or 32 bits
+// and 32-bit float files are understood.
+fn parse(bytes: &[u8]) -> Result<Sample> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        bail!("not a WAV file");
+    }
+
+    // Format code, channels, sample rate and bits per sample.
+    let mut format = None;
+    let mut data = None;
+    let mut rest = &bytes[12..];
+    while rest.len() >= 8 {
+        let id = &rest[..4];
+        let len = read_le(&rest[4..], 4)? as usize;
+        let body = match rest.get(8..8 + len) {
+            Some(body) => body,
+            None => bail!("file is cut short"),
+        };
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    bail!("format chunk is too short");
+                }
+                let mut code = read_le(body, 2)?;
+                if code == 0xFFFE {
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real format
+                    // code at the start of its subformat GUID.
+                    code = read_le(body.get(24..).unwrap_or(&[]), 2)?;
+                }
+                format = Some((
+                    code,
+                    read_le(&body[2..], 2)? as usize,
+                    read_le(&body[4..], 4)?,
+                    read_le(&body[14..], 2)? as usize,
+                ));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        rest = &rest[(8 + len + len % 2).min(rest.len())..];
+    }
+
+    let (code, channels, rate, bits) = match format {
+        Some(format) => format,
+        None => bail!("no format chunk"),
+    };
+    let data = match data {
+        Some(data) => data,
+        None => bail!("no data chunk"),
+    };
+    if channels == 0 || rate == 0 {
+        bail!("no channels");
+    }
+
+    let width = bits / 8;
+    let decode: fn(&[u8]) -> f32 = match (code, bits) {
+        (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+        (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+        (1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.0,
+        (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        _ => bail!(format!("unsupported format {} with {} bits", code, bits)),
+    };
+
+    let data = data
+        .chunks_exact(width * channels)
+        .map(|frame| frame.chunks(width).map(decode).sum::<f32>() / channels as f32)
+        .collect();
+    Ok(Sample { rate, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a WAV file of 16-bit samples.
+    fn wav(channels: u16, rate: u32, samples: &[i16]) -> Vec<u8> {
+        let mut bytes = header(rate, channels, samples.len());
+        bytes.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn parse_test() {
+        let sample = parse(&wav(1, 8000, &[0, 16384, -32768])).unwrap();
+        assert_eq!(sample.rate, 8000);
+        assert_eq!(sample.data, vec![0.0, 0.5, -1.0]);
+
+        // Stereo is mixed down to mono.
+        let sample = parse(&wav(2, 44100, &[16384, 0, -16384, -16384])).unwrap();
+        assert_eq!(sample.data, vec![0.25, -0.5]);
+
+        assert!(parse(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(parse(b"not a wav file").is_err());
+    }
+
+    #[test]
+    fn write_test() {
+        let path = std::env::temp_dir().join(format!("metronome-wav-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        write(path, 8000, 2, &[0.5, 0.5, -1.0, -1.0, 2.0, 2.0]).unwrap();
+        let sample = Sample::read(path);
+        std::fs::remove_file(path).unwrap();
+
+        let sample = sample.unwrap();
+        assert_eq!(sample.rate, 8000);
+        assert_eq!(sample.data.len(), 3);
+        assert!((sample.data[0] - 0.5).abs() < 1e-4);
+        assert!((sample.data[1] + 1.0).abs() < 1e-4);
+        assert!((sample.data[2] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn at_test() {
+        let sample = Sample {
+            rate: 2,
+            data: vec![0.0, 1.0],
+        };
+        assert_eq!(sample.duration(), Duration::from_secs(1));
+        assert_eq!(sample.at(0.25), 0.5);
+        assert_eq!(sample.at(0.5), 1.0);
+        assert_eq!(sample.at(-1.0), 0.0);
+        assert_eq!(sample.at(5.0), 0.0);
+    }
+}